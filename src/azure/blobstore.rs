@@ -14,7 +14,8 @@
 // limitations under the License.
 
 use crate::azure::credentials::*;
-use futures::{Future, Stream};
+use crate::cache::{SpooledEntry, SPOOL_CHUNK_SIZE};
+use futures::Future;
 use hmac::{Hmac, Mac, NewMac};
 use hyper::header::HeaderValue;
 use hyper::Method;
@@ -43,10 +44,12 @@ fn signature(to_sign: &str, secret: &str) -> String {
     base64::encode_config(&sig, base64::STANDARD)
 }
 
-fn md5(data: &[u8]) -> String {
+fn md5(entry: &SpooledEntry) -> Result<String> {
     let mut digest = Md5::new();
-    digest.update(data);
-    base64::encode_config(&digest.finalize(), base64::STANDARD)
+    for chunk in entry.chunks(SPOOL_CHUNK_SIZE)? {
+        digest.update(&chunk?);
+    }
+    Ok(base64::encode_config(digest.finalize(), base64::STANDARD))
 }
 
 pub struct BlobContainer {
@@ -72,9 +75,18 @@ impl BlobContainer {
         })
     }
 
-    pub fn get(&self, key: &str, creds: &AzureCredentials) -> SFuture<Vec<u8>> {
-        let url_string = format!("{}{}", self.url, key);
-        let uri = Url::from_str(&url_string).unwrap();
+    /// The URL of the blob `key`, with the SAS token from `creds`, if any.
+    fn blob_url(&self, key: &str, creds: &AzureCredentials) -> Url {
+        let mut url_string = format!("{}{}", self.url, key);
+        if let Some(ref sas_token) = *creds.azure_sas_token() {
+            url_string.push('?');
+            url_string.push_str(sas_token);
+        }
+        Url::from_str(&url_string).unwrap()
+    }
+
+    pub fn get(&self, key: &str, creds: &AzureCredentials) -> SFuture<SpooledEntry> {
+        let uri = self.blob_url(key, creds);
         let dt = chrono::Utc::now();
         let date = format!("{}", dt.format("%a, %d %b %Y %T GMT"));
 
@@ -124,36 +136,35 @@ impl BlobContainer {
                     }
                 })
                 .and_then(|(body, content_length)| {
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .fcontext("failed to read HTTP body")
-                    .and_then(move |bytes| {
-                        if let Some(len) = content_length {
-                            if len != bytes.len() as u64 {
-                                bail!(format!(
-                                    "Bad HTTP body size read: {}, expected {}",
-                                    bytes.len(),
-                                    len
-                                ));
-                            } else {
-                                info!("Read {} bytes from {}", bytes.len(), uri_second_copy);
+                    SpooledEntry::from_stream(body)
+                        .fcontext("failed to read HTTP body")
+                        .and_then(move |entry| {
+                            if let Some(len) = content_length {
+                                if len != entry.len() {
+                                    bail!(format!(
+                                        "Bad HTTP body size read: {}, expected {}",
+                                        entry.len(),
+                                        len
+                                    ));
+                                } else {
+                                    info!("Read {} bytes from {}", entry.len(), uri_second_copy);
+                                }
                             }
-                        }
-                        Ok(bytes)
-                    })
+                            Ok(entry)
+                        })
                 }),
         )
     }
 
-    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AzureCredentials) -> SFuture<()> {
-        let url_string = format!("{}{}", self.url, key);
-        let uri = Url::from_str(&url_string).unwrap();
+    pub fn put(&self, key: &str, content: SpooledEntry, creds: &AzureCredentials) -> SFuture<()> {
+        let uri = self.blob_url(key, creds);
         let dt = chrono::Utc::now();
         let date = format!("{}", dt.format("%a, %d %b %Y %T GMT"));
         let content_type = "application/octet-stream";
-        let content_md5 = md5(&content);
+        let content_md5 = match md5(&content) {
+            Ok(md5) => md5,
+            Err(e) => return f_err(e),
+        };
 
         let content_length = if content.is_empty() {
             "".to_owned()
@@ -182,7 +193,7 @@ impl BlobContainer {
             .set(header::ContentType(content_type.parse().unwrap()));
         request
             .headers_mut()
-            .set(header::ContentLength(content.len() as u64));
+            .set(header::ContentLength(content.len()));
         request
             .headers_mut()
             .insert("x-ms-blob-type", HeaderValue::from_static("BlockBlob"));
@@ -204,7 +215,10 @@ impl BlobContainer {
             HeaderValue::from_str(&content_md5).expect("Invalid Content-MD5 header"),
         );
 
-        *request.body_mut() = Some(content.into());
+        match content.into_stream() {
+            Ok(body) => *request.body_mut() = Some(body.into()),
+            Err(e) => return f_err(e),
+        }
 
         Box::new(self.client.execute(request).then(|result| match result {
             Ok(res) => {
@@ -222,6 +236,55 @@ impl BlobContainer {
             }
         }))
     }
+
+    pub fn delete(&self, key: &str, creds: &AzureCredentials) -> SFuture<()> {
+        let uri = self.blob_url(key, creds);
+        let dt = chrono::Utc::now();
+        let date = format!("{}", dt.format("%a, %d %b %Y %T GMT"));
+
+        let canonical_headers = format!("x-ms-date:{}\nx-ms-version:{}\n", date, BLOB_API_VERSION);
+
+        let auth = compute_auth_header(
+            "DELETE",
+            "", // content_length
+            "", // content_md5
+            "", // content_type
+            &canonical_headers,
+            &uri,
+            creds,
+        );
+
+        let mut request = Request::new(Method::DELETE, uri);
+        request.headers_mut().insert(
+            "x-ms-date",
+            HeaderValue::from_str(&date).expect("Invalid x-ms-date header"),
+        );
+        request
+            .headers_mut()
+            .insert("x-ms-version", HeaderValue::from_static(BLOB_API_VERSION));
+        if let Some(auth) = auth {
+            request.headers_mut().insert(
+                "Authorization",
+                HeaderValue::from_str(&auth).expect("Invalid Authorization header"),
+            );
+        }
+
+        Box::new(self.client.execute(request).then(|result| match result {
+            Ok(res) => {
+                if res.status().is_success() {
+                    trace!("DELETE succeeded");
+                    Ok(())
+                } else {
+                    trace!("DELETE failed with HTTP status: {}", res.status());
+                    Err(BadHttpStatusError(res.status()).into())
+                }
+            }
+            Err(e) => {
+                trace!("DELETE failed with error: {:?}", e);
+                Err(e.into())
+            }
+        }))
+    }
 }
 
 fn compute_auth_header(
@@ -254,6 +317,10 @@ fn compute_auth_header(
            CanonicalizedHeaders + // CanonicalizedHeaders is defined to end with "\n"
            CanonicalizedResource;
     */
+    // Requests with a SAS token are authorized by its signature instead.
+    if creds.azure_sas_token().is_some() {
+        return None;
+    }
     creds.azure_account_key().as_ref().map(|account_key| {
         let canonical_resource = canonicalize_resource(uri, creds.azure_account_name());
         let string_to_sign = format!("{verb}\n\n\n{length}\n{md5}\n{type}\n\n\n\n\n\n\n{headers}{resource}",
@@ -285,6 +352,11 @@ fn canonicalize_resource(uri: &Url, account_name: &str) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use futures::{future, Stream};
+    use hyper::service::service_fn;
+    use hyper::{Body, Response, Server, StatusCode};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
     use tokio_compat::runtime::current_thread::Runtime;
 
     #[test]
@@ -309,6 +381,70 @@ mod test {
         assert_eq!("/testaccount/container/key", &canon);
     }
 
+    #[test]
+    fn test_sas_token() {
+        let blobs = Arc::new(Mutex::new(HashMap::new()));
+        let mut runtime = Runtime::new().unwrap();
+        let make_service = {
+            let blobs = blobs.clone();
+            move || {
+                let blobs = blobs.clone();
+                service_fn(move |req: hyper::Request<Body>| {
+                    let blobs = blobs.clone();
+                    // Requests are authorized by the token alone.
+                    let authorized = req.uri().query() == Some("sv=2020-08-04&sp=rw&sig=abc")
+                        && !req.headers().contains_key("Authorization");
+                    let method = req.method().clone();
+                    let path = req.uri().path().to_owned();
+                    req.into_body().concat2().map(move |body| {
+                        let mut blobs = blobs.lock().unwrap();
+                        let status = match method {
+                            _ if !authorized => StatusCode::FORBIDDEN,
+                            Method::PUT => {
+                                blobs.insert(path, body.to_vec());
+                                StatusCode::CREATED
+                            }
+                            Method::GET if blobs.contains_key(&path) => {
+                                return Response::new(Body::from(blobs[&path].clone()));
+                            }
+                            Method::DELETE if blobs.remove(&path).is_some() => StatusCode::ACCEPTED,
+                            _ => StatusCode::NOT_FOUND,
+                        };
+                        let mut res = Response::new(Body::empty());
+                        *res.status_mut() = status;
+                        res
+                    })
+                })
+            }
+        };
+        let server = runtime
+            .block_on(future::lazy(|| {
+                future::ok::<_, ()>(Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service))
+            }))
+            .unwrap();
+        let blob_endpoint = format!("http://{}/account/", server.local_addr());
+        runtime.spawn(server.map_err(|e| panic!("HTTP server failed: {}", e)));
+
+        let mut creds = AzureCredentials::new(&blob_endpoint, "", None, "sccache".to_owned());
+        creds.set_sas_token("?sv=2020-08-04&sp=rw&sig=abc");
+        let container = BlobContainer::new(creds.azure_blob_endpoint(), "sccache").unwrap();
+
+        let content = SpooledEntry::from_reader(&mut &b"barbell"[..]).unwrap();
+        runtime
+            .block_on(future::lazy(|| container.put("foo", content, &creds)))
+            .unwrap();
+        assert!(blobs.lock().unwrap().contains_key("/account/sccache/foo"));
+        let result = runtime
+            .block_on(future::lazy(|| container.get("foo", &creds)))
+            .unwrap();
+        assert_eq!(b"barbell".to_vec(), result.into_vec().unwrap());
+
+        runtime
+            .block_on(future::lazy(|| container.delete("foo", &creds)))
+            .unwrap();
+        assert!(blobs.lock().unwrap().is_empty());
+    }
+
     #[test]
     #[ignore]
     fn test_put_blob() {
@@ -342,12 +478,13 @@ mod test {
 
         let container = BlobContainer::new(creds.azure_blob_endpoint(), container_name).unwrap();
 
-        let put_future = container.put("foo", b"barbell".to_vec(), &creds);
+        let content = SpooledEntry::from_reader(&mut &b"barbell"[..]).unwrap();
+        let put_future = container.put("foo", content, &creds);
         runtime.block_on(put_future).unwrap();
 
         let get_future = container.get("foo", &creds);
         let result = runtime.block_on(get_future).unwrap();
 
-        assert_eq!(b"barbell".to_vec(), result);
+        assert_eq!(b"barbell".to_vec(), result.into_vec().unwrap());
     }
 }
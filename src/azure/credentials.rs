@@ -13,7 +13,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::env::*;
+use crate::config::AzureCacheConfig;
 
 use crate::errors::*;
 
@@ -23,6 +23,8 @@ pub struct AzureCredentials {
     account_name: String,
     /// Account key can be omitted to enable anonymous reads.
     account_key: Option<String>,
+    /// A shared access signature, used instead of the account key if set.
+    sas_token: Option<String>,
     container_name: String,
 }
 
@@ -43,10 +45,30 @@ impl AzureCredentials {
             blob_endpoint: endpoint,
             account_name: account_name.to_owned(),
             account_key,
+            sas_token: None,
             container_name,
         }
     }
 
+    /// Get the credentials for the container in `config`, from its
+    /// connection string or its blob endpoint and SAS token.
+    pub fn from_config(config: &AzureCacheConfig) -> Result<AzureCredentials> {
+        if config.container.is_empty() {
+            bail!("No Azure blob container configured");
+        }
+        let mut creds = match (&config.connection_string, &config.endpoint) {
+            (Some(conn), _) => parse_connection_string(conn, config.container.clone())?,
+            (None, Some(endpoint)) => {
+                AzureCredentials::new(endpoint, "", None, config.container.clone())
+            }
+            (None, None) => bail!("Azure needs either a connection string or a blob endpoint"),
+        };
+        if let Some(ref sas_token) = config.sas_token {
+            creds.set_sas_token(sas_token);
+        }
+        Ok(creds)
+    }
+
     pub fn azure_blob_endpoint(&self) -> &str {
         &self.blob_endpoint
     }
@@ -59,31 +81,34 @@ impl AzureCredentials {
         &self.account_key
     }
 
+    pub fn azure_sas_token(&self) -> &Option<String> {
+        &self.sas_token
+    }
+
     pub fn blob_container_name(&self) -> &str {
         &self.container_name
     }
-}
-
-pub trait AzureCredentialsProvider {
-    fn provide_credentials(&self) -> Result<AzureCredentials>;
-}
-
-pub struct EnvironmentProvider;
 
-impl AzureCredentialsProvider for EnvironmentProvider {
-    fn provide_credentials(&self) -> Result<AzureCredentials> {
-        credentials_from_environment()
+    /// Authenticate with the shared access signature `sas_token`, which may
+    /// start with the `?` of the query string it's appended to.
+    pub fn set_sas_token(&mut self, sas_token: &str) {
+        self.sas_token = Some(sas_token.trim_start_matches('?').to_owned());
     }
-}
-
-fn credentials_from_environment() -> Result<AzureCredentials> {
-    let env_conn_str = var("SCCACHE_AZURE_CONNECTION_STRING")
-        .context("No SCCACHE_AZURE_CONNECTION_STRING in environment")?;
-
-    let container_name = var("SCCACHE_AZURE_BLOB_CONTAINER")
-        .context("No SCCACHE_AZURE_BLOB_CONTAINER in environment")?;
 
-    parse_connection_string(&env_conn_str, container_name)
+    /// Whether these credentials may write blobs. A SAS token only may if its
+    /// signed permissions (`sp`) include write or create; one that refers to
+    /// a stored access policy instead is assumed to.
+    pub fn can_write(&self) -> bool {
+        let sas_token = match self.sas_token {
+            Some(ref sas_token) => sas_token,
+            None => return true,
+        };
+        sas_token
+            .split('&')
+            .find(|param| param.starts_with("sp="))
+            .map(|param| param["sp=".len()..].contains(['w', 'c']))
+            .unwrap_or(true)
+    }
 }
 
 fn parse_connection_string(conn: &str, container_name: String) -> Result<AzureCredentials> {
@@ -91,6 +116,7 @@ fn parse_connection_string(conn: &str, container_name: String) -> Result<AzureCr
     let mut default_endpoint_protocol: String = "https".to_owned();
     let mut account_name = String::default();
     let mut account_key = None;
+    let mut sas_token = None;
     let mut endpoint_suffix = String::default();
 
     let split = conn.split(';');
@@ -115,6 +141,11 @@ fn parse_connection_string(conn: &str, container_name: String) -> Result<AzureCr
             continue;
         }
 
+        if part.starts_with("SharedAccessSignature=") {
+            sas_token = Some(substr(part, "SharedAccessSignature=".len()).to_owned());
+            continue;
+        }
+
         if part.starts_with("EndpointSuffix=") {
             endpoint_suffix = substr(part, "EndpointSuffix=".len()).to_owned();
         }
@@ -134,7 +165,8 @@ fn parse_connection_string(conn: &str, container_name: String) -> Result<AzureCr
         }
     }
 
-    if blob_endpoint.is_empty() || account_name.is_empty() {
+    // Only requests signed with the account key need the account name.
+    if blob_endpoint.is_empty() || (account_name.is_empty() && sas_token.is_none()) {
         bail!("Azure connection string missing at least one of BlobEndpoint (or DefaultEndpointProtocol and EndpointSuffix), or AccountName.");
     }
 
@@ -142,12 +174,12 @@ fn parse_connection_string(conn: &str, container_name: String) -> Result<AzureCr
         blob_endpoint = format!("{}://{}", default_endpoint_protocol, blob_endpoint);
     }
 
-    Ok(AzureCredentials::new(
-        &blob_endpoint,
-        &account_name,
-        account_key,
-        container_name,
-    ))
+    let mut creds =
+        AzureCredentials::new(&blob_endpoint, &account_name, account_key, container_name);
+    if let Some(sas_token) = sas_token {
+        creds.set_sas_token(&sas_token);
+    }
+    Ok(creds)
 }
 
 fn substr(text: &str, to_skip: usize) -> &str {
@@ -189,6 +221,50 @@ mod test {
         assert_eq!("container", creds.blob_container_name());
     }
 
+    #[test]
+    fn test_parse_connection_string_with_sas() {
+        let conn = "BlobEndpoint=https://foo.blob.core.windows.net/;SharedAccessSignature=sv=2020-08-04&ss=b&srt=co&sp=rl&se=2030-01-01T00:00:00Z&sig=abc%3D";
+
+        let creds = parse_connection_string(conn, "container".to_string()).unwrap();
+        assert_eq!(
+            "https://foo.blob.core.windows.net/",
+            creds.azure_blob_endpoint()
+        );
+        assert!(creds.azure_account_key().is_none());
+        assert_eq!(
+            "sv=2020-08-04&ss=b&srt=co&sp=rl&se=2030-01-01T00:00:00Z&sig=abc%3D",
+            creds.azure_sas_token().as_ref().unwrap()
+        );
+        assert!(!creds.can_write());
+    }
+
+    #[test]
+    fn test_credentials_from_config() {
+        let config = AzureCacheConfig {
+            connection_string: None,
+            endpoint: Some("https://foo.blob.core.windows.net".to_owned()),
+            sas_token: Some("?sv=2020-08-04&sp=racw&sig=abc".to_owned()),
+            container: "container".to_owned(),
+            key_prefix: String::new(),
+        };
+        let creds = AzureCredentials::from_config(&config).unwrap();
+        assert_eq!(
+            "https://foo.blob.core.windows.net/",
+            creds.azure_blob_endpoint()
+        );
+        assert_eq!(
+            "sv=2020-08-04&sp=racw&sig=abc",
+            creds.azure_sas_token().as_ref().unwrap()
+        );
+        assert!(creds.can_write());
+
+        let config = AzureCacheConfig {
+            endpoint: None,
+            ..config
+        };
+        assert!(AzureCredentials::from_config(&config).is_err());
+    }
+
     #[test]
     fn test_conn_str_with_endpoint_suffix_only() {
         let conn = "DefaultEndpointsProtocol=https;AccountName=foo;EndpointSuffix=core.windows.net;AccountKey=bar;";
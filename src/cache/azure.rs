@@ -16,8 +16,8 @@
 use crate::azure::BlobContainer;
 use crate::azure::*;
 use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::config::AzureCacheConfig;
 use futures::future::Future;
-use std::io;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
@@ -26,14 +26,14 @@ use crate::errors::*;
 pub struct AzureBlobCache {
     container: Rc<BlobContainer>,
     credentials: AzureCredentials,
+    /// Prefix to be used for blob names.
+    key_prefix: String,
 }
 
 impl AzureBlobCache {
-    pub fn new() -> Result<AzureBlobCache> {
-        let credentials = match EnvironmentProvider.provide_credentials() {
-            Ok(creds) => creds,
-            Err(_) => bail!("Could not find Azure credentials in the environment"),
-        };
+    pub fn new(config: &AzureCacheConfig) -> Result<AzureBlobCache> {
+        let credentials = AzureCredentials::from_config(config)
+            .context("Could not find Azure credentials in the configuration")?;
 
         let container = match BlobContainer::new(
             credentials.azure_blob_endpoint(),
@@ -43,21 +43,37 @@ impl AzureBlobCache {
             Err(e) => bail!("Error instantiating BlobContainer: {:?}", e),
         };
 
+        let key_prefix = config.key_prefix.trim_matches('/');
         Ok(AzureBlobCache {
             container: Rc::new(container),
             credentials,
+            key_prefix: if key_prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", key_prefix)
+            },
         })
     }
+
+    /// Whether the credentials are a read-only SAS token, which developers
+    /// may have instead of one that can write.
+    pub fn is_read_only(&self) -> bool {
+        !self.credentials.can_write()
+    }
+
+    fn normalize_key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
 }
 
 impl Storage for AzureBlobCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
         Box::new(
             self.container
-                .get(key, &self.credentials)
+                .get(&self.normalize_key(key), &self.credentials)
                 .then(|result| match result {
                     Ok(data) => {
-                        let hit = CacheRead::from(io::Cursor::new(data))?;
+                        let hit = CacheRead::from(data.into_reader()?)?;
                         Ok(Cache::Hit(hit))
                     }
                     Err(e) => {
@@ -77,12 +93,20 @@ impl Storage for AzureBlobCache {
 
         let response = self
             .container
-            .put(key, data, &self.credentials)
+            .put(&self.normalize_key(key), data, &self.credentials)
             .fcontext("Failed to put cache entry in Azure");
 
         Box::new(response.map(move |_| start.elapsed()))
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        Box::new(
+            self.container
+                .delete(&self.normalize_key(key), &self.credentials)
+                .fcontext("Failed to remove cache entry from Azure"),
+        )
+    }
+
     fn location(&self) -> String {
         format!("Azure, container: {}", self.container)
     }
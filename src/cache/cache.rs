@@ -14,22 +14,32 @@
 
 #[cfg(feature = "azure")]
 use crate::cache::azure::AzureBlobCache;
+use crate::cache::cas::CasStorage;
+use crate::cache::compression::{self, CompressedStorage, Compression, Dictionary};
 use crate::cache::disk::DiskCache;
+use crate::cache::encryption::{self, EncryptedStorage};
 #[cfg(feature = "gcs")]
-use crate::cache::gcs::{self, GCSCache, GCSCredentialProvider, RWMode, ServiceAccountInfo};
+use crate::cache::gcs::GCSCache;
 #[cfg(feature = "memcached")]
 use crate::cache::memcached::MemcachedCache;
+#[cfg(feature = "reapi")]
+use crate::cache::reapi::ReapiCache;
 #[cfg(feature = "redis")]
 use crate::cache::redis::RedisCache;
+use crate::cache::rw_mode::RWModeStorage;
 #[cfg(feature = "s3")]
 use crate::cache::s3::S3Cache;
-use crate::config::{self, CacheType, Config};
-use crate::util::SpawnExt;
+use crate::cache::signing::{SignedStorage, Signing};
+use crate::cache::tiered::TieredStorage;
+#[cfg(feature = "webdav")]
+use crate::cache::webdav::WebdavCache;
+use crate::config::{self, CacheRWMode, CacheType, Config};
+use crate::util::{Digest, SpawnExt};
+use futures::Stream;
 use futures_03::executor::ThreadPool;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs;
-#[cfg(feature = "gcs")]
-use std::fs::File;
 use std::io::{self, Cursor, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -87,14 +97,33 @@ impl fmt::Debug for Cache {
     }
 }
 
+/// The object in a cache entry that holds the digests of the other objects.
+pub(crate) const CHECKSUMS_OBJECT: &str = ".checksums";
+
+/// The size of the chunks that spooled cache entries are streamed in.
+pub const SPOOL_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Trait objects can't be bounded by more than one non-builtin trait.
 pub trait ReadSeek: Read + Seek + Send {}
 
 impl<T: Read + Seek + Send> ReadSeek for T {}
 
+/// The tier of a tiered cache that an entry was read from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheTier {
+    /// The local disk cache.
+    Local,
+    /// The remote cache behind it.
+    Remote,
+}
+
 /// Data stored in the compiler cache.
 pub struct CacheRead {
     zip: ZipArchive<Box<dyn ReadSeek>>,
+    tier: Option<CacheTier>,
+    /// The digests of the raw contents of the objects, by name. Entries
+    /// written before objects had digests have none.
+    checksums: HashMap<String, String>,
 }
 
 /// Represents a failure to decompress stored object data.
@@ -109,19 +138,149 @@ impl std::fmt::Display for DecompressionFailure {
 
 impl std::error::Error for DecompressionFailure {}
 
+/// Represents an object whose contents don't match the digest stored with
+/// it, such as one truncated or corrupted by the storage.
+#[derive(Debug)]
+pub struct ChecksumMismatch(pub String);
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cache entry object `{}` doesn't match its checksum",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// A reader that computes the digest of everything read through it.
+struct DigestReader<R> {
+    inner: R,
+    digest: Digest,
+}
+
+impl<R: Read> DigestReader<R> {
+    fn new(inner: R) -> DigestReader<R> {
+        DigestReader {
+            inner,
+            digest: Digest::new(),
+        }
+    }
+
+    /// Read the rest of the contents and return their digest.
+    fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(self.digest.finish())
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A writer that computes the digest of everything written through it.
+struct DigestWriter<W> {
+    inner: W,
+    digest: Digest,
+    len: u64,
+}
+
+impl<W: Write> DigestWriter<W> {
+    fn new(inner: W) -> DigestWriter<W> {
+        DigestWriter {
+            inner,
+            digest: Digest::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Check `digest` against the digest stored for the object at `name`, if any.
+fn check_digest(checksums: &HashMap<String, String>, name: &str, digest: &str) -> Result<()> {
+    match checksums.get(name) {
+        Some(expected) if expected != digest => bail!(ChecksumMismatch(name.to_owned())),
+        _ => Ok(()),
+    }
+}
+
+/// Represents a write that was skipped because the cache is read-only.
+#[derive(Debug)]
+pub struct CacheWriteSkipped;
+
+impl std::fmt::Display for CacheWriteSkipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cache is read-only, write skipped")
+    }
+}
+
+impl std::error::Error for CacheWriteSkipped {}
+
 impl CacheRead {
     /// Create a cache entry from `reader`.
     pub fn from<R>(reader: R) -> Result<CacheRead>
     where
         R: ReadSeek + 'static,
     {
-        let z = ZipArchive::new(Box::new(reader) as Box<dyn ReadSeek>)
+        let mut z = ZipArchive::new(Box::new(reader) as Box<dyn ReadSeek>)
             .context("Failed to parse cache entry")?;
-        Ok(CacheRead { zip: z })
+        let checksums = match z.by_name(CHECKSUMS_OBJECT) {
+            Ok(file) => {
+                let bytes = zstd::stream::decode_all(file).or(Err(DecompressionFailure))?;
+                bincode::deserialize(&bytes).context("Failed to parse cache entry checksums")?
+            }
+            Err(_) => HashMap::new(),
+        };
+        Ok(CacheRead {
+            zip: z,
+            tier: None,
+            checksums,
+        })
+    }
+
+    /// Record that this entry was read from `tier` of a tiered cache.
+    pub fn with_tier(self, tier: CacheTier) -> CacheRead {
+        CacheRead {
+            tier: Some(tier),
+            ..self
+        }
+    }
+
+    /// The tier of a tiered cache this entry was read from, if any.
+    pub fn tier(&self) -> Option<CacheTier> {
+        self.tier
+    }
+
+    /// Spool the raw contents of this cache entry to a temporary file.
+    pub fn into_spooled(self) -> Result<SpooledEntry> {
+        let mut reader = self.zip.into_inner();
+        reader.seek(io::SeekFrom::Start(0))?;
+        SpooledEntry::from_reader(&mut reader)
     }
 
     /// Get an object from this cache entry at `name` and write it to `to`.
     /// If the file has stored permissions, return them.
+    ///
+    /// Fails with `ChecksumMismatch` if the object doesn't match its digest,
+    /// in which case some of its contents may already have been written.
     pub fn get_object<T>(&mut self, name: &str, to: &mut T) -> Result<Option<u32>>
     where
         T: Write,
@@ -131,24 +290,64 @@ impl CacheRead {
             bail!(DecompressionFailure);
         }
         let mode = file.unix_mode();
-        zstd::stream::copy_decode(file, to).or(Err(DecompressionFailure))?;
+        let mut reader = DigestReader::new(file);
+        {
+            let mut decoder = compression::decoder(&mut reader).or(Err(DecompressionFailure))?;
+            io::copy(&mut decoder, to).or(Err(DecompressionFailure))?;
+        }
+        check_digest(&self.checksums, name, &reader.finish()?)?;
         Ok(mode)
     }
 
-    /// Get the stdout from this cache entry, if it exists.
-    pub fn get_stdout(&mut self) -> Vec<u8> {
+    /// The names of the objects in this cache entry, in no particular order.
+    pub fn object_names(&self) -> Vec<String> {
+        self.zip
+            .file_names()
+            .filter(|&name| name != CHECKSUMS_OBJECT)
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Get the raw, still compressed, contents of the object at `name` and
+    /// write them to `to`. If the file has stored permissions, return them.
+    ///
+    /// Fails with `ChecksumMismatch` if the object doesn't match its digest.
+    pub fn get_raw_object<T>(&mut self, name: &str, to: &mut T) -> Result<Option<u32>>
+    where
+        T: Write,
+    {
+        let file = self.zip.by_name(name).or(Err(DecompressionFailure))?;
+        let mode = file.unix_mode();
+        let mut reader = DigestReader::new(file);
+        io::copy(&mut reader, to)?;
+        check_digest(&self.checksums, name, &reader.finish()?)?;
+        Ok(mode)
+    }
+
+    /// The size of the raw, still compressed, contents of the object at `name`.
+    pub fn raw_object_size(&mut self, name: &str) -> Result<u64> {
+        let file = self.zip.by_name(name).or(Err(DecompressionFailure))?;
+        Ok(file.size())
+    }
+
+    /// Get the stdout from this cache entry, which is empty if the entry
+    /// doesn't have any.
+    pub fn get_stdout(&mut self) -> Result<Vec<u8>> {
         self.get_bytes("stdout")
     }
 
-    /// Get the stderr from this cache entry, if it exists.
-    pub fn get_stderr(&mut self) -> Vec<u8> {
+    /// Get the stderr from this cache entry, which is empty if the entry
+    /// doesn't have any.
+    pub fn get_stderr(&mut self) -> Result<Vec<u8>> {
         self.get_bytes("stderr")
     }
 
-    fn get_bytes(&mut self, name: &str) -> Vec<u8> {
+    fn get_bytes(&mut self, name: &str) -> Result<Vec<u8>> {
         let mut bytes = Vec::new();
-        drop(self.get_object(name, &mut bytes));
-        bytes
+        if self.zip.file_names().any(|n| n == name) {
+            self.get_object(name, &mut bytes)?;
+        }
+        Ok(bytes)
     }
 
     pub fn extract_objects<T>(mut self, objects: T, pool: &ThreadPool) -> SFuture<()>
@@ -177,25 +376,83 @@ impl CacheRead {
 }
 
 /// Data to be stored in the compiler cache.
+///
+/// Objects are spooled to a temporary file as they are added, so entries
+/// never need to be held in memory in their entirety.
 pub struct CacheWrite {
-    zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    zip: ZipWriter<NamedTempFile>,
+    /// The digests of the raw contents of the objects written so far, by name.
+    checksums: HashMap<String, String>,
+    /// How objects are compressed, or `None` if they were copied from
+    /// elsewhere and may have been compressed in different ways.
+    compression: Option<Compression>,
+    /// The total size of the objects written so far, before compression.
+    uncompressed_size: u64,
+    /// The total size of the objects written so far, after compression.
+    compressed_size: u64,
 }
 
 impl CacheWrite {
     /// Create a new, empty cache entry.
-    pub fn new() -> CacheWrite {
-        CacheWrite {
-            zip: ZipWriter::new(io::Cursor::new(vec![])),
+    pub fn new() -> Result<CacheWrite> {
+        CacheWrite::with_compression(Some(Compression::default()))
+    }
+
+    /// Create a new, empty cache entry that compresses objects with
+    /// `compression`. Raw objects added to it should be compressed the same
+    /// way, or `compression` should be `None`.
+    pub fn with_compression(compression: Option<Compression>) -> Result<CacheWrite> {
+        let file = NamedTempFile::new().context("Failed to create cache entry file")?;
+        Ok(CacheWrite {
+            zip: ZipWriter::new(file),
+            checksums: HashMap::new(),
+            compression,
+            uncompressed_size: 0,
+            compressed_size: 0,
+        })
+    }
+
+    /// How the objects of this entry are compressed, if they're all
+    /// compressed the same way.
+    pub fn compression(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    /// The total size of the objects added with `put_object`, before and
+    /// after compression.
+    pub fn object_sizes(&self) -> (u64, u64) {
+        (self.uncompressed_size, self.compressed_size)
+    }
+
+    /// Create a new cache entry with the same contents as the finished
+    /// cache entry read from `reader`.
+    pub fn from_reader<R: ReadSeek>(reader: R) -> Result<CacheWrite> {
+        let mut zip = ZipArchive::new(reader).context("Failed to parse cache entry")?;
+        let mut entry = CacheWrite::with_compression(None)?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            let name = file.name().to_owned();
+            if name == CHECKSUMS_OBJECT {
+                continue;
+            }
+            let mode = file.unix_mode();
+            entry.put_raw_object(&name, &mut file, mode)?;
         }
+        Ok(entry)
     }
 
-    /// Create a new cache entry populated with the contents of `objects`.
-    pub fn from_objects<T>(objects: T, pool: &ThreadPool) -> SFuture<CacheWrite>
+    /// Create a new cache entry populated with the contents of `objects`,
+    /// compressed with `compression`.
+    pub fn from_objects<T>(
+        objects: T,
+        compression: Compression,
+        pool: &ThreadPool,
+    ) -> SFuture<CacheWrite>
     where
         T: IntoIterator<Item = (String, PathBuf)> + Send + Sync + 'static,
     {
         Box::new(pool.spawn_fn(move || -> Result<_> {
-            let mut entry = CacheWrite::new();
+            let mut entry = CacheWrite::with_compression(Some(compression))?;
             for (key, path) in objects {
                 let mut f = fs::File::open(&path)?;
                 let mode = get_file_mode(&f)?;
@@ -213,6 +470,31 @@ impl CacheWrite {
     where
         T: Read,
     {
+        self.start_object(name, mode)?;
+        let compression = self.compression.clone().unwrap_or_default();
+        let mut writer = DigestWriter::new(&mut self.zip);
+        self.uncompressed_size += compression.compress(from, &mut writer)?;
+        self.compressed_size += writer.len;
+        self.checksums
+            .insert(name.to_owned(), writer.digest.finish());
+        Ok(())
+    }
+
+    /// Add an object containing the raw, already compressed, contents of `from`
+    /// to this cache entry at `name`, as read by `CacheRead::get_raw_object`.
+    pub fn put_raw_object<T>(&mut self, name: &str, from: &mut T, mode: Option<u32>) -> Result<()>
+    where
+        T: Read,
+    {
+        self.start_object(name, mode)?;
+        let mut writer = DigestWriter::new(&mut self.zip);
+        io::copy(from, &mut writer)?;
+        self.checksums
+            .insert(name.to_owned(), writer.digest.finish());
+        Ok(())
+    }
+
+    fn start_object(&mut self, name: &str, mode: Option<u32>) -> Result<()> {
         // We're going to declare the compression method as "stored",
         // but we're actually going to store zstd-compressed blobs.
         let opts = FileOptions::default().compression_method(CompressionMethod::Stored);
@@ -224,7 +506,6 @@ impl CacheWrite {
         self.zip
             .start_file(name, opts)
             .context("Failed to start cache entry object")?;
-        zstd::stream::copy_encode(from, &mut self.zip, 3)?;
         Ok(())
     }
 
@@ -244,17 +525,169 @@ impl CacheWrite {
         Ok(())
     }
 
-    /// Finish writing data to the cache entry writer, and return the data.
-    pub fn finish(self) -> Result<Vec<u8>> {
-        let CacheWrite { mut zip } = self;
-        let cur = zip.finish().context("Failed to finish cache entry zip")?;
-        Ok(cur.into_inner())
+    /// Finish this entry, and create a new one with the same objects
+    /// compressed with `compression`.
+    pub fn recompress(self, compression: Compression) -> Result<CacheWrite> {
+        let mut entry = CacheRead::from(self.finish()?.into_reader()?)?;
+        let mut recompressed = CacheWrite::with_compression(Some(compression))?;
+        for name in entry.object_names() {
+            let mut object = SpooledEntry::new()?;
+            let mode = entry.get_object(&name, &mut object)?;
+            recompressed.put_object(&name, &mut object.into_reader()?, mode)?;
+        }
+        Ok(recompressed)
+    }
+
+    /// Finish writing data to the cache entry writer, and return the spooled data.
+    pub fn finish(mut self) -> Result<SpooledEntry> {
+        let bytes = bincode::serialize(&self.checksums).context("Failed to serialize checksums")?;
+        self.start_object(CHECKSUMS_OBJECT, None)?;
+        zstd::stream::copy_encode(&bytes[..], &mut self.zip, 3)?;
+        let CacheWrite { mut zip, .. } = self;
+        let mut file = zip.finish().context("Failed to finish cache entry zip")?;
+        let len = file.seek(io::SeekFrom::End(0))?;
+        Ok(SpooledEntry { file, len })
+    }
+}
+
+/// The raw contents of a finished cache entry, spooled to a temporary file.
+///
+/// Storage implementations stream entries to and from these so that the
+/// memory used by a cache lookup or write doesn't grow with the entry size.
+pub struct SpooledEntry {
+    file: NamedTempFile,
+    len: u64,
+}
+
+impl SpooledEntry {
+    /// Create a new, empty spooled entry.
+    pub fn new() -> Result<SpooledEntry> {
+        let file = NamedTempFile::new().context("Failed to create cache entry file")?;
+        Ok(SpooledEntry { file, len: 0 })
+    }
+
+    /// Spool the contents of `reader` to a new entry.
+    pub fn from_reader<R: Read>(reader: &mut R) -> Result<SpooledEntry> {
+        let mut entry = SpooledEntry::new()?;
+        io::copy(reader, &mut entry)?;
+        Ok(entry)
+    }
+
+    /// Spool the chunks of `stream`, such as an HTTP response body, to a new entry.
+    pub fn from_stream<S>(stream: S) -> SFuture<SpooledEntry>
+    where
+        S: Stream + 'static,
+        S::Item: AsRef<[u8]>,
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let entry = match SpooledEntry::new() {
+            Ok(entry) => entry,
+            Err(e) => return f_err(e),
+        };
+        Box::new(
+            stream
+                .map_err(Error::from)
+                .fold(entry, |mut entry, chunk| -> Result<_> {
+                    entry.write_all(chunk.as_ref())?;
+                    Ok(entry)
+                }),
+        )
+    }
+
+    /// The size of the entry, in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the entry is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Open a new reader over the contents of this entry, positioned
+    /// independently of any other reader.
+    pub fn reader(&self) -> Result<fs::File> {
+        Ok(self.file.reopen()?)
+    }
+
+    /// Turn this entry into a reader over its contents.
+    pub fn into_reader(self) -> Result<NamedTempFile> {
+        let mut file = self.file;
+        file.seek(io::SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    /// Read the contents of this entry into memory, for storage that can
+    /// only store whole buffers.
+    pub fn into_vec(self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.len as usize);
+        self.into_reader()?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Iterate over the contents of this entry in chunks of at most
+    /// `chunk_size` bytes.
+    pub fn chunks(&self, chunk_size: usize) -> Result<Chunks<fs::File>> {
+        Ok(Chunks {
+            reader: self.reader()?,
+            chunk_size,
+        })
+    }
+
+    /// Turn this entry into an iterator over its contents in chunks of at
+    /// most `chunk_size` bytes.
+    pub fn into_chunks(self, chunk_size: usize) -> Result<Chunks<NamedTempFile>> {
+        Ok(Chunks {
+            reader: self.into_reader()?,
+            chunk_size,
+        })
+    }
+
+    /// Stream the contents of this entry without consuming it, such as for
+    /// an HTTP request body that may need to be sent again.
+    pub fn stream(&self) -> Result<Box<dyn Stream<Item = Vec<u8>, Error = io::Error> + Send>> {
+        let chunks = self.chunks(SPOOL_CHUNK_SIZE)?;
+        Ok(Box::new(futures::stream::iter_result(chunks)))
+    }
+
+    /// Stream the contents of this entry, such as for an HTTP request body.
+    pub fn into_stream(self) -> Result<Box<dyn Stream<Item = Vec<u8>, Error = io::Error> + Send>> {
+        let chunks = self.into_chunks(SPOOL_CHUNK_SIZE)?;
+        Ok(Box::new(futures::stream::iter_result(chunks)))
     }
 }
 
-impl Default for CacheWrite {
-    fn default() -> Self {
-        Self::new()
+impl Write for SpooledEntry {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// An iterator over the contents of a reader in fixed-size chunks.
+pub struct Chunks<R> {
+    reader: R,
+    chunk_size: usize,
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+        match (&mut self.reader)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(chunk)),
+            Err(e) => Some(Err(e)),
+        }
     }
 }
 
@@ -271,10 +704,41 @@ pub trait Storage {
 
     /// Put `entry` in the cache under `key`.
     ///
+    /// Implementations should stream the finished entry from its spooled
+    /// file rather than reading it into memory where they can.
+    ///
     /// Returns a `Future` that will provide the result or error when the put is
     /// finished.
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration>;
 
+    /// Check whether an entry exists under `key`, without fetching it.
+    ///
+    /// Storage that can't check this cheaply returns `false`, so only a
+    /// `true` result can be relied on.
+    fn contains(&self, _key: &str) -> SFuture<bool> {
+        f_ok(false)
+    }
+
+    /// Remove the entry under `key`, such as one found to be corrupt.
+    ///
+    /// Storage that can't remove entries leaves them in place, to be
+    /// overwritten by the next write under `key`.
+    fn remove(&self, _key: &str) -> SFuture<()> {
+        f_ok(())
+    }
+
+    /// Whether entries can be written to this storage at all. Storage that
+    /// can't be written to fails every `put` with `CacheWriteSkipped`.
+    fn can_write(&self) -> bool {
+        true
+    }
+
+    /// How entries written to this storage should compress their objects,
+    /// so that they don't need to be recompressed.
+    fn compression(&self) -> Compression {
+        Compression::default()
+    }
+
     /// Get the storage location.
     fn location(&self) -> String;
 
@@ -286,100 +750,203 @@ pub trait Storage {
 }
 
 /// Get a suitable `Storage` implementation from configuration.
-#[allow(clippy::cognitive_complexity)] // TODO simplify!
 pub fn storage_from_config(config: &Config, pool: &ThreadPool) -> Arc<dyn Storage> {
+    // The local tier of a tiered cache is always read and written, so the
+    // read/write mode only applies to the remote tier.
+    let rw_mode = config.cache_rw_mode;
+    let with_rw_mode = |storage: Arc<dyn Storage>| -> Arc<dyn Storage> {
+        match rw_mode {
+            CacheRWMode::ReadWrite => storage,
+            mode => {
+                trace!("Using {:?} mode", mode);
+                Arc::new(RWModeStorage::new(storage, mode))
+            }
+        }
+    };
+    // Only the shared storage is encrypted and signed; the local tier of a
+    // tiered cache is only ever read by this machine.
+    let with_encryption = |storage: Arc<dyn Storage>| -> Arc<dyn Storage> {
+        let encryption = match config.cache_encryption {
+            Some(ref encryption) => encryption,
+            None => return storage,
+        };
+        trace!("Using encrypted cache entries");
+        let keys = encryption::keys_from_config(encryption).unwrap_or_else(|e| {
+            // Never fall back to storing entries unencrypted.
+            error!(
+                "Failed to load cache encryption keys, caching is disabled: {:#}",
+                e
+            );
+            vec![]
+        });
+        Arc::new(EncryptedStorage::new(storage, keys, pool))
+    };
+    let with_signing = |storage: Arc<dyn Storage>| -> Arc<dyn Storage> {
+        let signing = match config.cache_signing {
+            Some(ref signing) => signing,
+            None => return storage,
+        };
+        trace!("Using signed cache entries");
+        let signing = Signing::from_config(signing).unwrap_or_else(|e| {
+            // Never fall back to trusting unsigned entries.
+            error!(
+                "Failed to load cache signing keys, caching is disabled: {:#}",
+                e
+            );
+            Signing::none()
+        });
+        Arc::new(SignedStorage::new(storage, signing, pool))
+    };
+    // The dictionary is needed to read entries from any storage, so it's
+    // used for all of them.
+    let dictionary = config
+        .cache_compression_dictionary
+        .as_ref()
+        .and_then(|path| match Dictionary::from_file(path) {
+            Ok(dictionary) => {
+                let dictionary = Arc::new(dictionary);
+                compression::register_dictionary(dictionary.clone());
+                Some(dictionary)
+            }
+            Err(e) => {
+                warn!("{:#}", e);
+                None
+            }
+        });
+    let compression_at = |level: Option<i32>| -> Option<Compression> {
+        if level.is_none() && dictionary.is_none() {
+            return None;
+        }
+        let level = level.unwrap_or(compression::DEFAULT_LEVEL);
+        Some(Compression::new(level, dictionary.clone()))
+    };
+    let with_compression = |storage: Arc<dyn Storage>, level| -> Arc<dyn Storage> {
+        match compression_at(level) {
+            Some(compression) => {
+                trace!("Using {:?}", compression);
+                Arc::new(CompressedStorage::new(storage, compression, pool))
+            }
+            None => storage,
+        }
+    };
+    let shared = |storage: Arc<dyn Storage>, level| {
+        with_rw_mode(with_compression(
+            with_signing(with_encryption(storage)),
+            level,
+        ))
+    };
+    let remote = remote_storage_from_config(config, pool);
+    let remote_level = config.cache_compression_level;
+    let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
+    let disk_level = config.fallback_cache.compression_level;
+    let storage: Arc<dyn Storage> = match (remote, config.fallback_cache.local_tier) {
+        (Some(remote), Some(policy)) => {
+            trace!("Using DiskCache({:?}, {}) as a local tier", dir, size);
+            let local = DiskCache::new(&dir, size, pool);
+            let remote = shared(remote, remote_level);
+            let local_compression = compression_at(disk_level).unwrap_or_default();
+            Arc::new(
+                TieredStorage::new(local, remote, policy, pool)
+                    .with_local_compression(local_compression),
+            )
+        }
+        (Some(remote), None) => shared(remote, remote_level),
+        (None, _) => {
+            info!("No configured caches successful, falling back to default");
+            trace!("Using DiskCache({:?}, {})", dir, size);
+            shared(Arc::new(DiskCache::new(&dir, size, pool)), disk_level)
+        }
+    };
+    if config.cache_content_addressed {
+        trace!("Using content-addressed cache entries");
+        Arc::new(CasStorage::new(storage, pool))
+    } else {
+        storage
+    }
+}
+
+/// Make `storage` read-only if its backend can't be written to with the
+/// configured credentials, so that entries aren't built only to be dropped.
+#[cfg(any(feature = "azure", feature = "gcs"))]
+fn with_backend_rw_mode(storage: Arc<dyn Storage>, read_only: bool) -> Arc<dyn Storage> {
+    if read_only {
+        trace!("Using read-only credentials");
+        Arc::new(RWModeStorage::new(storage, CacheRWMode::ReadOnly))
+    } else {
+        storage
+    }
+}
+
+/// Get the first remote `Storage` implementation from configuration that
+/// can be created, if any.
+#[allow(clippy::cognitive_complexity)] // TODO simplify!
+fn remote_storage_from_config(config: &Config, pool: &ThreadPool) -> Option<Arc<dyn Storage>> {
+    #[cfg(not(any(feature = "memcached", feature = "reapi")))]
+    let _ = pool;
     for cache_type in config.caches.iter() {
         match *cache_type {
-            CacheType::Azure(config::AzureCacheConfig) => {
-                debug!("Trying Azure Blob Store account");
+            CacheType::Azure(ref c) => {
+                debug!(
+                    "Trying Azure Blob Store account({}, {})",
+                    c.container, c.key_prefix
+                );
                 #[cfg(feature = "azure")]
-                match AzureBlobCache::new() {
+                match AzureBlobCache::new(c) {
                     Ok(storage) => {
                         trace!("Using AzureBlobCache");
-                        return Arc::new(storage);
+                        let read_only = storage.is_read_only();
+                        return Some(with_backend_rw_mode(Arc::new(storage), read_only));
                     }
                     Err(e) => warn!("Failed to create Azure cache: {:?}", e),
                 }
             }
-            CacheType::GCS(config::GCSCacheConfig {
-                ref bucket,
-                ref cred_path,
-                ref url,
-                rw_mode,
-            }) => {
+            CacheType::GCS(ref c) => {
                 debug!(
-                    "Trying GCS bucket({}, {:?}, {:?}, {:?})",
-                    bucket, cred_path, url, rw_mode
+                    "Trying GCS bucket({}, {:?}, {:?}, {:?}, {})",
+                    c.bucket, c.cred_path, c.url, c.rw_mode, c.key_prefix
                 );
                 #[cfg(feature = "gcs")]
-                {
-                    let service_account_info_opt: Option<gcs::ServiceAccountInfo> =
-                        if let Some(ref cred_path) = *cred_path {
-                            // Attempt to read the service account key from file
-                            let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
-                                let mut file = File::open(&cred_path)?;
-                                let mut service_account_json = String::new();
-                                file.read_to_string(&mut service_account_json)?;
-                                Ok(serde_json::from_str(&service_account_json)?)
-                            })(
-                            );
-
-                            // warn! if an error was encountered reading the key from the file
-                            if let Err(ref e) = service_account_key_res {
-                                warn!(
-                                    "Failed to parse service account credentials from file: {:?}. \
-                                     Continuing without authentication.",
-                                    e
-                                );
-                            }
-
-                            service_account_key_res
-                                .ok()
-                                .map(ServiceAccountInfo::AccountKey)
-                        } else if let Some(ref url) = *url {
-                            Some(ServiceAccountInfo::URL(url.clone()))
-                        } else {
-                            warn!(
-                            "No SCCACHE_GCS_KEY_PATH specified-- no authentication will be used."
-                        );
-                            None
-                        };
-
-                    let gcs_read_write_mode = match rw_mode {
-                        config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
-                        config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
-                    };
-
-                    let gcs_cred_provider = service_account_info_opt
-                        .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
-
-                    match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode) {
-                        Ok(s) => {
-                            trace!("Using GCSCache");
-                            return Arc::new(s);
-                        }
-                        Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
+                match GCSCache::new(c) {
+                    Ok(s) => {
+                        trace!("Using GCSCache");
+                        let read_only = s.is_read_only();
+                        return Some(with_backend_rw_mode(Arc::new(s), read_only));
                     }
+                    Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
                 }
             }
-            CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
-                debug!("Trying Memcached({})", url);
+            CacheType::Memcached(config::MemcachedCacheConfig {
+                ref url,
+                expiration,
+            }) => {
+                debug!("Trying Memcached({}, {}s expiration)", url, expiration);
                 #[cfg(feature = "memcached")]
-                match MemcachedCache::new(&url, pool) {
+                match MemcachedCache::new(url, expiration, pool) {
                     Ok(s) => {
                         trace!("Using Memcached: {}", url);
-                        return Arc::new(s);
+                        return Some(Arc::new(s));
                     }
                     Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
                 }
             }
-            CacheType::Redis(config::RedisCacheConfig { ref url }) => {
-                debug!("Trying Redis({})", url);
+            CacheType::Reapi(ref c) => {
+                debug!("Trying ReapiCache({}, {})", c.endpoint, c.instance_name);
+                #[cfg(feature = "reapi")]
+                match ReapiCache::new(c, pool) {
+                    Ok(s) => {
+                        trace!("Using ReapiCache");
+                        return Some(Arc::new(s));
+                    }
+                    Err(e) => warn!("Failed to create ReapiCache: {:?}", e),
+                }
+            }
+            CacheType::Redis(ref c) => {
+                debug!("Trying Redis({})", c.url);
                 #[cfg(feature = "redis")]
-                match RedisCache::new(&url) {
+                match RedisCache::new(c) {
                     Ok(s) => {
-                        trace!("Using Redis: {}", url);
-                        return Arc::new(s);
+                        trace!("Using Redis: {}", c.url);
+                        return Some(Arc::new(s));
                     }
                     Err(e) => warn!("Failed to create RedisCache: {:?}", e),
                 }
@@ -387,19 +954,153 @@ pub fn storage_from_config(config: &Config, pool: &ThreadPool) -> Arc<dyn Storag
             CacheType::S3(ref c) => {
                 debug!("Trying S3Cache({}, {})", c.bucket, c.endpoint);
                 #[cfg(feature = "s3")]
-                match S3Cache::new(&c.bucket, &c.endpoint, c.use_ssl, &c.key_prefix) {
+                match S3Cache::new(c) {
                     Ok(s) => {
                         trace!("Using S3Cache");
-                        return Arc::new(s);
+                        return Some(Arc::new(s));
                     }
                     Err(e) => warn!("Failed to create S3Cache: {:?}", e),
                 }
             }
+            CacheType::Webdav(ref c) => {
+                debug!("Trying WebdavCache({}, {})", c.endpoint, c.key_prefix);
+                #[cfg(feature = "webdav")]
+                match WebdavCache::new(c) {
+                    Ok(s) => {
+                        trace!("Using WebdavCache");
+                        return Some(Arc::new(s));
+                    }
+                    Err(e) => warn!("Failed to create WebdavCache: {:?}", e),
+                }
+            }
         }
     }
 
-    info!("No configured caches successful, falling back to default");
-    let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
-    trace!("Using DiskCache({:?}, {})", dir, size);
-    Arc::new(DiskCache::new(&dir, size, pool))
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::*;
+    use futures::Future;
+    use rand::RngCore;
+
+    #[test]
+    fn test_spooled_entry_stream() {
+        // Incompressible, and larger than a single chunk but not a multiple of its size.
+        let mut contents = vec![0; SPOOL_CHUNK_SIZE * 5 / 2];
+        rand::thread_rng().fill_bytes(&mut contents);
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(&contents), None)
+            .unwrap();
+        let spooled = entry.finish().unwrap();
+        let len = spooled.len();
+
+        let chunks: Vec<_> = spooled
+            .chunks(SPOOL_CHUNK_SIZE)
+            .unwrap()
+            .map(|c| c.unwrap().len())
+            .collect();
+        assert_eq!(3, chunks.len());
+        assert!(chunks.iter().all(|&n| n > 0 && n <= SPOOL_CHUNK_SIZE));
+        assert_eq!(len, chunks.iter().sum::<usize>() as u64);
+
+        let stream = spooled.into_stream().unwrap();
+        let spooled = SpooledEntry::from_stream(stream).wait().unwrap();
+        assert_eq!(len, spooled.len());
+        let mut read = CacheRead::from(spooled.into_reader().unwrap()).unwrap();
+        let mut bytes = vec![];
+        read.get_object("obj", &mut bytes).unwrap();
+        assert_eq!(contents, bytes);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(b"contents"), None)
+            .unwrap();
+        entry
+            .put_object("other", &mut Cursor::new(b"other contents"), None)
+            .unwrap();
+        entry.put_stdout(b"output").unwrap();
+        // Record digests that the stored contents can't match.
+        entry.checksums.insert("obj".to_owned(), "bogus".to_owned());
+        entry
+            .checksums
+            .insert("stdout".to_owned(), "bogus".to_owned());
+        let spooled = entry.finish().unwrap();
+
+        let mut read = CacheRead::from(spooled.reader().unwrap()).unwrap();
+        let mut names = read.object_names();
+        names.sort();
+        assert_eq!(names, vec!["obj", "other", "stdout"]);
+        let err = read.get_stdout().unwrap_err();
+        assert!(err.downcast_ref::<ChecksumMismatch>().is_some());
+        assert!(read.get_stderr().unwrap().is_empty());
+        let mut bytes = vec![];
+        read.get_object("other", &mut bytes).unwrap();
+        assert_eq!(bytes, b"other contents");
+        let err = read.get_object("obj", &mut vec![]).unwrap_err();
+        assert!(err.downcast_ref::<ChecksumMismatch>().is_some());
+        let err = read.get_raw_object("obj", &mut vec![]).unwrap_err();
+        assert!(err.downcast_ref::<ChecksumMismatch>().is_some());
+
+        // Copies of an entry get digests of their own contents.
+        let copy = CacheWrite::from_reader(spooled.reader().unwrap()).unwrap();
+        let mut read = CacheRead::from(copy.finish().unwrap().into_reader().unwrap()).unwrap();
+        read.get_object("obj", &mut vec![]).unwrap();
+
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let read = CacheRead::from(spooled.into_reader().unwrap()).unwrap();
+        let objects = vec![
+            ("other".to_owned(), f.tempdir.path().join("other")),
+            ("obj".to_owned(), f.tempdir.path().join("obj")),
+        ];
+        let err = read.extract_objects(objects, &pool).wait().unwrap_err();
+        assert!(err.downcast_ref::<ChecksumMismatch>().is_some());
+        assert!(!f.tempdir.path().join("obj").exists());
+    }
+
+    #[test]
+    fn test_no_checksums() {
+        // Entries written before objects had digests are read as they are.
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file(
+            "obj",
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )
+        .unwrap();
+        zstd::stream::copy_encode(&b"contents"[..], &mut zip, 3).unwrap();
+        let mut read = CacheRead::from(zip.finish().unwrap()).unwrap();
+        let mut bytes = vec![];
+        read.get_object("obj", &mut bytes).unwrap();
+        assert_eq!(bytes, b"contents");
+    }
+
+    #[cfg(feature = "azure")]
+    #[test]
+    fn test_read_only_sas_token() {
+        let pool = ThreadPool::sized(1);
+        let azure = |sas_token: &str| Config {
+            caches: vec![CacheType::Azure(config::AzureCacheConfig {
+                connection_string: None,
+                endpoint: Some("https://account.blob.core.windows.net".to_owned()),
+                sas_token: Some(sas_token.to_owned()),
+                container: "container".to_owned(),
+                key_prefix: String::new(),
+            })],
+            ..Default::default()
+        };
+
+        let storage = storage_from_config(&azure("sv=2019-12-12&sp=rl&sig=abc"), &pool);
+        assert!(!storage.can_write());
+        assert!(storage.location().ends_with("(read-only)"));
+
+        let storage = storage_from_config(&azure("sv=2019-12-12&sp=rwl&sig=abc"), &pool);
+        assert!(storage.can_write());
+    }
 }
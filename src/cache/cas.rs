@@ -0,0 +1,454 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::compression::{self, Compression};
+use crate::cache::{Cache, CacheRead, CacheWrite, SpooledEntry, Storage};
+use crate::util::{Digest, SpawnExt};
+use futures::future::{self, Future};
+use futures_03::executor::ThreadPool;
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// The object in a manifest entry that lists the objects stored as blobs.
+const MANIFEST_OBJECT: &str = "cas-manifest";
+
+/// The object in a blob entry that holds the blob's contents.
+const BLOB_OBJECT: &str = "blob";
+
+/// Objects smaller than this, once compressed, are kept in the manifest
+/// entry itself, since storing them separately would cost more than it saves.
+const INLINE_OBJECT_LIMIT: u64 = 4 * 1024;
+
+/// An object of a cache entry that is stored as a separate blob.
+#[derive(Debug, Serialize, Deserialize)]
+struct BlobRef {
+    name: String,
+    digest: String,
+    mode: Option<u32>,
+}
+
+fn blob_key(digest: &str) -> String {
+    format!("{}.blob", digest)
+}
+
+/// A cache that stores the objects of its entries as blobs addressed by the
+/// digest of their contents, so that byte-identical outputs of different
+/// compilations are only stored once.
+///
+/// Entries are stored as manifests listing the blobs they reference. Entries
+/// without a manifest, such as those written before this layout was enabled,
+/// are read as they are.
+pub struct CasStorage {
+    inner: Arc<dyn Storage>,
+    pool: ThreadPool,
+}
+
+impl CasStorage {
+    /// Create a new `CasStorage` storing manifests and blobs in `inner`.
+    pub fn new(inner: Arc<dyn Storage>, pool: &ThreadPool) -> CasStorage {
+        CasStorage {
+            inner,
+            pool: pool.clone(),
+        }
+    }
+}
+
+/// Split `entry` into a manifest entry and the blobs it references, by digest.
+fn split_entry(entry: CacheWrite) -> Result<(CacheWrite, Vec<(String, CacheWrite)>)> {
+    // Raw objects are copied as they are, so the new entries are compressed
+    // the same way as `entry`.
+    let entry_compression = entry.compression().cloned();
+    let mut entry = CacheRead::from(entry.finish()?.into_reader()?)?;
+    let mut manifest = CacheWrite::with_compression(entry_compression.clone())?;
+    let mut refs = vec![];
+    let mut blobs = vec![];
+    let mut digests = HashSet::new();
+    for name in entry.object_names() {
+        if entry.raw_object_size(&name)? < INLINE_OBJECT_LIMIT {
+            let mut raw = vec![];
+            let mode = entry.get_raw_object(&name, &mut raw)?;
+            manifest.put_raw_object(&name, &mut raw.as_slice(), mode)?;
+            continue;
+        }
+        let mut raw = SpooledEntry::new()?;
+        let mode = entry.get_raw_object(&name, &mut raw)?;
+        // Address blobs by their uncompressed contents, so that they don't
+        // depend on how they were compressed.
+        let digest = Digest::reader_sync(compression::decoder(raw.reader()?)?)?;
+        if digests.insert(digest.clone()) {
+            let mut blob = CacheWrite::with_compression(entry_compression.clone())?;
+            blob.put_raw_object(BLOB_OBJECT, &mut raw.into_reader()?, None)?;
+            blobs.push((digest.clone(), blob));
+        }
+        refs.push(BlobRef { name, digest, mode });
+    }
+    let bytes = bincode::serialize(&refs).context("Failed to serialize CAS manifest")?;
+    manifest.put_object(MANIFEST_OBJECT, &mut io::Cursor::new(bytes), None)?;
+    Ok((manifest, blobs))
+}
+
+/// Read the blobs referenced by `entry`, or `None` if it isn't a manifest.
+fn read_manifest(entry: &mut CacheRead) -> Result<Option<Vec<BlobRef>>> {
+    if !entry
+        .object_names()
+        .iter()
+        .any(|name| name == MANIFEST_OBJECT)
+    {
+        return Ok(None);
+    }
+    let mut bytes = vec![];
+    entry.get_object(MANIFEST_OBJECT, &mut bytes)?;
+    let refs = bincode::deserialize(&bytes).context("Failed to deserialize CAS manifest")?;
+    Ok(Some(refs))
+}
+
+/// Read the contents of `blob`, checking that they're what `blob_ref` was
+/// stored with.
+fn read_blob(blob_ref: &BlobRef, mut blob: CacheRead) -> Result<SpooledEntry> {
+    let mut raw = SpooledEntry::new()?;
+    blob.get_raw_object(BLOB_OBJECT, &mut raw)?;
+    let digest = Digest::reader_sync(compression::decoder(raw.reader()?)?)?;
+    if digest != blob_ref.digest {
+        bail!("Blob contents have digest {}", digest);
+    }
+    Ok(raw)
+}
+
+/// Rebuild the cache entry described by `manifest` from the `blobs` it references.
+fn join_entry(
+    mut manifest: CacheRead,
+    refs: Vec<BlobRef>,
+    blobs: Vec<SpooledEntry>,
+) -> Result<CacheRead> {
+    let tier = manifest.tier();
+    let mut entry = CacheWrite::new()?;
+    for name in manifest.object_names() {
+        if name != MANIFEST_OBJECT {
+            let mut raw = vec![];
+            let mode = manifest.get_raw_object(&name, &mut raw)?;
+            entry.put_raw_object(&name, &mut raw.as_slice(), mode)?;
+        }
+    }
+    for (blob_ref, raw) in refs.into_iter().zip(blobs) {
+        entry.put_raw_object(&blob_ref.name, &mut raw.into_reader()?, blob_ref.mode)?;
+    }
+    let entry = CacheRead::from(entry.finish()?.into_reader()?)?;
+    Ok(match tier {
+        Some(tier) => entry.with_tier(tier),
+        None => entry,
+    })
+}
+
+impl Storage for CasStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = key.to_owned();
+        let inner = self.inner.clone();
+        let pool = self.pool.clone();
+        Box::new(self.inner.get(&key).and_then(move |res| -> SFuture<Cache> {
+            let mut manifest = match res {
+                Cache::Hit(entry) => entry,
+                res => return f_ok(res),
+            };
+            let refs = match read_manifest(&mut manifest) {
+                Ok(Some(refs)) => refs,
+                Ok(None) => return f_ok(Cache::Hit(manifest)),
+                Err(e) => return f_err(e),
+            };
+            let blobs: Vec<_> = refs
+                .iter()
+                .map(|blob_ref| inner.get(&blob_key(&blob_ref.digest)))
+                .collect();
+            let check_pool = pool.clone();
+            Box::new(
+                future::join_all(blobs)
+                    .and_then(move |blobs| {
+                        // Blobs are checked against the digests in the
+                        // manifest, so that a signed or otherwise trusted
+                        // manifest covers them too.
+                        check_pool.spawn_fn(move || {
+                            let raws: Vec<_> = refs
+                                .iter()
+                                .zip(blobs)
+                                .map(|(blob_ref, blob)| match blob {
+                                    Cache::Hit(blob) => read_blob(blob_ref, blob)
+                                        .map_err(|e| {
+                                            warn!("Ignoring blob {}: {:#}", blob_ref.digest, e)
+                                        })
+                                        .ok(),
+                                    _ => None,
+                                })
+                                .collect();
+                            Ok((refs, raws))
+                        })
+                    })
+                    .and_then(move |(refs, raws)| -> SFuture<Cache> {
+                        let missing: Vec<_> = refs
+                            .iter()
+                            .zip(&raws)
+                            .filter(|(_, raw)| raw.is_none())
+                            .map(|(blob_ref, _)| blob_key(&blob_ref.digest))
+                            .collect();
+                        if !missing.is_empty() {
+                            // Remove the manifest, and anything left of the
+                            // blobs, so that the next write stores them all
+                            // again rather than the entry missing for good.
+                            debug!("Blobs {:?} of {} are missing or corrupt", missing, key);
+                            let removes: Vec<_> = missing
+                                .iter()
+                                .chain(Some(&key))
+                                .map(|key| inner.remove(key))
+                                .collect();
+                            return Box::new(future::join_all(removes).then(move |res| {
+                                if let Err(e) = res {
+                                    warn!("Failed to remove CAS manifest {}: {}", key, e);
+                                }
+                                Ok(Cache::Miss)
+                            }));
+                        }
+                        let raws = raws.into_iter().flatten().collect();
+                        Box::new(
+                            pool.spawn_fn(move || join_entry(manifest, refs, raws).map(Cache::Hit)),
+                        )
+                    }),
+            )
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let key = key.to_owned();
+        let inner = self.inner.clone();
+        let split = self.pool.spawn_fn(move || split_entry(entry));
+        Box::new(split.and_then(move |(manifest, blobs)| {
+            // Store the blobs first, so that readers never see a manifest
+            // referencing blobs that haven't been stored yet. Blobs that are
+            // already stored don't need to be written again.
+            let puts: Vec<_> = blobs
+                .into_iter()
+                .map(|(digest, blob)| {
+                    let key = blob_key(&digest);
+                    let inner = inner.clone();
+                    inner.contains(&key).and_then(move |exists| -> SFuture<_> {
+                        if exists {
+                            f_ok(Duration::default())
+                        } else {
+                            inner.put(&key, blob)
+                        }
+                    })
+                })
+                .collect();
+            future::join_all(puts)
+                .and_then(move |_| inner.put(&key, manifest))
+                .map(move |_| start.elapsed())
+        }))
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        self.inner.contains(key)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        // Only the manifest is removed, since its blobs may be shared with
+        // other entries.
+        self.inner.remove(key)
+    }
+
+    fn can_write(&self) -> bool {
+        self.inner.can_write()
+    }
+
+    fn compression(&self) -> Compression {
+        self.inner.compression()
+    }
+
+    fn location(&self) -> String {
+        format!("{} (content-addressed)", self.inner.location())
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::cache::signing::{SignedStorage, Signing};
+    use crate::config::CacheSigningConfig;
+    use crate::test::utils::*;
+    use rand::RngCore;
+    use std::io::Cursor;
+
+    fn entry(object: &[u8], stdout: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(object), Some(0o644))
+            .unwrap();
+        entry.put_stdout(stdout).unwrap();
+        entry
+    }
+
+    fn contents(cache: Cache) -> (Vec<u8>, Option<u32>, Vec<u8>) {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut bytes = vec![];
+                let mode = entry.get_object("obj", &mut bytes).unwrap();
+                (bytes, mode, entry.get_stdout().unwrap())
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_cas_dedup() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = CasStorage::new(Arc::new(disk.clone()), &pool);
+        // Incompressible, so that it's stored as a blob.
+        let mut object = vec![0; 64 * 1024];
+        rand::thread_rng().fill_bytes(&mut object);
+
+        storage.put("abcd", entry(&object, b"one")).wait().unwrap();
+        let size = disk.current_size().wait().unwrap().unwrap();
+        assert!(size > object.len() as u64);
+        storage.put("efgh", entry(&object, b"two")).wait().unwrap();
+        // Only the second manifest was added.
+        let added = disk.current_size().wait().unwrap().unwrap() - size;
+        assert!(added < INLINE_OBJECT_LIMIT);
+
+        for &(key, stdout) in &[("abcd", b"one"), ("efgh", b"two")] {
+            let (bytes, mode, out) = contents(storage.get(key).wait().unwrap());
+            assert_eq!(
+                (&object, Some(0o100644), stdout.to_vec()),
+                (&bytes, mode, out)
+            );
+        }
+        // The stored entry is a manifest, without the object itself.
+        match disk.get("abcd").wait().unwrap() {
+            Cache::Hit(entry) => {
+                let mut names = entry.object_names();
+                names.sort();
+                assert_eq!(vec![MANIFEST_OBJECT, "stdout"], names);
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_cas_missing_blob() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = CasStorage::new(Arc::new(disk.clone()), &pool);
+        let mut object = vec![0; 64 * 1024];
+        rand::thread_rng().fill_bytes(&mut object);
+
+        storage.put("abcd", entry(&object, b"")).wait().unwrap();
+        let refs = match disk.get("abcd").wait().unwrap() {
+            Cache::Hit(mut entry) => read_manifest(&mut entry).unwrap().unwrap(),
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        };
+        disk.remove(&blob_key(&refs[0].digest)).wait().unwrap();
+        // The manifest goes along with the blob, so writing the entry again
+        // stores the blob again.
+        match storage.get("abcd").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+        assert!(!disk.contains("abcd").wait().unwrap());
+        storage.put("abcd", entry(&object, b"")).wait().unwrap();
+        let (bytes, _, _) = contents(storage.get("abcd").wait().unwrap());
+        assert_eq!(object, bytes);
+    }
+
+    #[test]
+    fn test_cas_corrupt_blob() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = CasStorage::new(Arc::new(disk.clone()), &pool);
+        let mut object = vec![0; 64 * 1024];
+        rand::thread_rng().fill_bytes(&mut object);
+
+        storage.put("abcd", entry(&object, b"")).wait().unwrap();
+        let refs = match disk.get("abcd").wait().unwrap() {
+            Cache::Hit(mut entry) => read_manifest(&mut entry).unwrap().unwrap(),
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        };
+        let key = blob_key(&refs[0].digest);
+        let mut blob = CacheWrite::new().unwrap();
+        blob.put_object(BLOB_OBJECT, &mut Cursor::new(b"planted"), None)
+            .unwrap();
+        disk.put(&key, blob).wait().unwrap();
+        // A blob that doesn't match its digest is treated like a missing one.
+        match storage.get("abcd").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+        assert!(!disk.contains(&key).wait().unwrap());
+        assert!(!disk.contains("abcd").wait().unwrap());
+    }
+
+    #[test]
+    fn test_cas_signed_dedup() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let signing = Signing::from_config(&CacheSigningConfig {
+            key: Some("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        let signed = SignedStorage::new(Arc::new(disk.clone()), signing, &pool);
+        let storage = CasStorage::new(Arc::new(signed), &pool);
+        let mut object = vec![0; 64 * 1024];
+        rand::thread_rng().fill_bytes(&mut object);
+
+        // Trusted writers don't store blobs that are already stored again.
+        storage.put("abcd", entry(&object, b"one")).wait().unwrap();
+        let size = disk.current_size().wait().unwrap().unwrap();
+        storage.put("efgh", entry(&object, b"two")).wait().unwrap();
+        let added = disk.current_size().wait().unwrap().unwrap() - size;
+        assert!(added < INLINE_OBJECT_LIMIT);
+        let (bytes, _, out) = contents(storage.get("efgh").wait().unwrap());
+        assert_eq!((object, b"two".to_vec()), (bytes, out));
+    }
+
+    #[test]
+    fn test_cas_plain_entry() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = CasStorage::new(Arc::new(disk.clone()), &pool);
+        disk.put("abcd", entry(b"plain", b"")).wait().unwrap();
+        assert_eq!(
+            (b"plain".to_vec(), Some(0o100644), vec![]),
+            contents(storage.get("abcd").wait().unwrap())
+        );
+        match storage.get("efgh").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The compression of cache entry objects.
+//!
+//! Objects are always stored as zstd frames, so that reads never need to know
+//! how an object was compressed: objects stored uncompressed are written as
+//! frames of raw blocks, and objects compressed with a dictionary record its
+//! id in their frame header.
+
+use crate::cache::{Cache, CacheWrite, Storage};
+use crate::util::SpawnExt;
+use futures::Future;
+use futures_03::executor::ThreadPool;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// The zstd level objects are compressed with unless configured otherwise.
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// The highest zstd level.
+pub const MAX_LEVEL: i32 = 22;
+
+/// Objects up to this size are compressed with the dictionary, if there is
+/// one. Larger objects have enough data of their own to find patterns in.
+const DICTIONARY_OBJECT_LIMIT: usize = 64 * 1024;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+/// Enough of a frame header to find its dictionary id.
+const FRAME_HEADER_PREFIX_LEN: u64 = 10;
+
+/// The size of the blocks of frames stored uncompressed, the largest zstd allows.
+const RAW_BLOCK_SIZE: usize = 128 * 1024;
+
+/// The window descriptor of frames stored uncompressed: a window of
+/// `RAW_BLOCK_SIZE`, as blocks can't be larger than the window.
+const RAW_WINDOW_DESCRIPTOR: u8 = (17 - 10) << 3;
+
+lazy_static! {
+    /// The dictionaries objects can be decompressed with, by id. These are
+    /// global, since entries can be read from any storage.
+    static ref DICTIONARIES: RwLock<HashMap<u32, Arc<Dictionary>>> = RwLock::new(HashMap::new());
+}
+
+/// A zstd dictionary, as trained by `zstd --train`.
+pub struct Dictionary {
+    id: u32,
+    bytes: Vec<u8>,
+}
+
+impl Dictionary {
+    /// Parse the dictionary `bytes`. Only dictionaries with an id can be
+    /// used, since objects record the id of the dictionary they need.
+    pub fn new(bytes: Vec<u8>) -> Result<Dictionary> {
+        if bytes.len() < 8 || bytes[..4] != DICTIONARY_MAGIC {
+            bail!("Not a zstd dictionary");
+        }
+        let id = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if id == 0 {
+            bail!("Compression dictionaries need a non-zero id");
+        }
+        Ok(Dictionary { id, bytes })
+    }
+
+    /// Read the dictionary in the file at `path`.
+    pub fn from_file(path: &Path) -> Result<Dictionary> {
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read compression dictionary {:?}", path))?;
+        Dictionary::new(bytes).with_context(|| format!("Invalid dictionary {:?}", path))
+    }
+}
+
+/// Make `dictionary` available to decompress objects with.
+pub fn register_dictionary(dictionary: Arc<Dictionary>) {
+    DICTIONARIES
+        .write()
+        .unwrap()
+        .insert(dictionary.id, dictionary);
+}
+
+/// How the objects of a cache entry are compressed.
+#[derive(Clone)]
+pub struct Compression {
+    level: i32,
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            level: DEFAULT_LEVEL,
+            dictionary: None,
+        }
+    }
+}
+
+impl PartialEq for Compression {
+    fn eq(&self, other: &Compression) -> bool {
+        self.level == other.level
+            && self.dictionary.as_ref().map(|d| d.id) == other.dictionary.as_ref().map(|d| d.id)
+    }
+}
+
+impl std::fmt::Debug for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Compression")
+            .field("level", &self.level)
+            .field("dictionary", &self.dictionary.as_ref().map(|d| d.id))
+            .finish()
+    }
+}
+
+impl Compression {
+    /// Compress with zstd at `level`, or not at all if `level` is 0, using
+    /// `dictionary` for small objects.
+    pub fn new(level: i32, dictionary: Option<Arc<Dictionary>>) -> Compression {
+        let level = if (0..=MAX_LEVEL).contains(&level) {
+            level
+        } else {
+            warn!(
+                "Invalid compression level {}, using {}",
+                level, DEFAULT_LEVEL
+            );
+            DEFAULT_LEVEL
+        };
+        Compression { level, dictionary }
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// Compress everything read from `from` to a zstd frame written to `to`.
+    /// Returns the number of bytes read.
+    pub fn compress<R, W>(&self, from: &mut R, to: &mut W) -> Result<u64>
+    where
+        R: Read,
+        W: Write,
+    {
+        if self.level == 0 {
+            return write_raw_frame(from, to);
+        }
+        let mut head = vec![];
+        if let Some(ref dictionary) = self.dictionary {
+            from.by_ref()
+                .take(DICTIONARY_OBJECT_LIMIT as u64 + 1)
+                .read_to_end(&mut head)?;
+            if head.len() <= DICTIONARY_OBJECT_LIMIT {
+                let mut encoder = zstd::stream::write::Encoder::with_dictionary(
+                    to,
+                    self.level,
+                    &dictionary.bytes,
+                )?;
+                encoder.write_all(&head)?;
+                encoder.finish()?;
+                return Ok(head.len() as u64);
+            }
+        }
+        let mut encoder = zstd::stream::write::Encoder::new(to, self.level)?;
+        let len = io::copy(&mut Cursor::new(head).chain(from), &mut encoder)?;
+        encoder.finish()?;
+        Ok(len)
+    }
+}
+
+/// Write everything read from `from` to `to` as a zstd frame of raw blocks,
+/// which any zstd decoder can read. Returns the number of bytes read.
+fn write_raw_frame<R: Read, W: Write>(from: &mut R, to: &mut W) -> Result<u64> {
+    to.write_all(&ZSTD_MAGIC)?;
+    // No content size, checksum or dictionary id, so a window descriptor.
+    to.write_all(&[0, RAW_WINDOW_DESCRIPTOR])?;
+    let mut len = 0;
+    let mut block = Vec::with_capacity(RAW_BLOCK_SIZE);
+    let mut next = Vec::with_capacity(RAW_BLOCK_SIZE);
+    from.by_ref()
+        .take(RAW_BLOCK_SIZE as u64)
+        .read_to_end(&mut block)?;
+    loop {
+        next.clear();
+        from.by_ref()
+            .take(RAW_BLOCK_SIZE as u64)
+            .read_to_end(&mut next)?;
+        // The block size, the block type (0, raw) and whether it's the last block.
+        let last = next.is_empty();
+        let header = ((block.len() as u32) << 3) | last as u32;
+        to.write_all(&header.to_le_bytes()[..3])?;
+        to.write_all(&block)?;
+        len += block.len() as u64;
+        if last {
+            return Ok(len);
+        }
+        mem::swap(&mut block, &mut next);
+    }
+}
+
+/// The id of the dictionary the frame starting with `header` was
+/// compressed with, if any.
+fn frame_dictionary_id(header: &[u8]) -> Option<u32> {
+    if header.len() < 5 || header[..4] != ZSTD_MAGIC {
+        return None;
+    }
+    let descriptor = header[4];
+    // Frames without the single segment flag have a window descriptor.
+    let start = if descriptor & 0x20 == 0 { 6 } else { 5 };
+    let len = [0, 1, 2, 4][(descriptor & 0x3) as usize];
+    let id = header
+        .get(start..start + len)?
+        .iter()
+        .rev()
+        .fold(0, |id, &byte| (id << 8) | u32::from(byte));
+    if id == 0 {
+        None
+    } else {
+        Some(id)
+    }
+}
+
+/// A reader of the decompressed contents of the zstd frames read from
+/// `from`, however they were compressed.
+pub fn decoder<'a, R: Read + 'a>(mut from: R) -> Result<Box<dyn Read + 'a>> {
+    let mut header = vec![];
+    (&mut from)
+        .take(FRAME_HEADER_PREFIX_LEN)
+        .read_to_end(&mut header)?;
+    let dictionary = match frame_dictionary_id(&header) {
+        Some(id) => Some(
+            DICTIONARIES
+                .read()
+                .unwrap()
+                .get(&id)
+                .cloned()
+                .with_context(|| format!("Compression dictionary {} isn't configured", id))?,
+        ),
+        None => None,
+    };
+    let from = Cursor::new(header).chain(from);
+    Ok(match dictionary {
+        Some(dictionary) => Box::new(zstd::stream::read::Decoder::with_dictionary(
+            io::BufReader::new(from),
+            &dictionary.bytes,
+        )?),
+        None => Box::new(zstd::stream::read::Decoder::new(from)?),
+    })
+}
+
+/// A cache that compresses the objects of entries in `inner` in a particular
+/// way, recompressing entries that were compressed differently.
+pub struct CompressedStorage {
+    inner: Arc<dyn Storage>,
+    compression: Compression,
+    pool: ThreadPool,
+}
+
+impl CompressedStorage {
+    /// Create a new `CompressedStorage` storing entries in `inner`.
+    pub fn new(inner: Arc<dyn Storage>, compression: Compression, pool: &ThreadPool) -> Self {
+        CompressedStorage {
+            inner,
+            compression,
+            pool: pool.clone(),
+        }
+    }
+}
+
+impl Storage for CompressedStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.inner.get(key)
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if entry.compression() == Some(&self.compression) {
+            return self.inner.put(key, entry);
+        }
+        let start = Instant::now();
+        let key = key.to_owned();
+        let inner = self.inner.clone();
+        let compression = self.compression.clone();
+        let entry = self.pool.spawn_fn(move || entry.recompress(compression));
+        Box::new(
+            entry
+                .and_then(move |entry| inner.put(&key, entry))
+                .map(move |_| start.elapsed()),
+        )
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        self.inner.contains(key)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn can_write(&self) -> bool {
+        self.inner.can_write()
+    }
+
+    fn compression(&self) -> Compression {
+        self.compression.clone()
+    }
+
+    fn location(&self) -> String {
+        self.inner.location()
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::utils::*;
+
+    fn roundtrip(compression: &Compression, contents: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut compressed = vec![];
+        let len = compression
+            .compress(&mut Cursor::new(contents), &mut compressed)
+            .unwrap();
+        assert_eq!(len, contents.len() as u64);
+        let mut decompressed = vec![];
+        decoder(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        (compressed, decompressed)
+    }
+
+    /// A dictionary with the given id, in the format `zstd --train` writes.
+    fn dictionary(id: u32) -> Arc<Dictionary> {
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| {
+                format!(
+                    "foo{0}.o: /src/foo{0}.c /usr/include/stdio.h \\\n /src/bar{1}.h\n",
+                    i,
+                    i * 7 % 13
+                )
+                .into_bytes()
+            })
+            .collect();
+        let mut bytes = zstd::dict::from_samples(&samples, 4096).unwrap();
+        bytes[4..8].copy_from_slice(&id.to_le_bytes());
+        Arc::new(Dictionary::new(bytes).unwrap())
+    }
+
+    #[test]
+    fn test_uncompressed() {
+        let compression = Compression::new(0, None);
+        // Empty, several blocks with a partial last block, and an exact
+        // number of blocks.
+        for &len in &[0, RAW_BLOCK_SIZE * 5 / 2, RAW_BLOCK_SIZE] {
+            let contents: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let (compressed, decompressed) = roundtrip(&compression, &contents);
+            assert_eq!(contents, decompressed);
+            assert!(compressed.len() > contents.len());
+        }
+    }
+
+    #[test]
+    fn test_levels() {
+        let contents = b"sccache ".repeat(10_000);
+        for &level in &[1, DEFAULT_LEVEL, 19] {
+            let (compressed, decompressed) = roundtrip(&Compression::new(level, None), &contents);
+            assert_eq!(contents, decompressed);
+            assert!(compressed.len() < contents.len() / 10);
+            assert_eq!(None, frame_dictionary_id(&compressed));
+        }
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let dictionary = dictionary(0x5cca_c4e1);
+        let compression = Compression::new(DEFAULT_LEVEL, Some(dictionary.clone()));
+        let contents = b"foo.o: /src/foo.c /usr/include/stdio.h \\\n /src/bar.h\n".to_vec();
+        let mut compressed = vec![];
+        compression
+            .compress(&mut Cursor::new(&contents), &mut compressed)
+            .unwrap();
+        assert_eq!(Some(0x5cca_c4e1), frame_dictionary_id(&compressed));
+        // Frames can't be read until their dictionary is available.
+        assert!(decoder(&compressed[..]).is_err());
+        register_dictionary(dictionary);
+        let (_, decompressed) = roundtrip(&compression, &contents);
+        assert_eq!(contents, decompressed);
+
+        // Large objects don't use the dictionary.
+        let contents = vec![0; DICTIONARY_OBJECT_LIMIT + 1];
+        let (compressed, decompressed) = roundtrip(&compression, &contents);
+        assert_eq!(contents, decompressed);
+        assert_eq!(None, frame_dictionary_id(&compressed));
+    }
+
+    #[test]
+    fn test_compressed_storage() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage = CompressedStorage::new(Arc::new(disk), Compression::new(0, None), &pool);
+        let contents = b"sccache ".repeat(10_000);
+
+        // Entries compressed some other way are recompressed.
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(&contents), None)
+            .unwrap();
+        storage.put("abcd", entry).wait().unwrap();
+        match storage.get("abcd").wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                assert!(entry.raw_object_size("obj").unwrap() > contents.len() as u64);
+                let mut bytes = vec![];
+                entry.get_object("obj", &mut bytes).unwrap();
+                assert_eq!(contents, bytes);
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+}
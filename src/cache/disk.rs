@@ -12,12 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, SpooledEntry, Storage};
 use crate::lru_disk_cache::Error as LruError;
 use crate::lru_disk_cache::LruDiskCache;
 use crate::util::SpawnExt;
 use futures_03::executor::ThreadPool;
 use std::ffi::OsStr;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -35,15 +36,38 @@ pub struct DiskCache {
 
 impl DiskCache {
     /// Create a new `DiskCache` rooted at `root`, with `max_size` as the maximum cache size on-disk, in bytes.
+    /// Other processes may use the same `root` at the same time.
     pub fn new<T: AsRef<OsStr>>(root: &T, max_size: u64, pool: &ThreadPool) -> DiskCache {
         DiskCache {
             //TODO: change this function to return a Result
             lru: Arc::new(Mutex::new(
-                LruDiskCache::new(root, max_size).expect("Couldn't instantiate disk cache!"),
+                LruDiskCache::new_shared(root, max_size).expect("Couldn't instantiate disk cache!"),
             )),
             pool: pool.clone(),
         }
     }
+
+    /// Store the finished cache entry `entry` under `key`.
+    pub fn put_raw(&self, key: &str, entry: SpooledEntry) -> SFuture<Duration> {
+        trace!("DiskCache::put_raw({})", key);
+        let lru = self.lru.clone();
+        let key = make_key_path(key);
+        Box::new(self.pool.spawn_fn(move || {
+            let start = Instant::now();
+            insert_entry(&lru, key, entry)?;
+            Ok(start.elapsed())
+        }))
+    }
+}
+
+fn insert_entry(lru: &Mutex<LruDiskCache>, key: PathBuf, entry: SpooledEntry) -> Result<()> {
+    let mut reader = entry.reader()?;
+    let mut lru = lru.lock().unwrap();
+    if !lru.can_store(entry.len()) {
+        return Err(LruError::FileTooLarge.into());
+    }
+    lru.insert_with(key, |mut f| io::copy(&mut reader, &mut f).map(|_| ()))?;
+    Ok(())
 }
 
 /// Make a path to the cache entry with key `key`.
@@ -77,25 +101,48 @@ impl Storage for DiskCache {
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        // We should probably do this on a background thread if we're going to buffer
-        // everything in memory...
         trace!("DiskCache::finish_put({})", key);
         let lru = self.lru.clone();
         let key = make_key_path(key);
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
-            let v = entry.finish()?;
-            lru.lock().unwrap().insert_bytes(key, &v)?;
+            let entry = entry.finish()?;
+            insert_entry(&lru, key, entry)?;
             Ok(start.elapsed())
         }))
     }
 
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        let lru = self.lru.clone();
+        let key = make_key_path(key);
+        Box::new(self.pool.spawn_fn(move || {
+            let mut lru = lru.lock().unwrap();
+            lru.refresh()?;
+            Ok(lru.contains_key(key))
+        }))
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        trace!("DiskCache::remove({})", key);
+        let lru = self.lru.clone();
+        let key = make_key_path(key);
+        Box::new(self.pool.spawn_fn(move || {
+            lru.lock().unwrap().remove(key)?;
+            Ok(())
+        }))
+    }
+
     fn location(&self) -> String {
         format!("Local disk: {:?}", self.lru.lock().unwrap().path())
     }
 
     fn current_size(&self) -> SFuture<Option<u64>> {
-        f_ok(Some(self.lru.lock().unwrap().size()))
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let mut lru = lru.lock().unwrap();
+            lru.refresh()?;
+            Ok(Some(lru.size()))
+        }))
     }
     fn max_size(&self) -> SFuture<Option<u64>> {
         f_ok(Some(self.lru.lock().unwrap().capacity()))
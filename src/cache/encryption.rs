@@ -0,0 +1,421 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::compression::Compression;
+use crate::cache::{
+    Cache, CacheRead, CacheWrite, CacheWriteSkipped, SpooledEntry, Storage, SPOOL_CHUNK_SIZE,
+};
+use crate::config::CacheEncryptionConfig;
+use crate::util::SpawnExt;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use futures::Future;
+use futures_03::executor::ThreadPool;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::cmp;
+use std::fs;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// The object in an encrypted entry that holds the encrypted entry.
+const ENCRYPTED_OBJECT: &str = "encrypted";
+
+/// The length of encryption keys, in bytes.
+const KEY_LEN: usize = 32;
+
+/// The length of the random part of chunk nonces. The rest is the chunk's
+/// index and whether it's the last chunk.
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 5;
+
+/// A key to encrypt cache entries with, and the id that entries encrypted
+/// with it record.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: String,
+    key: [u8; KEY_LEN],
+}
+
+impl EncryptionKey {
+    /// Parse a key written as `<key id>:<base64-encoded 256-bit key>`.
+    pub fn parse(s: &str) -> Result<EncryptionKey> {
+        let mut parts = s.trim().rsplitn(2, ':');
+        let (key, id) = match (parts.next(), parts.next()) {
+            (Some(key), Some(id)) if !id.is_empty() && id.len() <= 255 => (key, id),
+            _ => bail!(
+                "Encryption keys must be written as `<key id>:<base64 key>`, \
+                 with an id of at most 255 bytes"
+            ),
+        };
+        // Don't include the key in errors, it's a secret.
+        let bytes = base64::decode(key)
+            .ok()
+            .filter(|bytes| bytes.len() == KEY_LEN)
+            .with_context(|| format!("Encryption key `{}` isn't {} base64 bytes", id, KEY_LEN))?;
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&bytes);
+        Ok(EncryptionKey {
+            id: id.to_owned(),
+            key,
+        })
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &self.key).expect("Invalid key length");
+        LessSafeKey::new(key)
+    }
+}
+
+/// Load the keys in `config`. The first key encrypts new entries, and all
+/// of them decrypt entries.
+pub fn keys_from_config(config: &CacheEncryptionConfig) -> Result<Vec<EncryptionKey>> {
+    let mut keys = vec![];
+    if let Some(ref key) = config.key {
+        keys.push(EncryptionKey::parse(key)?);
+    }
+    if let Some(ref path) = config.key_file {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read encryption keys from {:?}", path))?;
+        for line in contents.lines().map(str::trim) {
+            if !line.is_empty() && !line.starts_with('#') {
+                keys.push(EncryptionKey::parse(line)?);
+            }
+        }
+    }
+    if keys.is_empty() {
+        bail!("No encryption keys configured");
+    }
+    Ok(keys)
+}
+
+/// The number of chunks an entry of `len` bytes is encrypted in. Even an
+/// empty entry has one, so that there's always a last chunk to authenticate.
+fn chunk_count(len: u64) -> Result<u32> {
+    let chunk_size = SPOOL_CHUNK_SIZE as u64;
+    let chunks = cmp::max(1, len.div_ceil(chunk_size));
+    if chunks > u64::from(u32::MAX) {
+        bail!("Cache entry is too large to encrypt");
+    }
+    Ok(chunks as u32)
+}
+
+/// The nonce of the chunk at `index`, so that chunks can't be reordered,
+/// and an entry can't be truncated without its last chunk failing to decrypt.
+fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], index: u32, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Encrypt the finished entry `entry` with `key`, for storage under `cache_key`.
+///
+/// The result is a header of the key id, the random nonce prefix and the
+/// length of `entry`, followed by `entry` in chunks sealed with
+/// ChaCha20-Poly1305. The chunks are authenticated along with `cache_key`,
+/// so that an entry can't be passed off as the entry of another key.
+fn encrypt(entry: &SpooledEntry, key: &EncryptionKey, cache_key: &str) -> Result<SpooledEntry> {
+    let mut prefix = [0; NONCE_PREFIX_LEN];
+    SystemRandom::new()
+        .fill(&mut prefix)
+        .map_err(|_| anyhow!("Failed to generate a nonce"))?;
+    let mut encrypted = SpooledEntry::new()?;
+    encrypted.write_u8(key.id.len() as u8)?;
+    encrypted.write_all(key.id.as_bytes())?;
+    encrypted.write_all(&prefix)?;
+    encrypted.write_u64::<LittleEndian>(entry.len())?;
+
+    let aead_key = key.aead_key();
+    let chunks = chunk_count(entry.len())?;
+    let mut reader = entry.reader()?;
+    for index in 0..chunks {
+        let mut chunk = Vec::with_capacity(SPOOL_CHUNK_SIZE + aead_key.algorithm().tag_len());
+        (&mut reader)
+            .take(SPOOL_CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)?;
+        aead_key
+            .seal_in_place_append_tag(
+                nonce(&prefix, index, index + 1 == chunks),
+                Aad::from(cache_key.as_bytes()),
+                &mut chunk,
+            )
+            .map_err(|_| anyhow!("Failed to encrypt cache entry"))?;
+        encrypted.write_all(&chunk)?;
+    }
+    Ok(encrypted)
+}
+
+/// Decrypt an entry stored under `cache_key` that was encrypted by
+/// `encrypt` with one of `keys`.
+fn decrypt<R: Read>(
+    mut reader: R,
+    keys: &[EncryptionKey],
+    cache_key: &str,
+) -> Result<SpooledEntry> {
+    let mut id = vec![0; reader.read_u8()? as usize];
+    reader.read_exact(&mut id)?;
+    let key = keys
+        .iter()
+        .find(|key| key.id.as_bytes() == id.as_slice())
+        .with_context(|| format!("Unknown key `{}`", String::from_utf8_lossy(&id)))?;
+    let mut prefix = [0; NONCE_PREFIX_LEN];
+    reader.read_exact(&mut prefix)?;
+    let len = reader.read_u64::<LittleEndian>()?;
+
+    let aead_key = key.aead_key();
+    let tag_len = aead_key.algorithm().tag_len();
+    let chunks = chunk_count(len)?;
+    let mut remaining = len;
+    let mut entry = SpooledEntry::new()?;
+    for index in 0..chunks {
+        let size = cmp::min(remaining, SPOOL_CHUNK_SIZE as u64) as usize;
+        let mut chunk = vec![0; size + tag_len];
+        reader.read_exact(&mut chunk)?;
+        let plaintext = aead_key
+            .open_in_place(
+                nonce(&prefix, index, index + 1 == chunks),
+                Aad::from(cache_key.as_bytes()),
+                &mut chunk,
+            )
+            .map_err(|_| anyhow!("Failed to authenticate cache entry"))?;
+        entry.write_all(plaintext)?;
+        remaining -= size as u64;
+    }
+    if reader.read(&mut [0])? != 0 {
+        bail!("Unexpected data after the last chunk");
+    }
+    Ok(entry)
+}
+
+/// A cache that encrypts entries before storing them in `inner`.
+///
+/// Entries that can't be decrypted, because they were written without
+/// encryption or with a key that isn't configured, or have been tampered
+/// with, are treated as misses.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    keys: Arc<Vec<EncryptionKey>>,
+    pool: ThreadPool,
+}
+
+impl EncryptedStorage {
+    /// Create a new `EncryptedStorage` storing entries in `inner`, encrypted
+    /// with the first of `keys`. Without keys, every lookup misses and
+    /// writes are skipped.
+    pub fn new(inner: Arc<dyn Storage>, keys: Vec<EncryptionKey>, pool: &ThreadPool) -> Self {
+        EncryptedStorage {
+            inner,
+            keys: Arc::new(keys),
+            pool: pool.clone(),
+        }
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = key.to_owned();
+        let keys = self.keys.clone();
+        let pool = self.pool.clone();
+        Box::new(self.inner.get(&key).and_then(move |res| -> SFuture<Cache> {
+            let mut entry = match res {
+                Cache::Hit(entry) => entry,
+                res => return f_ok(res),
+            };
+            Box::new(pool.spawn_fn(move || {
+                let decrypted = (|| -> Result<_> {
+                    let mut encrypted = SpooledEntry::new()?;
+                    entry.get_raw_object(ENCRYPTED_OBJECT, &mut encrypted)?;
+                    let decrypted = decrypt(encrypted.into_reader()?, &keys, &key)?;
+                    CacheRead::from(decrypted.into_reader()?)
+                })();
+                match decrypted {
+                    Ok(entry) => Ok(Cache::Hit(entry)),
+                    Err(e) => {
+                        warn!("Failed to decrypt cache entry {}: {:#}", key, e);
+                        Ok(Cache::Miss)
+                    }
+                }
+            }))
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let encryption_key = match self.keys.first() {
+            Some(encryption_key) => encryption_key.clone(),
+            None => return f_err(CacheWriteSkipped),
+        };
+        let key = key.to_owned();
+        let inner = self.inner.clone();
+        let cache_key = key.clone();
+        let encrypted = self.pool.spawn_fn(move || -> Result<_> {
+            let encrypted = encrypt(&entry.finish()?, &encryption_key, &cache_key)?;
+            let mut entry = CacheWrite::new()?;
+            entry.put_raw_object(ENCRYPTED_OBJECT, &mut encrypted.into_reader()?, None)?;
+            Ok(entry)
+        });
+        Box::new(
+            encrypted
+                .and_then(move |entry| inner.put(&key, entry))
+                .map(move |_| start.elapsed()),
+        )
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        self.inner.contains(key)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.inner.remove(key)
+    }
+
+    fn can_write(&self) -> bool {
+        self.inner.can_write()
+    }
+
+    fn compression(&self) -> Compression {
+        self.inner.compression()
+    }
+
+    fn location(&self) -> String {
+        format!("{} (encrypted)", self.inner.location())
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::utils::*;
+    use rand::RngCore;
+    use std::io::Cursor;
+
+    const KEY: &str = "2021-01:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const NEW_KEY: &str = "2021-06:Hx4dHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(contents), None)
+            .unwrap();
+        entry
+    }
+
+    fn contents(cache: Cache) -> Option<Vec<u8>> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut bytes = vec![];
+                entry.get_object("obj", &mut bytes).unwrap();
+                Some(bytes)
+            }
+            Cache::Miss => None,
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    fn keys(keys: &[&str]) -> Vec<EncryptionKey> {
+        keys.iter()
+            .map(|key| EncryptionKey::parse(key).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = EncryptionKey::parse(KEY).unwrap();
+        assert_eq!(key.id, "2021-01");
+        assert_eq!(key.key[31], 31);
+        assert!(EncryptionKey::parse("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").is_err());
+        assert!(EncryptionKey::parse("short:AAECAwQ=").is_err());
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = EncryptionKey::parse(KEY).unwrap();
+        // Several chunks, the last of them partial, an exact number of
+        // chunks, and nothing at all.
+        for &len in &[SPOOL_CHUNK_SIZE * 5 / 2, SPOOL_CHUNK_SIZE, 0] {
+            let mut contents = vec![0; len];
+            rand::thread_rng().fill_bytes(&mut contents);
+            let plaintext = SpooledEntry::from_reader(&mut Cursor::new(&contents)).unwrap();
+            let encrypted = encrypt(&plaintext, &key, "abcd").unwrap();
+            let encrypted = encrypted.into_vec().unwrap();
+            assert!(contents.is_empty() || !encrypted.windows(64).any(|w| w == &contents[..64]));
+
+            let keys = std::slice::from_ref(&key);
+            let decrypted = decrypt(&encrypted[..], keys, "abcd").unwrap();
+            assert_eq!(contents, decrypted.into_vec().unwrap());
+            // The entry of one key can't be passed off as the entry of another.
+            assert!(decrypt(&encrypted[..], keys, "efgh").is_err());
+            // Truncated, extended and modified entries don't decrypt.
+            let truncated = &encrypted[..encrypted.len() - 1];
+            assert!(decrypt(truncated, keys, "abcd").is_err());
+            let mut extended = encrypted.clone();
+            extended.push(0);
+            assert!(decrypt(&extended[..], keys, "abcd").is_err());
+            let mut modified = encrypted.clone();
+            *modified.last_mut().unwrap() ^= 1;
+            assert!(decrypt(&modified[..], keys, "abcd").is_err());
+        }
+    }
+
+    #[test]
+    fn test_encrypted_storage() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let disk: Arc<dyn Storage> = Arc::new(disk);
+        let storage = EncryptedStorage::new(disk.clone(), keys(&[KEY]), &pool);
+
+        storage.put("abcd", entry(b"secret")).wait().unwrap();
+        assert_eq!(
+            Some(b"secret".to_vec()),
+            contents(storage.get("abcd").wait().unwrap())
+        );
+        // The stored entry doesn't contain the object.
+        match disk.get("abcd").wait().unwrap() {
+            Cache::Hit(entry) => assert_eq!(entry.object_names(), vec![ENCRYPTED_OBJECT]),
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+
+        // After rotating keys, old entries can still be read as long as
+        // their key is configured.
+        let rotated = EncryptedStorage::new(disk.clone(), keys(&[NEW_KEY, KEY]), &pool);
+        assert_eq!(
+            Some(b"secret".to_vec()),
+            contents(rotated.get("abcd").wait().unwrap())
+        );
+        let rotated = EncryptedStorage::new(disk.clone(), keys(&[NEW_KEY]), &pool);
+        assert_eq!(None, contents(rotated.get("abcd").wait().unwrap()));
+
+        // Unencrypted entries are misses too.
+        disk.put("efgh", entry(b"plaintext")).wait().unwrap();
+        assert_eq!(None, contents(storage.get("efgh").wait().unwrap()));
+
+        // Without keys, nothing can be written.
+        let storage = EncryptedStorage::new(disk, vec![], &pool);
+        let err = storage.put("abcd", entry(b"secret")).wait().unwrap_err();
+        assert!(err.downcast_ref::<CacheWriteSkipped>().is_some());
+    }
+}
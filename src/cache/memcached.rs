@@ -13,32 +13,168 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, SpooledEntry, Storage};
 use crate::errors::*;
-use crate::util::SpawnExt;
+use crate::util::{Digest, SpawnExt};
 use futures_03::executor::ThreadPool;
 use memcached::client::Client;
-use memcached::proto::NoReplyOperation;
 use memcached::proto::Operation;
 use memcached::proto::ProtoType::Binary;
 use std::cell::RefCell;
-use std::io::Cursor;
+use std::io::Write;
 use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 thread_local! {
     static CLIENT: RefCell<Option<Client>> = RefCell::default();
 }
 
+/// Entries larger than this are split into chunks, as memcached's default
+/// item size limit of 1MiB also covers the key and the item's overhead.
+const CHUNK_SIZE: usize = 1000 * 1024;
+
+/// The flags of an item holding a `ChunkedEntry` rather than an entry.
+const FLAG_CHUNKED: u32 = 1;
+
+/// The record stored under an entry's key when the entry is too large for
+/// a single item.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkedEntry {
+    /// The keys and sizes of the chunks, in order.
+    chunks: Vec<(String, u64)>,
+    /// The digest of the whole entry.
+    digest: String,
+}
+
+/// The memcached operations that entries are stored with.
+trait Items {
+    /// Get the value and flags of the item at `key`, if it exists.
+    fn get_item(&mut self, key: &str) -> Result<Option<(Vec<u8>, u32)>>;
+
+    /// Set the item at `key` to `value`, with `flags`.
+    fn set_item(&mut self, key: &str, value: &[u8], flags: u32) -> Result<()>;
+
+    /// Delete the item at `key`, if it exists.
+    fn delete_item(&mut self, key: &str) -> Result<()>;
+}
+
+struct Connection<'a> {
+    client: &'a mut Client,
+    /// The expiration time of new items, in seconds.
+    expiration: u32,
+}
+
+impl<'a> Items for Connection<'a> {
+    fn get_item(&mut self, key: &str) -> Result<Option<(Vec<u8>, u32)>> {
+        Ok(self.client.get(key.as_bytes()).ok())
+    }
+
+    fn set_item(&mut self, key: &str, value: &[u8], flags: u32) -> Result<()> {
+        Ok(self
+            .client
+            .set(key.as_bytes(), value, flags, self.expiration)?)
+    }
+
+    fn delete_item(&mut self, key: &str) -> Result<()> {
+        // Deleting a missing item fails, like getting one does.
+        drop(self.client.delete(key.as_bytes()));
+        Ok(())
+    }
+}
+
+/// Read the entry at `key`, reassembling it if it was split into chunks.
+/// Returns `None` if the entry, or any of its chunks, doesn't exist.
+fn get_entry<I: Items>(items: &mut I, key: &str) -> Result<Option<SpooledEntry>> {
+    let (value, flags) = match items.get_item(key)? {
+        Some(item) => item,
+        None => return Ok(None),
+    };
+    if flags != FLAG_CHUNKED {
+        return SpooledEntry::from_reader(&mut &value[..]).map(Some);
+    }
+    let header: ChunkedEntry =
+        bincode::deserialize(&value).context("Failed to deserialize chunked entry")?;
+    let mut entry = SpooledEntry::new()?;
+    let mut digest = Digest::new();
+    for (chunk_key, size) in &header.chunks {
+        let chunk = match items.get_item(chunk_key)? {
+            Some((chunk, _)) => chunk,
+            // Chunks may be evicted separately from the header.
+            None => {
+                debug!("Missing chunk {} of {}", chunk_key, key);
+                return Ok(None);
+            }
+        };
+        if chunk.len() as u64 != *size {
+            bail!("Chunk {} of {} has the wrong size", chunk_key, key);
+        }
+        digest.update(&chunk);
+        entry.write_all(&chunk)?;
+    }
+    if digest.finish() != header.digest {
+        bail!("Chunks of {} don't match the entry's digest", key);
+    }
+    Ok(Some(entry))
+}
+
+/// Store `entry` at `key`, splitting it into chunks if it's too large for
+/// a single item.
+fn put_entry<I: Items>(items: &mut I, key: &str, entry: SpooledEntry) -> Result<()> {
+    if entry.len() <= CHUNK_SIZE as u64 {
+        return items.set_item(key, &entry.into_vec()?, 0);
+    }
+    // Chunk keys are unique to this write, so that concurrent writes of the
+    // same entry don't mix their chunks.
+    let write_id = Uuid::new_v4().to_simple();
+    let mut header = ChunkedEntry {
+        chunks: vec![],
+        digest: String::new(),
+    };
+    let mut digest = Digest::new();
+    for (i, chunk) in entry.chunks(CHUNK_SIZE)?.enumerate() {
+        let chunk = chunk?;
+        let chunk_key = format!("{}.{}.{}", key, write_id, i);
+        items.set_item(&chunk_key, &chunk, 0)?;
+        digest.update(&chunk);
+        header.chunks.push((chunk_key, chunk.len() as u64));
+    }
+    header.digest = digest.finish();
+    // The header is stored last, so that readers never find a partly
+    // written entry.
+    let header = bincode::serialize(&header).context("Failed to serialize chunked entry")?;
+    items.set_item(key, &header, FLAG_CHUNKED)
+}
+
+/// Remove the entry at `key`, along with its chunks if it was split into them.
+fn remove_entry<I: Items>(items: &mut I, key: &str) -> Result<()> {
+    let header = match items.get_item(key)? {
+        Some((value, FLAG_CHUNKED)) => bincode::deserialize::<ChunkedEntry>(&value).ok(),
+        _ => None,
+    };
+    items.delete_item(key)?;
+    // The chunks of a header that can't be read are left to expire or be evicted.
+    if let Some(header) = header {
+        for (chunk_key, _) in &header.chunks {
+            items.delete_item(chunk_key)?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct MemcachedCache {
     url: String,
+    expiration: u32,
     pool: ThreadPool,
 }
 
 impl MemcachedCache {
-    pub fn new(url: &str, pool: &ThreadPool) -> Result<MemcachedCache> {
+    /// Create a new `MemcachedCache` for the servers in `url`, storing items
+    /// that expire after `expiration` seconds, or never if it's 0.
+    pub fn new(url: &str, expiration: u32, pool: &ThreadPool) -> Result<MemcachedCache> {
         Ok(MemcachedCache {
             url: url.to_owned(),
+            expiration,
             pool: pool.clone(),
         })
     }
@@ -49,7 +185,7 @@ impl MemcachedCache {
 
     fn exec<U, F>(&self, f: F) -> U
     where
-        F: FnOnce(&mut Client) -> U,
+        F: FnOnce(&mut Connection<'_>) -> U,
     {
         CLIENT.with(|rc| {
             match *rc.borrow_mut() {
@@ -60,7 +196,12 @@ impl MemcachedCache {
                 }
             }
             .as_mut()
-            .map(f)
+            .map(|client| {
+                f(&mut Connection {
+                    client,
+                    expiration: self.expiration,
+                })
+            })
             .unwrap()
         })
     }
@@ -70,11 +211,13 @@ impl Storage for MemcachedCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
         let key = key.to_owned();
         let me = self.clone();
-        Box::new(self.pool.spawn_fn(move || {
-            me.exec(|c| c.get(&key.as_bytes()))
-                .map(|(d, _)| CacheRead::from(Cursor::new(d)).map(Cache::Hit))
-                .unwrap_or(Ok(Cache::Miss))
-        }))
+        Box::new(
+            self.pool
+                .spawn_fn(move || match me.exec(|c| get_entry(c, &key))? {
+                    Some(entry) => CacheRead::from(entry.into_reader()?).map(Cache::Hit),
+                    None => Ok(Cache::Miss),
+                }),
+        )
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
@@ -82,12 +225,21 @@ impl Storage for MemcachedCache {
         let me = self.clone();
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
-            let d = entry.finish()?;
-            me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, 0))?;
+            let entry = entry.finish()?;
+            me.exec(|c| put_entry(c, &key, entry))?;
             Ok(start.elapsed())
         }))
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = key.to_owned();
+        let me = self.clone();
+        Box::new(
+            self.pool
+                .spawn_fn(move || me.exec(|c| remove_entry(c, &key))),
+        )
+    }
+
     fn location(&self) -> String {
         format!("Memcached: {}", self.url)
     }
@@ -99,3 +251,73 @@ impl Storage for MemcachedCache {
         f_ok(None)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    impl Items for HashMap<String, (Vec<u8>, u32)> {
+        fn get_item(&mut self, key: &str) -> Result<Option<(Vec<u8>, u32)>> {
+            Ok(self.get(key).cloned())
+        }
+
+        fn set_item(&mut self, key: &str, value: &[u8], flags: u32) -> Result<()> {
+            assert!(value.len() <= CHUNK_SIZE, "item too large");
+            self.insert(key.to_owned(), (value.to_vec(), flags));
+            Ok(())
+        }
+
+        fn delete_item(&mut self, key: &str) -> Result<()> {
+            self.remove(key);
+            Ok(())
+        }
+    }
+
+    fn spooled(contents: &[u8]) -> SpooledEntry {
+        SpooledEntry::from_reader(&mut &contents[..]).unwrap()
+    }
+
+    fn contents(entry: Option<SpooledEntry>) -> Vec<u8> {
+        entry.unwrap().into_vec().unwrap()
+    }
+
+    #[test]
+    fn test_small_entry() {
+        let mut items = HashMap::new();
+        put_entry(&mut items, "abcd", spooled(b"contents")).unwrap();
+        assert_eq!(
+            Some(&(b"contents".to_vec(), 0)),
+            items.get("abcd"),
+            "small entries are stored as they are"
+        );
+        assert_eq!(
+            b"contents".to_vec(),
+            contents(get_entry(&mut items, "abcd").unwrap())
+        );
+        assert!(get_entry(&mut items, "efgh").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_chunked_entry() {
+        let data = (0..CHUNK_SIZE * 5 / 2).map(|i| i as u8).collect::<Vec<_>>();
+        let mut items = HashMap::new();
+        put_entry(&mut items, "abcd", spooled(&data)).unwrap();
+        assert_eq!(4, items.len());
+        assert_eq!(FLAG_CHUNKED, items["abcd"].1);
+        assert!(contents(get_entry(&mut items, "abcd").unwrap()) == data);
+
+        let header: ChunkedEntry = bincode::deserialize(&items["abcd"].0).unwrap();
+        let (ref last_chunk, _) = header.chunks[2];
+        // A chunk whose contents changed fails verification.
+        items.get_mut(last_chunk).unwrap().0[0] ^= 1;
+        assert!(get_entry(&mut items, "abcd").is_err());
+        // A missing chunk makes the whole entry a miss.
+        items.remove(last_chunk);
+        assert!(get_entry(&mut items, "abcd").unwrap().is_none());
+
+        // Removing the entry removes its remaining chunks.
+        remove_entry(&mut items, "abcd").unwrap();
+        assert!(items.is_empty());
+    }
+}
@@ -16,14 +16,24 @@
 pub mod azure;
 #[allow(clippy::module_inception)]
 pub mod cache;
+pub mod cas;
+pub mod compression;
 pub mod disk;
+pub mod encryption;
 #[cfg(feature = "gcs")]
 pub mod gcs;
 #[cfg(feature = "memcached")]
 pub mod memcached;
+#[cfg(feature = "reapi")]
+pub mod reapi;
 #[cfg(feature = "redis")]
 pub mod redis;
+pub mod rw_mode;
 #[cfg(feature = "s3")]
 pub mod s3;
+pub mod signing;
+pub mod tiered;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
 pub use crate::cache::cache::*;
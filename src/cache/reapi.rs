@@ -0,0 +1,578 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{Cache, CacheRead, CacheWrite, SpooledEntry, Storage};
+use crate::config::ReapiCacheConfig;
+use crate::reapi::{ActionResult, Digest, GrpcTransport, HttpTransport, OutputFile, Transport};
+use crate::util::SpawnExt;
+use futures::future::{self, Future};
+use futures_03::executor::ThreadPool;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor, Read};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// The names that a cache entry's stdout and stderr are stored at.
+const STDOUT_OBJECT: &str = "stdout";
+const STDERR_OBJECT: &str = "stderr";
+
+/// A cache that stores entries in a Remote Execution API server, such as
+/// bazel-remote or BuildBuddy.
+///
+/// Each entry is stored as the action result of an action whose digest is
+/// that of the sccache key. Its objects are output files whose contents are
+/// stored, uncompressed, in the content-addressable storage, and its stdout
+/// and stderr are inlined in the action result.
+pub struct ReapiCache {
+    transport: Rc<dyn Transport>,
+    pool: ThreadPool,
+}
+
+impl ReapiCache {
+    /// Create a new `ReapiCache` from `config`.
+    pub fn new(config: &ReapiCacheConfig, pool: &ThreadPool) -> Result<ReapiCache> {
+        let transport: Rc<dyn Transport> =
+            if config.endpoint.starts_with("http://") || config.endpoint.starts_with("https://") {
+                Rc::new(HttpTransport::new(&config.endpoint)?)
+            } else {
+                Rc::new(GrpcTransport::new(&config.endpoint, &config.instance_name)?)
+            };
+        Ok(ReapiCache::with_transport(transport, pool))
+    }
+
+    fn with_transport(transport: Rc<dyn Transport>, pool: &ThreadPool) -> ReapiCache {
+        ReapiCache {
+            transport,
+            pool: pool.clone(),
+        }
+    }
+}
+
+/// The digest of the action that `key` is stored as the result of.
+fn action_digest(key: &str) -> Digest {
+    Digest::of(key.as_bytes())
+}
+
+/// Split `entry` into an action result and the blobs its output files
+/// refer to, leaving out empty blobs, which servers always have.
+fn action_result(entry: CacheWrite) -> Result<(ActionResult, Vec<(Digest, SpooledEntry)>)> {
+    let mut entry = CacheRead::from(entry.finish()?.into_reader()?)?;
+    let mut result = ActionResult::default();
+    let mut blobs = vec![];
+    let mut names = entry.object_names();
+    names.sort();
+    for name in names {
+        match name.as_str() {
+            STDOUT_OBJECT => result.stdout_raw = entry.get_stdout()?,
+            STDERR_OBJECT => result.stderr_raw = entry.get_stderr()?,
+            _ => {
+                let mut blob = SpooledEntry::new()?;
+                let mode = entry.get_object(&name, &mut blob)?;
+                let digest = Digest::of_reader(blob.reader()?)?;
+                result.output_files.push(OutputFile {
+                    path: name,
+                    digest: Some(digest.clone()),
+                    is_executable: mode.unwrap_or(0) & 0o111 != 0,
+                    contents: vec![],
+                    unix_mode: mode.map(|mode| mode & 0o7777),
+                });
+                if !blob.is_empty() && !blobs.iter().any(|(d, _)| *d == digest) {
+                    blobs.push((digest, blob));
+                }
+            }
+        }
+    }
+    Ok((result, blobs))
+}
+
+/// The digests of the blobs that `result` refers to but doesn't inline.
+fn referenced_blobs(result: &ActionResult) -> Vec<Digest> {
+    let mut digests = HashSet::new();
+    for file in &result.output_files {
+        if let Some(ref digest) = file.digest {
+            if file.contents.is_empty() {
+                digests.insert(digest.clone());
+            }
+        }
+    }
+    if result.stdout_raw.is_empty() {
+        digests.extend(result.stdout_digest.clone());
+    }
+    if result.stderr_raw.is_empty() {
+        digests.extend(result.stderr_digest.clone());
+    }
+    digests.retain(|digest| digest.size_bytes != 0);
+    digests.into_iter().collect()
+}
+
+/// Build a cache entry from `result` and the `blobs` it refers to.
+fn cache_entry(result: ActionResult, blobs: HashMap<Digest, SpooledEntry>) -> Result<CacheRead> {
+    let read_blob = |digest: &Option<Digest>| -> Result<Box<dyn Read>> {
+        match *digest {
+            Some(ref digest) if digest.size_bytes != 0 => match blobs.get(digest) {
+                Some(blob) => Ok(Box::new(blob.reader()?)),
+                None => bail!("Missing blob {}", digest.hash),
+            },
+            _ => Ok(Box::new(io::empty())),
+        }
+    };
+    let mut entry = CacheWrite::new()?;
+    for file in &result.output_files {
+        let mut reader = if file.contents.is_empty() {
+            read_blob(&file.digest)?
+        } else {
+            Box::new(Cursor::new(file.contents.clone()))
+        };
+        let mode = match file.unix_mode {
+            Some(mode) => Some(mode),
+            None if file.is_executable => Some(0o755),
+            None => None,
+        };
+        entry.put_object(&file.path, &mut reader, mode)?;
+    }
+    let output = |raw: &[u8], digest: &Option<Digest>| -> Result<Vec<u8>> {
+        let mut bytes = raw.to_vec();
+        if bytes.is_empty() {
+            read_blob(digest)?.read_to_end(&mut bytes)?;
+        }
+        Ok(bytes)
+    };
+    let stdout = output(&result.stdout_raw, &result.stdout_digest)?;
+    if !stdout.is_empty() {
+        entry.put_stdout(&stdout)?;
+    }
+    let stderr = output(&result.stderr_raw, &result.stderr_digest)?;
+    if !stderr.is_empty() {
+        entry.put_stderr(&stderr)?;
+    }
+    CacheRead::from(entry.finish()?.into_reader()?)
+}
+
+impl Storage for ReapiCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let transport = self.transport.clone();
+        let pool = self.pool.clone();
+        Box::new(
+            self.transport
+                .get_action_result(&action_digest(key))
+                .and_then(move |result| -> SFuture<Cache> {
+                    let result = match result {
+                        Some(result) => result,
+                        None => return f_ok(Cache::Miss),
+                    };
+                    let reads = referenced_blobs(&result)
+                        .into_iter()
+                        .map(|digest| transport.read_blob(&digest).map(|blob| (digest, blob)))
+                        .collect::<Vec<_>>();
+                    Box::new(
+                        future::join_all(reads).and_then(move |reads| -> SFuture<Cache> {
+                            let mut blobs = HashMap::new();
+                            for (digest, blob) in reads {
+                                match blob {
+                                    Some(ref blob) if blob.len() as i64 != digest.size_bytes => {
+                                        return f_err(anyhow!(
+                                            "Blob {} has the wrong size",
+                                            digest.hash
+                                        ))
+                                    }
+                                    Some(blob) => {
+                                        blobs.insert(digest, blob);
+                                    }
+                                    // The blobs of an action result may be evicted
+                                    // before the result itself.
+                                    None => {
+                                        debug!("Missing blob {}", digest.hash);
+                                        return f_ok(Cache::Miss);
+                                    }
+                                }
+                            }
+                            Box::new(
+                                pool.spawn_fn(move || cache_entry(result, blobs).map(Cache::Hit)),
+                            )
+                        }),
+                    )
+                }),
+        )
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let action_digest = action_digest(key);
+        let transport = self.transport.clone();
+        let split = self.pool.spawn_fn(move || action_result(entry));
+        Box::new(split.and_then(move |(result, blobs)| {
+            let digests = blobs.iter().map(|(digest, _)| digest.clone()).collect();
+            transport
+                .find_missing_blobs(digests)
+                .and_then(move |missing| {
+                    // Upload the blobs before the action result that refers
+                    // to them, so that readers never see a partial entry.
+                    let writes = blobs
+                        .into_iter()
+                        .filter(|(digest, _)| missing.contains(digest))
+                        .map(|(digest, blob)| transport.write_blob(&digest, blob))
+                        .collect::<Vec<_>>();
+                    future::join_all(writes)
+                        .and_then(move |_| transport.update_action_result(&action_digest, result))
+                })
+                .map(move |_| start.elapsed())
+        }))
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        Box::new(
+            self.transport
+                .get_action_result(&action_digest(key))
+                .map(|result| result.is_some()),
+        )
+    }
+
+    fn location(&self) -> String {
+        self.transport.location()
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reapi::{
+        frame, FindMissingBlobsRequest, FindMissingBlobsResponse, GetActionResultRequest,
+        GrpcStatus, Message, ReadRequest, ReadResponse, UpdateActionResultRequest, WriteRequest,
+        WriteResponse,
+    };
+    use crate::test::utils::ThreadPoolExt;
+    use futures::{Async, Poll, Stream};
+    use hyper::body::Payload;
+    use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+    use hyper::service::service_fn;
+    use hyper::{Body, Chunk, Method, Response, Server, StatusCode};
+    use std::sync::{Arc, Mutex};
+    use tokio_compat::runtime::current_thread::Runtime;
+
+    /// The contents of a fake server, by `/ac/{hash}` or `/cas/{hash}` path.
+    type Blobs = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// A gRPC response body, with its status in the trailers.
+    struct GrpcBody {
+        data: Option<Chunk>,
+        trailers: Option<HeaderMap>,
+    }
+
+    impl Payload for GrpcBody {
+        type Data = Chunk;
+        type Error = hyper::Error;
+
+        fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+            Ok(Async::Ready(self.data.take()))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, hyper::Error> {
+            Ok(Async::Ready(self.trailers.take()))
+        }
+    }
+
+    fn unframe(mut body: &[u8]) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        while !body.is_empty() {
+            let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+            messages.push(body[5..len + 5].to_vec());
+            body = &body[len + 5..];
+        }
+        messages
+    }
+
+    /// Handle a call to `method` of the fake gRPC server, returning the
+    /// status and response messages.
+    fn handle_grpc(
+        blobs: &mut HashMap<String, Vec<u8>>,
+        method: &str,
+        requests: &[Vec<u8>],
+    ) -> (u32, Vec<Vec<u8>>) {
+        match method {
+            "/build.bazel.remote.execution.v2.ActionCache/GetActionResult" => {
+                let request = GetActionResultRequest::decode(&requests[0]).unwrap();
+                assert_eq!("main", request.instance_name);
+                match blobs.get(&format!("/ac/{}", request.action_digest.hash)) {
+                    Some(result) => (0, vec![result.clone()]),
+                    None => (GrpcStatus::NOT_FOUND, vec![]),
+                }
+            }
+            "/build.bazel.remote.execution.v2.ActionCache/UpdateActionResult" => {
+                let request = UpdateActionResultRequest::decode(&requests[0]).unwrap();
+                assert_eq!("main", request.instance_name);
+                let result = request.action_result.encode();
+                blobs.insert(
+                    format!("/ac/{}", request.action_digest.hash),
+                    result.clone(),
+                );
+                (0, vec![result])
+            }
+            "/build.bazel.remote.execution.v2.ContentAddressableStorage/FindMissingBlobs" => {
+                let request = FindMissingBlobsRequest::decode(&requests[0]).unwrap();
+                let response = FindMissingBlobsResponse {
+                    missing_blob_digests: request
+                        .blob_digests
+                        .into_iter()
+                        .filter(|digest| !blobs.contains_key(&format!("/cas/{}", digest.hash)))
+                        .collect(),
+                };
+                (0, vec![response.encode()])
+            }
+            "/google.bytestream.ByteStream/Read" => {
+                let request = ReadRequest::decode(&requests[0]).unwrap();
+                let parts = request.resource_name.split('/').collect::<Vec<_>>();
+                assert_eq!(&["main", "blobs"], &parts[..2]);
+                match blobs.get(&format!("/cas/{}", parts[2])) {
+                    // Send the blob in several messages.
+                    Some(blob) => (
+                        0,
+                        blob.chunks(64 * 1024)
+                            .map(|data| ReadResponse {
+                                data: data.to_vec(),
+                            })
+                            .map(|response| response.encode())
+                            .collect(),
+                    ),
+                    None => (GrpcStatus::NOT_FOUND, vec![]),
+                }
+            }
+            "/google.bytestream.ByteStream/Write" => {
+                let requests = requests
+                    .iter()
+                    .map(|request| WriteRequest::decode(request).unwrap())
+                    .collect::<Vec<_>>();
+                let parts = requests[0].resource_name.split('/').collect::<Vec<_>>();
+                assert_eq!(&["main", "uploads"], &parts[..2]);
+                assert!(requests.last().unwrap().finish_write);
+                let mut data = vec![];
+                for request in &requests {
+                    assert_eq!(data.len() as i64, request.write_offset);
+                    data.extend_from_slice(&request.data);
+                }
+                assert_eq!(Digest::of(&data).hash, parts[4]);
+                let response = WriteResponse {
+                    committed_size: data.len() as i64,
+                };
+                blobs.insert(format!("/cas/{}", parts[4]), data);
+                (0, vec![response.encode()])
+            }
+            // UNIMPLEMENTED
+            _ => (12, vec![]),
+        }
+    }
+
+    /// Serve the Remote Execution API over plaintext HTTP/2 on a local
+    /// port, storing everything in `blobs`. Returns the server's URL.
+    fn serve_grpc(runtime: &mut Runtime, blobs: &Blobs) -> String {
+        let blobs = blobs.clone();
+        let make_service = move || {
+            let blobs = blobs.clone();
+            service_fn(move |req: hyper::Request<Body>| {
+                let blobs = blobs.clone();
+                let method = req.uri().path().to_owned();
+                req.into_body().concat2().map(move |body| {
+                    let (status, messages) =
+                        handle_grpc(&mut blobs.lock().unwrap(), &method, &unframe(&body));
+                    let status = HeaderValue::from(status);
+                    let mut headers = HeaderMap::new();
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+                    // Failed calls get a response with only headers, as
+                    // servers send them.
+                    let body = if status == "0" {
+                        let mut trailers = HeaderMap::new();
+                        trailers.insert("grpc-status", status);
+                        GrpcBody {
+                            data: Some(
+                                messages
+                                    .iter()
+                                    .flat_map(|m| frame(m))
+                                    .collect::<Vec<_>>()
+                                    .into(),
+                            ),
+                            trailers: Some(trailers),
+                        }
+                    } else {
+                        headers.insert("grpc-status", status);
+                        GrpcBody {
+                            data: None,
+                            trailers: None,
+                        }
+                    };
+                    let mut res = Response::new(body);
+                    *res.headers_mut() = headers;
+                    res
+                })
+            })
+        };
+        let server = runtime
+            .block_on(future::lazy(|| {
+                future::ok::<_, ()>(
+                    Server::bind(&([127, 0, 0, 1], 0).into())
+                        .http2_only(true)
+                        .serve(make_service),
+                )
+            }))
+            .unwrap();
+        let url = format!("grpc://{}", server.local_addr());
+        runtime.spawn(server.map_err(|e| panic!("gRPC server failed: {}", e)));
+        url
+    }
+
+    /// Serve GET, PUT and HEAD requests for `blobs` on a local port, like
+    /// bazel-remote's HTTP API. Returns the server's URL.
+    fn serve_http(runtime: &mut Runtime, blobs: &Blobs) -> String {
+        let blobs = blobs.clone();
+        let make_service = move || {
+            let blobs = blobs.clone();
+            service_fn(move |req: hyper::Request<Body>| {
+                let blobs = blobs.clone();
+                let method = req.method().clone();
+                let path = req.uri().path().to_owned();
+                req.into_body().concat2().map(move |body| {
+                    let mut blobs = blobs.lock().unwrap();
+                    let status = match method {
+                        Method::PUT => {
+                            blobs.insert(path, body.to_vec());
+                            StatusCode::OK
+                        }
+                        Method::GET if blobs.contains_key(&path) => {
+                            return Response::new(Body::from(blobs[&path].clone()));
+                        }
+                        Method::HEAD if blobs.contains_key(&path) => StatusCode::OK,
+                        _ => StatusCode::NOT_FOUND,
+                    };
+                    let mut res = Response::new(Body::empty());
+                    *res.status_mut() = status;
+                    res
+                })
+            })
+        };
+        let server = runtime
+            .block_on(future::lazy(|| {
+                future::ok::<_, ()>(Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service))
+            }))
+            .unwrap();
+        let url = format!("http://{}/", server.local_addr());
+        runtime.spawn(server.map_err(|e| panic!("HTTP server failed: {}", e)));
+        url
+    }
+
+    fn get(runtime: &mut Runtime, storage: &ReapiCache, key: &str) -> Cache {
+        runtime.block_on(future::lazy(|| storage.get(key))).unwrap()
+    }
+
+    /// Store an entry in `storage`, check how it was stored in `blobs`, and
+    /// read it back.
+    fn check_put_get(runtime: &mut Runtime, storage: &ReapiCache, blobs: &Blobs) {
+        // Large enough to be written in several requests.
+        let contents = (0..3 * 512 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(&contents), Some(0o755))
+            .unwrap();
+        entry
+            .put_object("copy", &mut Cursor::new(&contents), None)
+            .unwrap();
+        entry.put_object("empty", &mut io::empty(), None).unwrap();
+        entry.put_stdout(b"out").unwrap();
+
+        match get(runtime, storage, "abcd") {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+        let contains = |runtime: &mut Runtime| {
+            runtime
+                .block_on(future::lazy(|| storage.contains("abcd")))
+                .unwrap()
+        };
+        assert!(!contains(runtime));
+        runtime
+            .block_on(future::lazy(|| storage.put("abcd", entry)))
+            .unwrap();
+        assert!(contains(runtime));
+
+        // Identical and empty objects don't need a blob of their own.
+        let blob_path = format!("/cas/{}", Digest::of(&contents).hash);
+        {
+            let blobs = blobs.lock().unwrap();
+            let mut paths = blobs.keys().cloned().collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(
+                vec![
+                    format!("/ac/{}", action_digest("abcd").hash),
+                    blob_path.clone()
+                ],
+                paths
+            );
+        }
+
+        match get(runtime, storage, "abcd") {
+            Cache::Hit(mut entry) => {
+                // Objects stored without a mode get the default mode of the entry format.
+                for &(name, mode) in &[("obj", 0o100755), ("copy", 0o100644)] {
+                    let mut bytes = vec![];
+                    assert_eq!(Some(mode), entry.get_object(name, &mut bytes).unwrap());
+                    assert!(bytes == contents);
+                }
+                let mut bytes = vec![];
+                entry.get_object("empty", &mut bytes).unwrap();
+                assert!(bytes.is_empty());
+                assert_eq!(b"out".to_vec(), entry.get_stdout().unwrap());
+                assert!(entry.get_stderr().unwrap().is_empty());
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+
+        // An action result whose blobs were evicted is a miss.
+        blobs.lock().unwrap().remove(&blob_path);
+        match get(runtime, storage, "abcd") {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_reapi_grpc() {
+        let mut runtime = Runtime::new().unwrap();
+        let blobs = Blobs::default();
+        let config = ReapiCacheConfig {
+            endpoint: serve_grpc(&mut runtime, &blobs),
+            instance_name: "main".to_owned(),
+        };
+        let storage = ReapiCache::new(&config, &ThreadPool::sized(1)).unwrap();
+        check_put_get(&mut runtime, &storage, &blobs);
+    }
+
+    #[test]
+    fn test_reapi_http() {
+        let mut runtime = Runtime::new().unwrap();
+        let blobs = Blobs::default();
+        let config = ReapiCacheConfig {
+            endpoint: serve_http(&mut runtime, &blobs),
+            instance_name: String::new(),
+        };
+        let storage = ReapiCache::new(&config, &ThreadPool::sized(1)).unwrap();
+        check_put_get(&mut runtime, &storage, &blobs);
+    }
+}
@@ -13,85 +13,628 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, SpooledEntry, Storage};
+use crate::config::RedisCacheConfig;
 use crate::errors::*;
 use futures_03::prelude::*;
 use redis::aio::Connection;
-use redis::{cmd, Client, InfoDict};
+use redis::{
+    cmd, Client, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, FromRedisValue, InfoDict,
+    RedisResult, Value,
+};
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::Cursor;
+use std::io::{Cursor, Write};
+use std::rc::Rc;
 use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-/// A cache that stores entries in a Redis.
+/// Entries are read and written in chunks of this size, so that large
+/// entries never need to be held in memory in their entirety.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Entries larger than a chunk are stored under a key of their own that's
+/// never changed once written, and the entry's key is set to this prefix
+/// followed by `<length>:<key>`. Swapping that pointer is atomic, so a reader
+/// never sees chunks of two different entries.
+const CHUNKED_ENTRY_PREFIX: &[u8] = b"sccache-chunked-entry:";
+
+/// How many seconds the chunks of an overwritten entry are kept, so that
+/// reads of them that are in progress can finish.
+const CHUNKED_ENTRY_GRACE: u64 = 60;
+
+/// The number of hash slots in a Redis Cluster.
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// The number of times a command is redirected to another cluster node
+/// before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// How the server that stores a key is found.
+enum Topology {
+    /// A single server.
+    Server(ConnectionInfo),
+    /// A Redis Cluster, whose slots are found through any of `seeds`.
+    Cluster {
+        seeds: Vec<ConnectionInfo>,
+        /// The first and last slot served by each node.
+        slots: RefCell<Vec<(u16, u16, ConnectionInfo)>>,
+    },
+    /// A master monitored by Redis Sentinel.
+    Sentinel {
+        sentinels: Vec<ConnectionInfo>,
+        master_name: String,
+        /// The connection details of the master, except for its address.
+        master_info: ConnectionInfo,
+        /// The master, once a sentinel has been asked for its address.
+        master: RefCell<Option<ConnectionInfo>>,
+    },
+}
+
+impl Topology {
+    /// Parse the whitespace-separated URLs in `url`. There's either a single
+    /// `redis://` URL, or one `redis+cluster://` URL for each cluster node to
+    /// start from, or one `redis+sentinel://host:port/master[/db]` URL for
+    /// each sentinel.
+    fn parse(url: &str) -> Result<Topology> {
+        let urls = url.split_whitespace().collect::<Vec<_>>();
+        let kinds = urls
+            .iter()
+            .map(|url| {
+                let i = url.find("://").unwrap_or(0);
+                let (scheme, rest) = url.split_at(i);
+                let mut parts = scheme.splitn(2, '+');
+                let scheme = parts.next().unwrap_or("");
+                (format!("{}{}", scheme, rest), parts.next())
+            })
+            .collect::<Vec<_>>();
+        match kinds.first() {
+            None => bail!("No Redis URL given"),
+            Some(&(ref url, None)) if kinds.len() == 1 => Ok(Topology::Server(
+                url.parse()
+                    .with_context(|| format!("Invalid Redis URL: {}", url))?,
+            )),
+            Some(&(_, Some("cluster"))) => {
+                let seeds = kinds
+                    .iter()
+                    .map(|(url, kind)| {
+                        if *kind != Some("cluster") {
+                            bail!("Can't mix Redis Cluster URLs with other URLs");
+                        }
+                        url.parse()
+                            .with_context(|| format!("Invalid Redis URL: {}", url))
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(Topology::Cluster {
+                    seeds,
+                    slots: RefCell::default(),
+                })
+            }
+            Some(&(_, Some("sentinel"))) => {
+                let mut sentinels = vec![];
+                let mut master = None;
+                for (url, kind) in &kinds {
+                    if *kind != Some("sentinel") {
+                        bail!("Can't mix Redis Sentinel URLs with other URLs");
+                    }
+                    // The path names the master, and optionally its database.
+                    let authority_end = url.find("://").unwrap() + 3;
+                    let (base, path) = match url[authority_end..].find('/') {
+                        Some(i) => url.split_at(authority_end + i),
+                        None => (url.as_str(), ""),
+                    };
+                    let mut path = path.trim_matches('/').splitn(2, '/');
+                    let master_name = match path.next() {
+                        Some(name) if !name.is_empty() => name.to_owned(),
+                        _ => bail!("Redis Sentinel URL without a master name: {}", url),
+                    };
+                    let db = path.next().unwrap_or("0");
+                    let master_info: ConnectionInfo = format!("{}/{}", base, db)
+                        .parse()
+                        .with_context(|| format!("Invalid Redis URL: {}", url))?;
+                    // The credentials in the URL are for the master.
+                    sentinels.push(ConnectionInfo {
+                        addr: master_info.addr.clone(),
+                        db: 0,
+                        username: None,
+                        passwd: None,
+                    });
+                    match master {
+                        Some((ref name, _)) if *name != master_name => {
+                            bail!("Redis Sentinel URLs name different masters")
+                        }
+                        Some(_) => {}
+                        None => master = Some((master_name, master_info)),
+                    }
+                }
+                let (master_name, master_info) = master.unwrap();
+                Ok(Topology::Sentinel {
+                    sentinels,
+                    master_name,
+                    master_info,
+                    master: RefCell::default(),
+                })
+            }
+            Some(&(_, Some(kind))) => bail!("Unsupported Redis URL scheme: redis+{}", kind),
+            Some(_) => bail!("Only one Redis URL may be given for a single server"),
+        }
+    }
+}
+
+/// The CRC16 (XMODEM) of `bytes`, which Redis Cluster hashes keys with.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The part of `key` between the first `{` and the next `}`, which is all
+/// that Redis Cluster hashes if it's not empty.
+fn hash_tag(key: &str) -> Option<&str> {
+    let open = key.find('{')?;
+    let len = key[open + 1..].find('}')?;
+    if len == 0 {
+        None
+    } else {
+        Some(&key[open + 1..open + 1 + len])
+    }
+}
+
+/// The cluster hash slot of `key`.
+fn key_slot(key: &str) -> u16 {
+    crc16(hash_tag(key).unwrap_or(key).as_bytes()) % CLUSTER_SLOTS
+}
+
+/// A new key to store the chunks of a large entry for `key` under, in the
+/// same cluster hash slot as `key`.
+fn chunks_key(key: &str) -> String {
+    let suffix = format!(".chunks.{}", Uuid::new_v4());
+    if hash_tag(key).is_some() {
+        format!("{}{}", key, suffix)
+    } else {
+        format!("{{{}}}{}", key, suffix)
+    }
+}
+
+/// If `value` points to the chunks of a large entry, return the entry's
+/// length and the key its chunks are stored under.
+fn parse_chunked_entry(value: &[u8]) -> Option<(u64, String)> {
+    if !value.starts_with(CHUNKED_ENTRY_PREFIX) {
+        return None;
+    }
+    let rest = std::str::from_utf8(&value[CHUNKED_ENTRY_PREFIX.len()..]).ok()?;
+    let i = rest.find(':')?;
+    Some((rest[..i].parse().ok()?, rest[i + 1..].to_owned()))
+}
+
+/// `info` with its address replaced by `addr`, a `host:port` string.
+fn with_addr(info: &ConnectionInfo, addr: &str) -> Result<ConnectionInfo> {
+    let i = match addr.rfind(':') {
+        Some(i) => i,
+        None => bail!("Invalid Redis node address: {}", addr),
+    };
+    let host = addr[..i].to_owned();
+    let port = addr[i + 1..]
+        .parse()
+        .with_context(|| format!("Invalid Redis node address: {}", addr))?;
+    let addr = match *info.addr {
+        ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+            host,
+            port,
+            insecure,
+        },
+        _ => ConnectionAddr::Tcp(host, port),
+    };
+    Ok(ConnectionInfo {
+        addr: Box::new(addr),
+        ..info.clone()
+    })
+}
+
+/// Parse the reply to `CLUSTER SLOTS` from the node at `seed`.
+fn parse_slots(reply: Value, seed: &ConnectionInfo) -> Result<Vec<(u16, u16, ConnectionInfo)>> {
+    let ranges = match reply {
+        Value::Bulk(ranges) => ranges,
+        _ => bail!("Invalid CLUSTER SLOTS reply"),
+    };
+    let mut slots = vec![];
+    for range in ranges {
+        let mut fields = match range {
+            Value::Bulk(fields) => fields.into_iter(),
+            _ => bail!("Invalid CLUSTER SLOTS reply"),
+        };
+        let (start, end, master) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(start), Some(end), Some(master)) => (start, end, master),
+            _ => bail!("Invalid CLUSTER SLOTS reply"),
+        };
+        let (host, port): (String, u16) = match master {
+            Value::Bulk(ref node) if node.len() >= 2 => (
+                String::from_redis_value(&node[0])?,
+                u16::from_redis_value(&node[1])?,
+            ),
+            _ => bail!("Invalid CLUSTER SLOTS reply"),
+        };
+        // An empty host is the node that was asked.
+        let host = if host.is_empty() {
+            match *seed.addr {
+                ConnectionAddr::Tcp(ref host, _) | ConnectionAddr::TcpTls { ref host, .. } => {
+                    host.clone()
+                }
+                ConnectionAddr::Unix(_) => bail!("Redis Cluster nodes must use TCP"),
+            }
+        } else {
+            host
+        };
+        let node = with_addr(seed, &format!("{}:{}", host, port))?;
+        slots.push((
+            u16::from_redis_value(&start)?,
+            u16::from_redis_value(&end)?,
+            node,
+        ));
+    }
+    Ok(slots)
+}
+
+/// The servers of a cache, and the connections to them that are not in use.
+struct Servers {
+    topology: Topology,
+    idle: RefCell<HashMap<String, Vec<Connection>>>,
+}
+
+/// The key that connections to `info` are pooled under.
+fn pool_key(info: &ConnectionInfo) -> String {
+    format!("{}/{}", info.addr, info.db)
+}
+
+impl Servers {
+    /// Run `cmd` on the server at `info`, reusing an idle connection to it if
+    /// there is one. If `asking`, the command is allowed to run on a cluster
+    /// node that a slot is being migrated to.
+    async fn query_node<T: FromRedisValue>(
+        &self,
+        info: &ConnectionInfo,
+        cmd: &Cmd,
+        asking: bool,
+    ) -> RedisResult<T> {
+        let idle = self
+            .idle
+            .borrow_mut()
+            .get_mut(&pool_key(info))
+            .and_then(Vec::pop);
+        let mut c = match idle {
+            Some(c) => c,
+            None => Client::open(info.clone())?.get_async_connection().await?,
+        };
+        if asking {
+            redis::cmd("ASKING").query_async::<_, ()>(&mut c).await?;
+        }
+        let result = cmd.query_async(&mut c).await;
+        // Connections are reused unless they failed.
+        match result {
+            Err(ref e) if e.is_io_error() => {}
+            _ => self
+                .idle
+                .borrow_mut()
+                .entry(pool_key(info))
+                .or_default()
+                .push(c),
+        }
+        result
+    }
+
+    /// Run `cmd`, which operates on `key`, on the server that stores `key`.
+    async fn query<T: FromRedisValue>(&self, key: &str, cmd: &Cmd) -> Result<T> {
+        match self.topology {
+            Topology::Server(ref info) => Ok(self.query_node(info, cmd, false).await?),
+            Topology::Sentinel { ref master, .. } => {
+                let info = self.master().await?;
+                let result = self.query_node(&info, cmd, false).await;
+                // The master may have failed over, so ask the sentinels
+                // again next time.
+                if let Err(ref e) = result {
+                    if e.is_io_error() {
+                        *master.borrow_mut() = None;
+                    }
+                }
+                Ok(result?)
+            }
+            Topology::Cluster { .. } => {
+                let mut node = self.slot_node(key_slot(key)).await?;
+                let mut asking = false;
+                for _ in 0..MAX_REDIRECTS {
+                    match self.query_node(&node, cmd, asking).await {
+                        Err(ref e)
+                            if e.kind() == ErrorKind::Moved || e.kind() == ErrorKind::Ask =>
+                        {
+                            let addr = e
+                                .detail()
+                                .and_then(|detail| detail.split_whitespace().nth(1));
+                            let addr = match addr {
+                                Some(addr) => addr,
+                                None => bail!("Invalid cluster redirection: {}", e),
+                            };
+                            node = with_addr(&node, addr)?;
+                            asking = e.kind() == ErrorKind::Ask;
+                            // A moved slot means the whole map may be stale.
+                            if !asking {
+                                self.refresh_slots().await?;
+                            }
+                        }
+                        result => return Ok(result?),
+                    }
+                }
+                bail!("Too many cluster redirections for {}", key)
+            }
+        }
+    }
+
+    /// A server to query for information about the cache as a whole.
+    async fn any_node(&self) -> Result<ConnectionInfo> {
+        match self.topology {
+            Topology::Server(ref info) => Ok(info.clone()),
+            Topology::Sentinel { .. } => self.master().await,
+            Topology::Cluster { ref seeds, .. } => Ok(seeds[0].clone()),
+        }
+    }
+
+    /// Ask the sentinels for the address of the master, if it's not known.
+    async fn master(&self) -> Result<ConnectionInfo> {
+        let (sentinels, master_name, master_info, master) = match self.topology {
+            Topology::Sentinel {
+                ref sentinels,
+                ref master_name,
+                ref master_info,
+                ref master,
+            } => (sentinels, master_name, master_info, master),
+            _ => unreachable!(),
+        };
+        if let Some(ref info) = *master.borrow() {
+            return Ok(info.clone());
+        }
+        for sentinel in sentinels {
+            let mut cmd = redis::cmd("SENTINEL");
+            cmd.arg("get-master-addr-by-name").arg(master_name);
+            match self
+                .query_node::<Option<(String, u16)>>(sentinel, &cmd, false)
+                .await
+            {
+                Ok(Some((host, port))) => {
+                    let info = with_addr(master_info, &format!("{}:{}", host, port))?;
+                    *master.borrow_mut() = Some(info.clone());
+                    return Ok(info);
+                }
+                Ok(None) => warn!("Sentinel {} doesn't know {}", sentinel.addr, master_name),
+                Err(e) => warn!("Failed to query sentinel {}: {}", sentinel.addr, e),
+            }
+        }
+        bail!("No sentinel knows the address of {}", master_name)
+    }
+
+    /// The cluster node that serves `slot`.
+    async fn slot_node(&self, slot: u16) -> Result<ConnectionInfo> {
+        let find = |slots: &[(u16, u16, ConnectionInfo)]| {
+            slots
+                .iter()
+                .find(|&&(start, end, _)| start <= slot && slot <= end)
+                .map(|(_, _, node)| node.clone())
+        };
+        if let Topology::Cluster { ref slots, .. } = self.topology {
+            if let Some(node) = find(&slots.borrow()) {
+                return Ok(node);
+            }
+            self.refresh_slots().await?;
+            if let Some(node) = find(&slots.borrow()) {
+                return Ok(node);
+            }
+        }
+        bail!("No Redis Cluster node serves slot {}", slot)
+    }
+
+    /// Ask the cluster which nodes serve which slots.
+    async fn refresh_slots(&self) -> Result<()> {
+        let (seeds, slots) = match self.topology {
+            Topology::Cluster {
+                ref seeds,
+                ref slots,
+            } => (seeds, slots),
+            _ => unreachable!(),
+        };
+        let mut cmd = redis::cmd("CLUSTER");
+        cmd.arg("SLOTS");
+        let mut error = None;
+        for seed in seeds {
+            match self.query_node::<Value>(seed, &cmd, false).await {
+                Ok(reply) => {
+                    *slots.borrow_mut() = parse_slots(reply, seed)?;
+                    return Ok(());
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(Error::from(error.unwrap()).context("Failed to get Redis Cluster slots"))
+    }
+}
+
+/// A cache that stores entries in a Redis, a Redis Cluster, or a Redis
+/// monitored by Sentinel.
 #[derive(Clone)]
 pub struct RedisCache {
     url: String,
-    client: Client,
+    key_prefix: String,
+    ttl: Option<u64>,
+    servers: Rc<Servers>,
 }
 
 impl RedisCache {
-    /// Create a new `RedisCache`.
-    pub fn new(url: &str) -> Result<RedisCache> {
+    /// Create a new `RedisCache` from `config`.
+    pub fn new(config: &RedisCacheConfig) -> Result<RedisCache> {
         Ok(RedisCache {
-            url: url.to_owned(),
-            client: Client::open(url)?,
+            url: config.url.clone(),
+            key_prefix: config.key_prefix.clone(),
+            ttl: config.ttl,
+            servers: Rc::new(Servers {
+                topology: Topology::parse(&config.url)?,
+                idle: RefCell::default(),
+            }),
         })
     }
-
-    /// Returns a connection with configured read and write timeouts.
-    async fn connect(self) -> Result<Connection> {
-        Ok(self.client.get_async_connection().await?)
-    }
 }
 
 impl Storage for RedisCache {
-    /// Open a connection and query for a key.
+    /// Query for a key.
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = key.to_owned();
-        let me = self.clone();
+        let key = format!("{}{}", self.key_prefix, key);
+        let servers = self.servers.clone();
         Box::new(
             Box::pin(async move {
-                let mut c = me.connect().await?;
-                let d: Vec<u8> = cmd("GET").arg(key).query_async(&mut c).await?;
-                if d.is_empty() {
-                    Ok(Cache::Miss)
-                } else {
-                    CacheRead::from(Cursor::new(d)).map(Cache::Hit)
+                let mut getrange = cmd("GETRANGE");
+                getrange.arg(&key).arg(0).arg(CHUNK_SIZE - 1);
+                let value: Vec<u8> = servers.query(&key, &getrange).await?;
+                if value.is_empty() {
+                    return Ok(Cache::Miss);
+                }
+                let (len, chunks_key) = match parse_chunked_entry(&value) {
+                    Some(chunked) => chunked,
+                    // Entries that fit in a chunk are stored under their key.
+                    None => return CacheRead::from(Cursor::new(value)).map(Cache::Hit),
+                };
+                let mut entry = SpooledEntry::new()?;
+                while entry.len() < len {
+                    let start = entry.len();
+                    let end = start + CHUNK_SIZE as u64 - 1;
+                    let mut getrange = cmd("GETRANGE");
+                    getrange.arg(&chunks_key).arg(start).arg(end);
+                    let chunk: Vec<u8> = servers.query(&chunks_key, &getrange).await?;
+                    if chunk.is_empty() {
+                        // The entry was overwritten or expired since.
+                        debug!("Chunks of {} are gone", key);
+                        return Ok(Cache::Miss);
+                    }
+                    entry.write_all(&chunk)?;
                 }
+                CacheRead::from(entry.into_reader()?).map(Cache::Hit)
             })
             .compat(),
         )
     }
 
-    /// Open a connection and store a object in the cache.
+    /// Store an object in the cache.
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = key.to_owned();
-        let me = self.clone();
+        let key = format!("{}{}", self.key_prefix, key);
+        let ttl = self.ttl;
+        let servers = self.servers.clone();
         let start = Instant::now();
         Box::new(
             Box::pin(async move {
-                let mut c = me.connect().await?;
-                let d = entry.finish()?;
-                cmd("SET").arg(key).arg(d).query_async(&mut c).await?;
+                let entry = entry.finish()?;
+                if entry.len() <= CHUNK_SIZE as u64 {
+                    // The chunks of a large entry this replaces are left to
+                    // expire or be evicted.
+                    let mut set = cmd("SET");
+                    set.arg(&key).arg(entry.into_vec()?);
+                    if let Some(ttl) = ttl {
+                        set.arg("EX").arg(ttl);
+                    }
+                    servers.query::<()>(&key, &set).await?;
+                    return Ok(start.elapsed());
+                }
+                // Larger entries are appended in chunks to a key of their own,
+                // and only pointed to from `key` once complete.
+                let chunks_key = chunks_key(&key);
+                let res: Result<()> = async {
+                    for chunk in entry.chunks(CHUNK_SIZE)? {
+                        let mut append = cmd("APPEND");
+                        append.arg(&chunks_key).arg(chunk?);
+                        servers.query::<()>(&chunks_key, &append).await?;
+                    }
+                    if let Some(ttl) = ttl {
+                        let mut expire = cmd("EXPIRE");
+                        expire.arg(&chunks_key).arg(ttl);
+                        servers.query::<()>(&chunks_key, &expire).await?;
+                    }
+                    Ok(())
+                }
+                .await;
+                if let Err(e) = res {
+                    let mut del = cmd("DEL");
+                    del.arg(&chunks_key);
+                    drop(servers.query::<()>(&chunks_key, &del).await);
+                    return Err(e);
+                }
+                let mut pointer = CHUNKED_ENTRY_PREFIX.to_vec();
+                pointer.extend(format!("{}:{}", entry.len(), chunks_key).into_bytes());
+                let mut getset = cmd("GETSET");
+                getset.arg(&key).arg(pointer);
+                let previous: Option<Vec<u8>> = servers.query(&key, &getset).await?;
+                if let Some(ttl) = ttl {
+                    let mut expire = cmd("EXPIRE");
+                    expire.arg(&key).arg(ttl);
+                    servers.query::<()>(&key, &expire).await?;
+                }
+                if let Some((_, previous)) = previous.and_then(|p| parse_chunked_entry(&p)) {
+                    let mut expire = cmd("EXPIRE");
+                    expire.arg(&previous).arg(CHUNKED_ENTRY_GRACE);
+                    servers.query::<()>(&previous, &expire).await?;
+                }
                 Ok(start.elapsed())
             })
             .compat(),
         )
     }
 
+    /// Remove an entry, along with the chunks of a large one.
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = format!("{}{}", self.key_prefix, key);
+        let servers = self.servers.clone();
+        Box::new(
+            Box::pin(async move {
+                let mut getrange = cmd("GETRANGE");
+                getrange.arg(&key).arg(0).arg(CHUNK_SIZE - 1);
+                let value: Vec<u8> = servers.query(&key, &getrange).await?;
+                let mut del = cmd("DEL");
+                del.arg(&key);
+                servers.query::<()>(&key, &del).await?;
+                if let Some((_, chunks_key)) = parse_chunked_entry(&value) {
+                    let mut del = cmd("DEL");
+                    del.arg(&chunks_key);
+                    servers.query::<()>(&chunks_key, &del).await?;
+                }
+                Ok(())
+            })
+            .compat(),
+        )
+    }
+
     /// Returns the cache location.
     fn location(&self) -> String {
-        format!("Redis: {}", self.url)
+        if self.key_prefix.is_empty() {
+            format!("Redis: {}", self.url)
+        } else {
+            format!("Redis: {}, key prefix: {}", self.url, self.key_prefix)
+        }
     }
 
     /// Returns the current cache size. This value is aquired via
-    /// the Redis INFO command (used_memory).
+    /// the Redis INFO command (used_memory). The memory of a cluster is
+    /// spread over its nodes, so its size is unknown.
     fn current_size(&self) -> SFuture<Option<u64>> {
-        let me = self.clone(); // TODO Remove clone
+        let servers = self.servers.clone();
         Box::new(
             Box::pin(async move {
-                let mut c = me.connect().await?;
-                let v: InfoDict = cmd("INFO").query_async(&mut c).await?;
+                if let Topology::Cluster { .. } = servers.topology {
+                    return Ok(None);
+                }
+                let node = servers.any_node().await?;
+                let v: InfoDict = servers.query_node(&node, &cmd("INFO"), false).await?;
                 Ok(v.get("used_memory"))
             })
             .compat(),
@@ -102,15 +645,17 @@ impl Storage for RedisCache {
     /// the Redis CONFIG command (maxmemory). If the server has no
     /// configured limit, the result is None.
     fn max_size(&self) -> SFuture<Option<u64>> {
-        let me = self.clone(); // TODO Remove clone
+        let servers = self.servers.clone();
         Box::new(
             Box::pin(async move {
-                let mut c = me.connect().await?;
-                let result: redis::RedisResult<HashMap<String, usize>> = cmd("CONFIG")
-                    .arg("GET")
-                    .arg("maxmemory")
-                    .query_async(&mut c)
-                    .await;
+                if let Topology::Cluster { .. } = servers.topology {
+                    return Ok(None);
+                }
+                let node = servers.any_node().await?;
+                let mut config = cmd("CONFIG");
+                config.arg("GET").arg("maxmemory");
+                let result: redis::RedisResult<HashMap<String, usize>> =
+                    servers.query_node(&node, &config, false).await;
                 match result {
                     Ok(h) => {
                         Ok(h.get("maxmemory")
@@ -123,3 +668,404 @@ impl Storage for RedisCache {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use std::io::{BufRead, BufReader, Cursor, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tokio_compat::runtime::current_thread::Runtime;
+
+    #[derive(Default)]
+    struct FakeState {
+        data: HashMap<Vec<u8>, Vec<u8>>,
+        ttls: HashMap<Vec<u8>, u64>,
+        connections: usize,
+        /// The reply to `CLUSTER SLOTS` or `SENTINEL`, in RESP.
+        reply: Vec<u8>,
+        /// The address that all keys have moved to, if any.
+        moved_to: Option<String>,
+    }
+
+    /// A Redis server on a local port that supports just the commands that
+    /// `RedisCache` uses.
+    struct FakeRedis {
+        addr: String,
+        state: Arc<Mutex<FakeState>>,
+    }
+
+    impl FakeRedis {
+        fn start(state: FakeState) -> FakeRedis {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let state = Arc::new(Mutex::new(state));
+            let server_state = state.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let state = server_state.clone();
+                    state.lock().unwrap().connections += 1;
+                    thread::spawn(move || serve(stream.unwrap(), &state));
+                }
+            });
+            FakeRedis { addr, state }
+        }
+    }
+
+    fn bulk(bytes: &[u8]) -> Vec<u8> {
+        let mut reply = format!("${}\r\n", bytes.len()).into_bytes();
+        reply.extend_from_slice(bytes);
+        reply.extend_from_slice(b"\r\n");
+        reply
+    }
+
+    fn serve(stream: TcpStream, state: &Mutex<FakeState>) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let read_line = |reader: &mut BufReader<TcpStream>| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line.trim_end().to_owned()
+        };
+        loop {
+            let header = read_line(&mut reader);
+            if header.is_empty() {
+                return;
+            }
+            let mut args = vec![];
+            for _ in 0..header[1..].parse::<usize>().unwrap() {
+                let len = read_line(&mut reader)[1..].parse::<usize>().unwrap();
+                let mut arg = vec![0; len + 2];
+                reader.read_exact(&mut arg).unwrap();
+                arg.truncate(len);
+                args.push(arg);
+            }
+            let name = String::from_utf8(args[0].clone()).unwrap().to_uppercase();
+            let mut state = state.lock().unwrap();
+            let reply = match (name.as_str(), &state.moved_to) {
+                ("AUTH", _) | ("SELECT", _) | ("ASKING", _) => b"+OK\r\n".to_vec(),
+                ("CLUSTER", _) | ("SENTINEL", _) => state.reply.clone(),
+                (_, Some(addr)) => format!("-MOVED {} {}\r\n", key_slot("x"), addr).into_bytes(),
+                ("GETRANGE", _) => {
+                    let value = state.data.get(&args[1]).cloned().unwrap_or_default();
+                    let start: usize = String::from_utf8_lossy(&args[2]).parse().unwrap();
+                    let end: usize = String::from_utf8_lossy(&args[3]).parse().unwrap();
+                    let end = std::cmp::min(end + 1, value.len());
+                    bulk(value.get(start..end).unwrap_or(&[]))
+                }
+                ("SET", _) => {
+                    if args.len() == 5 {
+                        let ttl = String::from_utf8_lossy(&args[4]).parse().unwrap();
+                        state.ttls.insert(args[1].clone(), ttl);
+                    }
+                    state.data.insert(args[1].clone(), args[2].clone());
+                    b"+OK\r\n".to_vec()
+                }
+                ("APPEND", _) => {
+                    let value = state.data.entry(args[1].clone()).or_default();
+                    value.extend_from_slice(&args[2]);
+                    format!(":{}\r\n", value.len()).into_bytes()
+                }
+                ("EXPIRE", _) => {
+                    let ttl = String::from_utf8_lossy(&args[2]).parse().unwrap();
+                    state.ttls.insert(args[1].clone(), ttl);
+                    b":1\r\n".to_vec()
+                }
+                ("GETSET", _) => {
+                    state.ttls.remove(&args[1]);
+                    match state.data.insert(args[1].clone(), args[2].clone()) {
+                        Some(value) => bulk(&value),
+                        None => b"$-1\r\n".to_vec(),
+                    }
+                }
+                ("DEL", _) => {
+                    state.ttls.remove(&args[1]);
+                    let deleted = state.data.remove(&args[1]).is_some();
+                    format!(":{}\r\n", deleted as u8).into_bytes()
+                }
+                _ => format!("-ERR unknown command {}\r\n", name).into_bytes(),
+            };
+            drop(state);
+            writer.write_all(&reply).unwrap();
+        }
+    }
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(contents), None)
+            .unwrap();
+        entry
+    }
+
+    /// Contents that don't compress, and are large enough for an entry with
+    /// them to be written and read in several chunks.
+    fn large_contents(seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..CHUNK_SIZE * 3 / 2)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    fn check_hit(runtime: &mut Runtime, cache: &RedisCache, key: &str, contents: &[u8]) {
+        match runtime.block_on(future::lazy(|| cache.get(key))).unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut bytes = vec![];
+                entry.get_object("obj", &mut bytes).unwrap();
+                assert!(bytes == contents);
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    fn cache(url: String) -> RedisCache {
+        RedisCache::new(&RedisCacheConfig {
+            url,
+            key_prefix: "project:".to_owned(),
+            ttl: Some(3600),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_redis_server() {
+        let mut runtime = Runtime::new().unwrap();
+        let server = FakeRedis::start(FakeState::default());
+        let cache = cache(format!("redis://{}", server.addr));
+
+        let large = large_contents(1);
+        for &(key, ref contents) in &[("small", b"contents".to_vec()), ("large", large)] {
+            runtime
+                .block_on(future::lazy(|| cache.put(key, entry(contents))))
+                .unwrap();
+            check_hit(&mut runtime, &cache, key, contents);
+        }
+        match runtime
+            .block_on(future::lazy(|| cache.get("missing")))
+            .unwrap()
+        {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+
+        let state = server.state.lock().unwrap();
+        let mut keys = state.data.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(3, keys.len());
+        assert_eq!(
+            &[b"project:large".to_vec(), b"project:small".to_vec()],
+            &keys[..2]
+        );
+        assert!(keys[2].starts_with(b"{project:large}.chunks."));
+        for key in &keys {
+            assert_eq!(Some(&3600), state.ttls.get(key));
+        }
+        // Every request reused the first connection.
+        assert_eq!(1, state.connections);
+    }
+
+    #[test]
+    fn test_redis_overwrite() {
+        let mut runtime = Runtime::new().unwrap();
+        let server = FakeRedis::start(FakeState::default());
+        let cache = cache(format!("redis://{}", server.addr));
+        let chunks_key = || {
+            let state = server.state.lock().unwrap();
+            let (_, key) = parse_chunked_entry(&state.data[&b"project:abcd"[..]]).unwrap();
+            key.into_bytes()
+        };
+
+        let (first, second) = (large_contents(1), large_contents(2));
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", entry(&first))))
+            .unwrap();
+        let first_chunks = chunks_key();
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", entry(&second))))
+            .unwrap();
+        check_hit(&mut runtime, &cache, "abcd", &second);
+        // The replaced chunks are kept for a while, for reads in progress.
+        let second_chunks = chunks_key();
+        assert_ne!(first_chunks, second_chunks);
+        {
+            let mut state = server.state.lock().unwrap();
+            assert!(state.data.contains_key(&first_chunks));
+            assert_eq!(Some(&CHUNKED_ENTRY_GRACE), state.ttls.get(&first_chunks));
+            // Once the chunks are gone, the entry is a miss.
+            state.data.remove(&second_chunks);
+        }
+        match runtime
+            .block_on(future::lazy(|| cache.get("abcd")))
+            .unwrap()
+        {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+
+        // Removing an entry removes its chunks too.
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", entry(&first))))
+            .unwrap();
+        let third_chunks = chunks_key();
+        runtime
+            .block_on(future::lazy(|| cache.remove("abcd")))
+            .unwrap();
+        let state = server.state.lock().unwrap();
+        assert!(!state.data.contains_key(&b"project:abcd"[..]));
+        assert!(!state.data.contains_key(&third_chunks));
+    }
+
+    #[test]
+    fn test_redis_cluster() {
+        let mut runtime = Runtime::new().unwrap();
+        let target = FakeRedis::start(FakeState::default());
+        // The seed claims every slot, giving its host as empty as servers
+        // do, but redirects all keys to `target`.
+        let seed = FakeRedis::start(FakeState {
+            moved_to: Some(target.addr.clone()),
+            ..FakeState::default()
+        });
+        let port = seed.addr.rsplit(':').next().unwrap();
+        seed.state.lock().unwrap().reply =
+            format!("*1\r\n*3\r\n:0\r\n:16383\r\n*2\r\n$0\r\n\r\n:{}\r\n", port).into_bytes();
+        let cache = cache(format!("redis+cluster://{}", seed.addr));
+
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", entry(b"contents"))))
+            .unwrap();
+        check_hit(&mut runtime, &cache, "abcd", b"contents");
+        assert!(target
+            .state
+            .lock()
+            .unwrap()
+            .data
+            .contains_key(&b"project:abcd"[..]));
+    }
+
+    #[test]
+    fn test_redis_sentinel() {
+        let mut runtime = Runtime::new().unwrap();
+        let master = FakeRedis::start(FakeState::default());
+        let (host, port) = master.addr.split_at(master.addr.rfind(':').unwrap());
+        let sentinel = FakeRedis::start(FakeState {
+            reply: [
+                b"*2\r\n".to_vec(),
+                bulk(host.as_bytes()),
+                bulk(&port.as_bytes()[1..]),
+            ]
+            .concat(),
+            ..FakeState::default()
+        });
+        let cache = cache(format!("redis+sentinel://{}/main", sentinel.addr));
+
+        runtime
+            .block_on(future::lazy(|| cache.put("abcd", entry(b"contents"))))
+            .unwrap();
+        check_hit(&mut runtime, &cache, "abcd", b"contents");
+        assert!(master
+            .state
+            .lock()
+            .unwrap()
+            .data
+            .contains_key(&b"project:abcd"[..]));
+    }
+
+    #[test]
+    fn test_key_slot() {
+        // The examples from the Redis Cluster specification.
+        assert_eq!(0x31c3, crc16(b"123456789"));
+        assert_eq!(12182, key_slot("foo"));
+        assert_eq!(key_slot("user1000"), key_slot("{user1000}.following"));
+        assert_eq!(key_slot("foo{}{bar}"), crc16(b"foo{}{bar}") % CLUSTER_SLOTS);
+        assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+
+        for key in &["prefix:abcd", "{tag}abcd"] {
+            assert_eq!(key_slot(key), key_slot(&chunks_key(key)));
+        }
+    }
+
+    #[test]
+    fn test_parse_topology() {
+        match Topology::parse("redis://:secret@127.0.0.1:6379/2").unwrap() {
+            Topology::Server(info) => {
+                assert_eq!("127.0.0.1:6379", info.addr.to_string());
+                assert_eq!((2, Some("secret")), (info.db, info.passwd.as_deref()));
+            }
+            _ => panic!("Expected a single server"),
+        }
+        match Topology::parse("redis+cluster://a:7000 redis+cluster://b:7001").unwrap() {
+            Topology::Cluster { seeds, .. } => assert_eq!(
+                vec!["a:7000", "b:7001"],
+                seeds
+                    .iter()
+                    .map(|seed| seed.addr.to_string())
+                    .collect::<Vec<_>>()
+            ),
+            _ => panic!("Expected a cluster"),
+        }
+        let sentinels =
+            "redis+sentinel://:secret@a:26379/main/3 redis+sentinel://:secret@b:26379/main/3";
+        match Topology::parse(sentinels).unwrap() {
+            Topology::Sentinel {
+                sentinels,
+                master_name,
+                master_info,
+                ..
+            } => {
+                assert_eq!(2, sentinels.len());
+                assert_eq!(None, sentinels[0].passwd);
+                assert_eq!("main", master_name);
+                assert_eq!(
+                    (3, Some("secret")),
+                    (master_info.db, master_info.passwd.as_deref())
+                );
+            }
+            _ => panic!("Expected sentinels"),
+        }
+
+        assert!(Topology::parse("redis://a:6379 redis://b:6379").is_err());
+        assert!(Topology::parse("redis+cluster://a:7000 redis://b:6379").is_err());
+        assert!(Topology::parse("redis+sentinel://a:26379").is_err());
+        assert!(Topology::parse("redis+sentinel://a:26379/x redis+sentinel://b:26379/y").is_err());
+    }
+
+    #[test]
+    fn test_parse_slots() {
+        let seed: ConnectionInfo = "redis://:secret@10.0.0.1:7000".parse().unwrap();
+        let node = |host: &str, port: i64| {
+            Value::Bulk(vec![
+                Value::Data(host.as_bytes().to_vec()),
+                Value::Int(port),
+                Value::Data(b"id".to_vec()),
+            ])
+        };
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![Value::Int(0), Value::Int(8191), node("", 7000)]),
+            Value::Bulk(vec![
+                Value::Int(8192),
+                Value::Int(16383),
+                node("10.0.0.2", 7001),
+                node("10.0.0.3", 7002),
+            ]),
+        ]);
+        let slots = parse_slots(reply, &seed).unwrap();
+        assert_eq!(
+            vec![
+                (0, 8191, "10.0.0.1:7000".to_owned()),
+                (8192, 16383, "10.0.0.2:7001".to_owned())
+            ],
+            slots
+                .iter()
+                .map(|(start, end, node)| (*start, *end, node.addr.to_string()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some("secret"), slots[1].2.passwd.as_deref());
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::compression::Compression;
+use crate::cache::{Cache, CacheWrite, CacheWriteSkipped, Storage};
+use crate::config::CacheRWMode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::*;
+
+/// A cache that only allows reads or writes to `inner` as `mode` permits.
+///
+/// Lookups in a write-only cache always miss, and writes to a read-only
+/// cache fail with `CacheWriteSkipped`.
+pub struct RWModeStorage {
+    inner: Arc<dyn Storage>,
+    mode: CacheRWMode,
+}
+
+impl RWModeStorage {
+    /// Create a new `RWModeStorage` restricting access to `inner`.
+    pub fn new(inner: Arc<dyn Storage>, mode: CacheRWMode) -> RWModeStorage {
+        RWModeStorage { inner, mode }
+    }
+}
+
+impl Storage for RWModeStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        if self.mode.can_read() {
+            self.inner.get(key)
+        } else {
+            f_ok(Cache::Miss)
+        }
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if self.mode.can_write() {
+            self.inner.put(key, entry)
+        } else {
+            f_err(CacheWriteSkipped)
+        }
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        self.inner.contains(key)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        if self.mode.can_write() {
+            self.inner.remove(key)
+        } else {
+            f_ok(())
+        }
+    }
+
+    fn can_write(&self) -> bool {
+        self.mode.can_write() && self.inner.can_write()
+    }
+
+    fn compression(&self) -> Compression {
+        self.inner.compression()
+    }
+
+    fn location(&self) -> String {
+        match self.mode {
+            CacheRWMode::ReadOnly => format!("{} (read-only)", self.inner.location()),
+            CacheRWMode::WriteOnly => format!("{} (write-only)", self.inner.location()),
+            CacheRWMode::ReadWrite => self.inner.location(),
+        }
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::utils::*;
+    use futures::Future;
+    use futures_03::executor::ThreadPool;
+    use std::io::Cursor;
+
+    fn entry() -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(b"contents"), None)
+            .unwrap();
+        entry
+    }
+
+    fn is_hit(cache: Cache) -> bool {
+        match cache {
+            Cache::Hit(_) => true,
+            Cache::Miss => false,
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_rw_mode() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        disk.put("abcd", entry()).wait().unwrap();
+        let disk: Arc<dyn Storage> = Arc::new(disk);
+
+        let read_only = RWModeStorage::new(disk.clone(), CacheRWMode::ReadOnly);
+        assert!(!read_only.can_write());
+        assert!(is_hit(read_only.get("abcd").wait().unwrap()));
+        let err = read_only.put("efgh", entry()).wait().unwrap_err();
+        assert!(err.downcast_ref::<CacheWriteSkipped>().is_some());
+        assert!(!is_hit(disk.get("efgh").wait().unwrap()));
+
+        let write_only = RWModeStorage::new(disk.clone(), CacheRWMode::WriteOnly);
+        assert!(write_only.can_write());
+        assert!(!is_hit(write_only.get("abcd").wait().unwrap()));
+        write_only.put("efgh", entry()).wait().unwrap();
+        assert!(is_hit(disk.get("efgh").wait().unwrap()));
+
+        let read_write = RWModeStorage::new(disk, CacheRWMode::ReadWrite);
+        assert!(is_hit(read_write.get("efgh").wait().unwrap()));
+        read_write.put("ijkl", entry()).wait().unwrap();
+        assert!(is_hit(read_write.get("ijkl").wait().unwrap()));
+    }
+}
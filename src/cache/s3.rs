@@ -12,14 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, SpooledEntry, Storage};
+use crate::config::S3CacheConfig;
 use crate::simples3::{
     AutoRefreshingProvider, Bucket, ChainProvider, ProfileProvider, ProvideAwsCredentials, Ssl,
+    DEFAULT_MULTIPART_THRESHOLD, DEFAULT_PART_SIZE, MIN_PART_SIZE,
 };
 use directories::UserDirs;
 use futures::future;
 use futures::future::Future;
-use std::io;
+use std::env;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
@@ -36,13 +38,19 @@ pub struct S3Cache {
 }
 
 impl S3Cache {
-    /// Create a new `S3Cache` storing data in `bucket`.
-    pub fn new(bucket: &str, endpoint: &str, use_ssl: bool, key_prefix: &str) -> Result<S3Cache> {
+    /// Create a new `S3Cache` storing data in the bucket described by `config`.
+    pub fn new(config: &S3CacheConfig) -> Result<S3Cache> {
         let user_dirs = UserDirs::new().context("Couldn't get user directories")?;
         let home = user_dirs.home_dir();
 
+        let profile = env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned());
+        // Profiles that assume a role are usually in the config file, while
+        // the keys of their source profiles are in the credentials file.
+        let mut aws_profile =
+            ProfileProvider::with_configuration(home.join(".aws").join("credentials"), &*profile);
+        aws_profile.set_config_file_path(home.join(".aws").join("config"));
         let profile_providers = vec![
-            ProfileProvider::with_configuration(home.join(".aws").join("credentials"), "default"),
+            aws_profile,
             //TODO: this is hacky, this is where our mac builders store their
             // credentials. We should either match what boto does more directly
             // or make those builders put their credentials in ~/.aws/credentials
@@ -50,12 +58,29 @@ impl S3Cache {
         ];
         let provider =
             AutoRefreshingProvider::new(ChainProvider::with_profile_providers(profile_providers));
-        let ssl_mode = if use_ssl { Ssl::Yes } else { Ssl::No };
-        let bucket = Rc::new(Bucket::new(bucket, endpoint, ssl_mode)?);
+        let ssl_mode = if config.use_ssl { Ssl::Yes } else { Ssl::No };
+        let multipart_threshold = config
+            .multipart_threshold
+            .unwrap_or(DEFAULT_MULTIPART_THRESHOLD);
+        let part_size = config.part_size.unwrap_or(DEFAULT_PART_SIZE);
+        if part_size < MIN_PART_SIZE {
+            bail!(
+                "S3 part size must be at least {} bytes, got {}",
+                MIN_PART_SIZE,
+                part_size
+            );
+        }
+        let bucket = Rc::new(Bucket::new(
+            &config.bucket,
+            &config.endpoint,
+            ssl_mode,
+            multipart_threshold,
+            part_size,
+        )?);
         Ok(S3Cache {
             bucket,
             provider,
-            key_prefix: key_prefix.to_owned(),
+            key_prefix: config.key_prefix.clone(),
         })
     }
 
@@ -75,9 +100,9 @@ impl Storage for S3Cache {
     fn get(&self, key: &str) -> SFuture<Cache> {
         let key = self.normalize_key(key);
 
-        let result_cb = |result| match result {
+        let result_cb = |result: Result<SpooledEntry>| match result {
             Ok(data) => {
-                let hit = CacheRead::from(io::Cursor::new(data))?;
+                let hit = CacheRead::from(data.into_reader()?)?;
                 Ok(Cache::Hit(hit))
             }
             Err(e) => {
@@ -123,6 +148,21 @@ impl Storage for S3Cache {
         Box::new(response.map(move |_| start.elapsed()))
     }
 
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let key = self.normalize_key(key);
+        let credentials = self
+            .provider
+            .credentials()
+            .fcontext("failed to get AWS credentials");
+
+        let bucket = self.bucket.clone();
+        Box::new(credentials.and_then(move |credentials| {
+            bucket
+                .delete(&key, &credentials)
+                .fcontext("failed to remove cache entry from s3")
+        }))
+    }
+
     fn location(&self) -> String {
         format!("S3, bucket: {}", self.bucket)
     }
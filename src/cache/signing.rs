@@ -0,0 +1,453 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::compression::Compression;
+use crate::cache::{Cache, CacheRead, CacheWrite, CacheWriteSkipped, SpooledEntry, Storage};
+use crate::config::CacheSigningConfig;
+use crate::util::{Digest, SpawnExt};
+use futures::Future;
+use futures_03::executor::ThreadPool;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// The object in a signed entry that holds the signed entry.
+const SIGNED_OBJECT: &str = "signed";
+
+/// The object in a signed entry that holds the signature.
+const SIGNATURE_OBJECT: &str = "signature";
+
+/// The length of Ed25519 private keys (seeds) and public keys, in bytes.
+const KEY_LEN: usize = 32;
+
+fn decode_key(key: &str, kind: &str) -> Result<Vec<u8>> {
+    // Don't include the key in errors, private keys are secrets.
+    base64::decode(key.trim())
+        .ok()
+        .filter(|bytes| bytes.len() == KEY_LEN)
+        .with_context(|| format!("Signing {} isn't {} base64 bytes", kind, KEY_LEN))
+}
+
+/// The keys to sign and verify entries with, and where to put entries that
+/// can't be signed.
+pub struct Signing {
+    key: Option<Ed25519KeyPair>,
+    trusted_keys: Vec<Vec<u8>>,
+    untrusted_namespace: Option<String>,
+    read_untrusted_namespace: bool,
+}
+
+impl Signing {
+    /// Load the keys in `config`. A client's own public key is always trusted.
+    pub fn from_config(config: &CacheSigningConfig) -> Result<Signing> {
+        let key = match config.key {
+            Some(ref key) => Some(
+                Ed25519KeyPair::from_seed_unchecked(&decode_key(key, "key")?)
+                    .map_err(|e| anyhow!("Invalid signing key: {}", e))?,
+            ),
+            None => None,
+        };
+        let mut trusted_keys = config
+            .trusted_keys
+            .iter()
+            .map(|key| decode_key(key, "public key"))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(ref key) = key {
+            trusted_keys.push(key.public_key().as_ref().to_vec());
+        }
+        if trusted_keys.is_empty() {
+            bail!("No trusted signing keys configured");
+        }
+        Ok(Signing {
+            key,
+            trusted_keys,
+            untrusted_namespace: config.untrusted_namespace.clone(),
+            read_untrusted_namespace: config.read_untrusted_namespace,
+        })
+    }
+
+    /// Settings that never trust an entry, or write one.
+    pub fn none() -> Signing {
+        Signing {
+            key: None,
+            trusted_keys: vec![],
+            untrusted_namespace: None,
+            read_untrusted_namespace: false,
+        }
+    }
+
+    /// The key that this client, which has no signing key, stores `key`
+    /// under, if it uses the untrusted namespace.
+    fn untrusted_key(&self, key: &str) -> Option<String> {
+        match (&self.key, &self.untrusted_namespace) {
+            (None, Some(namespace)) => Some(format!("{}.{}", key, namespace)),
+            _ => None,
+        }
+    }
+
+    /// The key that this client looks up `key` under when there's no trusted
+    /// entry, if it was told to read the untrusted namespace.
+    fn untrusted_read_key(&self, key: &str) -> Option<String> {
+        self.untrusted_key(key)
+            .filter(|_| self.read_untrusted_namespace)
+    }
+}
+
+/// The message signed for `entry` stored under `cache_key`. Signing the cache
+/// key too means a signed entry can't be copied to another key.
+fn message(entry: &SpooledEntry, cache_key: &str) -> Result<Vec<u8>> {
+    let digest = Digest::reader_sync(entry.reader()?)?;
+    Ok(format!("sccache signed entry\0{}\0{}", cache_key, digest).into_bytes())
+}
+
+/// Sign the finished entry `entry`, for storage under `cache_key`.
+fn sign(entry: SpooledEntry, key: &Ed25519KeyPair, cache_key: &str) -> Result<CacheWrite> {
+    let signature = key.sign(&message(&entry, cache_key)?);
+    let mut signed = CacheWrite::new()?;
+    signed.put_raw_object(SIGNED_OBJECT, &mut entry.into_reader()?, None)?;
+    signed.put_raw_object(SIGNATURE_OBJECT, &mut signature.as_ref(), None)?;
+    Ok(signed)
+}
+
+/// Check that `signed`, stored under `cache_key`, was signed by one of
+/// `trusted_keys`, and return the entry it holds.
+fn verify(mut signed: CacheRead, trusted_keys: &[Vec<u8>], cache_key: &str) -> Result<CacheRead> {
+    let mut signature = vec![];
+    signed
+        .get_raw_object(SIGNATURE_OBJECT, &mut signature)
+        .context("Entry isn't signed")?;
+    let mut entry = SpooledEntry::new()?;
+    signed.get_raw_object(SIGNED_OBJECT, &mut entry)?;
+    let message = message(&entry, cache_key)?;
+    if !trusted_keys.iter().any(|key| {
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(&message, &signature)
+            .is_ok()
+    }) {
+        bail!("Entry isn't signed by a trusted key");
+    }
+    CacheRead::from(entry.into_reader()?)
+}
+
+/// A cache that only returns entries signed by trusted writers, so that
+/// anyone else with write access to `inner` can't plant entries in it.
+///
+/// Clients with a signing key sign the entries they write. Clients without
+/// one don't write entries, unless an untrusted namespace is configured, in
+/// which case they write unsigned entries there. Since anyone can write to
+/// that namespace, those clients only look entries up there, when there's no
+/// trusted entry, if they're told to. Clients with a signing key never read
+/// the untrusted namespace.
+pub struct SignedStorage {
+    inner: Arc<dyn Storage>,
+    signing: Arc<Signing>,
+    pool: ThreadPool,
+}
+
+impl SignedStorage {
+    /// Create a new `SignedStorage` storing entries in `inner`.
+    pub fn new(inner: Arc<dyn Storage>, signing: Signing, pool: &ThreadPool) -> Self {
+        SignedStorage {
+            inner,
+            signing: Arc::new(signing),
+            pool: pool.clone(),
+        }
+    }
+}
+
+impl Storage for SignedStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let untrusted_key = self.signing.untrusted_read_key(key);
+        let key = key.to_owned();
+        let inner = self.inner.clone();
+        let signing = self.signing.clone();
+        let pool = self.pool.clone();
+        let verified = self.inner.get(&key).and_then(move |res| -> SFuture<Cache> {
+            let signed = match res {
+                Cache::Hit(entry) => entry,
+                res => return f_ok(res),
+            };
+            Box::new(
+                pool.spawn_fn(move || match verify(signed, &signing.trusted_keys, &key) {
+                    Ok(entry) => Ok(Cache::Hit(entry)),
+                    Err(e) => {
+                        warn!("Ignoring cache entry {}: {:#}", key, e);
+                        Ok(Cache::Miss)
+                    }
+                }),
+            )
+        });
+        let untrusted_key = match untrusted_key {
+            Some(untrusted_key) => untrusted_key,
+            None => return Box::new(verified),
+        };
+        Box::new(verified.and_then(move |res| -> SFuture<Cache> {
+            match res {
+                Cache::Miss => inner.get(&untrusted_key),
+                res => f_ok(res),
+            }
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        if let Some(untrusted_key) = self.signing.untrusted_key(key) {
+            return self.inner.put(&untrusted_key, entry);
+        }
+        if self.signing.key.is_none() {
+            return f_err(CacheWriteSkipped);
+        }
+        let key = key.to_owned();
+        let inner = self.inner.clone();
+        let signing = self.signing.clone();
+        let cache_key = key.clone();
+        let signed = self.pool.spawn_fn(move || -> Result<_> {
+            let signing_key = signing.key.as_ref().expect("No signing key");
+            sign(entry.finish()?, signing_key, &cache_key)
+        });
+        Box::new(
+            signed
+                .and_then(move |entry| inner.put(&key, entry))
+                .map(move |_| start.elapsed()),
+        )
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        // This only checks whether there's an object where `put` would write
+        // one. Whether it's signed isn't known without fetching it, but an
+        // entry that turns out not to be is a miss when it's read, and is
+        // removed then, such as by `CasStorage`, so it's written again.
+        match self.signing.untrusted_key(key) {
+            Some(untrusted_key) => self.inner.contains(&untrusted_key),
+            None if self.signing.key.is_some() => self.inner.contains(key),
+            None => f_ok(false),
+        }
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        match self.signing.untrusted_key(key) {
+            Some(untrusted_key) => Box::new(
+                self.inner
+                    .remove(key)
+                    .join(self.inner.remove(&untrusted_key))
+                    .map(|_| ()),
+            ),
+            None => self.inner.remove(key),
+        }
+    }
+
+    fn can_write(&self) -> bool {
+        self.inner.can_write()
+    }
+
+    fn compression(&self) -> Compression {
+        self.inner.compression()
+    }
+
+    fn location(&self) -> String {
+        format!("{} (signed)", self.inner.location())
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::test::utils::*;
+    use std::io::Cursor;
+
+    const CI_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const OTHER_KEY: &str = "Hx4dHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(contents), None)
+            .unwrap();
+        entry
+    }
+
+    fn contents(cache: Cache) -> Option<Vec<u8>> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut bytes = vec![];
+                entry.get_object("obj", &mut bytes).unwrap();
+                Some(bytes)
+            }
+            Cache::Miss => None,
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    fn public_key(key: &str) -> String {
+        let key = Ed25519KeyPair::from_seed_unchecked(&base64::decode(key).unwrap()).unwrap();
+        base64::encode(key.public_key().as_ref())
+    }
+
+    fn signing(key: Option<&str>, trusted_keys: &[&str], namespace: Option<&str>) -> Signing {
+        Signing::from_config(&CacheSigningConfig {
+            key: key.map(str::to_owned),
+            trusted_keys: trusted_keys.iter().map(|key| public_key(key)).collect(),
+            untrusted_namespace: namespace.map(str::to_owned),
+            read_untrusted_namespace: true,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = CacheSigningConfig {
+            key: None,
+            trusted_keys: vec![],
+            untrusted_namespace: None,
+            read_untrusted_namespace: false,
+        };
+        assert!(Signing::from_config(&config).is_err());
+        let config = CacheSigningConfig {
+            key: Some("AAECAwQ=".to_owned()),
+            ..config
+        };
+        assert!(Signing::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_signed_storage() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let disk: Arc<dyn Storage> = Arc::new(disk);
+        let ci = SignedStorage::new(disk.clone(), signing(Some(CI_KEY), &[], None), &pool);
+        let client = SignedStorage::new(disk.clone(), signing(None, &[CI_KEY], None), &pool);
+
+        // Entries signed by a trusted writer are hits.
+        ci.put("abcd", entry(b"trusted")).wait().unwrap();
+        assert_eq!(
+            Some(b"trusted".to_vec()),
+            contents(client.get("abcd").wait().unwrap())
+        );
+        assert_eq!(
+            Some(b"trusted".to_vec()),
+            contents(ci.get("abcd").wait().unwrap())
+        );
+
+        // Clients without a signing key can't write entries.
+        let err = client.put("efgh", entry(b"client")).wait().unwrap_err();
+        assert!(err.downcast_ref::<CacheWriteSkipped>().is_some());
+
+        // Unsigned entries, entries signed by other keys and signed entries
+        // copied to another key are all misses.
+        disk.put("efgh", entry(b"unsigned")).wait().unwrap();
+        assert_eq!(None, contents(client.get("efgh").wait().unwrap()));
+        // Trusted writers only check that something is stored under a key,
+        // and clients that don't write entries don't check at all.
+        assert!(!client.contains("efgh").wait().unwrap());
+        assert!(ci.contains("efgh").wait().unwrap());
+        assert!(ci.contains("abcd").wait().unwrap());
+        assert!(!ci.contains("qrst").wait().unwrap());
+        let other = SignedStorage::new(disk.clone(), signing(Some(OTHER_KEY), &[], None), &pool);
+        other.put("ijkl", entry(b"untrusted")).wait().unwrap();
+        assert_eq!(None, contents(client.get("ijkl").wait().unwrap()));
+        match disk.get("abcd").wait().unwrap() {
+            Cache::Hit(signed) => {
+                let signed = signed.into_spooled().unwrap().into_reader().unwrap();
+                let copy = CacheWrite::from_reader(signed).unwrap();
+                disk.put("mnop", copy).wait().unwrap();
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+        assert_eq!(None, contents(client.get("mnop").wait().unwrap()));
+    }
+
+    #[test]
+    fn test_untrusted_namespace() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let disk: Arc<dyn Storage> = Arc::new(disk);
+        let ci = SignedStorage::new(
+            disk.clone(),
+            signing(Some(CI_KEY), &[], Some("untrusted")),
+            &pool,
+        );
+        let client = SignedStorage::new(
+            disk.clone(),
+            signing(None, &[CI_KEY], Some("untrusted")),
+            &pool,
+        );
+
+        // Clients write unsigned entries to their namespace, and read them
+        // back, but trusted writers don't see them.
+        client.put("abcd", entry(b"client")).wait().unwrap();
+        assert!(disk.contains("abcd.untrusted").wait().unwrap());
+        assert!(client.contains("abcd").wait().unwrap());
+        assert_eq!(
+            Some(b"client".to_vec()),
+            contents(client.get("abcd").wait().unwrap())
+        );
+        assert_eq!(None, contents(ci.get("abcd").wait().unwrap()));
+
+        // Trusted entries take precedence.
+        ci.put("abcd", entry(b"trusted")).wait().unwrap();
+        assert_eq!(
+            Some(b"trusted".to_vec()),
+            contents(client.get("abcd").wait().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_untrusted_namespace_write_only() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let disk: Arc<dyn Storage> = Arc::new(disk);
+        let config = CacheSigningConfig {
+            key: None,
+            trusted_keys: vec![public_key(CI_KEY)],
+            untrusted_namespace: Some("untrusted".to_owned()),
+            read_untrusted_namespace: false,
+        };
+        let client =
+            SignedStorage::new(disk.clone(), Signing::from_config(&config).unwrap(), &pool);
+
+        // Without the opt-in, entries are still written to the namespace, but
+        // whatever anyone plants there isn't served.
+        client.put("abcd", entry(b"client")).wait().unwrap();
+        assert!(disk.contains("abcd.untrusted").wait().unwrap());
+        disk.put("efgh.untrusted", entry(b"planted"))
+            .wait()
+            .unwrap();
+        assert_eq!(None, contents(client.get("abcd").wait().unwrap()));
+        assert_eq!(None, contents(client.get("efgh").wait().unwrap()));
+
+        let config = CacheSigningConfig {
+            read_untrusted_namespace: true,
+            ..config
+        };
+        let client = SignedStorage::new(disk, Signing::from_config(&config).unwrap(), &pool);
+        assert_eq!(
+            Some(b"planted".to_vec()),
+            contents(client.get("efgh").wait().unwrap())
+        );
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::compression::Compression;
+use crate::cache::disk::DiskCache;
+use crate::cache::{Cache, CacheRead, CacheTier, CacheWrite, CacheWriteSkipped, Storage};
+use crate::config::CacheWritePolicy;
+use crate::util::SpawnExt;
+use futures::{future, Future};
+use futures_03::executor::ThreadPool;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// A cache that keeps a local disk cache in front of a remote cache.
+///
+/// Lookups check the local tier first and fall back to the remote tier,
+/// copying remote hits into the local tier. Writes go to both tiers.
+pub struct TieredStorage {
+    local: DiskCache,
+    remote: Arc<dyn Storage>,
+    policy: CacheWritePolicy,
+    local_compression: Compression,
+    pool: ThreadPool,
+}
+
+impl TieredStorage {
+    /// Create a new `TieredStorage` with `local` in front of `remote`,
+    /// writing to `remote` according to `policy`.
+    pub fn new(
+        local: DiskCache,
+        remote: Arc<dyn Storage>,
+        policy: CacheWritePolicy,
+        pool: &ThreadPool,
+    ) -> TieredStorage {
+        TieredStorage {
+            local,
+            remote,
+            policy,
+            local_compression: Compression::default(),
+            pool: pool.clone(),
+        }
+    }
+
+    /// Compress the objects of new entries with `compression`. Entries are
+    /// recompressed for the remote tier if it compresses objects differently.
+    pub fn with_local_compression(self, compression: Compression) -> TieredStorage {
+        TieredStorage {
+            local_compression: compression,
+            ..self
+        }
+    }
+}
+
+impl Storage for TieredStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = key.to_owned();
+        let local = self.local.clone();
+        let remote = self.remote.clone();
+        let pool = self.pool.clone();
+        Box::new(self.local.get(&key).then(move |res| -> SFuture<Cache> {
+            match res {
+                Ok(Cache::Hit(entry)) => {
+                    return f_ok(Cache::Hit(entry.with_tier(CacheTier::Local)))
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to read from local cache tier: {}", e),
+            }
+            Box::new(remote.get(&key).and_then(move |res| -> SFuture<Cache> {
+                let entry = match res {
+                    Cache::Hit(entry) => entry,
+                    res => return f_ok(res),
+                };
+                // Fill the local tier so the next lookup doesn't need the remote.
+                let spooled = pool.spawn_fn(move || entry.into_spooled());
+                Box::new(spooled.and_then(move |spooled| {
+                    let hit = spooled.reader().and_then(CacheRead::from);
+                    local.put_raw(&key, spooled).then(move |res| {
+                        if let Err(e) = res {
+                            warn!("Failed to fill local cache tier: {}", e);
+                        }
+                        hit.map(|entry| Cache::Hit(entry.with_tier(CacheTier::Remote)))
+                    })
+                }))
+            }))
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let key = key.to_owned();
+        let local = self.local.clone();
+        let remote = self.remote.clone();
+        let policy = self.policy;
+        let entries = self.pool.spawn_fn(move || {
+            let spooled = entry.finish()?;
+            let remote_entry = CacheWrite::from_reader(spooled.reader()?)?;
+            Ok((spooled, remote_entry))
+        });
+        Box::new(
+            entries.and_then(move |(spooled, remote_entry)| -> SFuture<Duration> {
+                let local_put = local.put_raw(&key, spooled);
+                // A read-only remote tier doesn't make the write fail, since the
+                // entry is still stored in the local tier.
+                let remote_put = remote.put(&key, remote_entry).then(|res| match res {
+                    Err(ref e) if e.downcast_ref::<CacheWriteSkipped>().is_some() => {
+                        Ok(Duration::default())
+                    }
+                    res => res,
+                });
+                match policy {
+                    CacheWritePolicy::WriteThrough => {
+                        Box::new(local_put.join(remote_put).map(move |_| start.elapsed()))
+                    }
+                    CacheWritePolicy::WriteBack => {
+                        let remote_put = remote_put.then(move |res| {
+                            if let Err(e) = res {
+                                warn!("Failed to write {} to remote cache tier: {}", key, e);
+                            }
+                            Ok(())
+                        });
+                        // Keep hold of the write, so it can still be done in the
+                        // foreground if it can't be spawned.
+                        let remote_put = Rc::new(RefCell::new(Some(remote_put)));
+                        let background = {
+                            let remote_put = remote_put.clone();
+                            future::lazy(move || remote_put.borrow_mut().take().unwrap())
+                        };
+                        match tokio_compat::runtime::current_thread::TaskExecutor::current()
+                            .spawn_local(Box::new(background))
+                        {
+                            Ok(()) => Box::new(local_put.map(move |_| start.elapsed())),
+                            Err(e) => {
+                                debug!(
+                                    "Can't write to remote cache tier in the background ({}), \
+                                     writing in the foreground",
+                                    e
+                                );
+                                let remote_put = remote_put.borrow_mut().take().unwrap();
+                                Box::new(local_put.and_then(move |_| {
+                                    remote_put.then(move |_| Ok(start.elapsed()))
+                                }))
+                            }
+                        }
+                    }
+                }
+            }),
+        )
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        // Entries missing from the local tier are copied there when they're
+        // read, so only the remote tier decides whether one needs storing.
+        self.remote.contains(key)
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        // A corrupt remote entry has already been copied to the local tier.
+        Box::new(
+            self.local
+                .remove(key)
+                .join(self.remote.remove(key))
+                .map(|_| ()),
+        )
+    }
+
+    fn compression(&self) -> Compression {
+        self.local_compression.clone()
+    }
+
+    fn location(&self) -> String {
+        format!(
+            "{} (local tier), {} (remote tier)",
+            self.local.location(),
+            self.remote.location()
+        )
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.local.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.local.max_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::*;
+    use std::io::Cursor;
+    use tokio_compat::runtime::current_thread::Runtime;
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(contents), Some(0o755))
+            .unwrap();
+        entry
+    }
+
+    fn contents(cache: Cache) -> (Option<CacheTier>, Vec<u8>, Option<u32>) {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut bytes = vec![];
+                let mode = entry.get_object("obj", &mut bytes).unwrap();
+                (entry.tier(), bytes, mode)
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    fn tiered(f: &TestFixture, policy: CacheWritePolicy) -> (TieredStorage, DiskCache, DiskCache) {
+        let pool = ThreadPool::sized(1);
+        let local = DiskCache::new(&f.tempdir.path().join("local"), u64::MAX, &pool);
+        let remote = DiskCache::new(&f.tempdir.path().join("remote"), u64::MAX, &pool);
+        let storage = TieredStorage::new(local.clone(), Arc::new(remote.clone()), policy, &pool);
+        (storage, local, remote)
+    }
+
+    #[test]
+    fn test_tiered_get_fills_local() {
+        let f = TestFixture::new();
+        let (storage, local, remote) = tiered(&f, CacheWritePolicy::WriteThrough);
+        remote.put("abcd", entry(b"remote")).wait().unwrap();
+
+        let hit = storage.get("abcd").wait().unwrap();
+        assert_eq!(
+            (Some(CacheTier::Remote), b"remote".to_vec(), Some(0o100755)),
+            contents(hit)
+        );
+        // The entry was copied to the local tier, so the next lookup finds it there.
+        let (tier, bytes, _) = contents(local.get("abcd").wait().unwrap());
+        assert_eq!((None, b"remote".to_vec()), (tier, bytes));
+        let (tier, _, _) = contents(storage.get("abcd").wait().unwrap());
+        assert_eq!(Some(CacheTier::Local), tier);
+
+        match storage.get("efgh").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_tiered_put() {
+        let f = TestFixture::new();
+        let mut runtime = Runtime::new().unwrap();
+        for &(policy, key) in &[
+            (CacheWritePolicy::WriteThrough, "abcd"),
+            (CacheWritePolicy::WriteBack, "efgh"),
+        ] {
+            let (storage, local, remote) = tiered(&f, policy);
+            runtime
+                .block_on(future::lazy(|| storage.put(key, entry(b"both"))))
+                .unwrap();
+            // Let any background write to the remote tier finish.
+            runtime.run().unwrap();
+            for tier in &[local, remote] {
+                let (_, bytes, mode) = contents(tier.get(key).wait().unwrap());
+                assert_eq!((b"both".to_vec(), Some(0o100755)), (bytes, mode));
+            }
+        }
+    }
+
+    #[test]
+    fn test_tiered_put_write_back_without_runtime() {
+        let f = TestFixture::new();
+        let (storage, _, remote) = tiered(&f, CacheWritePolicy::WriteBack);
+        // Without a runtime to run it on, the remote write is done before
+        // `put` finishes instead of being dropped.
+        storage.put("abcd", entry(b"both")).wait().unwrap();
+        let (_, bytes, _) = contents(remote.get("abcd").wait().unwrap());
+        assert_eq!(b"both".to_vec(), bytes);
+    }
+
+    #[test]
+    fn test_tiered_contains() {
+        let f = TestFixture::new();
+        let (storage, local, remote) = tiered(&f, CacheWritePolicy::WriteThrough);
+        remote.put("abcd", entry(b"remote")).wait().unwrap();
+        local.put("efgh", entry(b"local")).wait().unwrap();
+        assert!(storage.contains("abcd").wait().unwrap());
+        assert!(!storage.contains("efgh").wait().unwrap());
+    }
+
+    #[test]
+    fn test_tiered_remove() {
+        let f = TestFixture::new();
+        let (storage, local, remote) = tiered(&f, CacheWritePolicy::WriteThrough);
+        remote.put("abcd", entry(b"remote")).wait().unwrap();
+        storage.get("abcd").wait().unwrap();
+        assert!(local.contains("abcd").wait().unwrap());
+
+        storage.remove("abcd").wait().unwrap();
+        for tier in &[local, remote] {
+            assert!(!tier.contains("abcd").wait().unwrap());
+            match tier.get("abcd").wait().unwrap() {
+                Cache::Miss => {}
+                c => panic!("Unexpected cache lookup result: {:?}", c),
+            }
+        }
+    }
+}
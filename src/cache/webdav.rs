@@ -0,0 +1,359 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{Cache, CacheRead, CacheWrite, SpooledEntry, Storage};
+use crate::config::WebdavCacheConfig;
+use futures::future::Future;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Method, StatusCode};
+use hyperx::header::{ContentLength, ContentType};
+use reqwest::r#async::{Client, Request};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::util::HeadersExt;
+
+/// A cache that stores entries on an HTTP server supporting GET, PUT and
+/// HEAD, such as a WebDAV server or bazel-remote.
+pub struct WebdavCache {
+    /// The URL that keys are appended to.
+    base_url: String,
+    /// The value of the `Authorization` header, if any.
+    authorization: Option<HeaderValue>,
+    client: Client,
+}
+
+impl WebdavCache {
+    /// Create a new `WebdavCache` from `config`.
+    pub fn new(config: &WebdavCacheConfig) -> Result<WebdavCache> {
+        let mut base_url = config.endpoint.trim_end_matches('/').to_owned();
+        let key_prefix = config.key_prefix.trim_matches('/');
+        if !key_prefix.is_empty() {
+            base_url.push('/');
+            base_url.push_str(key_prefix);
+        }
+        base_url.push('/');
+        base_url
+            .parse::<reqwest::Url>()
+            .with_context(|| format!("Invalid WebDAV endpoint: {}", base_url))?;
+
+        let authorization = match (&config.username, &config.password, &config.token) {
+            (_, _, Some(token)) => Some(format!("Bearer {}", token)),
+            (Some(username), password, None) => {
+                let credentials = format!("{}:{}", username, password.as_deref().unwrap_or(""));
+                Some(format!("Basic {}", base64::encode(&credentials)))
+            }
+            (None, Some(_), None) => bail!("A WebDAV password was given without a user name"),
+            (None, None, None) => None,
+        };
+        let authorization = authorization
+            .map(|value| HeaderValue::from_str(&value))
+            .transpose()
+            .context("Invalid WebDAV credentials")?;
+
+        let mut builder = Client::builder();
+        if let Some(ref path) = config.ca_certs {
+            for cert in read_certificates(path)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        let client = builder.build().context("Failed to create HTTP client")?;
+        Ok(WebdavCache {
+            base_url,
+            authorization,
+            client,
+        })
+    }
+
+    fn request(&self, method: Method, key: &str) -> Request {
+        let url = format!("{}{}", self.base_url, key);
+        let mut request = Request::new(method, url.parse().unwrap());
+        if let Some(ref authorization) = self.authorization {
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, authorization.clone());
+        }
+        request
+    }
+}
+
+/// Read the PEM certificates in the file at `path`.
+fn read_certificates(path: &Path) -> Result<Vec<reqwest::Certificate>> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let pem = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    let mut certs = vec![];
+    let mut rest = pem.as_str();
+    while let Some(end) = rest.find(END) {
+        let (cert, tail) = rest.split_at(end + END.len());
+        let cert = reqwest::Certificate::from_pem(cert.as_bytes())
+            .with_context(|| format!("Invalid certificate in {}", path.display()))?;
+        certs.push(cert);
+        rest = tail;
+    }
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+impl Storage for WebdavCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let request = self.request(Method::GET, key);
+        let key = key.to_owned();
+        Box::new(
+            self.client
+                .execute(request)
+                .fwith_context(move || format!("failed GET: {}", key))
+                .and_then(|res| -> SFuture<Cache> {
+                    match res.status() {
+                        StatusCode::NOT_FOUND => f_ok(Cache::Miss),
+                        status if status.is_success() => Box::new(
+                            SpooledEntry::from_stream(res.into_body())
+                                .fcontext("failed to read HTTP body")
+                                .and_then(|entry| {
+                                    CacheRead::from(entry.into_reader()?).map(Cache::Hit)
+                                }),
+                        ),
+                        status => f_err(BadHttpStatusError(status)),
+                    }
+                }),
+        )
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let mut request = self.request(Method::PUT, key);
+        let entry = match entry.finish() {
+            Ok(entry) => entry,
+            Err(e) => return f_err(e),
+        };
+        request.headers_mut().set(ContentType::octet_stream());
+        request.headers_mut().set(ContentLength(entry.len()));
+        match entry.into_stream() {
+            Ok(body) => *request.body_mut() = Some(body.into()),
+            Err(e) => return f_err(e),
+        }
+        Box::new(
+            self.client
+                .execute(request)
+                .fcontext("failed to put cache entry over HTTP")
+                .and_then(move |res| {
+                    if res.status().is_success() {
+                        Ok(start.elapsed())
+                    } else {
+                        Err(BadHttpStatusError(res.status()).into())
+                    }
+                }),
+        )
+    }
+
+    fn contains(&self, key: &str) -> SFuture<bool> {
+        let request = self.request(Method::HEAD, key);
+        Box::new(
+            self.client
+                .execute(request)
+                .fcontext("failed to check for cache entry over HTTP")
+                .and_then(|res| match res.status() {
+                    StatusCode::NOT_FOUND => Ok(false),
+                    status if status.is_success() => Ok(true),
+                    status => Err(BadHttpStatusError(status).into()),
+                }),
+        )
+    }
+
+    fn remove(&self, key: &str) -> SFuture<()> {
+        let request = self.request(Method::DELETE, key);
+        Box::new(
+            self.client
+                .execute(request)
+                .fcontext("failed to remove cache entry over HTTP")
+                .and_then(|res| match res.status() {
+                    StatusCode::NOT_FOUND => Ok(()),
+                    status if status.is_success() => Ok(()),
+                    status => Err(BadHttpStatusError(status).into()),
+                }),
+        )
+    }
+
+    fn location(&self) -> String {
+        format!("WebDAV, endpoint: {}", self.base_url)
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        f_ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{future, Stream};
+    use hyper::service::service_fn;
+    use hyper::{Body, Response, Server};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use tokio_compat::runtime::current_thread::Runtime;
+
+    type Blobs = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Serve GET, PUT, HEAD and DELETE requests for `blobs` on a local port, for
+    /// requests with the `Authorization` header `authorization`. Returns the
+    /// server's base URL.
+    fn serve(runtime: &mut Runtime, blobs: &Blobs, authorization: &'static str) -> String {
+        let blobs = blobs.clone();
+        let make_service = move || {
+            let blobs = blobs.clone();
+            service_fn(move |req: hyper::Request<Body>| {
+                let blobs = blobs.clone();
+                let authorized = req.headers().get(AUTHORIZATION)
+                    == Some(&HeaderValue::from_static(authorization));
+                let method = req.method().clone();
+                let path = req.uri().path().to_owned();
+                req.into_body().concat2().map(move |body| {
+                    let mut blobs = blobs.lock().unwrap();
+                    let status = match method {
+                        _ if !authorized => StatusCode::UNAUTHORIZED,
+                        Method::PUT => {
+                            blobs.insert(path, body.to_vec());
+                            StatusCode::CREATED
+                        }
+                        Method::GET if blobs.contains_key(&path) => {
+                            return Response::new(Body::from(blobs[&path].clone()));
+                        }
+                        Method::HEAD if blobs.contains_key(&path) => StatusCode::OK,
+                        Method::DELETE if blobs.remove(&path).is_some() => StatusCode::NO_CONTENT,
+                        _ => StatusCode::NOT_FOUND,
+                    };
+                    let mut res = Response::new(Body::empty());
+                    *res.status_mut() = status;
+                    res
+                })
+            })
+        };
+        let server = runtime
+            .block_on(future::lazy(|| {
+                future::ok::<_, ()>(Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service))
+            }))
+            .unwrap();
+        let url = format!("http://{}/", server.local_addr());
+        runtime.spawn(server.map_err(|e| panic!("HTTP server failed: {}", e)));
+        url
+    }
+
+    fn config(endpoint: String) -> WebdavCacheConfig {
+        WebdavCacheConfig {
+            endpoint,
+            key_prefix: "/sccache/".to_owned(),
+            username: None,
+            password: None,
+            token: None,
+            ca_certs: None,
+        }
+    }
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new().unwrap();
+        entry
+            .put_object("obj", &mut Cursor::new(contents), None)
+            .unwrap();
+        entry
+    }
+
+    #[test]
+    fn test_webdav_put_get() {
+        let mut runtime = Runtime::new().unwrap();
+        let blobs = Blobs::default();
+        let endpoint = serve(&mut runtime, &blobs, "Bearer secret");
+        let storage = WebdavCache::new(&WebdavCacheConfig {
+            token: Some("secret".to_owned()),
+            ..config(endpoint)
+        })
+        .unwrap();
+
+        let contains = |runtime: &mut Runtime, key| {
+            runtime
+                .block_on(future::lazy(|| storage.contains(key)))
+                .unwrap()
+        };
+        assert!(!contains(&mut runtime, "abcd"));
+        runtime
+            .block_on(future::lazy(|| storage.put("abcd", entry(b"contents"))))
+            .unwrap();
+        assert!(blobs.lock().unwrap().contains_key("/sccache/abcd"));
+        assert!(contains(&mut runtime, "abcd"));
+
+        match runtime
+            .block_on(future::lazy(|| storage.get("abcd")))
+            .unwrap()
+        {
+            Cache::Hit(mut entry) => {
+                let mut bytes = vec![];
+                entry.get_object("obj", &mut bytes).unwrap();
+                assert_eq!(b"contents".to_vec(), bytes);
+            }
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+        match runtime
+            .block_on(future::lazy(|| storage.get("efgh")))
+            .unwrap()
+        {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+
+        runtime
+            .block_on(future::lazy(|| storage.remove("abcd")))
+            .unwrap();
+        assert!(!contains(&mut runtime, "abcd"));
+    }
+
+    #[test]
+    fn test_webdav_basic_auth() {
+        let mut runtime = Runtime::new().unwrap();
+        let blobs = Blobs::default();
+        // The base64 encoding of "user:pass".
+        let endpoint = serve(&mut runtime, &blobs, "Basic dXNlcjpwYXNz");
+        let storage = WebdavCache::new(&WebdavCacheConfig {
+            username: Some("user".to_owned()),
+            password: Some("pass".to_owned()),
+            ..config(endpoint.clone())
+        })
+        .unwrap();
+        runtime
+            .block_on(future::lazy(|| storage.put("abcd", entry(b"contents"))))
+            .unwrap();
+
+        // Requests with the wrong credentials fail, rather than missing.
+        let storage = WebdavCache::new(&WebdavCacheConfig {
+            token: Some("secret".to_owned()),
+            ..config(endpoint)
+        })
+        .unwrap();
+        assert!(runtime
+            .block_on(future::lazy(|| storage.get("abcd")))
+            .is_err());
+        assert!(runtime
+            .block_on(future::lazy(|| storage.put("efgh", entry(b"contents"))))
+            .is_err());
+        assert_eq!(1, blobs.lock().unwrap().len());
+    }
+}
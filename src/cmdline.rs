@@ -57,6 +57,19 @@ pub enum Command {
         /// The environment variables to use for execution.
         env_vars: Vec<(OsString, OsString)>,
     },
+    /// Show the inputs to the cache key of a compiler command without running it.
+    Explain {
+        /// The binary to execute.
+        exe: OsString,
+        /// The commandline arguments to pass to `exe`.
+        cmdline: Vec<OsString>,
+        /// The directory in which to execute the command.
+        cwd: PathBuf,
+        /// The environment variables to use for execution.
+        env_vars: Vec<(OsString, OsString)>,
+        /// Whether to store the explanation in the cache.
+        store: bool,
+    },
 }
 
 /// Get the `App` used for argument parsing.
@@ -78,7 +91,9 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
              --stop-server    'stop background server'
              -z, --zero-stats 'zero statistics counters'
              --dist-auth      'authenticate for distributed compilation'
-             --dist-status    'show status of the distributed client'"
+             --dist-status    'show status of the distributed client'
+             --explain        'show the inputs to the cache key of a compiler command without running it'
+             --explain-store  'like --explain, and store the explanation in the cache next to the entry'"
                 )
         .arg(Arg::from_usage("--package-toolchain <executable> <out> 'package toolchain for distributed compilation'")
              .required(false))
@@ -150,6 +165,8 @@ pub fn parse() -> Result<Command> {
     let dist_auth = matches.is_present("dist-auth");
     let dist_status = matches.is_present("dist-status");
     let package_toolchain = matches.is_present("package-toolchain");
+    let explain_store = matches.is_present("explain-store");
+    let explain = explain_store || matches.is_present("explain");
     let cmd = matches.values_of_os("cmd");
     // Ensure that we've only received one command to run.
     fn is_some<T>(x: &Option<T>) -> bool {
@@ -162,7 +179,7 @@ pub fn parse() -> Result<Command> {
         stop_server,
         zero_stats,
         package_toolchain,
-        is_some(&cmd),
+        is_some(&cmd) || explain,
     ]
     .iter()
     .filter(|&&x| x)
@@ -211,15 +228,27 @@ pub fn parse() -> Result<Command> {
                 env_vars.retain(|(k, _v)| k != "LD_PRELOAD" && k != "RUNNING_UNDER_RR");
             }
 
-            Ok(Command::Compile {
-                exe: exe.to_owned(),
-                cmdline,
-                cwd,
-                env_vars,
-            })
+            if explain {
+                Ok(Command::Explain {
+                    exe: exe.to_owned(),
+                    cmdline,
+                    cwd,
+                    env_vars,
+                    store: explain_store,
+                })
+            } else {
+                Ok(Command::Compile {
+                    exe: exe.to_owned(),
+                    cmdline,
+                    cwd,
+                    env_vars,
+                })
+            }
         } else {
             bail!("No compile command");
         }
+    } else if explain {
+        bail!("No compile command to explain");
     } else {
         bail!("No command specified");
     }
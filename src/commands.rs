@@ -18,7 +18,9 @@ use crate::compiler::ColorMode;
 use crate::config::Config;
 use crate::jobserver::Client;
 use crate::mock_command::{CommandChild, CommandCreatorSync, ProcessCommandCreator, RunCommand};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
+use crate::protocol::{
+    Compile, CompileFinished, CompileResponse, Explain, ExplainResponse, Request, Response,
+};
 use crate::server::{self, DistInfo, ServerInfo, ServerStartup};
 use crate::util::daemonize;
 use atty::Stream;
@@ -307,7 +309,7 @@ pub fn request_zero_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
         .request(Request::ZeroStats)
         .context("failed to send zero statistics command to server or failed to receive respone")?;
     if let Response::Stats(stats) = response {
-        Ok(*stats)
+        stats.into_info()
     } else {
         bail!("Unexpected server response!")
     }
@@ -320,7 +322,7 @@ pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
         .request(Request::GetStats)
         .context("Failed to send data to or receive data from server")?;
     if let Response::Stats(stats) = response {
-        Ok(*stats)
+        stats.into_info()
     } else {
         bail!("Unexpected server response!")
     }
@@ -347,7 +349,7 @@ pub fn request_shutdown(mut conn: ServerConnection) -> Result<ServerInfo> {
         .request(Request::Shutdown)
         .context("Failed to send data to or receive data from server")?;
     if let Response::ShuttingDown(stats) = response {
-        Ok(*stats)
+        stats.into_info()
     } else {
         bail!("Unexpected server response!")
     }
@@ -384,6 +386,36 @@ where
     }
 }
 
+/// Send an `Explain` request to the server, and return the server response if successful.
+fn request_explain(
+    mut conn: ServerConnection,
+    exe: &Path,
+    cmdline: Vec<OsString>,
+    cwd: &Path,
+    env_vars: Vec<(OsString, OsString)>,
+    store: bool,
+) -> Result<ExplainResponse> {
+    let exe_path = which_in(exe, env::var_os("PATH"), cwd)?;
+    let req = Request::Explain(Explain {
+        compile: Compile {
+            exe: exe_path.into(),
+            cwd: cwd.to_owned().into(),
+            args: cmdline,
+            env_vars,
+        },
+        store,
+    });
+    trace!("request_explain: {:?}", req);
+    let response = conn
+        .request(req)
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::Explain(response) = response {
+        Ok(response)
+    } else {
+        bail!("Unexpected response from server")
+    }
+}
+
 /// Return the signal that caused a process to exit from `status`.
 #[cfg(unix)]
 #[allow(dead_code)]
@@ -723,6 +755,36 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             );
             return res.context("failed to execute compile");
         }
+        Command::Explain {
+            exe,
+            cmdline,
+            cwd,
+            env_vars,
+            store,
+        } => {
+            trace!("Command::Explain {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let conn = connect_or_start_server(get_port())?;
+            match request_explain(conn, exe.as_ref(), cmdline, &cwd, env_vars, store)? {
+                ExplainResponse::Explained {
+                    explanation,
+                    stored,
+                } => {
+                    println!("{}", explanation);
+                    if let Some(key) = stored {
+                        println!("Explanation stored in the cache as {}", key);
+                    } else if store {
+                        println!("Failed to store the explanation in the cache");
+                    }
+                }
+                ExplainResponse::UnhandledCompile(reason) => {
+                    bail!("Can't explain this command, {}", reason)
+                }
+                ExplainResponse::UnsupportedCompiler(s) => {
+                    bail!("Compiler not supported: {:?}", s)
+                }
+                ExplainResponse::Failed(e) => bail!("Failed to compute the hash key: {}", e),
+            }
+        }
     }
 
     Ok(0)
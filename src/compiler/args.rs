@@ -15,7 +15,6 @@ pub type PathTransformerFn<'a> = &'a mut dyn FnMut(&Path) -> Option<String>;
 pub enum ArgParseError {
     UnexpectedEndOfArgs,
     InvalidUnicode(OsString),
-    Other(&'static str),
 }
 
 impl Display for ArgParseError {
@@ -23,7 +22,6 @@ impl Display for ArgParseError {
         let s = match self {
             ArgParseError::UnexpectedEndOfArgs => "Unexpected end of args".into(),
             ArgParseError::InvalidUnicode(s) => format!("String {:?} contained invalid unicode", s),
-            ArgParseError::Other(s) => format!("Arg-specific parsing failed: {}", s),
         };
         write!(f, "{}", s)
     }
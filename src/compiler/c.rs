@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::{Cache, CacheRead, CacheWrite, CacheWriteSkipped, Storage};
 use crate::compiler::{
     Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
     CompilerKind, HashResult,
@@ -22,7 +23,7 @@ use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::mock_command::CommandCreatorSync;
-use crate::util::{hash_all, Digest, HashToDigest};
+use crate::util::{base_dir_relative, hash_all, Digest, HashKeyDigest, HashToDigest, SpawnExt};
 use futures::Future;
 use futures_03::executor::ThreadPool;
 use std::borrow::Cow;
@@ -31,10 +32,11 @@ use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs;
 use std::hash::Hash;
-#[cfg(feature = "dist-client")]
 use std::io;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
 
 use crate::errors::*;
 
@@ -59,6 +61,8 @@ where
     executable: PathBuf,
     executable_digest: String,
     compiler: I,
+    /// Paths under this directory are hashed relative to the working directory.
+    base_dir: Option<PathBuf>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -145,6 +149,10 @@ struct CCompilation<I: CCompilerImpl> {
     compiler: I,
     cwd: PathBuf,
     env_vars: Vec<(OsString, OsString)>,
+    /// Whether the hash key was computed in direct mode.
+    direct_mode: bool,
+    /// The directory paths were hashed relative to, if any.
+    base_dir: Option<PathBuf>,
 }
 
 /// Supported C compilers.
@@ -168,11 +176,13 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
     fn kind(&self) -> CCompilerKind;
     /// Return true iff this is g++ or clang++.
     fn plusplus(&self) -> bool;
-    /// Determine whether `arguments` are supported by this compiler.
+    /// Determine whether `arguments` are supported by this compiler, making
+    /// paths under `base_dir` relative to `cwd` if it's set.
     fn parse_arguments(
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        base_dir: Option<&Path>,
     ) -> CompilerArguments<ParsedArguments>;
     /// Run the C preprocessor with the specified set of arguments.
     #[allow(clippy::too_many_arguments)]
@@ -245,13 +255,15 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        base_dir: Option<&Path>,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
-        match self.compiler.parse_arguments(arguments, cwd) {
+        match self.compiler.parse_arguments(arguments, cwd, base_dir) {
             CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(CCompilerHasher {
                 parsed_args: args,
                 executable: self.executable.clone(),
                 executable_digest: self.executable_digest.clone(),
                 compiler: self.compiler.clone(),
+                base_dir: base_dir.map(Path::to_owned),
             })),
             CompilerArguments::CannotCache(why, extra_info) => {
                 CompilerArguments::CannotCache(why, extra_info)
@@ -265,34 +277,273 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
     }
 }
 
-impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
+impl<I> CCompilerHasher<I>
 where
-    T: CommandCreatorSync,
     I: CCompilerImpl,
 {
-    fn generate_hash_key(
-        self: Box<Self>,
+    /// Whether this compilation can be looked up in direct mode.
+    fn can_use_direct_mode(&self) -> bool {
+        match self.compiler.kind() {
+            CCompilerKind::GCC | CCompilerKind::Clang => {}
+            _ => return false,
+        }
+        // The dependency file is cached along with the outputs in direct mode,
+        // so we need to know where the compiler is going to write it.
+        self.parsed_args.dependency_args.is_empty() || self.parsed_args.depfile.is_some()
+    }
+
+    /// Compute the key under which the direct mode manifest for this
+    /// compilation is stored.
+    fn manifest_key(
+        &self,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        extra_hashes: &[String],
+        input_digest: &str,
+    ) -> String {
+        let parsed_args = &self.parsed_args;
+        let mut m = Digest::new();
+        m.update(b"manifest");
+        m.update(CACHE_VERSION);
+        m.update(self.executable_digest.as_bytes());
+        m.update(&[self.compiler.plusplus() as u8]);
+        m.update(parsed_args.language.as_str().as_bytes());
+        for arg in parsed_args
+            .preprocessor_args
+            .iter()
+            .chain(&parsed_args.dependency_args)
+            .chain(&parsed_args.common_args)
+        {
+            arg.hash(&mut HashToDigest { digest: &mut m });
+        }
+        for hash in extra_hashes {
+            m.update(hash.as_bytes());
+        }
+        for (var, val) in env_vars {
+            if CACHED_ENV_VARS.contains(var.as_os_str())
+                || DIRECT_MODE_ENV_VARS.contains(var.as_os_str())
+            {
+                var.hash(&mut HashToDigest { digest: &mut m });
+                m.update(&b"="[..]);
+                val.hash(&mut HashToDigest { digest: &mut m });
+            }
+        }
+        // Relative include paths are resolved against the working directory
+        // and the directory of the input file.
+        cwd.hash(&mut HashToDigest { digest: &mut m });
+        parsed_args.input.hash(&mut HashToDigest { digest: &mut m });
+        m.update(input_digest.as_bytes());
+        m.finish()
+    }
+
+    /// Look up the compilation in direct mode: hash the source file and the
+    /// arguments, and if a stored manifest has an entry whose included files
+    /// are all unchanged, use the hash key recorded there without running the
+    /// preprocessor. Otherwise preprocess as usual and record the included
+    /// files in the manifest for next time.
+    ///
+    /// If `explain` is set, the preprocessor always runs so that the inputs
+    /// to the key can be recorded, and the manifests are left untouched.
+    #[allow(clippy::too_many_arguments)]
+    fn generate_direct_hash_key<T>(
+        self,
+        creator: &T,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        pool: &ThreadPool,
+        rewrite_includes_only: bool,
+        storage: Arc<dyn Storage>,
+        explain: bool,
+    ) -> SFuture<HashResult>
+    where
+        T: CommandCreatorSync,
+    {
+        let input_path = cwd.join(&self.parsed_args.input);
+        let input_digest = pool.spawn_fn(move || digest_source(&input_path));
+        let extra_hashes = hash_all(&self.parsed_args.extra_hash_files, pool);
+        let creator = creator.clone();
+        let pool = pool.clone();
+        let out_pretty = self.parsed_args.output_pretty().into_owned();
+
+        Box::new(
+            input_digest
+                .then(|res| Ok(res.ok()))
+                .join(extra_hashes)
+                .and_then(move |(input_digest, extra_hashes)| -> SFuture<HashResult> {
+                    let input_digest = match input_digest {
+                        Some((digest, false)) => digest,
+                        // Inputs we can't read are left for the preprocessor to
+                        // report.
+                        other => {
+                            if other.is_some() {
+                                debug!(
+                                    "[{}]: Input uses time macros, not using direct mode",
+                                    out_pretty
+                                );
+                            }
+                            return Box::new(
+                                self.preprocess_and_hash(
+                                    &creator,
+                                    cwd,
+                                    env_vars,
+                                    false,
+                                    &pool,
+                                    rewrite_includes_only,
+                                    false,
+                                    explain,
+                                )
+                                .map(|(result, _)| result),
+                            );
+                        }
+                    };
+                    if explain {
+                        // The key is computed the same way as on a manifest
+                        // miss, which is the key any manifest would record.
+                        return Box::new(
+                            self.preprocess_and_hash(
+                                &creator,
+                                cwd,
+                                env_vars,
+                                false,
+                                &pool,
+                                rewrite_includes_only,
+                                true,
+                                true,
+                            )
+                            .map(|(result, _)| result),
+                        );
+                    }
+                    let manifest_key =
+                        self.manifest_key(&cwd, &env_vars, &extra_hashes, &input_digest);
+                    trace!("[{}]: Manifest key: {}", out_pretty, manifest_key);
+                    let manifest = storage.get(&manifest_key).then(move |res| {
+                        let manifest = match res {
+                            Ok(Cache::Hit(mut entry)) => Manifest::from_entry(&mut entry)
+                                .unwrap_or_else(|e| {
+                                    debug!("Failed to read manifest: {}", e);
+                                    Manifest::default()
+                                }),
+                            Ok(_) => Manifest::default(),
+                            Err(e) => {
+                                debug!("Failed to look up manifest: {}", e);
+                                Manifest::default()
+                            }
+                        };
+                        Ok(manifest)
+                    });
+                    let lookup_pool = pool.clone();
+                    Box::new(
+                        manifest
+                            .and_then(move |manifest| {
+                                lookup_pool.spawn_fn(move || {
+                                    let key = manifest.find_key();
+                                    Ok((key, manifest))
+                                })
+                            })
+                            .and_then(move |(key, manifest)| -> SFuture<HashResult> {
+                                if let Some(key) = key {
+                                    debug!("[{}]: Direct mode manifest hit", out_pretty);
+                                    return f_ok(self.into_direct_hash_result(key, cwd, env_vars));
+                                }
+                                debug!("[{}]: Direct mode manifest miss", out_pretty);
+                                let result = self.preprocess_and_hash(
+                                    &creator,
+                                    cwd,
+                                    env_vars,
+                                    false,
+                                    &pool,
+                                    rewrite_includes_only,
+                                    true,
+                                    false,
+                                );
+                                Box::new(result.and_then(move |(result, includes)| {
+                                    record_manifest(
+                                        manifest,
+                                        manifest_key,
+                                        includes,
+                                        result.key.clone(),
+                                        storage,
+                                        &pool,
+                                    )
+                                    .map(move |_| result)
+                                }))
+                            }),
+                    )
+                }),
+        )
+    }
+
+    /// Build the hash result for a compilation whose key came from a direct
+    /// mode manifest.
+    fn into_direct_hash_result(
+        self,
+        key: String,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+    ) -> HashResult {
+        let CCompilerHasher {
+            mut parsed_args,
+            executable,
+            executable_digest,
+            compiler,
+            base_dir,
+        } = self;
+        if let Some(ref depfile) = parsed_args.depfile {
+            parsed_args.outputs.insert("d", depfile.clone());
+        }
+        let weak_toolchain_key = weak_toolchain_key(&executable, &executable_digest);
+        HashResult {
+            key,
+            compilation: Box::new(CCompilation {
+                parsed_args,
+                #[cfg(feature = "dist-client")]
+                preprocessed_input: vec![],
+                executable,
+                compiler,
+                cwd,
+                env_vars,
+                direct_mode: true,
+                base_dir,
+            }),
+            weak_toolchain_key,
+            explanation: vec![],
+        }
+    }
+
+    /// Run the preprocessor and compute the hash key from its output. In
+    /// direct mode, the preprocessor's line markers are kept and the files
+    /// they name are returned along with the result. If `explain` is set, the
+    /// inputs to the hash key are recorded in the result.
+    #[allow(clippy::too_many_arguments)]
+    fn preprocess_and_hash<T>(
+        self,
         creator: &T,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
         may_dist: bool,
         pool: &ThreadPool,
         rewrite_includes_only: bool,
-    ) -> SFuture<HashResult> {
-        let me = *self;
+        direct_mode: bool,
+        explain: bool,
+    ) -> SFuture<(HashResult, Vec<PathBuf>)>
+    where
+        T: CommandCreatorSync,
+    {
         let CCompilerHasher {
-            parsed_args,
+            mut parsed_args,
             executable,
             executable_digest,
             compiler,
-        } = me;
+            base_dir,
+        } = self;
+        // Direct mode needs the line markers to find out which files were included.
         let result = compiler.preprocess(
             creator,
             &executable,
             &parsed_args,
             &cwd,
             &env_vars,
-            may_dist,
+            may_dist || direct_mode,
             rewrite_includes_only,
         );
         let out_pretty = parsed_args.output_pretty().into_owned();
@@ -352,39 +603,119 @@ where
                     );
 
                     Box::new(extra_hashes.and_then(move |extra_hashes| {
-                        let key = {
-                            hash_key(
-                                &executable_digest,
-                                parsed_args.language,
-                                &parsed_args.common_args,
-                                &extra_hashes,
-                                &env_vars,
+                        let mut arguments = Cow::Borrowed(&parsed_args.common_args[..]);
+                        let mut includes = vec![];
+                        if direct_mode {
+                            // The dependency file is part of the cached outputs in
+                            // direct mode, so the arguments that shape it are part
+                            // of the key.
+                            arguments
+                                .to_mut()
+                                .extend(parsed_args.dependency_args.iter().cloned());
+                            includes = parse_line_markers(&preprocessor_result.stdout, &cwd);
+                        }
+                        // Line markers name files by the paths the preprocessor
+                        // found them at, which differ between checkouts.
+                        let preprocessed = match base_dir {
+                            Some(ref base_dir) => Cow::Owned(relativize_line_markers(
                                 &preprocessor_result.stdout,
-                                compiler.plusplus(),
-                            )
+                                base_dir,
+                                &cwd,
+                            )),
+                            None => Cow::Borrowed(&preprocessor_result.stdout[..]),
                         };
-                        // A compiler binary may be a symlink to another and so has the same digest, but that means
-                        // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
-                        // executable path to try and prevent this
+                        let mut m = HashKeyDigest::new(explain);
+                        hash_key_inputs(
+                            &mut m,
+                            &executable_digest,
+                            parsed_args.language,
+                            &arguments,
+                            &parsed_args.extra_hash_files,
+                            &extra_hashes,
+                            &env_vars,
+                            &preprocessed,
+                            compiler.plusplus(),
+                        );
+                        drop(preprocessed);
+                        let (key, explanation) = m.finish();
+                        drop(arguments);
+                        if direct_mode {
+                            if let Some(ref depfile) = parsed_args.depfile {
+                                parsed_args.outputs.insert("d", depfile.clone());
+                            }
+                        }
                         let weak_toolchain_key =
-                            format!("{}-{}", executable.to_string_lossy(), executable_digest);
-                        Ok(HashResult {
-                            key,
-                            compilation: Box::new(CCompilation {
-                                parsed_args,
-                                #[cfg(feature = "dist-client")]
-                                preprocessed_input: preprocessor_result.stdout,
-                                executable,
-                                compiler,
-                                cwd,
-                                env_vars,
-                            }),
-                            weak_toolchain_key,
-                        })
+                            weak_toolchain_key(&executable, &executable_digest);
+                        Ok((
+                            HashResult {
+                                key,
+                                compilation: Box::new(CCompilation {
+                                    parsed_args,
+                                    #[cfg(feature = "dist-client")]
+                                    preprocessed_input: preprocessor_result.stdout,
+                                    executable,
+                                    compiler,
+                                    cwd,
+                                    env_vars,
+                                    direct_mode,
+                                    base_dir,
+                                }),
+                                weak_toolchain_key,
+                                explanation,
+                            },
+                            includes,
+                        ))
                     }))
                 }),
         )
     }
+}
+
+impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
+where
+    T: CommandCreatorSync,
+    I: CCompilerImpl,
+{
+    #[allow(clippy::too_many_arguments)]
+    fn generate_hash_key(
+        self: Box<Self>,
+        creator: &T,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        may_dist: bool,
+        pool: &ThreadPool,
+        rewrite_includes_only: bool,
+        storage: Arc<dyn Storage>,
+        explain: bool,
+    ) -> SFuture<HashResult> {
+        let me = *self;
+        // Distributed compilation needs the preprocessed source, so there's
+        // nothing to gain from direct mode.
+        if !may_dist && direct_mode_enabled(&env_vars) && me.can_use_direct_mode() {
+            return me.generate_direct_hash_key(
+                creator,
+                cwd,
+                env_vars,
+                pool,
+                rewrite_includes_only,
+                storage,
+                explain,
+            );
+        }
+        Box::new(
+            me.preprocess_and_hash(
+                creator,
+                cwd,
+                env_vars,
+                may_dist,
+                pool,
+                rewrite_includes_only,
+                false,
+                explain,
+            )
+            .map(|(result, _)| result),
+        )
+    }
 
     fn color_mode(&self) -> ColorMode {
         self.parsed_args.color_mode
@@ -411,16 +742,25 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
             ref compiler,
             ref cwd,
             ref env_vars,
+            direct_mode,
             ..
         } = *self;
-        compiler.generate_compile_commands(
+        let (mut command, dist_command, cacheable) = compiler.generate_compile_commands(
             path_transformer,
             executable,
             parsed_args,
             cwd,
             env_vars,
             rewrite_includes_only,
-        )
+        )?;
+        // In direct mode the preprocessor may not have run, so the compiler
+        // has to write the dependency file itself.
+        if direct_mode && parsed_args.depfile.is_some() {
+            command
+                .arguments
+                .extend(parsed_args.dependency_args.iter().cloned());
+        }
+        Ok((command, dist_command, cacheable))
     }
 
     #[cfg(feature = "dist-client")]
@@ -456,6 +796,17 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
         Box::new(self.parsed_args.outputs.iter().map(|(k, v)| (*k, &**v)))
     }
+
+    fn relative_depfiles(&self) -> Option<(PathBuf, Vec<PathBuf>)> {
+        // The dependency file is only cached in direct mode.
+        let base_dir = self.base_dir.clone()?;
+        let depfile = self
+            .parsed_args
+            .depfile
+            .clone()
+            .filter(|_| self.direct_mode)?;
+        Some((base_dir, vec![depfile]))
+    }
 }
 
 #[cfg(feature = "dist-client")]
@@ -496,7 +847,7 @@ impl pkg::InputsPackager for CInputsPackager {
             if !super::CAN_DIST_DYLIBS
                 && input_path
                     .extension()
-                    .map_or(false, |ext| ext == std::env::consts::DLL_EXTENSION)
+                    .is_some_and(|ext| ext == std::env::consts::DLL_EXTENSION)
             {
                 bail!(
                     "Cannot distribute dylib input {} on this platform",
@@ -646,9 +997,20 @@ lazy_static! {
         "MACOSX_DEPLOYMENT_TARGET",
         "IPHONEOS_DEPLOYMENT_TARGET",
     ].iter().map(OsStr::new).collect();
+
+    /// Environment variables that affect where included files are found,
+    /// factored into direct mode manifest keys.
+    static ref DIRECT_MODE_ENV_VARS: HashSet<&'static OsStr> = [
+        "CPATH",
+        "C_INCLUDE_PATH",
+        "CPLUS_INCLUDE_PATH",
+        "OBJC_INCLUDE_PATH",
+        "OBJCPLUS_INCLUDE_PATH",
+    ].iter().map(OsStr::new).collect();
 }
 
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
+#[cfg(test)]
 pub fn hash_key(
     compiler_digest: &str,
     language: Language,
@@ -658,36 +1020,371 @@ pub fn hash_key(
     preprocessor_output: &[u8],
     plusplus: bool,
 ) -> String {
+    let mut m = HashKeyDigest::new(false);
+    hash_key_inputs(
+        &mut m,
+        compiler_digest,
+        language,
+        arguments,
+        &[],
+        extra_hashes,
+        env_vars,
+        preprocessor_output,
+        plusplus,
+    );
+    m.finish().0
+}
+
+/// Add the inputs of `hash_key` to `m`. `extra_hash_files` are the files
+/// `extra_hashes` were computed from, used to describe them when the key is
+/// being explained.
+#[allow(clippy::too_many_arguments)]
+fn hash_key_inputs(
+    m: &mut HashKeyDigest,
+    compiler_digest: &str,
+    language: Language,
+    arguments: &[OsString],
+    extra_hash_files: &[PathBuf],
+    extra_hashes: &[String],
+    env_vars: &[(OsString, OsString)],
+    preprocessor_output: &[u8],
+    plusplus: bool,
+) {
     // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
-    let mut m = Digest::new();
-    m.update(compiler_digest.as_bytes());
+    m.input(
+        || "compiler".to_owned(),
+        |m| m.update(compiler_digest.as_bytes()),
+    );
     // clang and clang++ have different behavior despite being byte-for-byte identical binaries, so
     // we have to incorporate that into the hash as well.
-    m.update(&[plusplus as u8]);
-    m.update(CACHE_VERSION);
-    m.update(language.as_str().as_bytes());
+    m.input(
+        || format!("plusplus {}", plusplus),
+        |m| m.update(&[plusplus as u8]),
+    );
+    m.input(|| "cache version".to_owned(), |m| m.update(CACHE_VERSION));
+    m.input(
+        || format!("language {}", language.as_str()),
+        |m| m.update(language.as_str().as_bytes()),
+    );
     for arg in arguments {
-        arg.hash(&mut HashToDigest { digest: &mut m });
+        m.input(
+            || format!("argument {}", arg.to_string_lossy()),
+            |m| arg.hash(&mut HashToDigest { digest: m }),
+        );
     }
-    for hash in extra_hashes {
-        m.update(hash.as_bytes());
+    for (i, hash) in extra_hashes.iter().enumerate() {
+        m.input(
+            || match extra_hash_files.get(i) {
+                Some(path) => format!("extra file {}", path.display()),
+                None => "extra file".to_owned(),
+            },
+            |m| m.update(hash.as_bytes()),
+        );
     }
 
-    for &(ref var, ref val) in env_vars.iter() {
+    for (var, val) in env_vars {
         if CACHED_ENV_VARS.contains(var.as_os_str()) {
-            var.hash(&mut HashToDigest { digest: &mut m });
-            m.update(&b"="[..]);
-            val.hash(&mut HashToDigest { digest: &mut m });
+            m.input(
+                || {
+                    format!(
+                        "env var {}={}",
+                        var.to_string_lossy(),
+                        val.to_string_lossy()
+                    )
+                },
+                |m| {
+                    var.hash(&mut HashToDigest { digest: m });
+                    m.update(&b"="[..]);
+                    val.hash(&mut HashToDigest { digest: m });
+                },
+            );
         }
     }
-    m.update(preprocessor_output);
-    m.finish()
+    m.input(
+        || "preprocessor output".to_owned(),
+        |m| m.update(preprocessor_output),
+    );
+}
+
+/// The maximum number of entries kept in a direct mode manifest.
+const MAX_MANIFEST_ENTRIES: usize = 100;
+
+/// The name of the object holding the manifest in its cache entry.
+const MANIFEST_OBJECT: &str = "manifest";
+
+/// Return true if direct mode was requested for this compilation.
+fn direct_mode_enabled(env_vars: &[(OsString, OsString)]) -> bool {
+    env_vars
+        .iter()
+        .any(|(k, v)| k.as_os_str() == OsStr::new("SCCACHE_DIRECT") && v != "0" && v != "false")
+}
+
+fn weak_toolchain_key(executable: &Path, executable_digest: &str) -> String {
+    // A compiler binary may be a symlink to another and so has the same digest, but that means
+    // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
+    // executable path to try and prevent this
+    format!("{}-{}", executable.to_string_lossy(), executable_digest)
+}
+
+/// A direct mode manifest: the sets of files included by previous
+/// compilations of a source file, and the hash keys they resulted in.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    /// The included files, with the digests of their contents.
+    includes: Vec<(PathBuf, String)>,
+    /// The hash key of the compilation.
+    key: String,
+}
+
+impl Manifest {
+    fn from_entry(entry: &mut CacheRead) -> Result<Manifest> {
+        let mut bytes = vec![];
+        entry.get_object(MANIFEST_OBJECT, &mut bytes)?;
+        bincode::deserialize(&bytes).context("Failed to deserialize manifest")
+    }
+
+    fn to_entry(&self) -> Result<CacheWrite> {
+        let bytes = bincode::serialize(self).context("Failed to serialize manifest")?;
+        let mut entry = CacheWrite::new()?;
+        entry.put_object(MANIFEST_OBJECT, &mut io::Cursor::new(bytes), None)?;
+        Ok(entry)
+    }
+
+    /// Return the key of the most recent entry whose included files are all
+    /// unchanged.
+    fn find_key(&self) -> Option<String> {
+        let mut digests: HashMap<&Path, Option<String>> = HashMap::new();
+        self.entries
+            .iter()
+            .find(|entry| {
+                entry.includes.iter().all(|(path, digest)| {
+                    let current = digests
+                        .entry(path)
+                        .or_insert_with(|| digest_source(path).ok().map(|(d, _)| d));
+                    current.as_ref() == Some(digest)
+                })
+            })
+            .map(|entry| entry.key.clone())
+    }
+}
+
+/// Add an entry mapping the files in `includes` to `key` to `manifest`, and
+/// store it under `manifest_key`, unless `storage` can't be written to.
+/// Failures are only logged, since all they cost is a later direct mode miss.
+fn record_manifest(
+    mut manifest: Manifest,
+    manifest_key: String,
+    includes: Vec<PathBuf>,
+    key: String,
+    storage: Arc<dyn Storage>,
+    pool: &ThreadPool,
+) -> SFuture<()> {
+    if !storage.can_write() {
+        return f_ok(());
+    }
+    let entry = pool.spawn_fn(move || -> Result<_> {
+        let mut digests = Vec::with_capacity(includes.len());
+        for path in includes {
+            let (digest, uses_time_macros) = digest_source(&path)?;
+            if uses_time_macros {
+                debug!(
+                    "{} uses time macros, not recording manifest",
+                    path.display()
+                );
+                return Ok(None);
+            }
+            digests.push((path, digest));
+        }
+        manifest.entries.insert(
+            0,
+            ManifestEntry {
+                includes: digests,
+                key,
+            },
+        );
+        manifest.entries.truncate(MAX_MANIFEST_ENTRIES);
+        manifest.to_entry().map(Some)
+    });
+    Box::new(
+        entry
+            .and_then(move |entry| -> SFuture<()> {
+                match entry {
+                    Some(entry) => Box::new(storage.put(&manifest_key, entry).map(|_| ())),
+                    None => f_ok(()),
+                }
+            })
+            .or_else(|e| {
+                if e.downcast_ref::<CacheWriteSkipped>().is_some() {
+                    debug!("Not recording direct mode manifest: {}", e);
+                } else {
+                    warn!("Failed to record direct mode manifest: {}", e);
+                }
+                Ok(())
+            }),
+    )
+}
+
+/// If `line` is a line marker, return the file name it contains, and the
+/// range of `line` the quoted name occupies.
+fn line_marker_name(line: &[u8]) -> Option<(Vec<u8>, Range<usize>)> {
+    let rest = if line.starts_with(b"# ") {
+        &line[2..]
+    } else if line.starts_with(b"#line ") {
+        &line[6..]
+    } else {
+        return None;
+    };
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 || !rest[digits..].starts_with(b" \"") {
+        return None;
+    }
+    let start = line.len() - rest.len() + digits + 1;
+    let mut name = vec![];
+    let mut escaped = false;
+    let mut end = line.len();
+    for (i, &b) in line.iter().enumerate().skip(start + 1) {
+        match b {
+            _ if escaped => {
+                name.push(b);
+                escaped = false;
+            }
+            b'\\' => escaped = true,
+            b'"' => {
+                end = i + 1;
+                break;
+            }
+            _ => name.push(b),
+        }
+    }
+    Some((name, start..end))
+}
+
+/// Return the files named by the line markers in `preprocessed`, resolved
+/// against `cwd`. Pseudo-files such as `<built-in>` are skipped.
+fn parse_line_markers(preprocessed: &[u8], cwd: &Path) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+    for line in preprocessed.split(|&b| b == b'\n') {
+        let name = match line_marker_name(line) {
+            Some((name, _)) => name,
+            None => continue,
+        };
+        if name.is_empty() || name[0] == b'<' {
+            continue;
+        }
+        if seen.insert(name.clone()) {
+            files.push(cwd.join(&*String::from_utf8_lossy(&name)));
+        }
+    }
+    files
+}
+
+/// Rewrite the files named by the line markers in `preprocessed` that are
+/// under `base_dir` to be relative to `cwd`.
+fn relativize_line_markers(preprocessed: &[u8], base_dir: &Path, cwd: &Path) -> Vec<u8> {
+    let mut out = Vec::with_capacity(preprocessed.len());
+    for (i, line) in preprocessed.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            out.push(b'\n');
+        }
+        let relative = line_marker_name(line).and_then(|(name, range)| {
+            let name = String::from_utf8(name).ok()?;
+            let relative = base_dir_relative(Path::new(&name), base_dir, cwd)?;
+            Some((relative, range))
+        });
+        match relative {
+            Some((relative, range)) => {
+                out.extend_from_slice(&line[..range.start]);
+                out.push(b'"');
+                for b in relative.to_string_lossy().bytes() {
+                    if b == b'"' || b == b'\\' {
+                        out.push(b'\\');
+                    }
+                    out.push(b);
+                }
+                out.push(b'"');
+                out.extend_from_slice(&line[range.end..]);
+            }
+            None => out.extend_from_slice(line),
+        }
+    }
+    out
+}
+
+/// Compute the digest of the source file at `path`, and whether it uses any
+/// of the time macros that make it unsuitable for direct mode.
+fn digest_source(path: &Path) -> Result<(String, bool)> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut m = Digest::new();
+    m.update(&bytes);
+    Ok((m.finish(), uses_time_macros(&bytes)))
+}
+
+fn uses_time_macros(bytes: &[u8]) -> bool {
+    const TIME_MACROS: [&[u8]; 3] = [b"__DATE__", b"__TIME__", b"__TIMESTAMP__"];
+    bytes
+        .windows(2)
+        .enumerate()
+        .any(|(i, w)| w == b"__" && TIME_MACROS.iter().any(|m| bytes[i..].starts_with(m)))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    #[cfg(unix)]
+    fn test_parse_line_markers() {
+        let cwd = Path::new("/build");
+        let preprocessed = b"# 1 \"foo.c\"\n\
+                             # 1 \"<built-in>\"\n\
+                             # 1 \"/usr/include/stdio.h\" 1 3 4\n\
+                             int x;\n\
+                             # 12 \"foo.c\" 2\n\
+                             #line 3 \"dir with \\\"quotes\\\".h\"\n\
+                             # pragma once\n";
+        assert_eq!(
+            vec![
+                PathBuf::from("/build/foo.c"),
+                PathBuf::from("/usr/include/stdio.h"),
+                PathBuf::from("/build/dir with \"quotes\".h"),
+            ],
+            parse_line_markers(preprocessed, cwd)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_relativize_line_markers() {
+        let base_dir = Path::new("/ws");
+        let cwd = Path::new("/ws/a/build");
+        let preprocessed = b"# 1 \"/ws/a/src/foo.c\"\n\
+                             # 1 \"<built-in>\"\n\
+                             # 1 \"/usr/include/stdio.h\" 1 3 4\n\
+                             const char *f = \"/ws/a/src/foo.c\";\n\
+                             # 2 \"/ws/a/src/with \\\"quotes\\\".h\" 2\n";
+        assert_eq!(
+            &b"# 1 \"../src/foo.c\"\n\
+               # 1 \"<built-in>\"\n\
+               # 1 \"/usr/include/stdio.h\" 1 3 4\n\
+               const char *f = \"/ws/a/src/foo.c\";\n\
+               # 2 \"../src/with \\\"quotes\\\".h\" 2\n"[..],
+            &relativize_line_markers(preprocessed, base_dir, cwd)[..]
+        );
+    }
+
+    #[test]
+    fn test_uses_time_macros() {
+        assert!(uses_time_macros(b"const char *d = __DATE__;"));
+        assert!(uses_time_macros(b"__TIMESTAMP__"));
+        assert!(!uses_time_macros(b"int __DATE_x; int _TIME__;"));
+    }
+
     #[test]
     fn test_same_content() {
         let args = ovec!["a", "b", "c"];
@@ -698,6 +1395,63 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_hash_key_explained() {
+        let args = ovec!["a", "b"];
+        let env_vars = vec![
+            (
+                OsString::from("MACOSX_DEPLOYMENT_TARGET"),
+                OsString::from("10.9"),
+            ),
+            (OsString::from("FOO"), OsString::from("bar")),
+        ];
+        let extra_hashes = vec!["1234".to_owned()];
+        const PREPROCESSED: &[u8] = b"hello world";
+        let mut m = HashKeyDigest::new(true);
+        hash_key_inputs(
+            &mut m,
+            "abcd",
+            Language::C,
+            &args,
+            &[PathBuf::from("extra.txt")],
+            &extra_hashes,
+            &env_vars,
+            PREPROCESSED,
+            false,
+        );
+        let (key, inputs) = m.finish();
+        assert_eq!(
+            key,
+            hash_key(
+                "abcd",
+                Language::C,
+                &args,
+                &extra_hashes,
+                &env_vars,
+                PREPROCESSED,
+                false
+            )
+        );
+        let names = inputs.iter().map(|i| i.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "compiler",
+                "plusplus false",
+                "cache version",
+                "language c",
+                "argument a",
+                "argument b",
+                "extra file extra.txt",
+                "env var MACOSX_DEPLOYMENT_TARGET=10.9",
+                "preprocessor output",
+            ]
+        );
+        let mut d = Digest::new();
+        d.update(PREPROCESSED);
+        assert_eq!(inputs[8].digest, d.finish());
+    }
+
     #[test]
     fn test_plusplus_differs() {
         let args = ovec!["a", "b", "c"];
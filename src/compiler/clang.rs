@@ -48,10 +48,12 @@ impl CCompilerImpl for Clang {
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        base_dir: Option<&Path>,
     ) -> CompilerArguments<ParsedArguments> {
         gcc::parse_arguments(
             arguments,
             cwd,
+            base_dir,
             (&gcc::ARGS[..], &ARGS[..]),
             self.clangplusplus,
         )
@@ -118,7 +120,9 @@ counted_array!(pub static ARGS: [ArgInfo<gcc::ArgData>; _] = [
     take_arg!("-fplugin", PathBuf, CanBeConcatenated('='), ExtraHashFile),
     flag!("-fprofile-instr-generate", ProfileGenerate),
     // Can be either -fprofile-instr-use or -fprofile-instr-use=path
-    take_arg!("-fprofile-instr-use", OsString, Concatenated, TooHard),
+    take_arg!("-fprofile-instr-use", PathBuf, Concatenated('='), ClangProfileUse),
+    // Clang's -fprofile-use reads the same profile data as -fprofile-instr-use
+    take_arg!("-fprofile-use", PathBuf, Concatenated('='), ClangProfileUse),
     take_arg!("-fsanitize-blacklist", PathBuf, Concatenated('='), ExtraHashFile),
     take_arg!("-gcc-toolchain", OsString, Separated, PassThrough),
     take_arg!("-include-pch", PathBuf, CanBeSeparated, PreprocessorArgumentPath),
@@ -145,7 +149,7 @@ mod test {
         Clang {
             clangplusplus: false,
         }
-        .parse_arguments(&arguments, &std::env::current_dir().unwrap())
+        .parse_arguments(&arguments, &std::env::current_dir().unwrap(), None)
     }
 
     macro_rules! parses {
@@ -392,6 +396,36 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_fprofile_use() {
+        let f = TestFixture::new();
+        f.touch("foo.profdata").unwrap();
+        f.touch("default.profdata").unwrap();
+        let profdata = f.tempdir.path().join("foo.profdata");
+        let default = f.tempdir.path().join("default.profdata");
+        for flag in &["-fprofile-use", "-fprofile-instr-use"] {
+            // Either the profile data file...
+            let arg = format!("{}={}", flag, profdata.display());
+            let a = parses!("-c", "foo.c", "-o", "foo.o", arg);
+            assert_eq!(ovec![arg], a.common_args);
+            assert_eq!(vec![profdata.clone()], a.extra_hash_files);
+            // ...or a directory containing default.profdata.
+            let arg = format!("{}={}", flag, f.tempdir.path().display());
+            let a = parses!("-c", "foo.c", "-o", "foo.o", arg);
+            assert_eq!(ovec![arg], a.common_args);
+            assert_eq!(vec![default.clone()], a.extra_hash_files);
+        }
+        // Missing profile data can't be hashed.
+        let arg = format!(
+            "-fprofile-instr-use={}",
+            f.tempdir.path().join("missing").display()
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-fprofile-instr-use", None),
+            parse_arguments_(vec![arg])
+        );
+    }
+
     #[test]
     fn test_parse_color_diags() {
         let a = parses!("-c", "foo.c", "-o", "foo.o", "-fcolor-diagnostics");
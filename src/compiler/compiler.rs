@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheWrite, DecompressionFailure, Storage};
+use crate::cache::{
+    Cache, CacheTier, CacheWrite, CacheWriteSkipped, ChecksumMismatch, DecompressionFailure,
+    Storage,
+};
 use crate::compiler::c::{CCompiler, CCompilerKind};
 use crate::compiler::clang::Clang;
 use crate::compiler::diab::Diab;
@@ -26,7 +29,10 @@ use crate::dist;
 use crate::dist::pkg;
 use crate::lru_disk_cache;
 use crate::mock_command::{exit_status, CommandChild, CommandCreatorSync, RunCommand};
-use crate::util::{fmt_duration_as_secs, ref_env, run_input_output, SpawnExt};
+use crate::util::{
+    fmt_duration_as_secs, ref_env, relativize_depfile, restore_depfile, run_input_output,
+    HashInput, SpawnExt,
+};
 use filetime::FileTime;
 use futures::Future;
 use futures_03::executor::ThreadPool;
@@ -34,9 +40,9 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fmt;
-#[cfg(feature = "dist-client")]
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
@@ -122,10 +128,15 @@ where
     #[cfg(feature = "dist-client")]
     fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager>;
     /// Determine whether `arguments` are supported by this compiler.
+    ///
+    /// If `base_dir` is set, paths under it are made relative to `cwd` where
+    /// the compiler supports it, so that the compilation hashes the same in
+    /// every checkout under `base_dir`.
     fn parse_arguments(
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        base_dir: Option<&Path>,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>>;
     fn box_clone(&self) -> Box<dyn Compiler<T>>;
 }
@@ -165,6 +176,13 @@ where
     /// Given information about a compiler command, generate a hash key
     /// that can be used for cache lookups, as well as any additional
     /// information that can be reused for compilation if necessary.
+    ///
+    /// `storage` is available to hashers that keep auxiliary data, such as
+    /// direct mode manifests, in the cache.
+    ///
+    /// If `explain` is set, the inputs to the hash key are recorded in the
+    /// result's `explanation`, and the cache is left untouched.
+    #[allow(clippy::too_many_arguments)]
     fn generate_hash_key(
         self: Box<Self>,
         creator: &T,
@@ -173,8 +191,43 @@ where
         may_dist: bool,
         pool: &ThreadPool,
         rewrite_includes_only: bool,
+        storage: Arc<dyn Storage>,
+        explain: bool,
     ) -> SFuture<HashResult>;
 
+    /// Generate the hash key for this compiler command as
+    /// `get_cached_or_compile` would, without compiling, and return it along
+    /// with the inputs it was computed from.
+    fn explain_hash_key(
+        self: Box<Self>,
+        dist_client: Result<Option<Arc<dyn dist::Client>>>,
+        creator: T,
+        storage: Arc<dyn Storage>,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        pool: ThreadPool,
+    ) -> SFuture<Explanation> {
+        let may_dist = matches!(dist_client, Ok(Some(_)));
+        let rewrite_includes_only = match dist_client {
+            Ok(Some(ref client)) => client.rewrite_includes_only(),
+            _ => false,
+        };
+        let result = self.generate_hash_key(
+            &creator,
+            cwd,
+            env_vars,
+            may_dist,
+            &pool,
+            rewrite_includes_only,
+            storage,
+            true,
+        );
+        Box::new(result.map(|res| Explanation {
+            key: res.key,
+            inputs: res.explanation,
+        }))
+    }
+
     /// Return the state of any `--color` option passed to the compiler.
     fn color_mode(&self) -> ColorMode;
 
@@ -207,6 +260,8 @@ where
             may_dist,
             &pool,
             rewrite_includes_only,
+            storage.clone(),
+            false,
         );
         Box::new(result.then(move |res| -> SFuture<_> {
             debug!(
@@ -225,6 +280,7 @@ where
                     key,
                     compilation,
                     weak_toolchain_key,
+                    ..
                 }) => (key, compilation, weak_toolchain_key),
             };
             trace!("[{}]: Hash key: {}", out_pretty, key);
@@ -249,6 +305,7 @@ where
                     .outputs()
                     .map(|(key, path)| (key.to_string(), cwd.join(path)))
                     .collect::<HashMap<_, _>>();
+                let relative_depfiles = compilation.relative_depfiles();
 
                 let miss_type = Box::new(match result {
                     Ok(Cache::Hit(mut entry)) => {
@@ -257,23 +314,70 @@ where
                             out_pretty,
                             fmt_duration_as_secs(&duration)
                         );
-                        let stdout = entry.get_stdout();
-                        let stderr = entry.get_stderr();
-                        let write = entry.extract_objects(outputs.clone(), &pool);
-                        let output = process::Output {
-                            status: exit_status(0),
-                            stdout,
-                            stderr,
+                        let tier = entry.tier();
+                        let output = entry.get_stdout().and_then(|stdout| {
+                            Ok(process::Output {
+                                status: exit_status(0),
+                                stdout,
+                                stderr: entry.get_stderr()?,
+                            })
+                        });
+                        let write: SFuture<process::Output> = match output {
+                            Ok(output) => Box::new(
+                                entry
+                                    .extract_objects(outputs.clone(), &pool)
+                                    .map(move |()| output),
+                            ),
+                            Err(e) => f_err(e),
+                        };
+                        // Put the paths made relative in dependency files under
+                        // this compilation's working directory.
+                        let write = match relative_depfiles.clone() {
+                            Some((_, paths)) => {
+                                let cwd = cwd.clone();
+                                let pool = pool.clone();
+                                Box::new(write.and_then(move |output| {
+                                    pool.spawn_fn(move || {
+                                        for path in paths {
+                                            restore_depfile(&cwd.join(path), &cwd)?;
+                                        }
+                                        Ok(output)
+                                    })
+                                }))
+                            }
+                            None => write,
                         };
-                        let hit = CompileResult::CacheHit(duration);
-                        Box::new(write.then(move |result| match result {
-                            Ok(()) => f_ok(CacheLookupResult::Success(hit, output)),
-                            Err(e) => {
-                                if e.downcast_ref::<DecompressionFailure>().is_some() {
-                                    debug!("[{}]: Failed to decompress object", out_pretty);
-                                    f_ok(CacheLookupResult::Miss(MissType::CacheReadError))
-                                } else {
-                                    f_err(e)
+                        let hit = CompileResult::CacheHit(duration, tier);
+                        let storage = storage.clone();
+                        let key = key.clone();
+                        Box::new(write.then(move |result| -> SFuture<_> {
+                            match result {
+                                Ok(output) => f_ok(CacheLookupResult::Success(hit, output)),
+                                Err(e) => {
+                                    if e.downcast_ref::<ChecksumMismatch>().is_some() {
+                                        // Remove the entry so that later lookups don't hit it
+                                        // again before the local build overwrites it.
+                                        warn!(
+                                            "[{}]: Removing corrupt cache entry: {}",
+                                            out_pretty, e
+                                        );
+                                        Box::new(storage.remove(&key).then(move |res| {
+                                            if let Err(e) = res {
+                                                warn!(
+                                                    "[{}]: Failed to remove entry: {}",
+                                                    out_pretty, e
+                                                );
+                                            }
+                                            Ok::<_, Error>(CacheLookupResult::Miss(
+                                                MissType::CorruptEntry,
+                                            ))
+                                        }))
+                                    } else if e.downcast_ref::<DecompressionFailure>().is_some() {
+                                        debug!("[{}]: Failed to decompress object", out_pretty);
+                                        f_ok(CacheLookupResult::Miss(MissType::CacheReadError))
+                                    } else {
+                                        f_err(e)
+                                    }
                                 }
                             }
                         }))
@@ -326,7 +430,7 @@ where
                             let compile = dist_or_local_compile(
                                 dist_client,
                                 creator,
-                                cwd,
+                                cwd.clone(),
                                 compilation,
                                 weak_toolchain_key,
                                 out_pretty2.clone(),
@@ -351,19 +455,86 @@ where
                                             compiler_result,
                                         ));
                                     }
+                                    if !storage.can_write() {
+                                        // Don't bother building an entry that can't be stored.
+                                        debug!(
+                                            "[{}]: Compiled in {}, cache is read-only",
+                                            out_pretty2,
+                                            fmt_duration_as_secs(&duration)
+                                        );
+                                        return f_ok((
+                                            CompileResult::CacheMiss(
+                                                miss_type,
+                                                dist_type,
+                                                duration,
+                                                f_err(CacheWriteSkipped),
+                                            ),
+                                            compiler_result,
+                                        ));
+                                    }
+                                    // Dependency files are stored with the paths under
+                                    // the base directory made relative, and the local
+                                    // ones are left as they are.
+                                    let mut outputs = outputs;
+                                    let mut depfiles = vec![];
+                                    if let Some((base_dir, paths)) = relative_depfiles {
+                                        for path in paths {
+                                            let path = cwd.join(path);
+                                            let name = match outputs
+                                                .iter()
+                                                .find(|&(_, p)| *p == path)
+                                            {
+                                                Some((name, _)) => name.clone(),
+                                                None => continue,
+                                            };
+                                            match fs::read_to_string(&path) {
+                                                Ok(contents) => {
+                                                    outputs.remove(&name);
+                                                    let contents =
+                                                        relativize_depfile(&contents, &base_dir, &cwd);
+                                                    depfiles.push((name, contents));
+                                                }
+                                                Err(e) => {
+                                                    warn!(
+                                                        "[{}]: Failed to read dependency file {}: {}",
+                                                        out_pretty2,
+                                                        path.display(),
+                                                        e
+                                                    );
+                                                    return f_ok((
+                                                        CompileResult::NotCacheable,
+                                                        compiler_result,
+                                                    ));
+                                                }
+                                            }
+                                        }
+                                    }
                                     debug!(
                                         "[{}]: Compiled in {}, storing in cache",
                                         out_pretty2,
                                         fmt_duration_as_secs(&duration)
                                     );
-                                    let write = CacheWrite::from_objects(outputs, &pool);
+                                    let write = CacheWrite::from_objects(
+                                        outputs,
+                                        storage.compression(),
+                                        &pool,
+                                    );
                                     let write = write.fcontext("failed to zip up compiler outputs");
                                     let o = out_pretty2.clone();
                                     Box::new(
                                         write
                                             .and_then(move |mut entry| {
+                                                for (name, contents) in depfiles {
+                                                    entry.put_object(
+                                                        &name,
+                                                        &mut io::Cursor::new(contents),
+                                                        None,
+                                                    )?;
+                                                }
                                                 entry.put_stdout(&compiler_result.stdout)?;
                                                 entry.put_stderr(&compiler_result.stderr)?;
+                                                let (uncompressed_size, compressed_size) =
+                                                    entry.object_sizes();
 
                                                 // Try to finish storing the newly-written cache
                                                 // entry. We'll get the result back elsewhere.
@@ -382,6 +553,8 @@ where
                                                         res.map(|duration| CacheWriteInfo {
                                                             object_file_pretty: out_pretty2,
                                                             duration,
+                                                            uncompressed_size,
+                                                            compressed_size,
                                                         })
                                                     });
                                                 let future = Box::new(future);
@@ -649,6 +822,15 @@ pub trait Compilation {
     /// Each item is a descriptive (and unique) name of the output paired with
     /// the path where it'll show up.
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a>;
+
+    /// If this compilation was hashed independently of the directory it runs
+    /// in, returns the base directory that paths were hashed relative to, and
+    /// any dependency files among its outputs. Paths under the base directory
+    /// in those files are made relative before they're stored in the cache,
+    /// and made absolute again in the current checkout when they're restored.
+    fn relative_depfiles(&self) -> Option<(PathBuf, Vec<PathBuf>)> {
+        None
+    }
 }
 
 #[cfg(feature = "dist-client")]
@@ -684,6 +866,27 @@ pub struct HashResult {
     pub compilation: Box<dyn Compilation + 'static>,
     /// A weak key that may be used to identify the toolchain
     pub weak_toolchain_key: String,
+    /// The inputs to the hash key, if they were asked for.
+    pub explanation: Vec<HashInput>,
+}
+
+/// A hash key and the inputs it was computed from, as shown by
+/// `sccache --explain`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// The hash key.
+    pub key: String,
+    /// Each input to the hash key, in the order they were hashed.
+    pub inputs: Vec<HashInput>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for input in &self.inputs {
+            writeln!(f, "{} {}", input.digest, input.name)?;
+        }
+        write!(f, "{} hash key", self.key)
+    }
 }
 
 /// Possible results of parsing compiler arguments.
@@ -737,20 +940,26 @@ pub enum MissType {
     TimedOut,
     /// Error reading from cache
     CacheReadError,
+    /// The cache entry didn't match its checksums, and was removed
+    CorruptEntry,
 }
 
 /// Information about a successful cache write.
 pub struct CacheWriteInfo {
     pub object_file_pretty: String,
     pub duration: Duration,
+    /// The size of the entry's objects, before compression.
+    pub uncompressed_size: u64,
+    /// The size of the entry's objects, after compression.
+    pub compressed_size: u64,
 }
 
 /// The result of a compilation or cache retrieval.
 pub enum CompileResult {
     /// An error made the compilation not possible.
     Error,
-    /// Result was found in cache.
-    CacheHit(Duration),
+    /// Result was found in cache, in the given tier if the cache is tiered.
+    CacheHit(Duration, Option<CacheTier>),
     /// Result was not found in cache.
     ///
     /// The `CacheWriteFuture` will resolve when the result is finished
@@ -763,25 +972,22 @@ pub enum CompileResult {
 }
 
 /// The state of `--color` options passed to a compiler.
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub enum ColorMode {
     Off,
     On,
+    #[default]
     Auto,
 }
 
-impl Default for ColorMode {
-    fn default() -> ColorMode {
-        ColorMode::Auto
-    }
-}
-
 /// Can't derive(Debug) because of `CacheWriteFuture`.
 impl fmt::Debug for CompileResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             CompileResult::Error => write!(f, "CompileResult::Error"),
-            CompileResult::CacheHit(ref d) => write!(f, "CompileResult::CacheHit({:?})", d),
+            CompileResult::CacheHit(ref d, ref t) => {
+                write!(f, "CompileResult::CacheHit({:?}, {:?})", d, t)
+            }
             CompileResult::CacheMiss(ref m, ref dt, ref d, _) => {
                 write!(f, "CompileResult::CacheMiss({:?}, {:?}, {:?}, _)", d, m, dt)
             }
@@ -796,7 +1002,7 @@ impl PartialEq<CompileResult> for CompileResult {
     fn eq(&self, other: &CompileResult) -> bool {
         match (self, other) {
             (&CompileResult::Error, &CompileResult::Error) => true,
-            (&CompileResult::CacheHit(_), &CompileResult::CacheHit(_)) => true,
+            (CompileResult::CacheHit(_, t), CompileResult::CacheHit(_, t2)) => t == t2,
             (
                 &CompileResult::CacheMiss(ref m, ref dt, _, _),
                 &CompileResult::CacheMiss(ref n, ref dt2, _, _),
@@ -1157,19 +1363,25 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cache::cache::CHECKSUMS_OBJECT;
     use crate::cache::disk::DiskCache;
-    use crate::cache::Storage;
+    use crate::cache::rw_mode::RWModeStorage;
+    use crate::cache::{CacheRead, Storage};
+    use crate::config::CacheRWMode;
     use crate::mock_command::*;
     use crate::test::mock_storage::MockStorage;
     use crate::test::utils::*;
     use futures::{future, Future};
     use futures_03::executor::ThreadPool;
     use std::fs::{self, File};
-    use std::io::Write;
+    use std::io::{self, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::Arc;
     use std::time::Duration;
     use std::u64;
     use tokio_compat::runtime::current_thread::Runtime;
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
 
     #[test]
     fn test_detect_compiler_kind_gcc() {
@@ -1354,12 +1566,21 @@ LLVM version: 6.0",
                     &creator,
                     Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
                 );
-                let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+                let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
                     CompilerArguments::Ok(h) => h,
                     o => panic!("Bad result from parse_arguments: {:?}", o),
                 };
                 hasher
-                    .generate_hash_key(&creator, cwd.to_path_buf(), vec![], false, &pool, false)
+                    .generate_hash_key(
+                        &creator,
+                        cwd.to_path_buf(),
+                        vec![],
+                        false,
+                        &pool,
+                        false,
+                        Arc::new(MockStorage::new()),
+                        false,
+                    )
                     .wait()
                     .unwrap()
             })
@@ -1427,7 +1648,7 @@ LLVM version: 6.0",
         });
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
@@ -1482,12 +1703,374 @@ LLVM version: 6.0",
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0), None), cached);
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_get_cached_or_compile_read_only() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let mut runtime = Runtime::new().unwrap();
+        let disk = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(RWModeStorage::new(
+            Arc::new(disk.clone()),
+            CacheRWMode::ReadOnly,
+        ));
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The preprocessor invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        // The compiler invocation.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            File::create(&o)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let (cached, _) = runtime
+            .block_on(future::lazy(|| {
+                hasher.get_cached_or_compile(
+                    Ok(None),
+                    creator.clone(),
+                    storage.clone(),
+                    arguments.clone(),
+                    cwd.to_path_buf(),
+                    vec![],
+                    CacheControl::Default,
+                    pool.clone(),
+                )
+            }))
+            .unwrap();
+        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
+        // The write is skipped without an entry being built.
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => match f.wait() {
+                Err(e) => assert!(e.downcast_ref::<CacheWriteSkipped>().is_some()),
+                Ok(_) => panic!("Cache write to a read-only cache succeeded"),
+            },
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+        assert_eq!(Some(0), disk.current_size().wait().unwrap());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_direct_mode() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        let cwd = f.tempdir.path().to_path_buf();
+        create_file(&cwd, "foo.c", |mut f| f.write_all(b"#include \"foo.h\"")).unwrap();
+        create_file(&cwd, "foo.h", |mut f| f.write_all(b"int x;")).unwrap();
+        let obj = cwd.join("foo.o");
+        let depfile = cwd.join("foo.d");
+        let preprocessed = Arc::new(AtomicBool::new(false));
+        let mock_preprocessor = |preprocessed: &Arc<AtomicBool>| {
+            let preprocessed = preprocessed.clone();
+            let d = depfile.clone();
+            next_command_calls(&creator, move |args| {
+                // Direct mode needs line markers.
+                assert!(!args.iter().any(|a| a == "-P"));
+                preprocessed.store(true, Ordering::SeqCst);
+                File::create(&d)?.write_all(b"foo.o: foo.c foo.h")?;
+                Ok(MockChild::new(
+                    exit_status(0),
+                    "# 1 \"foo.c\"\n# 1 \"<built-in>\"\n# 1 \"foo.h\" 1\nint x;\n",
+                    "",
+                ))
+            });
+        };
+        let env_vars = vec![("SCCACHE_DIRECT".into(), "1".into())];
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o", "-MD"];
+        let compile = |runtime: &mut Runtime| {
+            let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            let (cached, _) = runtime
+                .block_on(future::lazy(|| {
+                    hasher.get_cached_or_compile(
+                        Ok(None),
+                        creator.clone(),
+                        storage.clone(),
+                        arguments.clone(),
+                        cwd.clone(),
+                        env_vars.clone(),
+                        CacheControl::Default,
+                        pool.clone(),
+                    )
+                }))
+                .unwrap();
+            cached
+        };
+        // The first compile misses the manifest and the cache.
+        mock_preprocessor(&preprocessed);
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            File::create(&o)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        match compile(&mut runtime) {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                f.wait().unwrap();
+            }
+            cached => panic!("Unexpected compile result: {:?}", cached),
+        }
+        assert!(preprocessed.swap(false, Ordering::SeqCst));
+        // The second compile finds the manifest and doesn't run the preprocessor,
+        // and the dependency file is restored from the cache.
+        fs::remove_file(&obj).unwrap();
+        fs::remove_file(&depfile).unwrap();
+        assert_eq!(
+            CompileResult::CacheHit(Duration::new(0, 0), None),
+            compile(&mut runtime)
+        );
+        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
+        assert!(fs::metadata(&depfile).map(|m| m.len() > 0).unwrap());
+        // Changing an included file means preprocessing again.
+        create_file(&cwd, "foo.h", |mut f| f.write_all(b"int y;")).unwrap();
+        mock_preprocessor(&preprocessed);
+        assert_eq!(
+            CompileResult::CacheHit(Duration::new(0, 0), None),
+            compile(&mut runtime)
+        );
+        assert!(preprocessed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_restores_depfile() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The same sources in two checkouts under the base directory.
+        let base_dir = f.tempdir.path().join("ws");
+        let env_vars = vec![("SCCACHE_DIRECT".into(), "1".into())];
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o", "-MD"];
+        let compile = |runtime: &mut Runtime, checkout: &str, compile: bool| {
+            let cwd = base_dir.join(checkout);
+            fs::create_dir_all(&cwd).unwrap();
+            create_file(&cwd, "foo.c", |mut f| f.write_all(b"#include \"foo.h\"")).unwrap();
+            create_file(&cwd, "foo.h", |mut f| f.write_all(b"int x;")).unwrap();
+            let depfile = cwd.join("foo.d");
+            let header = cwd.join("foo.h");
+            next_command_calls(&creator, move |_| {
+                // Only the dependency file from the cache is left on a hit.
+                if compile {
+                    let deps = format!("foo.o: foo.c {}\n", header.display());
+                    File::create(&depfile)?.write_all(deps.as_bytes())?;
+                }
+                Ok(MockChild::new(
+                    exit_status(0),
+                    format!("# 1 \"foo.c\"\n# 1 \"{}\" 1\nint x;\n", header.display()),
+                    "",
+                ))
+            });
+            if compile {
+                let o = cwd.join("foo.o");
+                next_command_calls(&creator, move |_| {
+                    File::create(&o)?.write_all(b"file contents")?;
+                    Ok(MockChild::new(exit_status(0), "", ""))
+                });
+            }
+            let hasher = match c.parse_arguments(&arguments, &cwd, Some(&base_dir)) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            let (cached, _) = runtime
+                .block_on(future::lazy(|| {
+                    hasher.get_cached_or_compile(
+                        Ok(None),
+                        creator.clone(),
+                        storage.clone(),
+                        arguments.clone(),
+                        cwd.clone(),
+                        env_vars.clone(),
+                        CacheControl::Default,
+                        pool.clone(),
+                    )
+                }))
+                .unwrap();
+            cached
+        };
+        match compile(&mut runtime, "a", true) {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                f.wait().unwrap();
+            }
+            cached => panic!("Unexpected compile result: {:?}", cached),
+        }
+        // The local dependency file is left alone.
+        let a = base_dir.join("a");
+        assert_eq!(
+            format!("foo.o: foo.c {}\n", a.join("foo.h").display()),
+            fs::read_to_string(a.join("foo.d")).unwrap()
+        );
+        // The hit in the other checkout names its own files.
+        assert_eq!(
+            CompileResult::CacheHit(Duration::new(0, 0), None),
+            compile(&mut runtime, "b", false)
+        );
+        let b = base_dir.join("b");
+        assert_eq!(
+            format!("foo.o: foo.c {}\n", b.join("foo.h").display()),
+            fs::read_to_string(b.join("foo.d")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compiler_explain_hash_key_direct_mode() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        let cwd = f.tempdir.path().to_path_buf();
+        create_file(&cwd, "foo.c", |mut f| f.write_all(b"#include \"foo.h\"")).unwrap();
+        create_file(&cwd, "foo.h", |mut f| f.write_all(b"int x;")).unwrap();
+        let depfile = cwd.join("foo.d");
+        let mock_preprocessor = || {
+            let d = depfile.clone();
+            next_command_calls(&creator, move |_| {
+                File::create(&d)?.write_all(b"foo.o: foo.c foo.h")?;
+                Ok(MockChild::new(
+                    exit_status(0),
+                    "# 1 \"foo.c\"\n# 1 \"<built-in>\"\n# 1 \"foo.h\" 1\nint x;\n",
+                    "",
+                ))
+            });
+        };
+        let env_vars = vec![("SCCACHE_DIRECT".into(), "1".into())];
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o", "-MD"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let explain = |runtime: &mut Runtime| {
+            mock_preprocessor();
+            let hasher = hasher.clone();
+            runtime
+                .block_on(future::lazy(|| {
+                    hasher.explain_hash_key(
+                        Ok(None),
+                        creator.clone(),
+                        storage.clone(),
+                        cwd.clone(),
+                        env_vars.clone(),
+                        pool.clone(),
+                    )
+                }))
+                .unwrap()
+        };
+        // Explaining doesn't record a manifest, so the compile still misses it.
+        let explanation = explain(&mut runtime);
+        assert!(!explanation.inputs.is_empty());
+        mock_preprocessor();
+        let o = cwd.join("foo.o");
+        next_command_calls(&creator, move |_| {
+            File::create(&o)?.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let (cached, _) = runtime
+            .block_on(future::lazy(|| {
+                hasher.clone().get_cached_or_compile(
+                    Ok(None),
+                    creator.clone(),
+                    storage.clone(),
+                    arguments.clone(),
+                    cwd.clone(),
+                    env_vars.clone(),
+                    CacheControl::Default,
+                    pool.clone(),
+                )
+            }))
+            .unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                f.wait().unwrap();
+            }
+            cached => panic!("Unexpected compile result: {:?}", cached),
+        }
+        // The entry was stored under the explained key, and explaining again
+        // once the manifest exists gives the same key.
+        match storage.get(&explanation.key).wait().unwrap() {
+            Cache::Hit(_) => {}
+            c => panic!("Unexpected cache lookup result: {:?}", c),
+        }
+        assert_eq!(explanation.key, explain(&mut runtime).key);
+    }
+
     #[test]
     #[cfg(feature = "dist-client")]
     fn test_compiler_get_cached_or_compile_dist() {
@@ -1528,7 +2111,7 @@ LLVM version: 6.0",
         ));
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
@@ -1583,7 +2166,7 @@ LLVM version: 6.0",
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0), None), cached);
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
@@ -1635,7 +2218,7 @@ LLVM version: 6.0",
         });
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
@@ -1670,6 +2253,87 @@ LLVM version: 6.0",
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    /// Test that a cache hit with an object that doesn't match its checksum
+    /// is removed from the cache and treated as a cache miss.
+    fn test_compiler_get_cached_or_compile_corrupt_entry() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = Arc::new(MockStorage::new());
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        // The preprocessor invocation.
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+        );
+        // The compiler invocation.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            // Pretend to compile something.
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        // The cache will return an entry whose object doesn't match its checksum.
+        let opts = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+        zip.start_file("obj", opts).unwrap();
+        zstd::stream::copy_encode(&b"truncated"[..], &mut zip, 3).unwrap();
+        let checksums: HashMap<_, _> = vec![("obj", "bogus")].into_iter().collect();
+        let checksums = bincode::serialize(&checksums).unwrap();
+        zip.start_file(CHECKSUMS_OBJECT, opts).unwrap();
+        zstd::stream::copy_encode(&checksums[..], &mut zip, 3).unwrap();
+        let entry = CacheRead::from(zip.finish().unwrap()).unwrap();
+        storage.next_get(f_ok(Cache::Hit(entry)));
+        let (cached, res) = runtime
+            .block_on(future::lazy(|| {
+                hasher.get_cached_or_compile(
+                    Ok(None),
+                    creator,
+                    storage.clone(),
+                    arguments.clone(),
+                    cwd.to_path_buf(),
+                    vec![],
+                    CacheControl::Default,
+                    pool,
+                )
+            }))
+            .unwrap();
+        // Ensure that the object file was compiled.
+        assert_eq!(b"file contents".to_vec(), fs::read(&obj).unwrap());
+        match cached {
+            CompileResult::CacheMiss(MissType::CorruptEntry, DistType::NoDist, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(1, storage.removed().len());
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_force_recache() {
         drop(env_logger::try_init());
@@ -1718,7 +2382,7 @@ LLVM version: 6.0",
         }
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
@@ -1821,7 +2485,7 @@ LLVM version: 6.0",
         );
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
@@ -1902,7 +2566,7 @@ LLVM version: 6.0",
         }
         let cwd = f.tempdir.path();
         let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
-        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref(), None) {
             CompilerArguments::Ok(h) => h,
             o => panic!("Bad result from parse_arguments: {:?}", o),
         };
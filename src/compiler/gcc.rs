@@ -17,13 +17,13 @@ use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments
 use crate::compiler::{clang, Cacheable, ColorMode, CompileCommand, CompilerArguments};
 use crate::dist;
 use crate::mock_command::{CommandCreatorSync, RunCommand};
-use crate::util::{run_input_output, OsStrExt};
+use crate::util::{base_dir_relative, run_input_output, OsStrExt};
 use log::Level::Trace;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process;
 
 use crate::errors::*;
@@ -45,8 +45,9 @@ impl CCompilerImpl for GCC {
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        base_dir: Option<&Path>,
     ) -> CompilerArguments<ParsedArguments> {
-        parse_arguments(arguments, cwd, &ARGS[..], self.gplusplus)
+        parse_arguments(arguments, cwd, base_dir, &ARGS[..], self.gplusplus)
     }
 
     fn preprocess<T>(
@@ -119,6 +120,9 @@ ArgData! { pub
     Language(OsString),
     SplitDwarf,
     ProfileGenerate,
+    ProfileUse(PathBuf),
+    // Only valid for clang, but this needs to be here since clang shares gcc's arg parsing.
+    ClangProfileUse(PathBuf),
     TestCoverage,
     Coverage,
     ExtraHashFile(PathBuf),
@@ -169,7 +173,8 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
     flag!("-fplugin=libcc1plugin", TooHardFlag),
     flag!("-fprofile-arcs", ProfileGenerate),
     flag!("-fprofile-generate", ProfileGenerate),
-    take_arg!("-fprofile-use", OsString, Concatenated, TooHard),
+    // Can be either -fprofile-use or -fprofile-use=path
+    take_arg!("-fprofile-use", PathBuf, Concatenated('='), ProfileUse),
     flag!("-frepo", TooHardFlag),
     flag!("-fsyntax-only", TooHardFlag),
     flag!("-ftest-coverage", TestCoverage),
@@ -209,9 +214,13 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
 /// Otherwise, return `CompilerArguments::Ok(ParsedArguments)`, with
 /// the `ParsedArguments` struct containing information parsed from
 /// `arguments`.
+///
+/// If `base_dir` is set, the input, output, include and dependency file
+/// paths under it are made relative to `cwd`.
 pub fn parse_arguments<S>(
     arguments: &[OsString],
     cwd: &Path,
+    base_dir: Option<&Path>,
     arg_info: S,
     plusplus: bool,
 ) -> CompilerArguments<ParsedArguments>
@@ -222,6 +231,8 @@ where
     let mut input_arg = None;
     let mut dep_target = None;
     let mut dep_flag = OsString::from("-MT");
+    let mut dep_path = None;
+    let mut dep_output = false;
     let mut common_args = vec![];
     let mut preprocessor_args = vec![];
     let mut dependency_args = vec![];
@@ -233,6 +244,7 @@ where
     let mut language = None;
     let mut compilation_flag = OsString::new();
     let mut profile_generate = false;
+    let mut profile_use = None;
     let mut outputs_gcno = false;
     let mut xclangs: Vec<OsString> = vec![];
     let mut color_mode = ColorMode::Auto;
@@ -263,6 +275,10 @@ where
             | Argument::UnknownFlag(_)
             | Argument::Flag(_, _) => {}
         }
+        let arg = match base_dir {
+            Some(base_dir) => relativize_arg(arg, base_dir, cwd),
+            None => arg,
+        };
 
         match arg.get_data() {
             Some(TooHardFlag) | Some(TooHard(_)) => {
@@ -275,6 +291,11 @@ where
                     OsString::from(arg.flag_str().expect("Compilation flag expected"));
             }
             Some(ProfileGenerate) => profile_generate = true,
+            Some(ProfileUse(path)) => profile_use = Some(path.clone()),
+            Some(ClangProfileUse(path)) => match clang_profile_path(cwd, path) {
+                Some(profile) => extra_hash_files.push(profile),
+                None => cannot_cache!(arg.flag_str().expect("Profile flag expected")),
+            },
             Some(TestCoverage) => outputs_gcno = true,
             Some(Coverage) => {
                 outputs_gcno = true;
@@ -290,13 +311,19 @@ where
                 };
             }
             Some(Output(p)) => output_arg = Some(p.clone()),
-            Some(NeedDepTarget) => need_explicit_dep_target = true,
+            Some(NeedDepTarget) => {
+                need_explicit_dep_target = true;
+                // -MP alone does not generate a dependency file.
+                if arg.flag_str() != Some("-MP") {
+                    dep_output = true;
+                }
+            }
             Some(DepTarget(s)) => {
                 dep_flag = OsString::from(arg.flag_str().expect("Dep target flag expected"));
                 dep_target = Some(s.clone());
             }
-            Some(DepArgumentPath(_))
-            | Some(ExtraHashFile(_))
+            Some(DepArgumentPath(p)) => dep_path = Some(p.clone()),
+            Some(ExtraHashFile(_))
             | Some(PreprocessorArgumentFlag)
             | Some(PreprocessorArgument(_))
             | Some(PreprocessorArgumentPath(_))
@@ -334,6 +361,8 @@ where
         let args = match arg.get_data() {
             Some(SplitDwarf)
             | Some(ProfileGenerate)
+            | Some(ProfileUse(_))
+            | Some(ClangProfileUse(_))
             | Some(TestCoverage)
             | Some(Coverage)
             | Some(DiagnosticsColor(_))
@@ -377,6 +406,8 @@ where
         let args = match arg.get_data() {
             Some(SplitDwarf)
             | Some(ProfileGenerate)
+            | Some(ProfileUse(_))
+            | Some(ClangProfileUse(_))
             | Some(TestCoverage)
             | Some(Coverage)
             | Some(DoCompilation)
@@ -472,6 +503,18 @@ where
         outputs.insert("gcno", gcno);
         profile_generate = true;
     }
+    // The dependency file is written next to the output unless -MF says otherwise.
+    let depfile = if dep_output {
+        Some(dep_path.unwrap_or_else(|| output.with_extension("d")))
+    } else {
+        None
+    };
+    if let Some(path) = profile_use {
+        match gcc_profile_path(cwd, &path, &output) {
+            Some(profile) => extra_hash_files.push(profile),
+            None => cannot_cache!("-fprofile-use"),
+        }
+    }
     if need_explicit_dep_target {
         dependency_args.push(dep_flag);
         dependency_args.push(dep_target.unwrap_or_else(|| output.clone().into_os_string()));
@@ -482,7 +525,7 @@ where
         input: input.into(),
         language,
         compilation_flag,
-        depfile: None,
+        depfile,
         outputs,
         dependency_args,
         preprocessor_args,
@@ -494,6 +537,85 @@ where
     })
 }
 
+/// Make the paths named by `arg` that are under `base_dir` relative to `cwd`,
+/// so that the compiler sees the same arguments in every checkout under
+/// `base_dir`, and writes the same paths into its outputs.
+fn relativize_arg(arg: Argument<ArgData>, base_dir: &Path, cwd: &Path) -> Argument<ArgData> {
+    let relative = |path: PathBuf| base_dir_relative(&path, base_dir, cwd).unwrap_or(path);
+    match arg {
+        Argument::WithValue(flag, data, disposition) => {
+            let data = match data {
+                PreprocessorArgumentPath(path) => PreprocessorArgumentPath(relative(path)),
+                Output(path) => Output(relative(path)),
+                DepArgumentPath(path) => DepArgumentPath(relative(path)),
+                data => data,
+            };
+            Argument::WithValue(flag, data, disposition)
+        }
+        // The input file.
+        Argument::Raw(input) => Argument::Raw(relative(input.into()).into()),
+        arg => arg,
+    }
+}
+
+/// Find the profile data gcc reads for `-fprofile-use=path` when compiling
+/// to `output`, so that it can be hashed.
+///
+/// Without a path the `.gcda` file lives next to the object file. With a
+/// directory, recent versions of gcc name it after the absolute path of the
+/// object file with `/` mangled to `#`, and older versions after its file
+/// name alone. Returns `None` if no profile data can be found, in which case
+/// the compilation shouldn't be cached.
+fn gcc_profile_path(cwd: &Path, path: &Path, output: &Path) -> Option<PathBuf> {
+    let candidates = if path.as_os_str().is_empty() {
+        vec![cwd.join(output.with_extension("gcda"))]
+    } else {
+        let path = cwd.join(path);
+        if path.is_file() {
+            return Some(path);
+        }
+        let mut mangled = cwd
+            .join(output.with_extension(""))
+            .components()
+            .filter_map(|c| match c {
+                Component::RootDir => None,
+                Component::ParentDir => Some("^".into()),
+                c => Some(c.as_os_str().to_owned()),
+            })
+            .fold(OsString::new(), |mut mangled, c| {
+                mangled.push("#");
+                mangled.push(c);
+                mangled
+            });
+        mangled.push(".gcda");
+        let mut candidates = vec![path.join(mangled)];
+        if let Some(name) = output.file_name() {
+            candidates.push(path.join(Path::new(name).with_extension("gcda")));
+        }
+        candidates
+    };
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+/// Find the profile data clang reads for `-fprofile-use=path` or
+/// `-fprofile-instr-use=path`, so that it can be hashed.
+///
+/// The path may name the `.profdata` file itself or a directory containing
+/// `default.profdata`, and defaults to the current directory.
+fn clang_profile_path(cwd: &Path, path: &Path) -> Option<PathBuf> {
+    let path = cwd.join(path);
+    let path = if path.is_dir() {
+        path.join("default.profdata")
+    } else {
+        path
+    };
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn preprocess<T>(
     creator: &T,
@@ -658,9 +780,15 @@ pub fn generate_compile_commands(
     Ok((command, dist_command, Cacheable::Yes))
 }
 
+/// The maximum number of `@file` arguments expanded for a single command
+/// line, matching the limit gcc uses to stop a response file that includes
+/// itself from expanding forever.
+const MAX_RESPONSE_FILE_EXPANSIONS: usize = 2000;
+
 pub struct ExpandIncludeFile<'a> {
     cwd: &'a Path,
     stack: Vec<OsString>,
+    expansions: usize,
 }
 
 impl<'a> ExpandIncludeFile<'a> {
@@ -668,6 +796,7 @@ impl<'a> ExpandIncludeFile<'a> {
         ExpandIncludeFile {
             stack: args.iter().rev().map(|a| a.to_owned()).collect(),
             cwd,
+            expansions: 0,
         }
     }
 }
@@ -703,27 +832,60 @@ impl<'a> Iterator for ExpandIncludeFile<'a> {
             //     recursively.
             //
             // So here we interpret any I/O errors as "just return this
-            // argument". Currently we don't implement handling of arguments
-            // with quotes, so if those are encountered we just pass the option
-            // through literally anyway.
-            //
-            // At this time we interpret all `@` arguments above as non
-            // cacheable, so if we fail to interpret this we'll just call the
-            // compiler anyway.
+            // argument". The `@` argument is then treated as non cacheable
+            // by the argument parser, so if we fail to read the file we'll
+            // just call the compiler anyway. The same goes for command lines
+            // with so many nested `@file` options that gcc would give up.
             //
             // [1]: https://gcc.gnu.org/onlinedocs/gcc/Overall-Options.html#Overall-Options
+            if self.expansions >= MAX_RESPONSE_FILE_EXPANSIONS {
+                debug!("too many nested @-files expanding `{}`", file.display());
+                return Some(arg);
+            }
             let mut contents = String::new();
             let res = File::open(&file).and_then(|mut f| f.read_to_string(&mut contents));
             if let Err(e) = res {
                 debug!("failed to read @-file `{}`: {}", file.display(), e);
                 return Some(arg);
             }
-            if contents.contains('"') || contents.contains('\'') {
-                return Some(arg);
+            self.expansions += 1;
+            let new_args = split_response_file(&contents);
+            self.stack
+                .extend(new_args.into_iter().rev().map(OsString::from));
+        }
+    }
+}
+
+/// Split the contents of a response file into arguments using the same
+/// quoting rules as gcc and clang: arguments are separated by whitespace,
+/// single and double quotes group characters (including whitespace) into
+/// one argument, and a backslash includes the following character literally.
+fn split_response_file(contents: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut chars = contents.chars().peekable();
+    loop {
+        while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return args;
+        }
+        let mut arg = String::new();
+        let mut quote = None;
+        while let Some(c) = chars.next() {
+            match (c, quote) {
+                ('\\', _) => {
+                    if let Some(c) = chars.next() {
+                        arg.push(c);
+                    }
+                }
+                (c, None) if c.is_whitespace() => break,
+                ('\'', None) | ('"', None) => quote = Some(c),
+                (c, Some(q)) if c == q => quote = None,
+                (c, _) => arg.push(c),
             }
-            let new_args = contents.split_whitespace().collect::<Vec<_>>();
-            self.stack.extend(new_args.iter().rev().map(|s| s.into()));
         }
+        args.push(arg);
     }
 }
 
@@ -743,7 +905,7 @@ mod test {
         plusplus: bool,
     ) -> CompilerArguments<ParsedArguments> {
         let args = arguments.iter().map(OsString::from).collect::<Vec<_>>();
-        parse_arguments(&args, ".".as_ref(), &ARGS[..], plusplus)
+        parse_arguments(&args, ".".as_ref(), None, &ARGS[..], plusplus)
     }
 
     #[test]
@@ -1168,6 +1330,33 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_depfile() {
+        let parse_depfile = |args: Vec<String>| match parse_arguments_(args, false) {
+            CompilerArguments::Ok(ParsedArguments { depfile, .. }) => depfile,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(
+            Some(PathBuf::from("file")),
+            parse_depfile(stringvec![
+                "-c", "foo.c", "-MF", "file", "-o", "foo.o", "-MD"
+            ])
+        );
+        assert_eq!(
+            Some(PathBuf::from("out/foo.d")),
+            parse_depfile(stringvec!["-c", "foo.c", "-o", "out/foo.o", "-MMD"])
+        );
+        assert_eq!(
+            Some(PathBuf::from("foo.d")),
+            parse_depfile(stringvec!["-c", "src/foo.c", "-MD", "-MP"])
+        );
+        assert_eq!(None, parse_depfile(stringvec!["-c", "foo.c", "-MP"]));
+        assert_eq!(
+            None,
+            parse_depfile(stringvec!["-c", "foo.c", "-o", "foo.o"])
+        );
+    }
+
     #[test]
     fn test_parse_arguments_empty_args() {
         assert_eq!(
@@ -1203,22 +1392,150 @@ mod test {
         );
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_parse_arguments_base_dir() {
+        let args = ovec![
+            "-c",
+            "/ws/a/src/foo.c",
+            "-o",
+            "/ws/a/build/foo.o",
+            "-I/ws/a/include",
+            "-include",
+            "/ws/a/config.h",
+            "-I/usr/include",
+            "-MD",
+            "-MF",
+            "/ws/a/build/foo.d"
+        ];
+        let parsed = parse_arguments(
+            &args,
+            "/ws/a/build".as_ref(),
+            Some("/ws".as_ref()),
+            &ARGS[..],
+            false,
+        );
+        let ParsedArguments {
+            input,
+            depfile,
+            outputs,
+            preprocessor_args,
+            dependency_args,
+            ..
+        } = match parsed {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Path::new("../src/foo.c"), input);
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(Some(PathBuf::from("foo.d")), depfile);
+        assert_eq!(
+            ovec!["-I../include", "-include", "../config.h", "-I/usr/include"],
+            preprocessor_args
+        );
+        assert_eq!(
+            ovec!["-MD", "-MF", "foo.d", "-MT", "foo.o"],
+            dependency_args
+        );
+    }
+
     #[test]
     fn test_parse_arguments_pgo() {
+        let f = TestFixture::new();
+        let parse = |args: Vec<String>| {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            parse_arguments(&args, f.tempdir.path(), None, &ARGS[..], false)
+        };
+        // Without profile data there's nothing to hash, so don't cache.
         assert_eq!(
             CompilerArguments::CannotCache("-fprofile-use", None),
-            parse_arguments_(
-                stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"],
-                false
-            )
+            parse(stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"])
         );
+
+        // The profile data lives next to the object file by default.
+        f.touch("foo.gcda").unwrap();
+        let gcda = f.tempdir.path().join("foo.gcda");
+        match parse(stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"]) {
+            CompilerArguments::Ok(ParsedArguments {
+                common_args,
+                extra_hash_files,
+                ..
+            }) => {
+                assert_eq!(ovec!["-fprofile-use"], common_args);
+                assert_eq!(vec![gcda], extra_hash_files);
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+
+        // A profile data file can be given explicitly.
+        f.touch("file.gcda").unwrap();
+        let file = f.tempdir.path().join("file.gcda");
+        match parse(stringvec![
+            "-c",
+            "foo.c",
+            "-fprofile-use=file.gcda",
+            "-o",
+            "foo.o"
+        ]) {
+            CompilerArguments::Ok(ParsedArguments {
+                common_args,
+                extra_hash_files,
+                ..
+            }) => {
+                assert_eq!(ovec!["-fprofile-use=file.gcda"], common_args);
+                assert_eq!(vec![file], extra_hash_files);
+            }
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+
+        // With a directory, the profile data is named after the object file.
+        let dir = f.tempdir.path().join("profile");
         assert_eq!(
             CompilerArguments::CannotCache("-fprofile-use", None),
-            parse_arguments_(
-                stringvec!["-c", "foo.c", "-fprofile-use=file", "-o", "foo.o"],
-                false
-            )
+            parse(stringvec![
+                "-c",
+                "foo.c",
+                "-fprofile-use=profile",
+                "-o",
+                "obj/foo.o"
+            ])
         );
+        f.touch("profile/foo.gcda").unwrap();
+        let plain = f.tempdir.path().join("profile/foo.gcda");
+        let parsed = parse(stringvec![
+            "-c",
+            "foo.c",
+            "-fprofile-use=profile",
+            "-o",
+            "obj/foo.o"
+        ]);
+        match parsed {
+            CompilerArguments::Ok(ParsedArguments {
+                extra_hash_files, ..
+            }) => assert_eq!(vec![plain], extra_hash_files),
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+        let mangled = f.tempdir.path().join("obj").join("foo");
+        let mangled = mangled
+            .components()
+            .filter(|c| *c != Component::RootDir)
+            .map(|c| c.as_os_str().to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        let mangled = dir.join(format!("#{}.gcda", mangled.join("#")));
+        File::create(&mangled).unwrap();
+        let parsed = parse(stringvec![
+            "-c",
+            "foo.c",
+            "-fprofile-use=profile",
+            "-o",
+            "obj/foo.o"
+        ]);
+        match parsed {
+            CompilerArguments::Ok(ParsedArguments {
+                extra_hash_files, ..
+            }) => assert_eq!(vec![mangled], extra_hash_files),
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
     }
 
     #[test]
@@ -1297,6 +1614,76 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_split_response_file() {
+        assert_eq!(
+            stringvec!["-c", "foo.c", "-o", "foo.o"],
+            split_response_file("  -c foo.c\r\n-o\tfoo.o\n")
+        );
+        assert_eq!(
+            stringvec!["-DA=a b", "-DB=\"b\"", "-DC='c'", "", "d e"],
+            split_response_file(r#""-DA=a b" '-DB="b"' -DC=\'c\' '' d\ e"#)
+        );
+        assert_eq!(
+            stringvec!["C:\\path with spaces\\foo.c", "-I\"foo\""],
+            split_response_file(r#""C:\\path with spaces\\foo.c" "-I\"foo\"""#)
+        );
+        assert!(split_response_file(" \n ").is_empty());
+    }
+
+    #[test]
+    fn test_parse_arguments_response_file_expanded() {
+        let td = tempfile::Builder::new()
+            .prefix("sccache")
+            .tempdir()
+            .unwrap();
+        File::create(td.path().join("inner"))
+            .unwrap()
+            .write_all(b"'-DFOO=a b' -fabc")
+            .unwrap();
+        File::create(td.path().join("outer"))
+            .unwrap()
+            .write_all(
+                format!(
+                    "-c \"foo bar.c\" @{} -o foo.o\n",
+                    td.path().join("inner").display()
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let arg = format!("@{}", td.path().join("outer").display());
+        let ParsedArguments {
+            input,
+            outputs,
+            preprocessor_args,
+            common_args,
+            ..
+        } = match parse_arguments_(vec![arg], false) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("foo bar.c"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert!(preprocessor_args.is_empty());
+        assert_eq!(ovec!["-DFOO=a b", "-fabc"], common_args);
+
+        // A response file that includes itself can't be expanded.
+        let recursive = td.path().join("recursive");
+        File::create(&recursive)
+            .unwrap()
+            .write_all(format!("-c foo.c @{}", recursive.display()).as_bytes())
+            .unwrap();
+        assert_eq!(
+            CompilerArguments::CannotCache("@", None),
+            parse_arguments_(vec![format!("@{}", recursive.display())], false)
+        );
+        // Neither can one that isn't readable.
+        assert_eq!(
+            CompilerArguments::CannotCache("@", None),
+            parse_arguments_(vec![format!("@{}", td.path().display())], false)
+        );
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
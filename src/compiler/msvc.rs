@@ -54,6 +54,7 @@ impl CCompilerImpl for MSVC {
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        _base_dir: Option<&Path>,
     ) -> CompilerArguments<ParsedArguments> {
         parse_arguments(arguments, cwd, self.is_clang)
     }
@@ -585,8 +586,16 @@ pub fn parse_arguments(
             // Eagerly bail if it looks like we need to do more complicated work
             use crate::compiler::gcc::ArgData::*;
             let mut args = match arg.get_data() {
-                Some(SplitDwarf) | Some(TestCoverage) | Some(Coverage) | Some(DoCompilation)
-                | Some(Language(_)) | Some(Output(_)) | Some(TooHardFlag) | Some(XClang(_))
+                Some(SplitDwarf)
+                | Some(TestCoverage)
+                | Some(Coverage)
+                | Some(DoCompilation)
+                | Some(ProfileUse(_))
+                | Some(ClangProfileUse(_))
+                | Some(Language(_))
+                | Some(Output(_))
+                | Some(TooHardFlag)
+                | Some(XClang(_))
                 | Some(TooHard(_)) => cannot_cache!(arg
                     .flag_str()
                     .unwrap_or("Can't handle complex arguments through clang",)),
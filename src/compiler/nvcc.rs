@@ -46,8 +46,9 @@ impl CCompilerImpl for NVCC {
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        base_dir: Option<&Path>,
     ) -> CompilerArguments<ParsedArguments> {
-        gcc::parse_arguments(arguments, cwd, (&gcc::ARGS[..], &ARGS[..]), false)
+        gcc::parse_arguments(arguments, cwd, base_dir, (&gcc::ARGS[..], &ARGS[..]), false)
     }
 
     fn preprocess<T>(
@@ -211,7 +212,7 @@ mod test {
 
     fn parse_arguments_(arguments: Vec<String>) -> CompilerArguments<ParsedArguments> {
         let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
-        NVCC.parse_arguments(&arguments, ".".as_ref())
+        NVCC.parse_arguments(&arguments, ".".as_ref(), None)
     }
 
     macro_rules! parses {
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::Storage;
 use crate::compiler::args::*;
 use crate::compiler::{
     Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
@@ -25,8 +26,8 @@ use crate::dist::pkg;
 #[cfg(feature = "dist-client")]
 use crate::lru_disk_cache::{LruCache, Meter};
 use crate::mock_command::{CommandCreatorSync, RunCommand};
-use crate::util::{fmt_duration_as_secs, hash_all, run_input_output, Digest};
-use crate::util::{ref_env, HashToDigest, OsStrExt, SpawnExt};
+use crate::util::{base_dir_relative, fmt_duration_as_secs, hash_all, run_input_output};
+use crate::util::{ref_env, HashKeyDigest, HashToDigest, OsStrExt, SpawnExt};
 use filetime::FileTime;
 use futures::Future;
 use futures_03::executor::ThreadPool;
@@ -41,8 +42,7 @@ use std::collections::{HashMap, HashSet};
 use std::env::consts::DLL_EXTENSION;
 #[cfg(feature = "dist-client")]
 use std::env::consts::{DLL_PREFIX, EXE_EXTENSION};
-use std::ffi::OsString;
-use std::fmt;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::hash::Hash;
 #[cfg(feature = "dist-client")]
@@ -51,8 +51,9 @@ use std::io::Read;
 use std::iter;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
 #[cfg(feature = "dist-client")]
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 use std::time;
 
 use crate::errors::*;
@@ -109,6 +110,8 @@ pub struct RustHasher {
     rlib_dep_reader: Option<Arc<RlibDepReader>>,
     /// Parsed arguments from the rustc invocation
     parsed_args: ParsedArguments,
+    /// The base directory configured for the server, if any.
+    base_dir: Option<PathBuf>,
 }
 
 /// a lookup proxy for determining the actual compiler used per file or directory
@@ -130,6 +133,12 @@ pub struct ParsedArguments {
     crate_link_paths: Vec<PathBuf>,
     /// Static libraries linked to in the compile.
     staticlibs: Vec<PathBuf>,
+    /// Other native libraries passed to the linker, for crate types that invoke it.
+    native_libs: Vec<PathBuf>,
+    /// The linker passed with `-C linker`, if any.
+    linker: Option<PathBuf>,
+    /// Files passed to the linker with `-C link-arg` or `-C link-args`.
+    link_arg_files: Vec<PathBuf>,
     /// The crate name passed to --crate-name.
     crate_name: String,
     /// The crate types that will be generated
@@ -175,6 +184,9 @@ pub struct RustCompilation {
     cwd: PathBuf,
     /// The environment variables
     env_vars: Vec<(OsString, OsString)>,
+    /// The directory that paths were hashed relative to and that's remapped
+    /// with `--remap-path-prefix`, if path-independent hashing was requested.
+    remapped_dir: Option<PathBuf>,
 }
 
 // The selection of crate types for this compilation
@@ -182,8 +194,13 @@ pub struct RustCompilation {
 pub struct CrateTypes {
     rlib: bool,
     staticlib: bool,
+    /// Whether any crate type that invokes the linker is generated.
+    linked: bool,
 }
 
+/// Crate types that rustc invokes the linker for.
+const LINKED_CRATE_TYPES: &[&str] = &["bin", "dylib", "cdylib", "proc-macro"];
+
 lazy_static! {
     /// Emit types that we will cache.
     static ref ALLOWED_EMIT: HashSet<&'static str> = [
@@ -196,7 +213,8 @@ lazy_static! {
 /// Version number for cache key.
 const CACHE_VERSION: &[u8] = b"6";
 
-/// Get absolute paths for all source files listed in rustc's dep-info output.
+/// Get absolute paths for all source files listed in rustc's dep-info output,
+/// and the environment variables it lists as read by the crate.
 fn get_source_files<T>(
     creator: &T,
     crate_name: &str,
@@ -205,7 +223,7 @@ fn get_source_files<T>(
     cwd: &Path,
     env_vars: &[(OsString, OsString)],
     pool: &ThreadPool,
-) -> SFuture<Vec<PathBuf>>
+) -> SFuture<(Vec<PathBuf>, Vec<String>)>
 where
     T: CommandCreatorSync,
 {
@@ -236,7 +254,7 @@ where
             parse_dep_file(&dep_file, &cwd)
                 .with_context(|| format!("Failed to parse dep info for {}", name2))
         });
-        Box::new(parsed.map(move |files| {
+        Box::new(parsed.map(move |(files, env_deps)| {
             trace!(
                 "[{}]: got {} source files from dep-info in {}",
                 crate_name,
@@ -245,14 +263,14 @@ where
             );
             // Just to make sure we capture temp_dir.
             drop(temp_dir);
-            files
+            (files, env_deps)
         }))
     }))
 }
 
-/// Parse dependency info from `file` and return a Vec of files mentioned.
-/// Treat paths as relative to `cwd`.
-fn parse_dep_file<T, U>(file: T, cwd: U) -> Result<Vec<PathBuf>>
+/// Parse dependency info from `file` and return a Vec of files mentioned,
+/// and of environment variables read. Treat paths as relative to `cwd`.
+fn parse_dep_file<T, U>(file: T, cwd: U) -> Result<(Vec<PathBuf>, Vec<String>)>
 where
     T: AsRef<Path>,
     U: AsRef<Path>,
@@ -260,7 +278,20 @@ where
     let mut f = fs::File::open(file)?;
     let mut deps = String::new();
     f.read_to_string(&mut deps)?;
-    Ok(parse_dep_info(&deps, cwd))
+    Ok((parse_dep_info(&deps, cwd), parse_env_deps(&deps)))
+}
+
+/// Return the names of the environment variables that rustc lists in
+/// `dep_info` as read by the crate, such as with `env!`.
+fn parse_env_deps(dep_info: &str) -> Vec<String> {
+    let mut vars: Vec<_> = dep_info
+        .lines()
+        .filter_map(|line| line.strip_prefix("# env-dep:"))
+        .map(|dep| dep.split('=').next().unwrap_or(dep).to_owned())
+        .collect();
+    vars.sort();
+    vars.dedup();
+    vars
 }
 
 fn parse_dep_info<T>(dep_info: &str, cwd: T) -> Vec<PathBuf>
@@ -471,6 +502,7 @@ where
         &self,
         arguments: &[OsString],
         cwd: &Path,
+        base_dir: Option<&Path>,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
         match parse_arguments(arguments, cwd) {
             CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(RustHasher {
@@ -481,6 +513,7 @@ where
                 #[cfg(feature = "dist-client")]
                 rlib_dep_reader: self.rlib_dep_reader.clone(),
                 parsed_args: args,
+                base_dir: base_dir.map(Path::to_owned),
             })),
             CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
             CompilerArguments::CannotCache(why, extra_info) => {
@@ -864,28 +897,37 @@ impl IntoArg for ArgCodegen {
 #[derive(Clone, Debug, PartialEq)]
 struct ArgExtern {
     name: String,
-    path: PathBuf,
+    /// The path of the crate, which isn't passed for crates in the sysroot
+    /// like `proc_macro`.
+    path: Option<PathBuf>,
 }
 impl FromArg for ArgExtern {
     fn process(arg: OsString) -> ArgParseResult<Self> {
-        if let (name, Some(path)) = split_os_string_arg(arg, "=")? {
-            Ok(ArgExtern {
-                name,
-                path: path.into(),
-            })
-        } else {
-            Err(ArgParseError::Other("no path for extern"))
-        }
+        let (name, path) = split_os_string_arg(arg, "=")?;
+        Ok(ArgExtern {
+            name,
+            path: path.map(PathBuf::from),
+        })
     }
 }
 impl IntoArg for ArgExtern {
     fn into_arg_os_string(self) -> OsString {
-        let ArgExtern { name, path } = self;
-        make_os_string!(name, "=", path)
+        match self {
+            ArgExtern {
+                name,
+                path: Some(path),
+            } => make_os_string!(name, "=", path),
+            ArgExtern { name, path: None } => make_os_string!(name),
+        }
     }
     fn into_arg_string(self, transformer: PathTransformerFn<'_>) -> ArgToStringResult {
-        let ArgExtern { name, path } = self;
-        Ok(format!("{}={}", name, path.into_arg_string(transformer)?))
+        Ok(match self {
+            ArgExtern {
+                name,
+                path: Some(path),
+            } => format!("{}={}", name, path.into_arg_string(transformer)?),
+            ArgExtern { name, path: None } => name,
+        })
     }
 }
 
@@ -1009,12 +1051,16 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     let mut crate_types = CrateTypes {
         rlib: false,
         staticlib: false,
+        linked: false,
     };
     let mut extra_filename = None;
     let mut externs = vec![];
     let mut crate_link_paths = vec![];
     let mut static_lib_names = vec![];
+    let mut dylib_names = vec![];
     let mut static_link_paths: Vec<PathBuf> = vec![];
+    let mut linker = None;
+    let mut link_args = vec![];
     let mut color_mode = ColorMode::Auto;
     let mut has_json = false;
 
@@ -1028,8 +1074,11 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 return CompilerArguments::NotCompilation
             }
             Some(LinkLibrary(ArgLinkLibrary { kind, name })) => {
-                if kind == "static" {
-                    static_lib_names.push(name.to_owned())
+                // The kind may be followed by modifiers, as in `static:+whole-archive`.
+                match kind.split(':').next() {
+                    Some("static") => static_lib_names.push(name.to_owned()),
+                    Some("framework") => {}
+                    _ => dylib_names.push(name.to_owned()),
                 }
             }
             Some(LinkPath(ArgLinkPath { kind, path })) => {
@@ -1054,32 +1103,41 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 staticlib,
                 others,
             })) => {
-                // We can't cache non-rlib/staticlib crates, because rustc invokes the
-                // system linker to link them, and we don't know about all the linker inputs.
-                if !others.is_empty() {
-                    let others: Vec<&str> = others.iter().map(String::as_str).collect();
-                    let others_string = others.join(",");
-                    cannot_cache!("crate-type", others_string)
+                // rustc invokes the system linker for some crate types, so we hash
+                // the linker and its inputs along with everything else. We don't know
+                // about any other crate types.
+                let mut unknown: Vec<&str> = others
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|ty| !LINKED_CRATE_TYPES.contains(ty))
+                    .collect();
+                if !unknown.is_empty() {
+                    unknown.sort_unstable();
+                    cannot_cache!("crate-type", unknown.join(","))
                 }
                 crate_types.rlib |= rlib;
                 crate_types.staticlib |= staticlib;
+                crate_types.linked |= !others.is_empty();
             }
             Some(CrateName(value)) => crate_name = Some(value.clone()),
             Some(OutDir(value)) => output_dir = Some(value.clone()),
-            Some(Extern(ArgExtern { path, .. })) => externs.push(path.clone()),
+            Some(Extern(ArgExtern { path, .. })) => externs.extend(path.clone()),
             Some(CodeGen(ArgCodegen { opt, value })) => {
                 match (opt.as_ref(), value) {
                     ("extra-filename", Some(value)) => extra_filename = Some(value.to_owned()),
                     ("extra-filename", None) => cannot_cache!("extra-filename"),
-                    // Incremental compilation makes a mess of sccache's entire world
-                    // view. It produces additional compiler outputs that we don't cache,
-                    // and just letting rustc do its work in incremental mode is likely
-                    // to be faster than trying to fetch a result from cache anyway, so
-                    // don't bother caching compiles where it's enabled currently.
-                    // Longer-term we would like to figure out better integration between
-                    // sccache and rustc in the incremental scenario:
+                    ("linker", Some(value)) => linker = Some(PathBuf::from(value)),
+                    ("link-arg", Some(value)) => link_args.push(value.to_owned()),
+                    ("link-args", Some(value)) => {
+                        link_args.extend(value.split_whitespace().map(str::to_owned))
+                    }
+                    // Incremental compilation keeps its state in a directory that we
+                    // don't cache. We cache the final artifacts instead, and leave the
+                    // incremental directory alone on a hit. rustc checks that state
+                    // against its inputs, so a stale directory only means less reuse
+                    // the next time the crate is compiled.
                     // https://github.com/mozilla/sccache/issues/236
-                    ("incremental", _) => cannot_cache!("incremental"),
+                    ("incremental", None) => cannot_cache!("incremental"),
                     (_, _) => (),
                 }
             }
@@ -1149,6 +1207,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     if let CrateTypes {
         rlib: false,
         staticlib: false,
+        linked: false,
     } = crate_types
     {
         cannot_cache!("crate-type", "No crate-type passed".to_owned())
@@ -1171,25 +1230,60 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         None
     };
     // Locate all static libs specified on the commandline.
-    let staticlibs = static_lib_names
-        .into_iter()
-        .filter_map(|name| {
-            for path in static_link_paths.iter() {
-                for f in &[
-                    format_args!("lib{}.a", name),
-                    format_args!("{}.lib", name),
-                    format_args!("{}.a", name),
-                ] {
-                    let lib_path = path.join(fmt::format(*f));
-                    if lib_path.exists() {
-                        return Some(lib_path);
-                    }
-                }
-            }
+    let mut staticlibs = vec![];
+    for name in static_lib_names {
+        let found = static_link_paths.iter().find_map(|path| {
+            [
+                format!("lib{}.a", name),
+                format!("{}.lib", name),
+                format!("{}.a", name),
+            ]
+            .iter()
+            .map(|f| path.join(f))
+            .find(|lib_path| lib_path.exists())
+        });
+        match found {
+            Some(lib_path) => staticlibs.push(lib_path),
+            // The linker would look for the library in system directories, and
+            // link whatever it finds there into the output without us hashing it.
+            None if crate_types.linked => cannot_cache!("static library", name),
             // rustc will just error if there's a missing static library, so don't worry about
             // it too much.
-            None
-        })
+            None => {}
+        }
+    }
+    // Crate types that invoke the linker may link other native libraries too. The linker
+    // also searches system directories, but we only hash the libraries that are found
+    // in the directories on the commandline.
+    let native_libs = if crate_types.linked {
+        dylib_names
+            .into_iter()
+            .filter_map(|name| {
+                for path in static_link_paths.iter() {
+                    for f in &[
+                        format!("lib{}.so", name),
+                        format!("lib{}.dylib", name),
+                        format!("{}.lib", name),
+                        format!("lib{}.a", name),
+                        format!("{}.a", name),
+                    ] {
+                        let lib_path = path.join(f);
+                        if lib_path.exists() {
+                            return Some(lib_path);
+                        }
+                    }
+                }
+                None
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    // Any link arguments that name files, like linker scripts or objects.
+    let link_arg_files = link_args
+        .into_iter()
+        .map(PathBuf::from)
+        .filter(|arg| cwd.join(arg).is_file())
         .collect();
     // We'll figure out the source files and outputs later in
     // `generate_hash_key` where we can run rustc.
@@ -1203,6 +1297,9 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         externs,
         crate_link_paths,
         staticlibs,
+        native_libs,
+        linker,
+        link_arg_files,
         crate_name,
         dep_info: dep_info.map(|s| s.into()),
         emit,
@@ -1215,6 +1312,7 @@ impl<T> CompilerHasher<T> for RustHasher
 where
     T: CommandCreatorSync,
 {
+    #[allow(clippy::too_many_arguments)]
     fn generate_hash_key(
         self: Box<Self>,
         creator: &T,
@@ -1223,6 +1321,8 @@ where
         _may_dist: bool,
         pool: &ThreadPool,
         _rewrite_includes_only: bool,
+        _storage: Arc<dyn Storage>,
+        explain: bool,
     ) -> SFuture<HashResult> {
         let RustHasher {
             executable,
@@ -1238,6 +1338,9 @@ where
                     externs,
                     crate_link_paths,
                     staticlibs,
+                    native_libs,
+                    linker,
+                    link_arg_files,
                     crate_name,
                     crate_types,
                     dep_info,
@@ -1245,8 +1348,15 @@ where
                     has_json,
                     ..
                 },
+            base_dir,
         } = *self;
         trace!("[{}]: generate_hash_key", crate_name);
+        let remapped_dir = path_independent_base(base_dir.as_deref(), &cwd, &env_vars);
+        // Paths under `remapped_dir` are hashed relative to the working directory.
+        let hashed_arguments: Vec<(OsString, Option<OsString>)> = arguments
+            .iter()
+            .map(|arg| hashed_argument(arg, remapped_dir.as_deref(), &cwd))
+            .collect();
         // TODO: this doesn't produce correct arguments if they should be concatenated - should use iter_os_strings
         let os_string_arguments: Vec<(OsString, Option<OsString>)> = arguments
             .iter()
@@ -1283,9 +1393,9 @@ where
             &env_vars,
             pool,
         );
-        let source_files_and_hashes = source_files.and_then(move |source_files| {
+        let source_files_and_hashes = source_files.and_then(move |(source_files, env_deps)| {
             hash_all(&source_files, &source_hashes_pool)
-                .map(|source_hashes| (source_files, source_hashes))
+                .map(|source_hashes| (source_files, source_hashes, env_deps))
         });
         // Hash the contents of the externs listed on the commandline.
         trace!("[{}]: hashing {} externs", crate_name, externs.len());
@@ -1295,20 +1405,75 @@ where
         trace!("[{}]: hashing {} staticlibs", crate_name, staticlibs.len());
         let abs_staticlibs = staticlibs.iter().map(|s| cwd.join(s)).collect::<Vec<_>>();
         let staticlib_hashes = hash_all(&abs_staticlibs, pool);
+        // Crate types that invoke the linker also depend on the linker itself, and on
+        // the native libraries and files that are passed to it.
+        let mut link_inputs = vec![];
+        if crate_types.linked {
+            let linker = linker.unwrap_or_else(|| {
+                let target = arguments
+                    .iter()
+                    .find_map(|arg| match arg.get_data() {
+                        Some(Target(ArgTarget::Name(name))) => Some(name.as_str()),
+                        _ => None,
+                    })
+                    .unwrap_or(&host);
+                default_linker(target)
+            });
+            let path = env_vars
+                .iter()
+                .find(|(k, _)| k == "PATH")
+                .map(|(_, v)| v.clone());
+            match which::which_in(&linker, path, &cwd) {
+                Ok(linker) => link_inputs.push(("linker", linker)),
+                // rustc will fail to link, so the result won't be cached anyway.
+                Err(e) => debug!(
+                    "[{}]: failed to find linker {:?}: {}",
+                    crate_name, linker, e
+                ),
+            }
+            link_inputs.extend(native_libs.iter().map(|l| ("native library", cwd.join(l))));
+            link_inputs.extend(
+                link_arg_files
+                    .iter()
+                    .map(|f| ("link-arg file", cwd.join(f))),
+            );
+        }
+        trace!(
+            "[{}]: hashing {} link inputs",
+            crate_name,
+            link_inputs.len()
+        );
+        let link_input_paths = link_inputs
+            .iter()
+            .map(|(_, path)| path.clone())
+            .collect::<Vec<_>>();
+        let link_input_hashes = hash_all(&link_input_paths, pool);
         let creator = creator.clone();
-        let hashes = source_files_and_hashes.join3(extern_hashes, staticlib_hashes);
+        let hashes =
+            source_files_and_hashes.join4(extern_hashes, staticlib_hashes, link_input_hashes);
         Box::new(hashes.and_then(
-            move |((source_files, source_hashes), extern_hashes, staticlib_hashes)| -> SFuture<_> {
+            move |(
+                (source_files, source_hashes, env_deps),
+                extern_hashes,
+                staticlib_hashes,
+                link_input_hashes,
+            )|
+                  -> SFuture<_> {
                 // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
-                let mut m = Digest::new();
+                let mut m = HashKeyDigest::new(explain);
                 // Hash inputs:
                 // 1. A version
-                m.update(CACHE_VERSION);
+                m.input(|| "cache version".to_owned(), |m| m.update(CACHE_VERSION));
                 // 2. compiler_shlibs_digests
-                for d in compiler_shlibs_digests {
-                    m.update(d.as_bytes());
-                }
-                let weak_toolchain_key = m.clone().finish();
+                m.input(
+                    || "compiler".to_owned(),
+                    |m| {
+                        for d in &compiler_shlibs_digests {
+                            m.update(d.as_bytes());
+                        }
+                    },
+                );
+                let weak_toolchain_key = m.clone().finish().0;
                 // 3. The full commandline (self.arguments)
                 // TODO: there will be full paths here, it would be nice to
                 // normalize them so we can get cross-machine cache hits.
@@ -1316,7 +1481,7 @@ where
                 // by cargo: --extern, -L, --cfg. We'll filter those out, sort them,
                 // and append them to the rest of the arguments.
                 let args = {
-                    let (mut sortables, rest): (Vec<_>, Vec<_>) = os_string_arguments
+                    let (mut sortables, rest): (Vec<_>, Vec<_>) = hashed_arguments
                         .iter()
                         // We exclude a few arguments from the hash:
                         //   -L, --extern, --out-dir
@@ -1339,18 +1504,43 @@ where
                             a
                         })
                 };
-                args.hash(&mut HashToDigest { digest: &mut m });
+                m.input(
+                    || format!("arguments {}", args.to_string_lossy()),
+                    |m| args.hash(&mut HashToDigest { digest: m }),
+                );
                 // 4. The digest of all source files (this includes src file from cmdline).
                 // 5. The digest of all files listed on the commandline (self.externs).
                 // 6. The digest of all static libraries listed on the commandline (self.staticlibs).
-                for h in source_hashes
-                    .into_iter()
-                    .chain(extern_hashes)
-                    .chain(staticlib_hashes)
-                {
-                    m.update(h.as_bytes());
+                // 7. The digest of the linker and its inputs, if the linker is invoked.
+                let hashed_files = source_files
+                    .iter()
+                    .zip(source_hashes)
+                    .map(|(path, h)| ("source file", path, h))
+                    .chain(
+                        abs_externs
+                            .iter()
+                            .zip(extern_hashes)
+                            .map(|(path, h)| ("extern", path, h)),
+                    )
+                    .chain(
+                        abs_staticlibs
+                            .iter()
+                            .zip(staticlib_hashes)
+                            .map(|(path, h)| ("staticlib", path, h)),
+                    )
+                    .chain(
+                        link_inputs
+                            .iter()
+                            .zip(link_input_hashes)
+                            .map(|(&(kind, ref path), h)| (kind, path, h)),
+                    );
+                for (kind, path, h) in hashed_files {
+                    m.input(
+                        || format!("{} {}", kind, path.display()),
+                        |m| m.update(h.as_bytes()),
+                    );
                 }
-                // 7. Environment variables. Ideally we'd use anything referenced
+                // 8. Environment variables. Ideally we'd use anything referenced
                 // via env! in the program, but we don't have a way to determine that
                 // currently, and hashing all environment variables is too much, so
                 // we'll just hash the CARGO_ env vars and hope that's sufficient.
@@ -1367,13 +1557,53 @@ where
                 for &(ref var, ref val) in env_vars.iter() {
                     // CARGO_MAKEFLAGS will have jobserver info which is extremely non-cacheable.
                     if var.eq("CARGO") || (var.starts_with("CARGO_") && var != "CARGO_MAKEFLAGS") {
-                        var.hash(&mut HashToDigest { digest: &mut m });
-                        m.update(b"=");
-                        val.hash(&mut HashToDigest { digest: &mut m });
+                        // Variables like CARGO_MANIFEST_DIR hold paths in the workspace.
+                        // Those that the crate reads, such as with
+                        // `include_str!(concat!(env!("CARGO_MANIFEST_DIR"), ...))`,
+                        // end up in the outputs as they are, so they're
+                        // hashed as they are too.
+                        let read = var
+                            .to_str()
+                            .is_some_and(|var| env_deps.iter().any(|dep| dep == var));
+                        let val: OsString = remapped_dir
+                            .as_ref()
+                            .filter(|_| !read)
+                            .and_then(|dir| base_dir_relative(Path::new(val), dir, &cwd))
+                            .map_or_else(|| val.clone(), Into::into);
+                        m.input(
+                            || {
+                                format!(
+                                    "env var {}={}",
+                                    var.to_string_lossy(),
+                                    val.to_string_lossy()
+                                )
+                            },
+                            |m| {
+                                var.hash(&mut HashToDigest { digest: m });
+                                m.update(b"=");
+                                val.hash(&mut HashToDigest { digest: m });
+                            },
+                        );
                     }
                 }
-                // 8. The cwd of the compile. This will wind up in the rlib.
-                cwd.hash(&mut HashToDigest { digest: &mut m });
+                // 9. The cwd of the compile. This will wind up in the rlib.
+                let hashed_cwd = remapped_dir
+                    .as_ref()
+                    .and_then(|dir| cwd.strip_prefix(dir).ok())
+                    .unwrap_or(&cwd);
+                m.input(
+                    || format!("cwd {}", hashed_cwd.display()),
+                    |m| hashed_cwd.hash(&mut HashToDigest { digest: m }),
+                );
+                // 10. Whether the compile remaps paths under a base directory,
+                // which changes the outputs.
+                if remapped_dir.is_some() {
+                    m.input(
+                        || "path independent".to_owned(),
+                        |m| m.update(b"path independent"),
+                    );
+                }
+                let (key, explanation) = m.finish();
                 // Turn arguments into a simple Vec<OsString> to calculate outputs.
                 let flat_os_string_arguments: Vec<OsString> = os_string_arguments
                     .into_iter()
@@ -1454,6 +1684,17 @@ where
                                 ArgDisposition::Separated,
                             ));
                         }
+                        // Remap paths under the base directory so the outputs don't
+                        // depend on where the compile ran.
+                        if let Some(ref dir) = remapped_dir {
+                            let mut remap = dir.as_os_str().to_owned();
+                            remap.push("=.");
+                            arguments.push(Argument::WithValue(
+                                "--remap-path-prefix",
+                                ArgData::PassThrough(remap),
+                                ArgDisposition::Concatenated(Some(b'=')),
+                            ));
+                        }
 
                         let inputs = source_files
                             .into_iter()
@@ -1462,7 +1703,7 @@ where
                             .collect();
 
                         HashResult {
-                            key: m.finish(),
+                            key,
                             compilation: Box::new(RustCompilation {
                                 executable,
                                 host,
@@ -1476,10 +1717,12 @@ where
                                 dep_info,
                                 cwd,
                                 env_vars,
+                                remapped_dir,
                                 #[cfg(feature = "dist-client")]
                                 rlib_dep_reader,
                             }),
                             weak_toolchain_key,
+                            explanation,
                         }
                     }),
                 )
@@ -1553,6 +1796,11 @@ impl Compilation for RustCompilation {
                 };
             }
 
+            // The build server doesn't have the system linker or native libraries.
+            if self.crate_types.linked {
+                return None;
+            }
+
             let mut dist_arguments = vec![];
             let mut saw_target = false;
 
@@ -1566,8 +1814,11 @@ impl Compilation for RustCompilation {
                         input_path.into_arg_string(path_transformer_fn)
                     ))
                 } else {
-                    if let Some(Target(_)) = argument.get_data() {
-                        saw_target = true
+                    match argument.get_data() {
+                        Some(Target(_)) => saw_target = true,
+                        // Incremental state on the build server would be thrown away.
+                        Some(CodeGen(ArgCodegen { opt, .. })) if opt == "incremental" => continue,
+                        _ => (),
                     }
                     for string_arg in argument.iter_strings(path_transformer_fn) {
                         dist_arguments.push(try_string_arg!(string_arg))
@@ -1683,6 +1934,94 @@ impl Compilation for RustCompilation {
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
         Box::new(self.outputs.iter().map(|(k, v)| (k.as_str(), &**v)))
     }
+
+    fn relative_depfiles(&self) -> Option<(PathBuf, Vec<PathBuf>)> {
+        let remapped_dir = self.remapped_dir.clone()?;
+        Some((remapped_dir, self.dep_info.iter().cloned().collect()))
+    }
+}
+
+/// Return the linker rustc uses for `target` when `-C linker` isn't passed.
+fn default_linker(target: &str) -> PathBuf {
+    if target.contains("msvc") {
+        "link.exe".into()
+    } else {
+        "cc".into()
+    }
+}
+
+/// Return the directory to hash paths relative to, if path-independent
+/// hashing was requested for this compilation with
+/// `SCCACHE_RUST_PATH_INDEPENDENT`.
+///
+/// That's the configured `base_dir` if the compile runs under it, and
+/// otherwise `CARGO_MANIFEST_DIR`, or the working directory when the manifest
+/// is under it, as it is for members of a workspace.
+fn path_independent_base(
+    base_dir: Option<&Path>,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Option<PathBuf> {
+    let requested = env_vars.iter().any(|(k, v)| {
+        k.as_os_str() == OsStr::new("SCCACHE_RUST_PATH_INDEPENDENT") && v != "0" && v != "false"
+    });
+    if !requested {
+        return None;
+    }
+    if let Some(base_dir) = base_dir.filter(|dir| cwd.starts_with(dir)) {
+        return Some(base_dir.to_owned());
+    }
+    let manifest_dir = env_vars
+        .iter()
+        .find(|(k, _)| k == "CARGO_MANIFEST_DIR")
+        .map(|(_, v)| PathBuf::from(v))
+        .filter(|dir| dir.is_absolute())?;
+    if manifest_dir.starts_with(cwd) {
+        Some(cwd.to_owned())
+    } else {
+        Some(manifest_dir)
+    }
+}
+
+/// Return `arg` as it's hashed, with any absolute path under `remapped_dir`
+/// relative to `cwd`. The incremental directory isn't hashed, since it
+/// doesn't change the outputs.
+fn hashed_argument(
+    arg: &Argument<ArgData>,
+    remapped_dir: Option<&Path>,
+    cwd: &Path,
+) -> (OsString, Option<OsString>) {
+    let relative = |path: &Path| remapped_dir.and_then(|dir| base_dir_relative(path, dir, cwd));
+    let value = arg.get_data().cloned().map(|data| {
+        match data {
+            CodeGen(ArgCodegen { opt, .. }) if opt == "incremental" => {
+                CodeGen(ArgCodegen { opt, value: None })
+            }
+            CodeGen(ArgCodegen {
+                opt,
+                value: Some(value),
+            }) => {
+                let value = relative(Path::new(&value))
+                    .map_or(value, |path| path.to_string_lossy().into_owned());
+                CodeGen(ArgCodegen {
+                    opt,
+                    value: Some(value),
+                })
+            }
+            Target(ArgTarget::Path(path)) => {
+                Target(ArgTarget::Path(relative(&path).unwrap_or(path)))
+            }
+            data => data,
+        }
+        .into_arg_os_string()
+    });
+    match arg {
+        Argument::Raw(input) => (
+            relative(Path::new(input)).map_or_else(|| input.clone(), Into::into),
+            value,
+        ),
+        _ => (arg.to_os_string(), value),
+    }
 }
 
 // TODO: we do end up with slashes facing the wrong way, but Windows is agnostic so it's
@@ -1848,6 +2187,7 @@ impl pkg::InputsPackager for RustInputsPackager {
             CrateTypes {
                 rlib: true,
                 staticlib: false,
+                linked: false,
             }
         );
 
@@ -2292,7 +2632,9 @@ mod test {
 
     use crate::compiler::*;
     use crate::mock_command::*;
+    use crate::test::mock_storage::MockStorage;
     use crate::test::utils::*;
+    use crate::util::Digest;
     use itertools::Itertools;
     use std::ffi::OsStr;
     use std::fs::File;
@@ -2507,6 +2849,20 @@ mod test {
             "lib"
         );
         let r = fails!(
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "-C",
+            "incremental"
+        );
+        assert_eq!(r, CompilerArguments::CannotCache("incremental", None));
+        let h = parses!(
             "--emit",
             "link",
             "foo.rs",
@@ -2519,7 +2875,7 @@ mod test {
             "-C",
             "incremental=/foo"
         );
-        assert_eq!(r, CompilerArguments::CannotCache("incremental", None))
+        assert_eq!(h.output_dir.to_str(), Some("out"));
     }
 
     #[test]
@@ -2626,9 +2982,9 @@ mod test {
             "--crate-name",
             "foo"
         );
-        fails!(
+        let h = parses!(
             "--crate-type",
-            "bin",
+            "rlib,staticlib",
             "--emit",
             "link",
             "foo.rs",
@@ -2637,9 +2993,40 @@ mod test {
             "--crate-name",
             "foo"
         );
-        fails!(
+        assert!(!h.crate_types.linked);
+        // Crate types that invoke the linker.
+        for crate_type in &["bin", "dylib", "cdylib", "proc-macro", "rlib,dylib"] {
+            let h = parses!(
+                "--crate-type",
+                crate_type,
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo"
+            );
+            assert!(h.crate_types.linked, "{}", crate_type);
+        }
+        // Cargo passes the sysroot's proc_macro crate without a path.
+        let h = parses!(
             "--crate-type",
-            "rlib,dylib",
+            "proc-macro",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--extern",
+            "proc_macro"
+        );
+        assert!(h.externs.is_empty());
+        let r = fails!(
+            "--crate-type",
+            "rlib,unknown",
             "--emit",
             "link",
             "foo.rs",
@@ -2648,6 +3035,103 @@ mod test {
             "--crate-name",
             "foo"
         );
+        assert_eq!(
+            r,
+            CompilerArguments::CannotCache("crate-type", Some("unknown".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_link_inputs() {
+        let f = TestFixture::new();
+        f.touch("libfoo.so").unwrap();
+        f.touch("libbar.a").unwrap();
+        f.touch("link.x").unwrap();
+        let dir = f.tempdir.path().to_str().unwrap();
+        let args = |crate_type| {
+            ovec![
+                "--crate-type",
+                crate_type,
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "-L",
+                format!("native={}", dir),
+                "-l",
+                "foo",
+                "-l",
+                "static:+whole-archive=bar",
+                "-l",
+                "missing",
+                "-C",
+                "linker=my-cc",
+                "-C",
+                "link-arg=-Wl,--gc-sections",
+                "-C",
+                format!("link-args=-T {}/link.x", dir)
+            ]
+        };
+        let h = match parse_arguments(&args("proc-macro"), f.tempdir.path()) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(h.staticlibs, vec![f.tempdir.path().join("libbar.a")]);
+        assert_eq!(h.native_libs, vec![f.tempdir.path().join("libfoo.so")]);
+        assert_eq!(h.linker, Some(PathBuf::from("my-cc")));
+        assert_eq!(h.link_arg_files, vec![f.tempdir.path().join("link.x")]);
+        // Dynamic libraries don't matter unless the linker is invoked.
+        let h = match parse_arguments(&args("rlib"), f.tempdir.path()) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert!(h.native_libs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_arguments_system_static_libs() {
+        let f = TestFixture::new();
+        f.touch("libbar.a").unwrap();
+        let dir = f.tempdir.path().to_str().unwrap();
+        let args = |crate_type| {
+            ovec![
+                "--crate-type",
+                crate_type,
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "-L",
+                format!("native={}", dir),
+                "-l",
+                "static=bar",
+                "-l",
+                "static=z"
+            ]
+        };
+        // The linker would find libz.a in a system directory, where it isn't hashed.
+        for crate_type in LINKED_CRATE_TYPES {
+            assert_eq!(
+                CompilerArguments::CannotCache("static library", Some("z".to_owned())),
+                parse_arguments(&args(crate_type), f.tempdir.path()),
+                "crate type {}",
+                crate_type
+            );
+        }
+        // rustc itself only looks for static libraries to bundle in `-L` directories.
+        for crate_type in &["lib", "rlib", "staticlib"] {
+            let h = match parse_arguments(&args(crate_type), f.tempdir.path()) {
+                CompilerArguments::Ok(h) => h,
+                o => panic!("Got unexpected parse result for {}: {:?}", crate_type, o),
+            };
+            assert_eq!(h.staticlibs, vec![f.tempdir.path().join("libbar.a")]);
+        }
     }
 
     #[test]
@@ -2755,6 +3239,26 @@ bar.rs:
         );
     }
 
+    #[test]
+    fn test_parse_env_deps() {
+        let deps = "foo: baz.rs abc.rs bar.rs
+
+baz.rs:
+
+abc.rs:
+
+bar.rs:
+
+# env-dep:CARGO_MANIFEST_DIR=/home/user/foo
+# env-dep:FOO_CONFIG
+# env-dep:CARGO_PKG_NAME=foo=bar
+";
+        assert_eq!(
+            vec!["CARGO_MANIFEST_DIR", "CARGO_PKG_NAME", "FOO_CONFIG"],
+            parse_env_deps(deps)
+        );
+    }
+
     #[test]
     fn test_parse_dep_info_with_escaped_spaces() {
         let deps = r#"foo: baz.rs abc\ def.rs
@@ -2866,8 +3370,20 @@ c:/foo/bar.rs:
     }
 
     fn mock_dep_info(creator: &Arc<Mutex<MockCommandCreator>>, dep_srcs: &[&str]) {
+        mock_dep_info_with_env(creator, dep_srcs, &[])
+    }
+
+    fn mock_dep_info_with_env(
+        creator: &Arc<Mutex<MockCommandCreator>>,
+        dep_srcs: &[&str],
+        env_deps: &[&str],
+    ) {
         // Mock the `rustc --emit=dep-info` process by writing
         // a dep-info file.
+        let env_deps = env_deps
+            .iter()
+            .map(|s| (*s).to_string())
+            .collect::<Vec<String>>();
         let mut sorted_deps = dep_srcs
             .iter()
             .map(|s| (*s).to_string())
@@ -2888,6 +3404,10 @@ c:/foo/bar.rs:
             for d in sorted_deps.iter() {
                 writeln!(f, "{}:", d)?;
             }
+            writeln!(f)?;
+            for e in env_deps.iter() {
+                writeln!(f, "# env-dep:{}", e)?;
+            }
             Ok(MockChild::new(exit_status(0), "", ""))
         });
     }
@@ -2942,16 +3462,21 @@ c:/foo/bar.rs:
                 externs: vec!["bar.rlib".into()],
                 crate_link_paths: vec![],
                 staticlibs: vec![f.tempdir.path().join("libbaz.a")],
+                native_libs: vec![],
+                linker: None,
+                link_arg_files: vec![],
                 crate_name: "foo".into(),
                 crate_types: CrateTypes {
                     rlib: true,
                     staticlib: false,
+                    linked: false,
                 },
                 dep_info: None,
                 emit,
                 color_mode: ColorMode::Auto,
                 has_json: false,
             },
+            base_dir: None,
         });
         let creator = new_creator();
         mock_dep_info(&creator, &["foo.rs", "bar.rs"]);
@@ -2970,6 +3495,8 @@ c:/foo/bar.rs:
                 false,
                 &pool,
                 false,
+                Arc::new(MockStorage::new()),
+                false,
             )
             .wait()
             .unwrap();
@@ -3016,6 +3543,31 @@ c:/foo/bar.rs:
         env_vars: &[(OsString, OsString)],
         pre_func: F,
     ) -> String
+    where
+        F: Fn(&Path) -> Result<()>,
+    {
+        hash_result(f, args, env_vars, pre_func).key
+    }
+
+    fn hash_result<F>(
+        f: &TestFixture,
+        args: &[&'static str],
+        env_vars: &[(OsString, OsString)],
+        pre_func: F,
+    ) -> HashResult
+    where
+        F: Fn(&Path) -> Result<()>,
+    {
+        hash_result_with_env_deps(f, args, env_vars, &[], pre_func)
+    }
+
+    fn hash_result_with_env_deps<F>(
+        f: &TestFixture,
+        args: &[&'static str],
+        env_vars: &[(OsString, OsString)],
+        env_deps: &[&str],
+        pre_func: F,
+    ) -> HashResult
     where
         F: Fn(&Path) -> Result<()>,
     {
@@ -3043,11 +3595,12 @@ c:/foo/bar.rs:
             #[cfg(feature = "dist-client")]
             rlib_dep_reader: None,
             parsed_args,
+            base_dir: None,
         });
 
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
-        mock_dep_info(&creator, &["foo.rs"]);
+        mock_dep_info_with_env(&creator, &["foo.rs"], env_deps);
         mock_file_names(&creator, &["foo.rlib"]);
         hasher
             .generate_hash_key(
@@ -3057,10 +3610,11 @@ c:/foo/bar.rs:
                 false,
                 &pool,
                 false,
+                Arc::new(MockStorage::new()),
+                false,
             )
             .wait()
             .unwrap()
-            .key
     }
 
     #[allow(clippy::unnecessary_wraps)]
@@ -3266,4 +3820,178 @@ c:/foo/bar.rs:
             )
         );
     }
+
+    #[test]
+    fn test_path_independent_hashes() {
+        let args = &[
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+        ];
+        let env_vars = |f: &TestFixture, path_independent: &str| {
+            vec![
+                (
+                    OsString::from("CARGO_MANIFEST_DIR"),
+                    f.tempdir.path().as_os_str().to_owned(),
+                ),
+                (
+                    OsString::from("SCCACHE_RUST_PATH_INDEPENDENT"),
+                    OsString::from(path_independent),
+                ),
+            ]
+        };
+        // The same crate in two different directories.
+        let f1 = TestFixture::new();
+        let f2 = TestFixture::new();
+        assert_ne!(
+            hash_key(&f1, args, &env_vars(&f1, "0"), nothing),
+            hash_key(&f2, args, &env_vars(&f2, "0"), nothing)
+        );
+        assert_eq!(
+            hash_key(&f1, args, &env_vars(&f1, "1"), nothing),
+            hash_key(&f2, args, &env_vars(&f2, "1"), nothing)
+        );
+        assert_ne!(
+            hash_key(&f1, args, &env_vars(&f1, "0"), nothing),
+            hash_key(&f1, args, &env_vars(&f1, "1"), nothing)
+        );
+        // The compile remaps the crate's directory, and its dep-info is made relative.
+        let res = hash_result(&f1, args, &env_vars(&f1, "1"), nothing);
+        let (command, _, _) = res
+            .compilation
+            .generate_compile_commands(&mut dist::PathTransformer::default(), false)
+            .unwrap();
+        let mut remap = OsString::from("--remap-path-prefix=");
+        remap.push(f1.tempdir.path());
+        remap.push("=.");
+        assert!(command.arguments.contains(&remap));
+        assert_eq!(
+            Some((f1.tempdir.path().to_owned(), vec![])),
+            res.compilation.relative_depfiles()
+        );
+        // Paths the crate reads with `env!` end up in its outputs, so they're
+        // hashed as they are.
+        let env_deps = &["CARGO_MANIFEST_DIR"];
+        assert_ne!(
+            hash_result_with_env_deps(&f1, args, &env_vars(&f1, "1"), env_deps, nothing).key,
+            hash_result_with_env_deps(&f2, args, &env_vars(&f2, "1"), env_deps, nothing).key
+        );
+        let env_deps = &["CARGO_PKG_NAME"];
+        assert_eq!(
+            hash_result_with_env_deps(&f1, args, &env_vars(&f1, "1"), env_deps, nothing).key,
+            hash_result_with_env_deps(&f2, args, &env_vars(&f2, "1"), env_deps, nothing).key
+        );
+    }
+
+    #[test]
+    fn test_equal_hashes_incremental() {
+        let f = TestFixture::new();
+        let args = |incremental| {
+            let mut args = vec![
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "--crate-type",
+                "lib",
+            ];
+            if let Some(incremental) = incremental {
+                args.extend(&["-C", incremental]);
+            }
+            args
+        };
+        // The incremental directory doesn't matter, but incremental compiles
+        // are kept apart from others.
+        assert_eq!(
+            hash_key(&f, &args(Some("incremental=/a")), &[], nothing),
+            hash_key(&f, &args(Some("incremental=/b")), &[], nothing)
+        );
+        assert_ne!(
+            hash_key(&f, &args(Some("incremental=/a")), &[], nothing),
+            hash_key(&f, &args(None), &[], nothing)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hashes_link_inputs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        fn mk_files(
+            linker: &'static [u8],
+            lib: &'static [u8],
+            script: &'static [u8],
+        ) -> impl Fn(&Path) -> Result<()> {
+            move |tempdir| {
+                let linker = create_file(tempdir, "my-cc", |mut f| f.write_all(linker))?;
+                fs::set_permissions(linker, fs::Permissions::from_mode(0o755))?;
+                create_file(tempdir, "libfoo.so", |mut f| f.write_all(lib))?;
+                create_file(tempdir, "link.x", |mut f| f.write_all(script))?;
+                Ok(())
+            }
+        }
+        let args = |crate_type| {
+            [
+                "--crate-type",
+                crate_type,
+                "--emit",
+                "link",
+                "foo.rs",
+                "--out-dir",
+                "out",
+                "--crate-name",
+                "foo",
+                "-L",
+                "native=.",
+                "-l",
+                "foo",
+                "-C",
+                "linker=./my-cc",
+                "-C",
+                "link-arg=link.x",
+            ]
+        };
+        let f = TestFixture::new();
+        // The arguments are parsed before the files are rewritten, so they need to exist.
+        mk_files(b"cc", b"lib", b"x")(f.tempdir.path()).unwrap();
+        for crate_type in &["bin", "dylib", "cdylib", "proc-macro"] {
+            let key = hash_key(&f, &args(crate_type), &[], mk_files(b"cc", b"lib", b"x"));
+            assert_eq!(
+                key,
+                hash_key(&f, &args(crate_type), &[], mk_files(b"cc", b"lib", b"x"))
+            );
+            assert_ne!(
+                key,
+                hash_key(&f, &args(crate_type), &[], mk_files(b"cc2", b"lib", b"x")),
+                "linker changed for {}",
+                crate_type
+            );
+            assert_ne!(
+                key,
+                hash_key(&f, &args(crate_type), &[], mk_files(b"cc", b"lib2", b"x")),
+                "native library changed for {}",
+                crate_type
+            );
+            assert_ne!(
+                key,
+                hash_key(&f, &args(crate_type), &[], mk_files(b"cc", b"lib", b"x2")),
+                "link-arg file changed for {}",
+                crate_type
+            );
+        }
+        // An rlib isn't linked, so the linker doesn't matter.
+        assert_eq!(
+            hash_key(&f, &args("rlib"), &[], mk_files(b"cc", b"lib", b"x")),
+            hash_key(&f, &args("rlib"), &[], mk_files(b"cc2", b"lib2", b"x2"))
+        );
+    }
 }
@@ -143,7 +143,34 @@ impl HTTPUrl {
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct AzureCacheConfig;
+pub struct AzureCacheConfig {
+    /// A connection string, holding an account key or a shared access
+    /// signature.
+    pub connection_string: Option<String>,
+    /// The blob service URL, for authenticating with `sas_token` instead of
+    /// a connection string.
+    pub endpoint: Option<String>,
+    /// A shared access signature to authenticate with.
+    pub sas_token: Option<String>,
+    /// The name of the blob container.
+    pub container: String,
+    /// A prefix for the names of the blobs that entries are stored at.
+    #[serde(default)]
+    pub key_prefix: String,
+}
+
+/// How writes to a tiered cache reach the remote tier.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum CacheWritePolicy {
+    /// Writes finish once both the local and the remote tier have the entry.
+    #[serde(rename = "write_through")]
+    WriteThrough,
+    /// Writes finish once the local tier has the entry, and the remote
+    /// tier is written in the background.
+    #[serde(rename = "write_back")]
+    WriteBack,
+}
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -152,6 +179,12 @@ pub struct DiskCacheConfig {
     pub dir: PathBuf,
     // TODO: use deserialize_with to allow human-readable sizes in toml
     pub size: u64,
+    /// If set, use the disk cache as a local tier in front of the remote
+    /// cache, with this policy for writes.
+    pub local_tier: Option<CacheWritePolicy>,
+    /// The zstd level to compress entries stored on disk with, or 0 to
+    /// store them uncompressed.
+    pub compression_level: Option<i32>,
 }
 
 impl Default for DiskCacheConfig {
@@ -159,6 +192,8 @@ impl Default for DiskCacheConfig {
         DiskCacheConfig {
             dir: default_disk_cache_dir(),
             size: default_disk_cache_size(),
+            local_tier: None,
+            compression_level: None,
         }
     }
 }
@@ -179,18 +214,33 @@ pub struct GCSCacheConfig {
     pub cred_path: Option<PathBuf>,
     pub url: Option<String>,
     pub rw_mode: GCSCacheRWMode,
+    /// A prefix for the names of the objects that entries are stored at.
+    #[serde(default)]
+    pub key_prefix: String,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct MemcachedCacheConfig {
     pub url: String,
+    /// The number of seconds after which entries expire, or 0 for never.
+    /// Memcached treats values over 30 days as a Unix timestamp.
+    #[serde(default)]
+    pub expiration: u32,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct RedisCacheConfig {
+    /// A `redis://` URL, or whitespace-separated `redis+cluster://` or
+    /// `redis+sentinel://` URLs.
     pub url: String,
+    /// A prefix for the keys that entries are stored at.
+    #[serde(default)]
+    pub key_prefix: String,
+    /// The number of seconds after which entries expire, if any. 0 means
+    /// entries never expire.
+    pub ttl: Option<u64>,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -200,6 +250,98 @@ pub struct S3CacheConfig {
     pub endpoint: String,
     pub use_ssl: bool,
     pub key_prefix: String,
+    /// Entries larger than this many bytes are uploaded in parts.
+    pub multipart_threshold: Option<u64>,
+    /// The size in bytes of the parts and ranges that entries are
+    /// transferred in.
+    pub part_size: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebdavCacheConfig {
+    /// The base URL that entries are stored under.
+    pub endpoint: String,
+    /// A path under `endpoint` to store entries in.
+    #[serde(default)]
+    pub key_prefix: String,
+    /// The user name for basic authentication.
+    pub username: Option<String>,
+    /// The password for basic authentication.
+    pub password: Option<String>,
+    /// A token for bearer authentication.
+    pub token: Option<String>,
+    /// A file of PEM certificates to trust in addition to the system's roots.
+    pub ca_certs: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReapiCacheConfig {
+    /// A `grpc://` or `grpcs://` URL for the gRPC API, or an `http://` or
+    /// `https://` URL for the HTTP API.
+    pub endpoint: String,
+    /// The instance name to use with the gRPC API.
+    #[serde(default)]
+    pub instance_name: String,
+}
+
+/// Whether the cache may be read from, written to, or both.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum CacheRWMode {
+    #[serde(rename = "READ_ONLY")]
+    ReadOnly,
+    #[serde(rename = "WRITE_ONLY")]
+    WriteOnly,
+    #[serde(rename = "READ_WRITE")]
+    #[default]
+    ReadWrite,
+}
+
+impl CacheRWMode {
+    pub fn can_read(self) -> bool {
+        self != CacheRWMode::WriteOnly
+    }
+
+    pub fn can_write(self) -> bool {
+        self != CacheRWMode::ReadOnly
+    }
+}
+
+/// Keys to encrypt cache entries with, each written as
+/// `<key id>:<base64-encoded 256-bit key>`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheEncryptionConfig {
+    /// The key to encrypt new entries with.
+    pub key: Option<String>,
+    /// A file of keys, one per line. Its first key encrypts new entries if
+    /// `key` isn't set, and the others decrypt entries written with keys
+    /// that have since been rotated.
+    pub key_file: Option<PathBuf>,
+}
+
+/// Ed25519 keys to sign cache entries with and verify them against, each
+/// written as a base64-encoded 32-byte key.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheSigningConfig {
+    /// The private key to sign new entries with. Only trusted writers, such
+    /// as CI, should have one.
+    pub key: Option<String>,
+    /// The public keys of trusted writers. Entries not signed by one of
+    /// these, or `key`, are ignored.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+    /// If set, clients without a signing key write unsigned entries to this
+    /// namespace.
+    pub untrusted_namespace: Option<String>,
+    /// Whether clients without a signing key fall back to reading unsigned
+    /// entries from `untrusted_namespace`. Anyone with write access to the
+    /// cache can plant entries there, so this is off by default.
+    #[serde(default)]
+    pub read_untrusted_namespace: bool,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -207,8 +349,10 @@ pub enum CacheType {
     Azure(AzureCacheConfig),
     GCS(GCSCacheConfig),
     Memcached(MemcachedCacheConfig),
+    Reapi(ReapiCacheConfig),
     Redis(RedisCacheConfig),
     S3(S3CacheConfig),
+    Webdav(WebdavCacheConfig),
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -218,8 +362,24 @@ pub struct CacheConfigs {
     pub disk: Option<DiskCacheConfig>,
     pub gcs: Option<GCSCacheConfig>,
     pub memcached: Option<MemcachedCacheConfig>,
+    pub reapi: Option<ReapiCacheConfig>,
     pub redis: Option<RedisCacheConfig>,
     pub s3: Option<S3CacheConfig>,
+    pub webdav: Option<WebdavCacheConfig>,
+    /// Whether the cache in use may be read from and written to.
+    pub rw_mode: Option<CacheRWMode>,
+    /// Whether cache entries store their objects as separate blobs,
+    /// addressed by the digest of their contents.
+    pub content_addressed: Option<bool>,
+    /// Keys to encrypt cache entries with.
+    pub encryption: Option<CacheEncryptionConfig>,
+    /// Keys to sign cache entries with and verify them against.
+    pub signing: Option<CacheSigningConfig>,
+    /// The zstd level to compress entries stored in the remote cache with,
+    /// or 0 to store them uncompressed.
+    pub compression_level: Option<i32>,
+    /// A zstd dictionary to compress small objects with.
+    pub compression_dictionary: Option<PathBuf>,
 }
 
 impl CacheConfigs {
@@ -231,8 +391,16 @@ impl CacheConfigs {
             disk,
             gcs,
             memcached,
+            reapi,
             redis,
             s3,
+            webdav,
+            rw_mode: _,
+            content_addressed: _,
+            encryption: _,
+            signing: _,
+            compression_level: _,
+            compression_dictionary: _,
         } = self;
 
         let caches = s3
@@ -242,6 +410,8 @@ impl CacheConfigs {
             .chain(memcached.map(CacheType::Memcached))
             .chain(gcs.map(CacheType::GCS))
             .chain(azure.map(CacheType::Azure))
+            .chain(webdav.map(CacheType::Webdav))
+            .chain(reapi.map(CacheType::Reapi))
             .collect();
         let fallback = disk.unwrap_or_else(Default::default);
 
@@ -255,8 +425,16 @@ impl CacheConfigs {
             disk,
             gcs,
             memcached,
+            reapi,
             redis,
             s3,
+            webdav,
+            rw_mode,
+            content_addressed,
+            encryption,
+            signing,
+            compression_level,
+            compression_dictionary,
         } = other;
 
         if azure.is_some() {
@@ -271,12 +449,36 @@ impl CacheConfigs {
         if memcached.is_some() {
             self.memcached = memcached
         }
+        if reapi.is_some() {
+            self.reapi = reapi
+        }
         if redis.is_some() {
             self.redis = redis
         }
         if s3.is_some() {
             self.s3 = s3
         }
+        if webdav.is_some() {
+            self.webdav = webdav
+        }
+        if rw_mode.is_some() {
+            self.rw_mode = rw_mode
+        }
+        if content_addressed.is_some() {
+            self.content_addressed = content_addressed
+        }
+        if encryption.is_some() {
+            self.encryption = encryption
+        }
+        if signing.is_some() {
+            self.signing = signing
+        }
+        if compression_level.is_some() {
+            self.compression_level = compression_level
+        }
+        if compression_dictionary.is_some() {
+            self.compression_dictionary = compression_dictionary
+        }
     }
 }
 
@@ -406,6 +608,7 @@ impl Default for DistConfig {
 pub struct FileConfig {
     pub cache: CacheConfigs,
     pub dist: DistConfig,
+    pub base_dir: Option<PathBuf>,
 }
 
 // If the file doesn't exist or we can't read it, log the issue and proceed. If the
@@ -443,6 +646,17 @@ pub fn try_read_config_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T
 #[derive(Debug)]
 pub struct EnvConfig {
     cache: CacheConfigs,
+    base_dir: Option<PathBuf>,
+}
+
+fn compression_level_from_env(var: &str) -> Option<i32> {
+    env::var(var).ok().and_then(|level| match level.parse() {
+        Ok(level) => Some(level),
+        Err(_) => {
+            warn!("Invalid {}-- using the default compression level.", var);
+            None
+        }
+    })
 }
 
 fn config_from_env() -> EnvConfig {
@@ -467,22 +681,40 @@ fn config_from_env() -> EnvConfig {
             .filter(|s| !s.is_empty())
             .map(|s| s.to_owned() + "/")
             .unwrap_or_default();
+        let multipart_threshold = env::var("SCCACHE_S3_MULTIPART_THRESHOLD")
+            .ok()
+            .and_then(|value| value.parse().ok());
+        let part_size = env::var("SCCACHE_S3_PART_SIZE")
+            .ok()
+            .and_then(|value| value.parse().ok());
 
         S3CacheConfig {
             bucket,
             endpoint,
             use_ssl,
             key_prefix,
+            multipart_threshold,
+            part_size,
         }
     });
 
-    let redis = env::var("SCCACHE_REDIS")
-        .ok()
-        .map(|url| RedisCacheConfig { url });
+    let redis = env::var("SCCACHE_REDIS").ok().map(|url| RedisCacheConfig {
+        url,
+        key_prefix: env::var("SCCACHE_REDIS_KEY_PREFIX").unwrap_or_default(),
+        ttl: env::var("SCCACHE_REDIS_TTL")
+            .ok()
+            .and_then(|value| value.parse().ok()),
+    });
 
     let memcached = env::var("SCCACHE_MEMCACHED")
         .ok()
-        .map(|url| MemcachedCacheConfig { url });
+        .map(|url| MemcachedCacheConfig {
+            url,
+            expiration: env::var("SCCACHE_MEMCACHED_EXPIRATION")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_default(),
+        });
 
     let gcs = env::var("SCCACHE_GCS_BUCKET").ok().map(|bucket| {
         let url = env::var("SCCACHE_GCS_CREDENTIALS_URL").ok();
@@ -513,37 +745,152 @@ fn config_from_env() -> EnvConfig {
             cred_path,
             url,
             rw_mode,
+            key_prefix: env::var("SCCACHE_GCS_KEY_PREFIX").unwrap_or_default(),
         }
     });
 
-    let azure = env::var("SCCACHE_AZURE_CONNECTION_STRING")
+    let connection_string = env::var("SCCACHE_AZURE_CONNECTION_STRING").ok();
+    let sas_token = env::var("SCCACHE_AZURE_SAS_TOKEN").ok();
+    let azure = if connection_string.is_some() || sas_token.is_some() {
+        Some(AzureCacheConfig {
+            connection_string,
+            endpoint: env::var("SCCACHE_AZURE_BLOB_ENDPOINT").ok(),
+            sas_token,
+            container: env::var("SCCACHE_AZURE_BLOB_CONTAINER").unwrap_or_default(),
+            key_prefix: env::var("SCCACHE_AZURE_KEY_PREFIX").unwrap_or_default(),
+        })
+    } else {
+        None
+    };
+
+    let webdav = env::var("SCCACHE_WEBDAV_ENDPOINT")
         .ok()
-        .map(|_| AzureCacheConfig);
+        .map(|endpoint| WebdavCacheConfig {
+            endpoint,
+            key_prefix: env::var("SCCACHE_WEBDAV_KEY_PREFIX").unwrap_or_default(),
+            username: env::var("SCCACHE_WEBDAV_USERNAME").ok(),
+            password: env::var("SCCACHE_WEBDAV_PASSWORD").ok(),
+            token: env::var("SCCACHE_WEBDAV_TOKEN").ok(),
+            ca_certs: env::var_os("SCCACHE_WEBDAV_CA_CERTS").map(PathBuf::from),
+        });
+
+    let reapi = env::var("SCCACHE_REAPI_ENDPOINT")
+        .ok()
+        .map(|endpoint| ReapiCacheConfig {
+            endpoint,
+            instance_name: env::var("SCCACHE_REAPI_INSTANCE_NAME").unwrap_or_default(),
+        });
 
     let disk_dir = env::var_os("SCCACHE_DIR").map(PathBuf::from);
     let disk_sz = env::var("SCCACHE_CACHE_SIZE")
         .ok()
         .and_then(|v| parse_size(&v));
+    let local_tier = env::var("SCCACHE_LOCAL_TIER")
+        .ok()
+        .map(|policy| match policy.as_str() {
+            "write_through" => CacheWritePolicy::WriteThrough,
+            "write_back" => CacheWritePolicy::WriteBack,
+            _ => {
+                warn!("Invalid SCCACHE_LOCAL_TIER-- defaulting to write_through.");
+                CacheWritePolicy::WriteThrough
+            }
+        });
 
-    let disk = if disk_dir.is_some() || disk_sz.is_some() {
+    let disk_compression_level = compression_level_from_env("SCCACHE_DISK_COMPRESSION_LEVEL");
+
+    let disk = if disk_dir.is_some()
+        || disk_sz.is_some()
+        || local_tier.is_some()
+        || disk_compression_level.is_some()
+    {
         Some(DiskCacheConfig {
             dir: disk_dir.unwrap_or_else(default_disk_cache_dir),
             size: disk_sz.unwrap_or_else(default_disk_cache_size),
+            local_tier,
+            compression_level: disk_compression_level,
+        })
+    } else {
+        None
+    };
+
+    let rw_mode = env::var("SCCACHE_RW_MODE")
+        .ok()
+        .map(|mode| match mode.as_str() {
+            "READ_ONLY" => CacheRWMode::ReadOnly,
+            "WRITE_ONLY" => CacheRWMode::WriteOnly,
+            "READ_WRITE" => CacheRWMode::ReadWrite,
+            _ => {
+                warn!("Invalid SCCACHE_RW_MODE-- defaulting to READ_WRITE.");
+                CacheRWMode::ReadWrite
+            }
+        });
+
+    let content_addressed = env::var("SCCACHE_CONTENT_ADDRESSED")
+        .ok()
+        .map(|value| value != "0" && value != "false");
+
+    let key = env::var("SCCACHE_ENCRYPTION_KEY").ok();
+    let key_file = env::var_os("SCCACHE_ENCRYPTION_KEY_FILE").map(PathBuf::from);
+    let encryption = if key.is_some() || key_file.is_some() {
+        Some(CacheEncryptionConfig { key, key_file })
+    } else {
+        None
+    };
+
+    let key = env::var("SCCACHE_SIGNING_KEY").ok();
+    let trusted_keys: Vec<_> = env::var("SCCACHE_TRUSTED_KEYS")
+        .map(|keys| {
+            keys.split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default();
+    let untrusted_namespace = env::var("SCCACHE_UNTRUSTED_NAMESPACE").ok();
+    let read_untrusted_namespace = env::var("SCCACHE_READ_UNTRUSTED_NAMESPACE")
+        .ok()
+        .map(|value| value != "0" && value != "false");
+    let signing = if key.is_some()
+        || !trusted_keys.is_empty()
+        || untrusted_namespace.is_some()
+        || read_untrusted_namespace.is_some()
+    {
+        Some(CacheSigningConfig {
+            key,
+            trusted_keys,
+            untrusted_namespace,
+            read_untrusted_namespace: read_untrusted_namespace.unwrap_or(false),
         })
     } else {
         None
     };
 
+    let compression_level = compression_level_from_env("SCCACHE_COMPRESSION_LEVEL");
+    let compression_dictionary = env::var_os("SCCACHE_COMPRESSION_DICTIONARY").map(PathBuf::from);
+
     let cache = CacheConfigs {
         azure,
         disk,
         gcs,
         memcached,
+        reapi,
         redis,
         s3,
+        webdav,
+        rw_mode,
+        content_addressed,
+        encryption,
+        signing,
+        compression_level,
+        compression_dictionary,
     };
 
-    EnvConfig { cache }
+    let base_dir = env::var_os("SCCACHE_BASEDIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from);
+
+    EnvConfig { cache, base_dir }
 }
 
 // The directories crate changed the location of `config_dir` on macos in version 3,
@@ -573,7 +920,16 @@ fn config_file(env_var: &str, leaf: &str) -> PathBuf {
 pub struct Config {
     pub caches: Vec<CacheType>,
     pub fallback_cache: DiskCacheConfig,
+    pub cache_rw_mode: CacheRWMode,
+    pub cache_content_addressed: bool,
+    pub cache_encryption: Option<CacheEncryptionConfig>,
+    pub cache_signing: Option<CacheSigningConfig>,
+    pub cache_compression_level: Option<i32>,
+    pub cache_compression_dictionary: Option<PathBuf>,
     pub dist: DistConfig,
+    /// Paths under this directory are hashed relative to the working
+    /// directory, so that checkouts in different places share cache entries.
+    pub base_dir: Option<PathBuf>,
 }
 
 impl Config {
@@ -591,17 +947,47 @@ impl Config {
     fn from_env_and_file_configs(env_conf: EnvConfig, file_conf: FileConfig) -> Config {
         let mut conf_caches: CacheConfigs = Default::default();
 
-        let FileConfig { cache, dist } = file_conf;
+        let FileConfig {
+            cache,
+            dist,
+            base_dir: file_base_dir,
+        } = file_conf;
         conf_caches.merge(cache);
 
-        let EnvConfig { cache } = env_conf;
+        let EnvConfig {
+            cache,
+            base_dir: env_base_dir,
+        } = env_conf;
         conf_caches.merge(cache);
 
+        // Redis rejects an expiry of 0 rather than keeping entries forever.
+        if let Some(ref mut redis) = conf_caches.redis {
+            redis.ttl = redis.ttl.filter(|&ttl| ttl != 0);
+        }
+        let cache_rw_mode = conf_caches.rw_mode.unwrap_or_default();
+        let cache_content_addressed = conf_caches.content_addressed.unwrap_or(false);
+        let cache_encryption = conf_caches.encryption.take();
+        let cache_signing = conf_caches.signing.take();
+        let cache_compression_level = conf_caches.compression_level;
+        let cache_compression_dictionary = conf_caches.compression_dictionary.take();
         let (caches, fallback_cache) = conf_caches.into_vec_and_fallback();
+        let base_dir = env_base_dir.or(file_base_dir).filter(|dir| {
+            if !dir.is_absolute() {
+                warn!("Ignoring base_dir {}, it isn't absolute", dir.display());
+            }
+            dir.is_absolute()
+        });
         Config {
             caches,
             fallback_cache,
+            cache_rw_mode,
+            cache_content_addressed,
+            cache_encryption,
+            cache_signing,
+            cache_compression_level,
+            cache_compression_dictionary,
             dist,
+            base_dir,
         }
     }
 }
@@ -814,16 +1200,28 @@ fn test_parse_size() {
 fn config_overrides() {
     let env_conf = EnvConfig {
         cache: CacheConfigs {
-            azure: Some(AzureCacheConfig),
+            azure: Some(AzureCacheConfig {
+                connection_string: Some("myconnectionstring".to_owned()),
+                endpoint: None,
+                sas_token: None,
+                container: "mycontainer".to_owned(),
+                key_prefix: String::new(),
+            }),
             disk: Some(DiskCacheConfig {
                 dir: "/env-cache".into(),
                 size: 5,
+                local_tier: Some(CacheWritePolicy::WriteBack),
+                compression_level: None,
             }),
             redis: Some(RedisCacheConfig {
                 url: "myotherredisurl".to_owned(),
+                key_prefix: String::new(),
+                ttl: None,
             }),
+            rw_mode: Some(CacheRWMode::ReadOnly),
             ..Default::default()
         },
+        base_dir: None,
     };
 
     let file_conf = FileConfig {
@@ -831,16 +1229,23 @@ fn config_overrides() {
             disk: Some(DiskCacheConfig {
                 dir: "/file-cache".into(),
                 size: 15,
+                local_tier: None,
+                compression_level: None,
             }),
             memcached: Some(MemcachedCacheConfig {
                 url: "memurl".to_owned(),
+                expiration: 0,
             }),
             redis: Some(RedisCacheConfig {
                 url: "myredisurl".to_owned(),
+                key_prefix: String::new(),
+                ttl: None,
             }),
+            rw_mode: Some(CacheRWMode::WriteOnly),
             ..Default::default()
         },
         dist: Default::default(),
+        base_dir: None,
     };
 
     assert_eq!(
@@ -848,22 +1253,401 @@ fn config_overrides() {
         Config {
             caches: vec![
                 CacheType::Redis(RedisCacheConfig {
-                    url: "myotherredisurl".to_owned()
+                    url: "myotherredisurl".to_owned(),
+                    key_prefix: String::new(),
+                    ttl: None,
                 }),
                 CacheType::Memcached(MemcachedCacheConfig {
-                    url: "memurl".to_owned()
+                    url: "memurl".to_owned(),
+                    expiration: 0,
+                }),
+                CacheType::Azure(AzureCacheConfig {
+                    connection_string: Some("myconnectionstring".to_owned()),
+                    endpoint: None,
+                    sas_token: None,
+                    container: "mycontainer".to_owned(),
+                    key_prefix: String::new(),
                 }),
-                CacheType::Azure(AzureCacheConfig),
             ],
             fallback_cache: DiskCacheConfig {
                 dir: "/env-cache".into(),
                 size: 5,
+                local_tier: Some(CacheWritePolicy::WriteBack),
+                compression_level: None,
             },
+            cache_rw_mode: CacheRWMode::ReadOnly,
+            cache_content_addressed: false,
+            cache_encryption: None,
+            cache_signing: None,
+            cache_compression_level: None,
+            cache_compression_dictionary: None,
             dist: Default::default(),
+            base_dir: None,
         }
     );
 }
 
+#[test]
+fn test_local_tier_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.disk]
+        dir = "/file-cache"
+        local_tier = "write_back"
+
+        [cache.redis]
+        url = "myredisurl"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.disk,
+        Some(DiskCacheConfig {
+            dir: "/file-cache".into(),
+            size: default_disk_cache_size(),
+            local_tier: Some(CacheWritePolicy::WriteBack),
+            compression_level: None,
+        })
+    );
+}
+
+#[test]
+fn test_compression_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache]
+        compression_level = 19
+        compression_dictionary = "/etc/sccache/dictionary"
+
+        [cache.disk]
+        compression_level = 0
+        "#,
+    )
+    .unwrap();
+    let env_conf = EnvConfig {
+        cache: Default::default(),
+        base_dir: None,
+    };
+    let config = Config::from_env_and_file_configs(env_conf, file_conf);
+    assert_eq!(config.cache_compression_level, Some(19));
+    assert_eq!(
+        config.cache_compression_dictionary,
+        Some("/etc/sccache/dictionary".into())
+    );
+    assert_eq!(config.fallback_cache.compression_level, Some(0));
+}
+
+#[test]
+fn test_azure_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.azure]
+        endpoint = "https://account.blob.core.windows.net"
+        sas_token = "sv=2020-08-04&sp=rl&sig=abc"
+        container = "sccache"
+        key_prefix = "project-a"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.azure,
+        Some(AzureCacheConfig {
+            connection_string: None,
+            endpoint: Some("https://account.blob.core.windows.net".to_owned()),
+            sas_token: Some("sv=2020-08-04&sp=rl&sig=abc".to_owned()),
+            container: "sccache".to_owned(),
+            key_prefix: "project-a".to_owned(),
+        })
+    );
+}
+
+#[test]
+fn test_gcs_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.gcs]
+        bucket = "sccache"
+        rw_mode = "READ_WRITE"
+        key_prefix = "project-a"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.gcs,
+        Some(GCSCacheConfig {
+            bucket: "sccache".to_owned(),
+            cred_path: None,
+            url: None,
+            rw_mode: GCSCacheRWMode::ReadWrite,
+            key_prefix: "project-a".to_owned(),
+        })
+    );
+}
+
+#[test]
+fn test_s3_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.s3]
+        bucket = "name"
+        endpoint = "s3.amazonaws.com"
+        use_ssl = true
+        key_prefix = "prefix/"
+        multipart_threshold = 67108864
+        part_size = 16777216
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.s3,
+        Some(S3CacheConfig {
+            bucket: "name".to_owned(),
+            endpoint: "s3.amazonaws.com".to_owned(),
+            use_ssl: true,
+            key_prefix: "prefix/".to_owned(),
+            multipart_threshold: Some(67_108_864),
+            part_size: Some(16_777_216),
+        })
+    );
+}
+
+#[test]
+fn test_webdav_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.webdav]
+        endpoint = "https://cache.example.com/dav"
+        key_prefix = "sccache"
+        token = "secret"
+        ca_certs = "/etc/ssl/example.pem"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.webdav,
+        Some(WebdavCacheConfig {
+            endpoint: "https://cache.example.com/dav".to_owned(),
+            key_prefix: "sccache".to_owned(),
+            username: None,
+            password: None,
+            token: Some("secret".to_owned()),
+            ca_certs: Some("/etc/ssl/example.pem".into()),
+        })
+    );
+}
+
+#[test]
+fn test_redis_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.redis]
+        url = "redis+sentinel://sentinel-a:26379/main redis+sentinel://sentinel-b:26379/main"
+        key_prefix = "project-a:"
+        ttl = 604800
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.redis,
+        Some(RedisCacheConfig {
+            url: "redis+sentinel://sentinel-a:26379/main redis+sentinel://sentinel-b:26379/main"
+                .to_owned(),
+            key_prefix: "project-a:".to_owned(),
+            ttl: Some(604800),
+        })
+    );
+}
+
+#[test]
+fn test_redis_zero_ttl() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.redis]
+        url = "redis://localhost"
+        ttl = 0
+        "#,
+    )
+    .unwrap();
+    let env_conf = EnvConfig {
+        cache: Default::default(),
+        base_dir: None,
+    };
+    let config = Config::from_env_and_file_configs(env_conf, file_conf);
+    assert_eq!(
+        config.caches,
+        vec![CacheType::Redis(RedisCacheConfig {
+            url: "redis://localhost".to_owned(),
+            key_prefix: "".to_owned(),
+            ttl: None,
+        })]
+    );
+}
+
+#[test]
+fn test_memcached_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.memcached]
+        url = "tcp://127.0.0.1:11211"
+        expiration = 86400
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.memcached,
+        Some(MemcachedCacheConfig {
+            url: "tcp://127.0.0.1:11211".to_owned(),
+            expiration: 86400,
+        })
+    );
+}
+
+#[test]
+fn test_reapi_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.reapi]
+        endpoint = "grpcs://remote.example.com:443"
+        instance_name = "main"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        file_conf.cache.reapi,
+        Some(ReapiCacheConfig {
+            endpoint: "grpcs://remote.example.com:443".to_owned(),
+            instance_name: "main".to_owned(),
+        })
+    );
+}
+
+#[test]
+fn test_rw_mode_file_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache]
+        rw_mode = "READ_ONLY"
+
+        [cache.s3]
+        bucket = "name"
+        endpoint = "s3.amazonaws.com"
+        use_ssl = true
+        key_prefix = "prefix"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(file_conf.cache.rw_mode, Some(CacheRWMode::ReadOnly));
+    let config = Config::from_env_and_file_configs(
+        EnvConfig {
+            cache: Default::default(),
+            base_dir: None,
+        },
+        file_conf,
+    );
+    assert_eq!(config.cache_rw_mode, CacheRWMode::ReadOnly);
+    assert!(config.cache_rw_mode.can_read());
+    assert!(!config.cache_rw_mode.can_write());
+}
+
+#[test]
+fn test_content_addressed_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache]
+        content_addressed = true
+        "#,
+    )
+    .unwrap();
+    let env_conf = |content_addressed| EnvConfig {
+        cache: CacheConfigs {
+            content_addressed,
+            ..Default::default()
+        },
+        base_dir: None,
+    };
+    let config = Config::from_env_and_file_configs(env_conf(None), file_conf);
+    assert!(config.cache_content_addressed);
+    // The environment overrides the config file.
+    let file_conf: FileConfig = toml::from_str("[cache]\ncontent_addressed = true").unwrap();
+    let config = Config::from_env_and_file_configs(env_conf(Some(false)), file_conf);
+    assert!(!config.cache_content_addressed);
+}
+
+#[test]
+fn test_encryption_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.encryption]
+        key_file = "/etc/sccache/keys"
+        "#,
+    )
+    .unwrap();
+    let env_conf = EnvConfig {
+        cache: Default::default(),
+        base_dir: None,
+    };
+    let config = Config::from_env_and_file_configs(env_conf, file_conf);
+    assert_eq!(
+        config.cache_encryption,
+        Some(CacheEncryptionConfig {
+            key: None,
+            key_file: Some("/etc/sccache/keys".into()),
+        })
+    );
+}
+
+#[test]
+fn test_signing_config() {
+    let file_conf: FileConfig = toml::from_str(
+        r#"
+        [cache.signing]
+        trusted_keys = ["O2onvM62pC1io6jQKm8Nc2UyFXcd4kOmOsBIoYtZ2ik="]
+        untrusted_namespace = "untrusted"
+        read_untrusted_namespace = true
+        "#,
+    )
+    .unwrap();
+    let env_conf = EnvConfig {
+        cache: Default::default(),
+        base_dir: None,
+    };
+    let config = Config::from_env_and_file_configs(env_conf, file_conf);
+    assert_eq!(
+        config.cache_signing,
+        Some(CacheSigningConfig {
+            key: None,
+            trusted_keys: vec!["O2onvM62pC1io6jQKm8Nc2UyFXcd4kOmOsBIoYtZ2ik=".to_owned()],
+            untrusted_namespace: Some("untrusted".to_owned()),
+            read_untrusted_namespace: true,
+        })
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_base_dir_config() {
+    let file_conf = || -> FileConfig {
+        toml::from_str(
+            r#"
+            base_dir = "/file-base"
+            "#,
+        )
+        .unwrap()
+    };
+    let env_conf = |base_dir: Option<&str>| EnvConfig {
+        cache: Default::default(),
+        base_dir: base_dir.map(PathBuf::from),
+    };
+    let config = Config::from_env_and_file_configs(env_conf(None), file_conf());
+    assert_eq!(config.base_dir, Some(PathBuf::from("/file-base")));
+    // The environment takes precedence over the file.
+    let config = Config::from_env_and_file_configs(env_conf(Some("/env-base")), file_conf());
+    assert_eq!(config.base_dir, Some(PathBuf::from("/env-base")));
+    // Relative paths are ignored.
+    let config = Config::from_env_and_file_configs(env_conf(Some("base")), Default::default());
+    assert_eq!(config.base_dir, None);
+}
+
 #[test]
 fn test_gcs_credentials_url() {
     env::set_var("SCCACHE_GCS_BUCKET", "my-bucket");
@@ -54,6 +54,8 @@ mod jobserver;
 pub mod lru_disk_cache;
 mod mock_command;
 mod protocol;
+#[cfg(feature = "reapi")]
+mod reapi;
 pub mod server;
 #[cfg(feature = "simple-s3")]
 mod simples3;
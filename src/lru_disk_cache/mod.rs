@@ -1,4 +1,5 @@
 pub mod lru_cache;
+mod shared;
 
 use std::borrow::Borrow;
 use std::boxed::Box;
@@ -14,6 +15,7 @@ use std::path::{Path, PathBuf};
 
 use filetime::{set_file_times, FileTime};
 pub use lru_cache::{LruCache, Meter};
+use shared::{Record, Replay, SharedIndex};
 use walkdir::WalkDir;
 
 struct FileSize;
@@ -59,6 +61,8 @@ fn get_all_files<P: AsRef<Path>>(path: P) -> Box<dyn Iterator<Item = (PathBuf, u
 pub struct LruDiskCache<S: BuildHasher = RandomState> {
     lru: LruCache<OsString, u64, S, FileSize>,
     root: PathBuf,
+    /// The index shared with other processes using `root`, if any.
+    index: Option<SharedIndex>,
 }
 
 /// Errors returned by this crate.
@@ -127,10 +131,44 @@ impl LruDiskCache {
         LruDiskCache {
             lru: LruCache::with_meter(size, FileSize),
             root: PathBuf::from(path),
+            index: None,
         }
         .init()
     }
 
+    /// Create an `LruDiskCache` that stores files in `path`, limited to `size` bytes, and that
+    /// other processes can use at the same time.
+    ///
+    /// The processes keep an index of the files in the cache in `path`, which they only change
+    /// with a lock on it held, so that they agree on the size of the cache and evict the files
+    /// that were used least recently by any of them. Each process evicts files to keep the cache
+    /// within its own `size`, so they should all use the same one. The index is built from the
+    /// existing files in `path` as in `new` when it doesn't exist yet, but after that files under
+    /// `path` that weren't added through an `LruDiskCache` aren't noticed. Files whose names
+    /// start with `.` are reserved for the index.
+    ///
+    /// `size`, `len` and `contains_key` reflect the changes made by other processes as of the
+    /// last call to one of the other methods, or to `refresh`.
+    pub fn new_shared<T>(path: T, size: u64) -> Result<Self>
+    where
+        PathBuf: From<T>,
+    {
+        let mut cache = LruDiskCache {
+            lru: LruCache::with_meter(size, FileSize),
+            root: PathBuf::from(path),
+            index: None,
+        };
+        fs::create_dir_all(&cache.root)?;
+        cache.index = Some(SharedIndex::open(&cache.root)?);
+        cache.refresh()?;
+        Ok(cache)
+    }
+
+    /// Catch up with the changes other processes made to the cache, if it's shared.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.locked(|_| Ok(()))
+    }
+
     /// Return the current size of all the files in the cache.
     pub fn size(&self) -> u64 {
         self.lru.size()
@@ -163,7 +201,17 @@ impl LruDiskCache {
     /// Scan `self.root` for existing files and store them.
     fn init(mut self) -> Result<Self> {
         fs::create_dir_all(&self.root)?;
+        self.scan();
+        Ok(self)
+    }
+
+    /// Add the existing files in `self.root` to the cache.
+    fn scan(&mut self) {
+        let is_shared = self.index.is_some();
         for (file, size) in get_all_files(&self.root) {
+            if is_shared && shared::is_private_file(&file) {
+                continue;
+            }
             if !self.can_store(size) {
                 fs::remove_file(file).unwrap_or_else(|e| {
                     error!(
@@ -176,7 +224,90 @@ impl LruDiskCache {
                     .unwrap_or_else(|e| error!("Error adding file: {}", e));
             }
         }
-        Ok(self)
+    }
+
+    /// Run `f` with the shared index, if any, locked and up to date, then write the changes `f`
+    /// made to the index.
+    fn locked<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        match self.index {
+            Some(ref index) => index.lock()?,
+            None => return f(self),
+        }
+        let result = self.sync().and_then(|()| {
+            let value = f(self);
+            self.write_index()?;
+            value
+        });
+        if let Some(ref index) = self.index {
+            index.unlock();
+        }
+        result
+    }
+
+    /// Replay the changes other processes made to the shared index.
+    fn sync(&mut self) -> Result<()> {
+        let replay = match self.index {
+            Some(ref mut index) => index.read()?,
+            None => return Ok(()),
+        };
+        match replay {
+            Replay::Rescan => {
+                self.lru.clear();
+                self.scan();
+                if let Some(ref mut index) = self.index {
+                    index.snapshot(self.lru.iter())?;
+                }
+            }
+            Replay::Reset(records) => {
+                self.lru.clear();
+                self.replay(records);
+            }
+            Replay::Append(records) => self.replay(records),
+        }
+        Ok(())
+    }
+
+    fn replay(&mut self, records: Vec<Record>) {
+        for record in records {
+            match record {
+                Record::Insert(rel_path, size) => {
+                    self.lru.remove(&rel_path);
+                    if self.can_store(size) {
+                        self.make_room(size);
+                        self.lru.insert(rel_path, size);
+                    } else {
+                        // Added by a process with a larger size limit.
+                        self.remove_evicted(&rel_path);
+                        self.record(Record::Remove(rel_path));
+                    }
+                }
+                Record::Use(rel_path) => {
+                    self.lru.get(&rel_path);
+                }
+                Record::Remove(rel_path) => {
+                    self.lru.remove(&rel_path);
+                }
+            }
+        }
+    }
+
+    /// Write the changes made to the cache to the shared index.
+    fn write_index(&mut self) -> Result<()> {
+        if let Some(ref mut index) = self.index {
+            if index.should_compact(self.lru.len()) {
+                index.snapshot(self.lru.iter())?;
+            } else {
+                index.append()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Note a change to the cache to write to the shared index, if any.
+    fn record(&mut self, record: Record) {
+        if let Some(ref mut index) = self.index {
+            index.record(record);
+        }
     }
 
     /// Returns `true` if the disk cache can store a file of `size` bytes.
@@ -193,18 +324,39 @@ impl LruDiskCache {
             AddFile::AbsPath(ref p) => p.strip_prefix(&self.root).expect("Bad path?").as_os_str(),
             AddFile::RelPath(p) => p,
         };
+        self.lru.remove(rel_path);
+        self.make_room(size);
+        self.record(Record::Insert(rel_path.to_owned(), size));
+        self.lru.insert(rel_path.to_owned(), size);
+        Ok(())
+    }
+
+    /// Evict the least-recently-used files until a file of `size` bytes fits in the cache.
+    fn make_room(&mut self, size: u64) {
         //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
         while self.lru.size() as u64 + size > self.lru.capacity() as u64 {
             let (rel_path, _) = self.lru.remove_lru().expect("Unexpectedly empty cache!");
-            let remove_path = self.rel_to_abs_path(rel_path);
+            self.remove_evicted(&rel_path);
+            self.record(Record::Remove(rel_path));
+        }
+    }
+
+    /// Remove the file at `rel_path`, which has been evicted from the cache.
+    fn remove_evicted(&self, rel_path: &OsStr) {
+        let remove_path = self.rel_to_abs_path(rel_path);
+        match fs::remove_file(&remove_path) {
+            Ok(()) => {}
+            // Other processes sharing the cache may have the file open, which stops it being
+            // removed on Windows, or may have removed it already.
+            Err(e) if self.index.is_some() => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Error removing file from cache: `{:?}`: {}", remove_path, e);
+                }
+            }
             //TODO: check that files are removable during `init`, so that this is only
             // due to outside interference.
-            fs::remove_file(&remove_path).unwrap_or_else(|e| {
-                panic!("Error removing file from cache: `{:?}`: {}", remove_path, e)
-            });
+            Err(e) => panic!("Error removing file from cache: `{:?}`: {}", remove_path, e),
         }
-        self.lru.insert(rel_path.to_owned(), size);
-        Ok(())
     }
 
     fn insert_by<K: AsRef<OsStr>, F: FnOnce(&Path) -> io::Result<()>>(
@@ -221,22 +373,38 @@ impl LruDiskCache {
         let rel_path = key.as_ref();
         let path = self.rel_to_abs_path(rel_path);
         fs::create_dir_all(path.parent().expect("Bad path?"))?;
-        by(&path)?;
+        // Other processes sharing the cache may be reading the file being replaced, so write
+        // the new one elsewhere and only rename it into place with the index locked.
+        let written = if self.index.is_some() {
+            shared::temp_path(&path)
+        } else {
+            path.clone()
+        };
+        by(&written).inspect_err(|_| {
+            if written != path {
+                let _ = fs::remove_file(&written);
+            }
+        })?;
         let size = match size {
             Some(size) => size,
-            None => fs::metadata(path)?.len(),
+            None => fs::metadata(&written)?.len(),
         };
-        self.add_file(AddFile::RelPath(rel_path), size)
-            .map_err(|e| {
-                error!(
-                    "Failed to insert file `{}`: {}",
-                    rel_path.to_string_lossy(),
-                    e
-                );
-                fs::remove_file(&self.rel_to_abs_path(rel_path))
-                    .expect("Failed to remove file we just created!");
+        self.locked(|c| {
+            if written != path {
+                fs::rename(&written, &path)?;
+            }
+            c.add_file(AddFile::RelPath(rel_path), size)
+        })
+        .map_err(|e| {
+            error!(
+                "Failed to insert file `{}`: {}",
+                rel_path.to_string_lossy(),
                 e
-            })
+            );
+            let created = if written.exists() { &written } else { &path };
+            fs::remove_file(created).expect("Failed to remove file we just created!");
+            e
+        })
     }
 
     /// Add a file by calling `with` with the open `File` corresponding to the cache at path `key`.
@@ -272,7 +440,8 @@ impl LruDiskCache {
         })
     }
 
-    /// Return `true` if a file with path `key` is in the cache.
+    /// Return `true` if a file with path `key` is in the cache. For a shared cache, this doesn't
+    /// reflect changes made by other processes since they were last caught up with.
     pub fn contains_key<K: AsRef<OsStr>>(&self, key: K) -> bool {
         self.lru.contains_key(key.as_ref())
     }
@@ -282,14 +451,13 @@ impl LruDiskCache {
     pub fn get_file<K: AsRef<OsStr>>(&mut self, key: K) -> Result<File> {
         let rel_path = key.as_ref();
         let path = self.rel_to_abs_path(rel_path);
-        self.lru
-            .get(rel_path)
-            .ok_or(Error::FileNotInCache)
-            .and_then(|_| {
-                let t = FileTime::now();
-                set_file_times(&path, t, t)?;
-                File::open(path).map_err(Into::into)
-            })
+        self.locked(|c| {
+            c.lru.get(rel_path).ok_or(Error::FileNotInCache)?;
+            c.record(Record::Use(rel_path.to_owned()));
+            let t = FileTime::now();
+            set_file_times(&path, t, t)?;
+            File::open(path).map_err(Into::into)
+        })
     }
 
     /// Get an opened readable and seekable handle to the file at `key`, if one exists and can
@@ -300,16 +468,18 @@ impl LruDiskCache {
 
     /// Remove the given key from the cache.
     pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Result<()> {
-        match self.lru.remove(key.as_ref()) {
+        let rel_path = key.as_ref();
+        self.locked(|c| match c.lru.remove(rel_path) {
             Some(_) => {
-                let path = self.rel_to_abs_path(key.as_ref());
+                c.record(Record::Remove(rel_path.to_owned()));
+                let path = c.rel_to_abs_path(rel_path);
                 fs::remove_file(&path).map_err(|e| {
                     error!("Error removing file from cache: `{:?}`: {}", path, e);
                     Into::into(e)
                 })
             }
             None => Ok(()),
-        }
+        })
     }
 }
 
@@ -567,4 +737,101 @@ mod tests {
         assert!(!f.tmp().join("cache").join("file2").exists());
         assert!(!p4.exists());
     }
+
+    #[test]
+    fn test_shared_size_limit() {
+        let f = TestFixture::new();
+        let mut c1 = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+        let mut c2 = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+        c1.insert_bytes("file1", &[1; 10]).unwrap();
+        c2.insert_bytes("file2", &[2; 10]).unwrap();
+        assert_eq!(c2.size(), 20);
+        // Get the file the other cache added to bump its LRU status.
+        assert_eq!(
+            read_all(&mut c2.get("file1").unwrap()).unwrap(),
+            vec![1u8; 10]
+        );
+        // Adding this third file should put the shared cache above the limit.
+        c1.insert_bytes("file3", &[3; 10]).unwrap();
+        assert_eq!(c1.size(), 20);
+        assert!(!c1.contains_key("file2"));
+        assert!(!f.tmp().join("file2").exists());
+        c2.refresh().unwrap();
+        assert_eq!(c2.size(), 20);
+        assert!(!c2.contains_key("file2"));
+        assert!(c2.contains_key("file3"));
+        c2.remove("file3").unwrap();
+        c1.refresh().unwrap();
+        assert_eq!(c1.len(), 1);
+        assert!(c1.contains_key("file1"));
+    }
+
+    #[test]
+    fn test_shared_index() {
+        let f = TestFixture::new();
+        f.create_file("file1", 10);
+        {
+            let mut c = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+            assert_eq!(c.len(), 1);
+            c.insert_bytes("a/b/c", &[0; 10]).unwrap();
+        }
+        // Files that aren't added through a cache once the index exists aren't noticed.
+        f.create_file("file2", 10);
+        {
+            let c = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+            assert_eq!(c.size(), 20);
+            assert!(c.contains_key("file1"));
+            assert!(c.contains_key("a/b/c"));
+            assert!(!c.contains_key("file2"));
+        }
+        // Without the index, the cache is rebuilt from the files on disk.
+        fs::remove_file(f.tmp().join(".lru-index")).unwrap();
+        let c = LruDiskCache::new_shared(f.tmp(), 35).unwrap();
+        assert_eq!(c.size(), 30);
+        assert!(c.contains_key("file2"));
+    }
+
+    #[test]
+    fn test_shared_index_compaction() {
+        let f = TestFixture::new();
+        let mut c1 = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+        let mut c2 = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+        c1.insert_bytes("file1", &[1; 10]).unwrap();
+        c2.insert_bytes("file2", &[2; 10]).unwrap();
+        // Enough uses to have the index replaced with a snapshot.
+        for _ in 0..2000 {
+            c1.get("file1").unwrap();
+        }
+        let index_len = fs::metadata(f.tmp().join(".lru-index")).unwrap().len();
+        assert!(index_len < 1024 * 10);
+        c2.refresh().unwrap();
+        assert_eq!(c2.len(), 2);
+        // file1 is still the most recently used.
+        c2.insert_bytes("file3", &[3; 10]).unwrap();
+        assert!(c2.contains_key("file1"));
+        assert!(!c2.contains_key("file2"));
+    }
+
+    #[test]
+    fn test_shared_index_incomplete_record() {
+        let f = TestFixture::new();
+        {
+            let mut c = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+            c.insert_bytes("file1", &[1; 10]).unwrap();
+        }
+        let mut index = fs::OpenOptions::new()
+            .append(true)
+            .open(f.tmp().join(".lru-index"))
+            .unwrap();
+        index.write_all(b"+ 10 fi").unwrap();
+        {
+            let mut c = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+            assert_eq!(c.len(), 1);
+            c.insert_bytes("file2", &[2; 10]).unwrap();
+        }
+        let c = LruDiskCache::new_shared(f.tmp(), 25).unwrap();
+        assert_eq!(c.len(), 2);
+        assert!(c.contains_key("file1"));
+        assert!(c.contains_key("file2"));
+    }
 }
@@ -0,0 +1,408 @@
+//! An on-disk index that lets several processes share one `LruDiskCache`.
+//!
+//! The index is a journal of the files added to, used from and removed from the cache, in order.
+//! Each process appends the changes it makes and replays the ones other processes made before it
+//! reads or changes the cache, so that they all agree on which files are in the cache, how large
+//! it is and which files were used least recently. The journal and the files in the cache are
+//! only changed with an advisory lock held on a separate lock file.
+//!
+//! When the journal grows much longer than the cache has entries, it's replaced with a snapshot
+//! of the cache, under a new generation number so that other processes know to replay it from
+//! the start.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the journal in the cache directory.
+const INDEX_FILE: &str = ".lru-index";
+/// The name of the lock file in the cache directory.
+const LOCK_FILE: &str = ".lru-index.lock";
+/// The first line of the journal, followed by its generation.
+const MAGIC: &str = "sccache-lru-index 1";
+/// The journal isn't compacted until it has at least this many records.
+const MIN_COMPACT_RECORDS: u64 = 1024;
+
+/// A change to the contents or the LRU order of the cache.
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    /// A file of the given size was added to the cache.
+    Insert(OsString, u64),
+    /// A file in the cache was used.
+    Use(OsString),
+    /// A file was removed from the cache.
+    Remove(OsString),
+}
+
+impl Record {
+    fn write_to(&self, out: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Record::Insert(path, size) => write_insert(out, path, *size),
+            Record::Use(path) => writeln!(out, "* {}", path_str(path)?),
+            Record::Remove(path) => writeln!(out, "- {}", path_str(path)?),
+        }
+    }
+
+    fn parse(line: &str) -> Option<Record> {
+        let (op, path) = (line.get(..2)?, &line[2..]);
+        match op {
+            "+ " => {
+                let mut parts = path.splitn(2, ' ');
+                let size = parts.next()?.parse().ok()?;
+                let path = parts.next().filter(|p| !p.is_empty())?;
+                Some(Record::Insert(path.into(), size))
+            }
+            "* " if !path.is_empty() => Some(Record::Use(path.into())),
+            "- " if !path.is_empty() => Some(Record::Remove(path.into())),
+            _ => None,
+        }
+    }
+}
+
+fn write_insert(out: &mut Vec<u8>, path: &OsStr, size: u64) -> io::Result<()> {
+    writeln!(out, "+ {} {}", size, path_str(path)?)
+}
+
+fn path_str(path: &OsStr) -> io::Result<&str> {
+    path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Can't add non-UTF-8 path {:?} to the cache index", path),
+        )
+    })
+}
+
+/// What has to be done to bring a cache up to date with the journal.
+pub enum Replay {
+    /// There's no usable journal, so the cache has to be rebuilt from the files on disk.
+    Rescan,
+    /// The journal was replaced since it was last read, so the cache has to be cleared before
+    /// these records are replayed.
+    Reset(Vec<Record>),
+    /// These records were appended since the journal was last read.
+    Append(Vec<Record>),
+}
+
+/// The journal of a cache shared with other processes.
+pub struct SharedIndex {
+    index_path: PathBuf,
+    lock: File,
+    /// The generation of the journal when it was last read or written.
+    generation: u64,
+    /// The offset of the end of the last record read or written.
+    offset: u64,
+    /// The number of records in the journal.
+    records: u64,
+    /// Records of the changes made since the journal was last written.
+    pending: Vec<Record>,
+}
+
+impl SharedIndex {
+    /// Open the index of the cache in `root`.
+    pub fn open(root: &Path) -> io::Result<SharedIndex> {
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(root.join(LOCK_FILE))?;
+        Ok(SharedIndex {
+            index_path: root.join(INDEX_FILE),
+            lock,
+            generation: 0,
+            offset: 0,
+            records: 0,
+            pending: vec![],
+        })
+    }
+
+    /// Wait for exclusive access to the index, and the cache.
+    pub fn lock(&self) -> io::Result<()> {
+        sys::lock(&self.lock)
+    }
+
+    pub fn unlock(&self) {
+        sys::unlock(&self.lock).unwrap_or_else(|e| error!("Error unlocking cache index: {}", e));
+    }
+
+    /// Note a change to write to the journal.
+    pub fn record(&mut self, record: Record) {
+        self.pending.push(record);
+    }
+
+    /// Read the records other processes wrote since the journal was last read or written. Must
+    /// be called with the lock held.
+    pub fn read(&mut self) -> io::Result<Replay> {
+        self.pending.clear();
+        let replay = self.read_journal();
+        if replay.is_err() {
+            self.invalidate();
+        }
+        replay
+    }
+
+    fn read_journal(&mut self) -> io::Result<Replay> {
+        let file = match File::open(&self.index_path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Replay::Rescan),
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let prefix = format!("{} ", MAGIC);
+        let generation = if header.starts_with(&prefix) && header.ends_with('\n') {
+            header[prefix.len()..header.len() - 1].parse().ok()
+        } else {
+            None
+        };
+        let generation = match generation {
+            Some(generation) => generation,
+            None => {
+                warn!("Ignoring unrecognized cache index {:?}", self.index_path);
+                return Ok(Replay::Rescan);
+            }
+        };
+        let reset = generation != self.generation || len < self.offset;
+        if reset {
+            self.generation = generation;
+            self.offset = header.len() as u64;
+            self.records = 0;
+        } else {
+            reader.seek(SeekFrom::Start(self.offset))?;
+        }
+        let mut records = vec![];
+        let mut line = vec![];
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            if line.last() != Some(&b'\n') {
+                // A process stopped part-way through appending this record. Drop it, so that the
+                // next record appended doesn't run into it.
+                warn!("Dropping incomplete record from cache index");
+                OpenOptions::new()
+                    .write(true)
+                    .open(&self.index_path)?
+                    .set_len(self.offset)?;
+                break;
+            }
+            self.offset += read as u64;
+            self.records += 1;
+            match std::str::from_utf8(&line[..read - 1])
+                .ok()
+                .and_then(Record::parse)
+            {
+                Some(record) => records.push(record),
+                None => warn!(
+                    "Ignoring malformed cache index record {:?}",
+                    String::from_utf8_lossy(&line)
+                ),
+            }
+        }
+        Ok(if reset {
+            Replay::Reset(records)
+        } else {
+            Replay::Append(records)
+        })
+    }
+
+    /// Return `true` if the journal should be replaced with a snapshot of the cache, which has
+    /// `entries` entries, instead of having the pending records appended.
+    pub fn should_compact(&self, entries: usize) -> bool {
+        self.records + self.pending.len() as u64 > MIN_COMPACT_RECORDS.max(4 * entries as u64)
+    }
+
+    /// Append the pending records to the journal. Must be called with the lock held, after `read`.
+    pub fn append(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let appended = self.append_pending();
+        if appended.is_err() {
+            self.invalidate();
+        }
+        appended
+    }
+
+    fn append_pending(&mut self) -> io::Result<()> {
+        let pending = mem::take(&mut self.pending);
+        let mut buf = vec![];
+        for record in &pending {
+            record.write_to(&mut buf)?;
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(&self.index_path)?
+            .write_all(&buf)?;
+        self.offset += buf.len() as u64;
+        self.records += pending.len() as u64;
+        Ok(())
+    }
+
+    /// Replace the journal with one that inserts `entries` in order, dropping the pending
+    /// records. Must be called with the lock held, after `read`.
+    pub fn snapshot<'a, I>(&mut self, entries: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (&'a OsString, &'a u64)>,
+    {
+        self.pending.clear();
+        let snapshot = self.write_snapshot(entries);
+        if snapshot.is_err() {
+            self.invalidate();
+        }
+        snapshot
+    }
+
+    fn write_snapshot<'a, I>(&mut self, entries: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (&'a OsString, &'a u64)>,
+    {
+        // Later generations only have to differ from the ones other processes last read, which
+        // are all earlier, but using the time also avoids reusing them if the journal is deleted.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let generation = now.max(self.generation + 1);
+        let mut buf = format!("{} {}\n", MAGIC, generation).into_bytes();
+        let mut records = 0;
+        for (path, &size) in entries {
+            write_insert(&mut buf, path, size)?;
+            records += 1;
+        }
+        let tmp_path = self.index_path.with_extension("tmp");
+        File::create(&tmp_path)?.write_all(&buf)?;
+        fs::rename(&tmp_path, &self.index_path)?;
+        self.generation = generation;
+        self.offset = buf.len() as u64;
+        self.records = records;
+        Ok(())
+    }
+
+    /// Forget how much of the journal has been read, so that it's replayed from the start next
+    /// time. Used after errors, which leave that unknown.
+    fn invalidate(&mut self) {
+        self.generation = 0;
+        self.offset = 0;
+        self.records = 0;
+        self.pending.clear();
+    }
+}
+
+/// Return `true` if `path` is one of the files that `SharedIndex` keeps in the cache directory,
+/// or a file that's being written to the cache.
+pub fn is_private_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Return a path next to `path` for a file to be written to before it's renamed to `path`.
+pub fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = OsString::from(".");
+    name.push(path.file_name().expect("Bad path?"));
+    name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    pub fn lock(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_EX)
+    }
+
+    pub fn unlock(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_UN)
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::{LockFileEx, UnlockFile};
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, OVERLAPPED};
+
+    pub fn lock(file: &File) -> io::Result<()> {
+        let ret = unsafe {
+            let mut overlapped: OVERLAPPED = mem::zeroed();
+            LockFileEx(
+                file.as_raw_handle() as _,
+                LOCKFILE_EXCLUSIVE_LOCK,
+                0,
+                !0,
+                !0,
+                &mut overlapped,
+            )
+        };
+        if ret == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn unlock(file: &File) -> io::Result<()> {
+        let ret = unsafe { UnlockFile(file.as_raw_handle() as _, 0, 0, !0, !0) };
+        if ret == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Record;
+
+    #[test]
+    fn test_parse_records() {
+        let records = vec![
+            Record::Insert("a/b/ab c".into(), 10),
+            Record::Use("a/b/ab c".into()),
+            Record::Remove("a/b/ab c".into()),
+        ];
+        let mut buf = vec![];
+        for record in &records {
+            record.write_to(&mut buf).unwrap();
+        }
+        let parsed: Vec<_> = std::str::from_utf8(&buf)
+            .unwrap()
+            .lines()
+            .map(|line| Record::parse(line).unwrap())
+            .collect();
+        assert_eq!(parsed, records);
+        assert_eq!(Record::parse("+ ten a/b/ab"), None);
+        assert_eq!(Record::parse("- "), None);
+        assert_eq!(Record::parse("? a/b/ab"), None);
+    }
+}
@@ -1,7 +1,13 @@
-use crate::compiler::ColorMode;
+use crate::compiler::{ColorMode, Explanation};
+use crate::errors::*;
 use crate::server::{DistInfo, ServerInfo};
 use std::ffi::OsString;
 
+/// The version of the `ServerInfo` layout. This must be bumped whenever
+/// `ServerInfo` or `ServerStats` change, so that a client and a server built
+/// from different versions of sccache can tell they don't agree on it.
+pub const STATS_VERSION: u32 = 1;
+
 /// A client request.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
@@ -15,6 +21,8 @@ pub enum Request {
     Shutdown,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// Compute the hash key of a compile without running it.
+    Explain(Explain),
 }
 
 /// A server response.
@@ -23,13 +31,48 @@ pub enum Response {
     /// Response for `Request::Compile`.
     Compile(CompileResponse),
     /// Response for `Request::GetStats`, containing server statistics.
-    Stats(Box<ServerInfo>),
+    Stats(VersionedServerInfo),
     /// Response for `Request::DistStatus`, containing client info.
     DistStatus(DistInfo),
     /// Response for `Request::Shutdown`, containing server statistics.
-    ShuttingDown(Box<ServerInfo>),
+    ShuttingDown(VersionedServerInfo),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::Explain`.
+    Explain(ExplainResponse),
+}
+
+/// Server statistics, serialized separately from the response they're part
+/// of and tagged with `STATS_VERSION`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionedServerInfo {
+    version: u32,
+    data: Vec<u8>,
+}
+
+impl VersionedServerInfo {
+    /// Serialize `info` with the current `STATS_VERSION`.
+    pub fn new(info: &ServerInfo) -> Result<VersionedServerInfo> {
+        Ok(VersionedServerInfo {
+            version: STATS_VERSION,
+            data: bincode::serialize(info)?,
+        })
+    }
+
+    /// Deserialize the statistics, failing if they were written by a server
+    /// using a different `STATS_VERSION`.
+    pub fn into_info(self) -> Result<ServerInfo> {
+        if self.version != STATS_VERSION {
+            bail!(
+                "Server statistics version {} doesn't match the client's version {}, \
+                 the server is probably from a different sccache version. \
+                 Restart it with `sccache --stop-server`",
+                self.version,
+                STATS_VERSION
+            );
+        }
+        bincode::deserialize(&self.data).context("Failed to decode server statistics")
+    }
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -43,6 +86,23 @@ pub enum CompileResponse {
     UnsupportedCompiler(OsString),
 }
 
+/// Possible responses from the server for an `Explain` request.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ExplainResponse {
+    /// The hash key was computed. If it was stored in the cache, the key it
+    /// was stored under is included.
+    Explained {
+        explanation: Explanation,
+        stored: Option<String>,
+    },
+    /// The server could not handle this compilation request, for the given reason.
+    UnhandledCompile(String),
+    /// The compiler was not supported.
+    UnsupportedCompiler(OsString),
+    /// Computing the hash key failed.
+    Failed(String),
+}
+
 /// Information about a finished compile, either from cache or executed locally.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CompileFinished {
@@ -70,3 +130,12 @@ pub struct Compile {
     /// The environment variables present when the compiler was executed, as (var, val).
     pub env_vars: Vec<(OsString, OsString)>,
 }
+
+/// The contents of an explain request from a client.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Explain {
+    /// The compile to explain.
+    pub compile: Compile,
+    /// Whether to store the explanation in the cache alongside the entry.
+    pub store: bool,
+}
@@ -0,0 +1,395 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::SpooledEntry;
+use crate::reapi::{
+    ActionResult, Digest, FindMissingBlobsRequest, FindMissingBlobsResponse,
+    GetActionResultRequest, Message, ReadRequest, ReadResponse, Transport,
+    UpdateActionResultRequest, WriteRequest, WriteResponse,
+};
+use futures::{Async, Future, Poll, Stream};
+use hyper::body::Payload;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, CONTENT_TYPE, TE};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use std::fmt;
+use std::io::Write;
+use uuid::Uuid;
+
+use crate::errors::*;
+
+/// The size of the chunks that blobs are written to `ByteStream.Write` in.
+const WRITE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The largest gRPC message that will be read from a response.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// A gRPC call that finished with a status other than `OK`.
+#[derive(Debug)]
+pub struct GrpcStatus {
+    pub code: u32,
+    pub message: String,
+}
+
+impl GrpcStatus {
+    /// The status code for a resource that doesn't exist.
+    pub const NOT_FOUND: u32 = 5;
+}
+
+impl fmt::Display for GrpcStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gRPC call failed with status {}", self.code)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GrpcStatus {}
+
+fn is_not_found(e: &Error) -> bool {
+    e.downcast_ref::<GrpcStatus>().map(|status| status.code) == Some(GrpcStatus::NOT_FOUND)
+}
+
+/// Check the `grpc-status` in `headers`, returning `None` if there isn't one.
+fn check_status(headers: &HeaderMap) -> Option<Result<()>> {
+    let code = headers.get("grpc-status")?;
+    let code = match code.to_str().ok().and_then(|code| code.parse().ok()) {
+        Some(code) => code,
+        None => return Some(Err(anyhow!("Invalid grpc-status: {:?}", code))),
+    };
+    if code == 0 {
+        return Some(Ok(()));
+    }
+    let message = headers
+        .get("grpc-message")
+        .and_then(|message| message.to_str().ok())
+        .unwrap_or("")
+        .to_owned();
+    Some(Err(GrpcStatus { code, message }.into()))
+}
+
+/// Frame an encoded message for the body of a gRPC request or response.
+pub fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(message.len() + 5);
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// The messages in a gRPC response body. The stream fails once the body is
+/// finished if the call's status isn't `OK`.
+pub struct Messages {
+    body: Body,
+    buf: Vec<u8>,
+    /// Whether the status was already given in the response headers, as
+    /// servers do for responses without messages.
+    status_checked: bool,
+    finished: bool,
+}
+
+impl Messages {
+    /// Take the next complete message out of the buffer, if there is one.
+    fn next_message(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buf.len() < 5 {
+            return Ok(None);
+        }
+        if self.buf[0] != 0 {
+            bail!("Compressed gRPC messages are not supported");
+        }
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            bail!("gRPC message of {} bytes is too large", len);
+        }
+        if self.buf.len() < len + 5 {
+            return Ok(None);
+        }
+        let message = self.buf[5..len + 5].to_vec();
+        self.buf.drain(..len + 5);
+        Ok(Some(message))
+    }
+}
+
+impl Stream for Messages {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, Error> {
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(Async::Ready(Some(message)));
+            }
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            let chunk = self
+                .body
+                .poll_data()
+                .context("failed to read gRPC response")?;
+            match chunk {
+                Async::Ready(Some(chunk)) => self.buf.extend_from_slice(&chunk),
+                Async::Ready(None) => {
+                    let trailers = try_ready!(self
+                        .body
+                        .poll_trailers()
+                        .context("failed to read gRPC response trailers"));
+                    match trailers.as_ref().and_then(check_status) {
+                        Some(result) => result?,
+                        None if self.status_checked => {}
+                        None => bail!("gRPC response without a status"),
+                    }
+                    if !self.buf.is_empty() {
+                        bail!("Truncated gRPC message");
+                    }
+                    self.finished = true;
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// A client for the gRPC services of the Remote Execution API, over
+/// plaintext HTTP/2 or HTTP/2 with TLS.
+pub struct GrpcTransport {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    /// The `http://` or `https://` URL that method paths are appended to.
+    base_url: String,
+    instance_name: String,
+}
+
+impl GrpcTransport {
+    /// Create a new `GrpcTransport` for the server at `endpoint`, which is
+    /// a `grpc://` URL for plaintext HTTP/2 or a `grpcs://` URL for TLS.
+    pub fn new(endpoint: &str, instance_name: &str) -> Result<GrpcTransport> {
+        let base_url = match endpoint.find("://").map(|i| endpoint.split_at(i)) {
+            Some(("grpc", rest)) => format!("http{}", rest.trim_end_matches('/')),
+            Some(("grpcs", rest)) => format!("https{}", rest.trim_end_matches('/')),
+            _ => bail!("Unsupported gRPC endpoint: {}", endpoint),
+        };
+        base_url
+            .parse::<hyper::Uri>()
+            .with_context(|| format!("Invalid REAPI endpoint: {}", endpoint))?;
+
+        let mut http = HttpConnector::new(1);
+        http.enforce_http(false);
+        let tls = native_tls::TlsConnector::builder()
+            .request_alpns(&["h2"])
+            .build()
+            .context("Failed to create TLS connector")?;
+        let client = Client::builder()
+            .http2_only(true)
+            .build(HttpsConnector::from((http, tls)));
+        Ok(GrpcTransport {
+            client,
+            base_url,
+            instance_name: instance_name.to_owned(),
+        })
+    }
+
+    /// Call `method` with the request messages in `body`.
+    fn call(&self, method: &str, body: Body) -> SFuture<Messages> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/{}", self.base_url, method))
+            .header(CONTENT_TYPE, "application/grpc")
+            .header(TE, "trailers")
+            .body(body);
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => return f_err(e),
+        };
+        let method = method.to_owned();
+        Box::new(
+            self.client
+                .request(request)
+                .fwith_context(move || format!("failed gRPC call: {}", method))
+                .and_then(|res| {
+                    if res.status() != StatusCode::OK {
+                        bail!(BadHttpStatusError(res.status()));
+                    }
+                    let status = check_status(res.headers()).transpose()?;
+                    Ok(Messages {
+                        body: res.into_body(),
+                        buf: vec![],
+                        status_checked: status.is_some(),
+                        finished: false,
+                    })
+                }),
+        )
+    }
+
+    /// Call `method` with the request messages in `body`, expecting a
+    /// single response message.
+    fn call_unary<Res: Message + 'static>(&self, method: &str, body: Body) -> SFuture<Res> {
+        Box::new(
+            self.call(method, body)
+                .and_then(|messages| messages.collect())
+                .and_then(|messages| match messages.as_slice() {
+                    [message] => Res::decode(message),
+                    _ => bail!("Expected one gRPC response, got {}", messages.len()),
+                }),
+        )
+    }
+
+    fn unary<Req: Message, Res: Message + 'static>(
+        &self,
+        method: &str,
+        request: &Req,
+    ) -> SFuture<Res> {
+        self.call_unary(method, Body::from(frame(&request.encode())))
+    }
+
+    /// The resource name of `path` in this transport's instance.
+    fn resource_name(&self, path: &str) -> String {
+        if self.instance_name.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}/{}", self.instance_name, path)
+        }
+    }
+}
+
+impl Transport for GrpcTransport {
+    fn get_action_result(&self, action_digest: &Digest) -> SFuture<Option<ActionResult>> {
+        let request = GetActionResultRequest {
+            instance_name: self.instance_name.clone(),
+            action_digest: action_digest.clone(),
+            inline_stdout: true,
+            inline_stderr: true,
+        };
+        Box::new(
+            self.unary(
+                "build.bazel.remote.execution.v2.ActionCache/GetActionResult",
+                &request,
+            )
+            .then(|result| match result {
+                Ok(result) => Ok(Some(result)),
+                Err(ref e) if is_not_found(e) => Ok(None),
+                Err(e) => Err(e),
+            }),
+        )
+    }
+
+    fn update_action_result(&self, action_digest: &Digest, result: ActionResult) -> SFuture<()> {
+        let request = UpdateActionResultRequest {
+            instance_name: self.instance_name.clone(),
+            action_digest: action_digest.clone(),
+            action_result: result,
+        };
+        Box::new(
+            self.unary::<_, ActionResult>(
+                "build.bazel.remote.execution.v2.ActionCache/UpdateActionResult",
+                &request,
+            )
+            .map(|_| ()),
+        )
+    }
+
+    fn find_missing_blobs(&self, digests: Vec<Digest>) -> SFuture<Vec<Digest>> {
+        let request = FindMissingBlobsRequest {
+            instance_name: self.instance_name.clone(),
+            blob_digests: digests,
+        };
+        Box::new(
+            self.unary::<_, FindMissingBlobsResponse>(
+                "build.bazel.remote.execution.v2.ContentAddressableStorage/FindMissingBlobs",
+                &request,
+            )
+            .map(|response| response.missing_blob_digests),
+        )
+    }
+
+    fn read_blob(&self, digest: &Digest) -> SFuture<Option<SpooledEntry>> {
+        let request = ReadRequest {
+            resource_name: self
+                .resource_name(&format!("blobs/{}/{}", digest.hash, digest.size_bytes)),
+            read_offset: 0,
+            read_limit: 0,
+        };
+        let entry = match SpooledEntry::new() {
+            Ok(entry) => entry,
+            Err(e) => return f_err(e),
+        };
+        let body = Body::from(frame(&request.encode()));
+        Box::new(
+            self.call("google.bytestream.ByteStream/Read", body)
+                .and_then(|messages| {
+                    messages.fold(entry, |mut entry, message| -> Result<_> {
+                        entry.write_all(&ReadResponse::decode(&message)?.data)?;
+                        Ok(entry)
+                    })
+                })
+                .then(|result| match result {
+                    Ok(entry) => Ok(Some(entry)),
+                    Err(ref e) if is_not_found(e) => Ok(None),
+                    Err(e) => Err(e),
+                }),
+        )
+    }
+
+    fn write_blob(&self, digest: &Digest, blob: SpooledEntry) -> SFuture<()> {
+        let size = blob.len();
+        let mut resource_name = Some(self.resource_name(&format!(
+            "uploads/{}/blobs/{}/{}",
+            Uuid::new_v4(),
+            digest.hash,
+            digest.size_bytes
+        )));
+        let chunks = match blob.into_chunks(WRITE_CHUNK_SIZE) {
+            Ok(chunks) => chunks,
+            Err(e) => return f_err(e),
+        };
+        // Only the first request needs the resource name, and the last one
+        // finishes the write.
+        let mut offset = 0;
+        let requests = chunks.map(move |chunk| {
+            chunk.map(|data| {
+                let request = WriteRequest {
+                    resource_name: resource_name.take().unwrap_or_default(),
+                    write_offset: offset as i64,
+                    finish_write: offset + data.len() as u64 == size,
+                    data,
+                };
+                offset += request.data.len() as u64;
+                frame(&request.encode())
+            })
+        });
+        let body = Body::wrap_stream(futures::stream::iter_result(requests));
+        Box::new(
+            self.call_unary::<WriteResponse>("google.bytestream.ByteStream/Write", body)
+                .and_then(move |response| {
+                    if response.committed_size != size as i64 {
+                        bail!(
+                            "Blob write committed {} of {} bytes",
+                            response.committed_size,
+                            size
+                        );
+                    }
+                    Ok(())
+                }),
+        )
+    }
+
+    fn location(&self) -> String {
+        let mut location = format!("REAPI over gRPC, endpoint: {}", self.base_url);
+        if !self.instance_name.is_empty() {
+            location.push_str(&format!(", instance: {}", self.instance_name));
+        }
+        location
+    }
+}
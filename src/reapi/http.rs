@@ -0,0 +1,161 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::SpooledEntry;
+use crate::reapi::{ActionResult, Digest, Message, Transport};
+use futures::future::{self, Future};
+use futures::Stream;
+use hyper::{Method, StatusCode};
+use hyperx::header::{ContentLength, ContentType};
+use reqwest::r#async::{Client, Request};
+
+use crate::errors::*;
+use crate::util::HeadersExt;
+
+/// A client for the HTTP protocol of bazel-remote and compatible caches,
+/// which stores action results at `/ac/{hash}` and blobs at `/cas/{hash}`.
+pub struct HttpTransport {
+    /// The URL that the `ac/` and `cas/` paths are appended to.
+    base_url: String,
+    client: Client,
+}
+
+impl HttpTransport {
+    /// Create a new `HttpTransport` for the server at `endpoint`.
+    pub fn new(endpoint: &str) -> Result<HttpTransport> {
+        let base_url = format!("{}/", endpoint.trim_end_matches('/'));
+        base_url
+            .parse::<reqwest::Url>()
+            .with_context(|| format!("Invalid REAPI endpoint: {}", base_url))?;
+        let client = Client::builder()
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(HttpTransport { base_url, client })
+    }
+
+    fn request(&self, method: Method, path: &str, digest: &Digest) -> Request {
+        let url = format!("{}{}/{}", self.base_url, path, digest.hash);
+        Request::new(method, url.parse().unwrap())
+    }
+
+    /// GET the resource at `path` for `digest`, returning `None` if the
+    /// server doesn't have it.
+    fn get(&self, path: &str, digest: &Digest) -> SFuture<Option<reqwest::r#async::Decoder>> {
+        let request = self.request(Method::GET, path, digest);
+        let url = request.url().clone();
+        Box::new(
+            self.client
+                .execute(request)
+                .fwith_context(move || format!("failed GET: {}", url))
+                .and_then(|res| match res.status() {
+                    StatusCode::NOT_FOUND => Ok(None),
+                    status if status.is_success() => Ok(Some(res.into_body())),
+                    status => Err(BadHttpStatusError(status).into()),
+                }),
+        )
+    }
+
+    /// PUT `body`, of `len` bytes, at `path` for `digest`.
+    fn put(
+        &self,
+        path: &str,
+        digest: &Digest,
+        len: u64,
+        body: reqwest::r#async::Body,
+    ) -> SFuture<()> {
+        let mut request = self.request(Method::PUT, path, digest);
+        request.headers_mut().set(ContentType::octet_stream());
+        request.headers_mut().set(ContentLength(len));
+        *request.body_mut() = Some(body);
+        let url = request.url().clone();
+        Box::new(
+            self.client
+                .execute(request)
+                .fwith_context(move || format!("failed PUT: {}", url))
+                .and_then(|res| {
+                    if res.status().is_success() {
+                        Ok(())
+                    } else {
+                        Err(BadHttpStatusError(res.status()).into())
+                    }
+                }),
+        )
+    }
+}
+
+impl Transport for HttpTransport {
+    fn get_action_result(&self, action_digest: &Digest) -> SFuture<Option<ActionResult>> {
+        Box::new(
+            self.get("ac", action_digest)
+                .and_then(|body| -> SFuture<_> {
+                    match body {
+                        Some(body) => Box::new(
+                            body.concat2()
+                                .fcontext("failed to read HTTP body")
+                                .and_then(|body| ActionResult::decode(&body).map(Some)),
+                        ),
+                        None => f_ok(None),
+                    }
+                }),
+        )
+    }
+
+    fn update_action_result(&self, action_digest: &Digest, result: ActionResult) -> SFuture<()> {
+        let body = result.encode();
+        self.put("ac", action_digest, body.len() as u64, body.into())
+    }
+
+    fn find_missing_blobs(&self, digests: Vec<Digest>) -> SFuture<Vec<Digest>> {
+        let checks = digests
+            .into_iter()
+            .map(|digest| {
+                let request = self.request(Method::HEAD, "cas", &digest);
+                self.client
+                    .execute(request)
+                    .fcontext("failed to check for blob over HTTP")
+                    .and_then(move |res| match res.status() {
+                        StatusCode::NOT_FOUND => Ok(Some(digest)),
+                        status if status.is_success() => Ok(None),
+                        status => Err(BadHttpStatusError(status).into()),
+                    })
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(checks).map(|missing| missing.into_iter().flatten().collect()))
+    }
+
+    fn read_blob(&self, digest: &Digest) -> SFuture<Option<SpooledEntry>> {
+        Box::new(self.get("cas", digest).and_then(|body| -> SFuture<_> {
+            match body {
+                Some(body) => Box::new(
+                    SpooledEntry::from_stream(body)
+                        .fcontext("failed to read HTTP body")
+                        .map(Some),
+                ),
+                None => f_ok(None),
+            }
+        }))
+    }
+
+    fn write_blob(&self, digest: &Digest, blob: SpooledEntry) -> SFuture<()> {
+        let len = blob.len();
+        match blob.into_stream() {
+            Ok(body) => self.put("cas", digest, len, body.into()),
+            Err(e) => f_err(e),
+        }
+    }
+
+    fn location(&self) -> String {
+        format!("REAPI over HTTP, endpoint: {}", self.base_url)
+    }
+}
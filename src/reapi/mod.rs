@@ -0,0 +1,51 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Clients for caches implementing the Remote Execution API, such as
+//! bazel-remote or BuildBuddy, over either gRPC or the simpler HTTP
+//! protocol with its `/ac/` and `/cas/` paths.
+
+mod grpc;
+mod http;
+mod proto;
+
+pub use crate::reapi::grpc::*;
+pub use crate::reapi::http::*;
+pub use crate::reapi::proto::*;
+
+use crate::cache::SpooledEntry;
+
+use crate::errors::*;
+
+/// A connection to the action cache and content-addressable storage of a
+/// Remote Execution API server.
+pub trait Transport {
+    /// Get the action result stored for `action_digest`, if there is one.
+    fn get_action_result(&self, action_digest: &Digest) -> SFuture<Option<ActionResult>>;
+
+    /// Store `result` as the result of `action_digest`.
+    fn update_action_result(&self, action_digest: &Digest, result: ActionResult) -> SFuture<()>;
+
+    /// Return those of `digests` that the storage doesn't have.
+    fn find_missing_blobs(&self, digests: Vec<Digest>) -> SFuture<Vec<Digest>>;
+
+    /// Read the blob with `digest`, if the storage has it.
+    fn read_blob(&self, digest: &Digest) -> SFuture<Option<SpooledEntry>>;
+
+    /// Store `blob`, whose digest is `digest`.
+    fn write_blob(&self, digest: &Digest, blob: SpooledEntry) -> SFuture<()>;
+
+    /// A description of the server.
+    fn location(&self) -> String;
+}
@@ -0,0 +1,610 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The messages of the Remote Execution API that sccache uses, with just
+//! enough of the protobuf wire format to encode and decode them.
+//!
+//! Fields that sccache has no use for are skipped when decoding, as the
+//! protobuf rules for unknown fields allow.
+
+use sha2::{Digest as _, Sha256};
+use std::fmt::Write as _;
+use std::io::Read;
+
+use crate::errors::*;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LENGTH_DELIMITED: u32 = 2;
+const WIRE_FIXED32: u32 = 5;
+
+/// A protobuf message.
+pub trait Message: Default + Sized {
+    /// Write the fields of this message to `encoder`.
+    fn encode_fields(&self, encoder: &mut Encoder);
+
+    /// Update this message with the decoded field numbered `field`.
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()>;
+
+    /// Encode this message in the protobuf wire format.
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        self.encode_fields(&mut encoder);
+        encoder.buf
+    }
+
+    /// Decode a message from the protobuf wire format.
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        let mut message = Self::default();
+        while !buf.is_empty() {
+            let tag = read_varint(&mut buf)?;
+            let field = (tag >> 3) as u32;
+            let value = match tag as u32 & 7 {
+                WIRE_VARINT => Value::Varint(read_varint(&mut buf)?),
+                WIRE_FIXED64 => {
+                    take(&mut buf, 8)?;
+                    Value::Fixed
+                }
+                WIRE_LENGTH_DELIMITED => {
+                    let len = read_varint(&mut buf)?;
+                    if len > buf.len() as u64 {
+                        bail!("Truncated protobuf message");
+                    }
+                    Value::Bytes(take(&mut buf, len as usize)?)
+                }
+                WIRE_FIXED32 => {
+                    take(&mut buf, 4)?;
+                    Value::Fixed
+                }
+                wire_type => bail!("Unsupported protobuf wire type {}", wire_type),
+            };
+            message.merge_field(field, value)?;
+        }
+        Ok(message)
+    }
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *take(buf, 1)?.first().unwrap();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Invalid protobuf varint")
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        bail!("Truncated protobuf message");
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+/// The value of a decoded field.
+pub enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// A fixed-size field, which none of the messages use.
+    Fixed,
+}
+
+impl<'a> Value<'a> {
+    fn varint(&self) -> Result<u64> {
+        match *self {
+            Value::Varint(value) => Ok(value),
+            _ => bail!("Expected a protobuf varint field"),
+        }
+    }
+
+    fn bytes(&self) -> Result<&'a [u8]> {
+        match *self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => bail!("Expected a length-delimited protobuf field"),
+        }
+    }
+
+    fn string(&self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).context("Invalid protobuf string")
+    }
+
+    fn message<M: Message>(&self) -> Result<M> {
+        M::decode(self.bytes()?)
+    }
+}
+
+/// Encodes the fields of a message. Fields with default values are omitted,
+/// except for embedded messages.
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn tag(&mut self, field: u32, wire_type: u32) {
+        self.varint_value(u64::from(field << 3 | wire_type));
+    }
+
+    fn varint_value(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn varint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.tag(field, WIRE_VARINT);
+            self.varint_value(value);
+        }
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            self.length_delimited(field, bytes);
+        }
+    }
+
+    fn length_delimited(&mut self, field: u32, bytes: &[u8]) {
+        self.tag(field, WIRE_LENGTH_DELIMITED);
+        self.varint_value(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn message<M: Message>(&mut self, field: u32, message: &M) {
+        self.length_delimited(field, &message.encode());
+    }
+}
+
+/// The digest of a blob: the lowercase hex SHA-256 of its contents, and
+/// its size.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Digest {
+    pub hash: String,
+    pub size_bytes: i64,
+}
+
+impl Digest {
+    /// Compute the digest of `bytes`.
+    pub fn of(bytes: &[u8]) -> Digest {
+        Digest {
+            hash: hex(&Sha256::digest(bytes)),
+            size_bytes: bytes.len() as i64,
+        }
+    }
+
+    /// Compute the digest of everything read from `reader`.
+    pub fn of_reader<R: Read>(mut reader: R) -> Result<Digest> {
+        let mut hasher = Sha256::new();
+        let mut size_bytes = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size_bytes += n as i64;
+        }
+        Ok(Digest {
+            hash: hex(&hasher.finalize()),
+            size_bytes,
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(s, "{:02x}", byte).unwrap();
+    }
+    s
+}
+
+impl Message for Digest {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(1, self.hash.as_bytes());
+        encoder.varint(2, self.size_bytes as u64);
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            1 => self.hash = value.string()?,
+            2 => self.size_bytes = value.varint()? as i64,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// An output file of an action.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutputFile {
+    pub path: String,
+    pub digest: Option<Digest>,
+    pub is_executable: bool,
+    /// The contents of the file, if they were inlined.
+    pub contents: Vec<u8>,
+    /// The permission bits of the file, from its `NodeProperties`.
+    pub unix_mode: Option<u32>,
+}
+
+impl Message for OutputFile {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(1, self.path.as_bytes());
+        if let Some(ref digest) = self.digest {
+            encoder.message(2, digest);
+        }
+        encoder.varint(4, self.is_executable as u64);
+        encoder.bytes(5, &self.contents);
+        if self.unix_mode.is_some() {
+            let properties = NodeProperties {
+                unix_mode: self.unix_mode,
+            };
+            encoder.message(7, &properties);
+        }
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            1 => self.path = value.string()?,
+            2 => self.digest = Some(value.message()?),
+            4 => self.is_executable = value.varint()? != 0,
+            5 => self.contents = value.bytes()?.to_vec(),
+            7 => self.unix_mode = value.message::<NodeProperties>()?.unix_mode,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The `NodeProperties` of an output file, of which only the mode is used.
+#[derive(Default)]
+struct NodeProperties {
+    unix_mode: Option<u32>,
+}
+
+impl Message for NodeProperties {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        if let Some(unix_mode) = self.unix_mode {
+            encoder.message(3, &UInt32Value(unix_mode));
+        }
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        if field == 3 {
+            self.unix_mode = Some(value.message::<UInt32Value>()?.0);
+        }
+        Ok(())
+    }
+}
+
+/// The `google.protobuf.UInt32Value` wrapper.
+#[derive(Default)]
+struct UInt32Value(u32);
+
+impl Message for UInt32Value {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.varint(1, u64::from(self.0));
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        if field == 1 {
+            self.0 = value.varint()? as u32;
+        }
+        Ok(())
+    }
+}
+
+/// The result of an action, as stored in the action cache.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ActionResult {
+    pub output_files: Vec<OutputFile>,
+    pub exit_code: i32,
+    pub stdout_raw: Vec<u8>,
+    pub stdout_digest: Option<Digest>,
+    pub stderr_raw: Vec<u8>,
+    pub stderr_digest: Option<Digest>,
+}
+
+impl Message for ActionResult {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        for file in &self.output_files {
+            encoder.message(2, file);
+        }
+        // Negative int32 values are sign-extended to 64 bits.
+        encoder.varint(4, i64::from(self.exit_code) as u64);
+        encoder.bytes(5, &self.stdout_raw);
+        if let Some(ref digest) = self.stdout_digest {
+            encoder.message(6, digest);
+        }
+        encoder.bytes(7, &self.stderr_raw);
+        if let Some(ref digest) = self.stderr_digest {
+            encoder.message(8, digest);
+        }
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            2 => self.output_files.push(value.message()?),
+            4 => self.exit_code = value.varint()? as i32,
+            5 => self.stdout_raw = value.bytes()?.to_vec(),
+            6 => self.stdout_digest = Some(value.message()?),
+            7 => self.stderr_raw = value.bytes()?.to_vec(),
+            8 => self.stderr_digest = Some(value.message()?),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A request to `ActionCache.GetActionResult`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GetActionResultRequest {
+    pub instance_name: String,
+    pub action_digest: Digest,
+    pub inline_stdout: bool,
+    pub inline_stderr: bool,
+}
+
+impl Message for GetActionResultRequest {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(1, self.instance_name.as_bytes());
+        encoder.message(2, &self.action_digest);
+        encoder.varint(3, self.inline_stdout as u64);
+        encoder.varint(4, self.inline_stderr as u64);
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            1 => self.instance_name = value.string()?,
+            2 => self.action_digest = value.message()?,
+            3 => self.inline_stdout = value.varint()? != 0,
+            4 => self.inline_stderr = value.varint()? != 0,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A request to `ActionCache.UpdateActionResult`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UpdateActionResultRequest {
+    pub instance_name: String,
+    pub action_digest: Digest,
+    pub action_result: ActionResult,
+}
+
+impl Message for UpdateActionResultRequest {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(1, self.instance_name.as_bytes());
+        encoder.message(2, &self.action_digest);
+        encoder.message(3, &self.action_result);
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            1 => self.instance_name = value.string()?,
+            2 => self.action_digest = value.message()?,
+            3 => self.action_result = value.message()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A request to `ContentAddressableStorage.FindMissingBlobs`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FindMissingBlobsRequest {
+    pub instance_name: String,
+    pub blob_digests: Vec<Digest>,
+}
+
+impl Message for FindMissingBlobsRequest {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(1, self.instance_name.as_bytes());
+        for digest in &self.blob_digests {
+            encoder.message(2, digest);
+        }
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            1 => self.instance_name = value.string()?,
+            2 => self.blob_digests.push(value.message()?),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The response to `ContentAddressableStorage.FindMissingBlobs`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FindMissingBlobsResponse {
+    pub missing_blob_digests: Vec<Digest>,
+}
+
+impl Message for FindMissingBlobsResponse {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        for digest in &self.missing_blob_digests {
+            encoder.message(2, digest);
+        }
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        if field == 2 {
+            self.missing_blob_digests.push(value.message()?);
+        }
+        Ok(())
+    }
+}
+
+/// A request to `ByteStream.Read`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReadRequest {
+    pub resource_name: String,
+    pub read_offset: i64,
+    pub read_limit: i64,
+}
+
+impl Message for ReadRequest {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(1, self.resource_name.as_bytes());
+        encoder.varint(2, self.read_offset as u64);
+        encoder.varint(3, self.read_limit as u64);
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            1 => self.resource_name = value.string()?,
+            2 => self.read_offset = value.varint()? as i64,
+            3 => self.read_limit = value.varint()? as i64,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// One of the responses streamed from `ByteStream.Read`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReadResponse {
+    pub data: Vec<u8>,
+}
+
+impl Message for ReadResponse {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(10, &self.data);
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        if field == 10 {
+            self.data = value.bytes()?.to_vec();
+        }
+        Ok(())
+    }
+}
+
+/// One of the requests streamed to `ByteStream.Write`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WriteRequest {
+    pub resource_name: String,
+    pub write_offset: i64,
+    pub finish_write: bool,
+    pub data: Vec<u8>,
+}
+
+impl Message for WriteRequest {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.bytes(1, self.resource_name.as_bytes());
+        encoder.varint(2, self.write_offset as u64);
+        encoder.varint(3, self.finish_write as u64);
+        encoder.bytes(10, &self.data);
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        match field {
+            1 => self.resource_name = value.string()?,
+            2 => self.write_offset = value.varint()? as i64,
+            3 => self.finish_write = value.varint()? != 0,
+            10 => self.data = value.bytes()?.to_vec(),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The response to `ByteStream.Write`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WriteResponse {
+    pub committed_size: i64,
+}
+
+impl Message for WriteResponse {
+    fn encode_fields(&self, encoder: &mut Encoder) {
+        encoder.varint(1, self.committed_size as u64);
+    }
+
+    fn merge_field(&mut self, field: u32, value: Value<'_>) -> Result<()> {
+        if field == 1 {
+            self.committed_size = value.varint()? as i64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(
+            Digest {
+                hash: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_owned(),
+                size_bytes: 0,
+            },
+            Digest::of(b"")
+        );
+        assert_eq!(Digest::of(b"abc"), Digest::of_reader(&b"abc"[..]).unwrap());
+    }
+
+    #[test]
+    fn test_action_result_round_trip() {
+        let result = ActionResult {
+            output_files: vec![
+                OutputFile {
+                    path: "obj".to_owned(),
+                    digest: Some(Digest::of(b"contents")),
+                    is_executable: true,
+                    contents: vec![],
+                    unix_mode: Some(0o755),
+                },
+                OutputFile {
+                    path: "d".to_owned(),
+                    digest: Some(Digest::of(b"")),
+                    is_executable: false,
+                    contents: vec![],
+                    unix_mode: Some(0),
+                },
+            ],
+            exit_code: -1,
+            stdout_raw: b"out".to_vec(),
+            stdout_digest: None,
+            stderr_raw: vec![],
+            stderr_digest: Some(Digest::of(b"err")),
+        };
+        assert_eq!(result, ActionResult::decode(&result.encode()).unwrap());
+    }
+
+    #[test]
+    fn test_decode_wire_format() {
+        // A `Digest` with hash "ab" and size 300, followed by an unknown
+        // fixed32 field that should be skipped.
+        let bytes = [0x0a, 0x02, b'a', b'b', 0x10, 0xac, 0x02, 0x1d, 1, 2, 3, 4];
+        assert_eq!(
+            Digest {
+                hash: "ab".to_owned(),
+                size_bytes: 300,
+            },
+            Digest::decode(&bytes).unwrap()
+        );
+        assert_eq!(&bytes[..7], &Digest::decode(&bytes).unwrap().encode()[..]);
+        assert!(Digest::decode(&bytes[..5]).is_err());
+    }
+}
@@ -15,7 +15,7 @@
 // For tokio_io::codec::length_delimited::Framed;
 #![allow(deprecated)]
 
-use crate::cache::{storage_from_config, Storage};
+use crate::cache::{storage_from_config, CacheTier, CacheWrite, CacheWriteSkipped, Storage};
 use crate::compiler::{
     get_compiler_info, CacheControl, CompileResult, Compiler, CompilerArguments, CompilerHasher,
     CompilerKind, CompilerProxy, DistType, MissType,
@@ -26,7 +26,10 @@ use crate::config::Config;
 use crate::dist;
 use crate::jobserver::Client;
 use crate::mock_command::{CommandCreatorSync, ProcessCommandCreator};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
+use crate::protocol::{
+    Compile, CompileFinished, CompileResponse, Explain, ExplainResponse, Request, Response,
+    VersionedServerInfo,
+};
 use crate::util;
 #[cfg(feature = "dist-client")]
 use anyhow::Context as _;
@@ -406,7 +409,8 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
     );
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
-        Ok(srv) => {
+        Ok(mut srv) => {
+            srv.set_base_dir(config.base_dir.clone());
             let port = srv.port();
             info!("server started, listening on port {}", port);
             notify_server_startup(&notify, ServerStartup::Ok { port })?;
@@ -478,6 +482,12 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         self.service.storage = storage;
     }
 
+    /// Set the directory under which paths are hashed relative to the
+    /// working directory of a compilation.
+    pub fn set_base_dir(&mut self, base_dir: Option<PathBuf>) {
+        self.service.base_dir = base_dir;
+    }
+
     /// Returns a reference to a thread pool to run work on
     #[allow(dead_code)]
     pub fn pool(&self) -> &ThreadPool {
@@ -653,6 +663,10 @@ struct SccacheService<C: CommandCreatorSync> {
     /// Thread pool to execute work in
     pool: ThreadPool,
 
+    /// Paths under this directory are hashed relative to the working
+    /// directory of a compilation.
+    base_dir: Option<PathBuf>,
+
     /// An object for creating commands.
     ///
     /// This is mostly useful for unit testing, where we
@@ -707,9 +721,16 @@ where
                 self.stats.borrow_mut().compile_requests += 1;
                 return self.handle_compile(compile);
             }
+            Request::Explain(explain) => {
+                debug!("handle_client: explain");
+                self.handle_explain(explain)
+            }
             Request::GetStats => {
                 debug!("handle_client: get_stats");
-                Box::new(self.get_info().map(|i| Response::Stats(Box::new(i))))
+                Box::new(
+                    self.get_info()
+                        .and_then(|i| Ok(Response::Stats(VersionedServerInfo::new(&i)?))),
+                )
             }
             Request::DistStatus => {
                 debug!("handle_client: dist_status");
@@ -718,7 +739,10 @@ where
             Request::ZeroStats => {
                 debug!("handle_client: zero_stats");
                 self.zero_stats();
-                Box::new(self.get_info().map(|i| Response::Stats(Box::new(i))))
+                Box::new(
+                    self.get_info()
+                        .and_then(|i| Ok(Response::Stats(VersionedServerInfo::new(&i)?))),
+                )
             }
             Request::Shutdown => {
                 debug!("handle_client: shutdown");
@@ -728,8 +752,9 @@ where
                     .send(ServerMessage::Shutdown)
                     .then(|_| Ok(()));
                 let info_future = self.get_info();
-                return Box::new(future.join(info_future).map(move |(_, info)| {
-                    Message::WithoutBody(Response::ShuttingDown(Box::new(info)))
+                return Box::new(future.join(info_future).and_then(move |(_, info)| {
+                    let info = VersionedServerInfo::new(&info)?;
+                    Ok(Message::WithoutBody(Response::ShuttingDown(info)))
                 }));
             }
         };
@@ -761,6 +786,7 @@ where
             compilers: Rc::new(RefCell::new(HashMap::new())),
             compiler_proxies: Rc::new(RefCell::new(HashMap::new())),
             pool,
+            base_dir: None,
             creator: C::new(client),
             tx,
             info,
@@ -857,6 +883,108 @@ where
         )
     }
 
+    /// Handle an explain request from a client.
+    ///
+    /// This parses the compiler arguments and generates the hash key as a
+    /// compile would, but doesn't look the key up or run the compiler. If
+    /// requested, the explanation is stored in the cache next to the entry.
+    fn handle_explain(&self, explain: Explain) -> SFuture<Response> {
+        let Explain { compile, store } = explain;
+        let cmd = compile.args;
+        let cwd: PathBuf = compile.cwd.into();
+        let env_vars = compile.env_vars;
+        let me = self.clone();
+
+        Box::new(
+            self.compiler_info(compile.exe.into(), cwd.clone(), &env_vars)
+                .and_then(move |info| me.explain(info, cmd, cwd, env_vars, store))
+                .map(Response::Explain),
+        )
+    }
+
+    /// Generate the hash key of `cmd` run with `compiler`, along with the
+    /// inputs it was computed from.
+    fn explain(
+        &self,
+        compiler: Result<Box<dyn Compiler<C>>>,
+        cmd: Vec<OsString>,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        store: bool,
+    ) -> SFuture<ExplainResponse> {
+        let c = match compiler {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("explain: Unsupported compiler: {}", e);
+                return f_ok(ExplainResponse::UnsupportedCompiler(OsString::from(
+                    e.to_string(),
+                )));
+            }
+        };
+        let hasher = match c.parse_arguments(&cmd, &cwd, self.base_dir.as_deref()) {
+            CompilerArguments::Ok(hasher) => hasher,
+            CompilerArguments::CannotCache(why, extra_info) => {
+                let reason = match extra_info {
+                    Some(extra_info) => format!("cannot cache {} ({})", why, extra_info),
+                    None => format!("cannot cache {}", why),
+                };
+                return f_ok(ExplainResponse::UnhandledCompile(reason));
+            }
+            CompilerArguments::NotCompilation => {
+                return f_ok(ExplainResponse::UnhandledCompile(
+                    "not a compilation".to_owned(),
+                ));
+            }
+        };
+        let storage = self.storage.clone();
+        let result = hasher.explain_hash_key(
+            self.dist_client.get_client(),
+            self.creator.clone(),
+            storage.clone(),
+            cwd,
+            env_vars,
+            self.pool.clone(),
+        );
+        Box::new(result.then(move |res| -> SFuture<ExplainResponse> {
+            let explanation = match res {
+                Ok(explanation) => explanation,
+                Err(e) => return f_ok(ExplainResponse::Failed(format!("{:#}", e))),
+            };
+            if !store {
+                return f_ok(ExplainResponse::Explained {
+                    explanation,
+                    stored: None,
+                });
+            }
+            let key = format!("{}.explain", explanation.key);
+            let text = explanation.to_string();
+            let entry = CacheWrite::new().and_then(|mut entry| {
+                entry.put_object("explanation", &mut io::Cursor::new(text), None)?;
+                Ok(entry)
+            });
+            let put = match entry {
+                Ok(entry) => storage.put(&key, entry),
+                Err(e) => f_err(e),
+            };
+            Box::new(put.then(move |res| {
+                let stored = match res {
+                    Ok(_) => Some(key),
+                    Err(e) => {
+                        warn!(
+                            "Failed to store explanation of {}: {:?}",
+                            explanation.key, e
+                        );
+                        None
+                    }
+                };
+                Ok(ExplainResponse::Explained {
+                    explanation,
+                    stored,
+                })
+            }))
+        }))
+    }
+
     /// Look up compiler info from the cache for the compiler `path`.
     /// If not cached, determine the compiler type and cache the result.
     fn compiler_info(
@@ -1046,7 +1174,7 @@ where
                 debug!("check_compiler: Supported compiler");
                 // Now check that we can handle this compiler with
                 // the provided commandline.
-                match c.parse_arguments(&cmd, &cwd) {
+                match c.parse_arguments(&cmd, &cwd, self.base_dir.as_deref()) {
                     CompilerArguments::Ok(hasher) => {
                         debug!("parse_arguments: Ok: {:?}", cmd);
                         stats.requests_executed += 1;
@@ -1126,9 +1254,14 @@ where
                         CompileResult::Error => {
                             stats.cache_errors.increment(&kind);
                         }
-                        CompileResult::CacheHit(duration) => {
+                        CompileResult::CacheHit(duration, tier) => {
                             stats.cache_hits.increment(&kind);
                             stats.cache_read_hit_duration += duration;
+                            match tier {
+                                Some(CacheTier::Local) => stats.local_tier_cache_hits += 1,
+                                Some(CacheTier::Remote) => stats.remote_tier_cache_hits += 1,
+                                None => {}
+                            }
                         }
                         CompileResult::CacheMiss(miss_type, dist_type, duration, future) => {
                             match dist_type {
@@ -1152,6 +1285,9 @@ where
                                 MissType::CacheReadError => {
                                     stats.cache_errors.increment(&kind);
                                 }
+                                MissType::CorruptEntry => {
+                                    stats.cache_corrupt_entries += 1;
+                                }
                             }
                             stats.cache_misses.increment(&kind);
                             stats.cache_read_miss_duration += duration;
@@ -1224,6 +1360,10 @@ where
             let me = me.clone();
             let cache_write = cache_write.then(move |result| {
                 match result {
+                    Err(ref e) if e.downcast_ref::<CacheWriteSkipped>().is_some() => {
+                        debug!("Cache write skipped: {}", e);
+                        me.stats.borrow_mut().cache_writes_skipped += 1;
+                    }
                     Err(e) => {
                         debug!("Error executing cache write: {}", e);
                         me.stats.borrow_mut().cache_write_errors += 1;
@@ -1235,8 +1375,11 @@ where
                             info.object_file_pretty,
                             util::fmt_duration_as_secs(&info.duration)
                         );
-                        me.stats.borrow_mut().cache_writes += 1;
-                        me.stats.borrow_mut().cache_write_duration += info.duration;
+                        let mut stats = me.stats.borrow_mut();
+                        stats.cache_writes += 1;
+                        stats.cache_write_duration += info.duration;
+                        stats.cache_write_uncompressed_size += info.uncompressed_size;
+                        stats.cache_write_compressed_size += info.compressed_size;
                     }
 
                     Ok(None) => {}
@@ -1297,10 +1440,16 @@ pub struct ServerStats {
     pub cache_hits: PerLanguageCount,
     /// The count of cache misses for handled compile requests (per language).
     pub cache_misses: PerLanguageCount,
+    /// The count of cache hits served by the local tier of a tiered cache.
+    pub local_tier_cache_hits: u64,
+    /// The count of cache hits served by the remote tier of a tiered cache.
+    pub remote_tier_cache_hits: u64,
     /// The count of cache misses because the cache took too long to respond.
     pub cache_timeouts: u64,
     /// The count of errors reading cache entries.
     pub cache_read_errors: u64,
+    /// The count of cache entries that didn't match their checksums and were removed.
+    pub cache_corrupt_entries: u64,
     /// The count of compilations which were successful but couldn't be cached.
     pub non_cacheable_compilations: u64,
     /// The count of compilations which forcibly ignored the cache.
@@ -1309,8 +1458,14 @@ pub struct ServerStats {
     pub cache_write_errors: u64,
     /// The number of successful cache writes.
     pub cache_writes: u64,
+    /// The count of cache writes skipped because the cache is read-only.
+    pub cache_writes_skipped: u64,
     /// The total time spent writing cache entries.
     pub cache_write_duration: Duration,
+    /// The total size of the objects of cache entries written, before compression.
+    pub cache_write_uncompressed_size: u64,
+    /// The total size of the objects of cache entries written, after compression.
+    pub cache_write_compressed_size: u64,
     /// The total time spent reading cache hits.
     pub cache_read_hit_duration: Duration,
     /// The total time spent reading cache misses.
@@ -1356,13 +1511,19 @@ impl Default for ServerStats {
             cache_errors: PerLanguageCount::new(),
             cache_hits: PerLanguageCount::new(),
             cache_misses: PerLanguageCount::new(),
+            local_tier_cache_hits: u64::default(),
+            remote_tier_cache_hits: u64::default(),
             cache_timeouts: u64::default(),
             cache_read_errors: u64::default(),
+            cache_corrupt_entries: u64::default(),
             non_cacheable_compilations: u64::default(),
             forced_recaches: u64::default(),
             cache_write_errors: u64::default(),
             cache_writes: u64::default(),
+            cache_writes_skipped: u64::default(),
             cache_write_duration: Duration::new(0, 0),
+            cache_write_uncompressed_size: u64::default(),
+            cache_write_compressed_size: u64::default(),
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
             compile_fails: u64::default(),
@@ -1417,11 +1578,29 @@ impl ServerStats {
             "Compile requests executed"
         );
         set_lang_stat!(stats_vec, self.cache_hits, "Cache hits");
+        if self.local_tier_cache_hits + self.remote_tier_cache_hits > 0 {
+            set_stat!(
+                stats_vec,
+                self.local_tier_cache_hits,
+                "Cache hits (local tier)"
+            );
+            set_stat!(
+                stats_vec,
+                self.remote_tier_cache_hits,
+                "Cache hits (remote tier)"
+            );
+        }
         set_lang_stat!(stats_vec, self.cache_misses, "Cache misses");
         set_stat!(stats_vec, self.cache_timeouts, "Cache timeouts");
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
+        set_stat!(
+            stats_vec,
+            self.cache_corrupt_entries,
+            "Corrupt cache entries"
+        );
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
+        set_stat!(stats_vec, self.cache_writes_skipped, "Cache writes skipped");
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
         set_lang_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(
@@ -1450,6 +1629,15 @@ impl ServerStats {
             self.cache_writes,
             "Average cache write"
         );
+        if self.cache_write_compressed_size > 0 {
+            let ratio =
+                self.cache_write_uncompressed_size as f64 / self.cache_write_compressed_size as f64;
+            set_stat!(
+                stats_vec,
+                format!("{:.2}", ratio),
+                "Cache compression ratio"
+            );
+        }
         set_duration_stat!(
             stats_vec,
             self.cache_read_miss_duration,
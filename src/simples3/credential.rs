@@ -24,6 +24,7 @@ use std::time::Duration as StdDuration;
 use tokio_timer::Timeout;
 
 use crate::errors::*;
+use crate::simples3::sts::Sts;
 use crate::util::RequestExt;
 
 /// AWS API access credentials, including access key, secret key, token (for IAM profiles), and
@@ -136,7 +137,9 @@ fn credentials_from_environment() -> Result<AwsCredentials> {
 pub struct ProfileProvider {
     credentials: Option<AwsCredentials>,
     file_path: PathBuf,
+    config_file_path: Option<PathBuf>,
     profile: String,
+    sts: Sts,
 }
 
 impl ProfileProvider {
@@ -152,7 +155,9 @@ impl ProfileProvider {
         Ok(ProfileProvider {
             credentials: None,
             file_path: profile_location,
+            config_file_path: None,
             profile: "default".to_owned(),
+            sts: Sts::from_env(),
         })
     }
 
@@ -166,7 +171,9 @@ impl ProfileProvider {
         ProfileProvider {
             credentials: None,
             file_path: file_path.into(),
+            config_file_path: None,
             profile: profile.into(),
+            sts: Sts::from_env(),
         }
     }
 
@@ -195,18 +202,128 @@ impl ProfileProvider {
     {
         self.profile = profile.into();
     }
+
+    /// Set the path of a config file, such as `~/.aws/config`, whose profiles
+    /// are merged with those of the same name in the credentials file. The
+    /// settings in the credentials file take precedence.
+    pub fn set_config_file_path<F>(&mut self, config_file_path: F)
+    where
+        F: Into<PathBuf>,
+    {
+        self.config_file_path = Some(config_file_path.into());
+    }
+
+    /// Set the STS used to assume the roles of profiles with a `role_arn`.
+    pub fn set_sts(&mut self, sts: Sts) {
+        self.sts = sts;
+    }
 }
 
 impl ProvideAwsCredentials for ProfileProvider {
     fn credentials(&self) -> SFuture<AwsCredentials> {
-        let result = parse_credentials_file(self.file_path());
-        let result = result
-            .and_then(|mut profiles| profiles.remove(self.profile()).context("profile not found"));
-        Box::new(future::result(result))
+        let mut file_paths = vec![self.file_path()];
+        file_paths.extend(self.config_file_path.as_deref());
+        let profiles = ftry!(parse_credentials_files(&file_paths));
+        profile_credentials(&profiles, self.profile(), &self.sts, 0)
+    }
+}
+
+/// The most `source_profile`s to follow to find a profile with keys.
+const MAX_SOURCE_PROFILES: usize = 5;
+
+/// Get the credentials for the profile `name`. A profile with a `role_arn`
+/// assumes that role using the credentials of its `source_profile`.
+fn profile_credentials(
+    profiles: &HashMap<String, Profile>,
+    name: &str,
+    sts: &Sts,
+    depth: usize,
+) -> SFuture<AwsCredentials> {
+    let profile = ftry!(profiles.get(name).context("profile not found"));
+    let role_arn = match profile.role_arn {
+        Some(ref role_arn) => role_arn.clone(),
+        None => return Box::new(future::result(profile.static_credentials())),
+    };
+    if depth == MAX_SOURCE_PROFILES {
+        return f_err(anyhow!("Too many nested source profiles"));
     }
+    let source_profile = ftry!(profile
+        .source_profile
+        .as_ref()
+        .context("profile has a role_arn but no source_profile"));
+    // A profile can be its own source, using its keys to assume its role.
+    let source_creds: SFuture<AwsCredentials> = if source_profile == name {
+        Box::new(future::result(profile.static_credentials()))
+    } else {
+        profile_credentials(profiles, source_profile, sts, depth + 1)
+    };
+    let sts = sts.clone();
+    let session_name = profile
+        .role_session_name
+        .clone()
+        .unwrap_or_else(default_session_name);
+    let external_id = profile.external_id.clone();
+    Box::new(source_creds.and_then(move |creds| {
+        sts.assume_role(&creds, &role_arn, &session_name, external_id.as_deref())
+    }))
+}
+
+/// The settings of a profile in a credentials file.
+#[derive(Clone, Debug, Default)]
+struct Profile {
+    access_key: Option<String>,
+    secret_key: Option<String>,
+    session_token: Option<String>,
+    role_arn: Option<String>,
+    source_profile: Option<String>,
+    role_session_name: Option<String>,
+    external_id: Option<String>,
 }
 
-fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredentials>> {
+impl Profile {
+    fn static_credentials(&self) -> Result<AwsCredentials> {
+        match (&self.access_key, &self.secret_key) {
+            (Some(access_key), Some(secret_key)) => Ok(AwsCredentials::new(
+                access_key.clone(),
+                secret_key.clone(),
+                self.session_token.clone(),
+                in_ten_minutes(),
+            )),
+            _ => bail!("profile has no aws_access_key_id and aws_secret_access_key"),
+        }
+    }
+}
+
+/// Parse the profiles in `file_paths`, merging the settings of profiles with
+/// the same name. Settings from earlier files take precedence, and files that
+/// can't be read are skipped as long as one of them can be.
+fn parse_credentials_files(file_paths: &[&Path]) -> Result<HashMap<String, Profile>> {
+    let mut profiles = HashMap::new();
+    let mut error = None;
+    let mut read_any = false;
+    for file_path in file_paths {
+        match parse_credentials_file(file_path, &mut profiles) {
+            Ok(()) => read_any = true,
+            Err(e) => error = Some(e),
+        }
+    }
+    if let (false, Some(e)) = (read_any, error) {
+        return Err(e);
+    }
+
+    profiles.retain(|_, profile| {
+        (profile.access_key.is_some() && profile.secret_key.is_some()) || profile.role_arn.is_some()
+    });
+    if profiles.is_empty() {
+        bail!("No credentials found.")
+    }
+
+    Ok(profiles)
+}
+
+/// Add the settings of the profiles in the file at `file_path` to `profiles`,
+/// keeping the settings already there.
+fn parse_credentials_file(file_path: &Path, profiles: &mut HashMap<String, Profile>) -> Result<()> {
     let metadata = fs::metadata(file_path).context("couldn't stat credentials file")?;
     if !metadata.is_file() {
         bail!("Couldn't open file.");
@@ -214,68 +331,110 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
 
     let file = File::open(file_path)?;
 
-    let profile_regex = Regex::new(r"^\[([^\]]+)\]$").unwrap();
-    let mut profiles: HashMap<String, AwsCredentials> = HashMap::new();
-    let mut access_key: Option<String> = None;
-    let mut secret_key: Option<String> = None;
+    // The AWS config file names its profiles `[profile name]`, rather than `[name]`.
+    let profile_regex = Regex::new(r"^\[(?:profile )?([^\]]+)\]$").unwrap();
     let mut profile_name: Option<String> = None;
 
     let file_lines = BufReader::new(&file);
     for line in file_lines.lines() {
-        let unwrapped_line: String = line.unwrap();
+        let unwrapped_line: String = line?;
+        let unwrapped_line = unwrapped_line.trim();
 
         // skip comments
-        if unwrapped_line.starts_with('#') {
+        if unwrapped_line.starts_with('#') || unwrapped_line.starts_with(';') {
             continue;
         }
 
         // handle the opening of named profile blocks
-        if profile_regex.is_match(&unwrapped_line) {
-            if let (Some(profile_name), Some(access_key), Some(secret_key)) =
-                (profile_name, access_key, secret_key)
-            {
-                let creds = AwsCredentials::new(access_key, secret_key, None, in_ten_minutes());
-                profiles.insert(profile_name, creds);
-            }
-
-            access_key = None;
-            secret_key = None;
-
-            let caps = profile_regex.captures(&unwrapped_line).unwrap();
-            profile_name = Some(caps.get(1).unwrap().as_str().to_string());
+        if let Some(caps) = profile_regex.captures(unwrapped_line) {
+            let name = caps.get(1).unwrap().as_str().trim().to_string();
+            profiles.entry(name.clone()).or_default();
+            profile_name = Some(name);
             continue;
         }
 
         // otherwise look for key=value pairs we care about
-        let lower_case_line = unwrapped_line.to_ascii_lowercase().to_string();
+        let mut parts = unwrapped_line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+            // we could potentially explode here to indicate that the file is invalid
+            _ => continue,
+        };
+        let profile = match profile_name {
+            Some(ref name) => profiles.get_mut(name).unwrap(),
+            None => continue,
+        };
+        let field = match key.as_str() {
+            "aws_access_key_id" => &mut profile.access_key,
+            "aws_secret_access_key" => &mut profile.secret_key,
+            "aws_session_token" => &mut profile.session_token,
+            "role_arn" => &mut profile.role_arn,
+            "source_profile" => &mut profile.source_profile,
+            "role_session_name" => &mut profile.role_session_name,
+            "external_id" => &mut profile.external_id,
+            _ => continue,
+        };
+        field.get_or_insert_with(|| value.to_string());
+    }
 
-        if lower_case_line.contains("aws_access_key_id") && access_key.is_none() {
-            let v: Vec<&str> = unwrapped_line.split('=').collect();
-            if !v.is_empty() {
-                access_key = Some(v[1].trim_matches(' ').to_string());
-            }
-        } else if lower_case_line.contains("aws_secret_access_key") && secret_key.is_none() {
-            let v: Vec<&str> = unwrapped_line.split('=').collect();
-            if !v.is_empty() {
-                secret_key = Some(v[1].trim_matches(' ').to_string());
-            }
-        }
+    Ok(())
+}
+
+/// Provides AWS credentials for a role by exchanging a web identity token,
+/// such as a Kubernetes service account token, with STS. This is how IAM
+/// roles for EKS service accounts work.
+#[derive(Clone, Debug)]
+pub struct WebIdentityProvider {
+    token_file: Option<PathBuf>,
+    role_arn: Option<String>,
+    session_name: String,
+    sts: Sts,
+}
 
-        // we could potentially explode here to indicate that the file is invalid
+impl WebIdentityProvider {
+    /// Create a new `WebIdentityProvider` for the token file in
+    /// `AWS_WEB_IDENTITY_TOKEN_FILE` and the role in `AWS_ROLE_ARN`.
+    pub fn new() -> WebIdentityProvider {
+        WebIdentityProvider {
+            token_file: var_os("AWS_WEB_IDENTITY_TOKEN_FILE").map(PathBuf::from),
+            role_arn: var("AWS_ROLE_ARN").ok(),
+            session_name: var("AWS_ROLE_SESSION_NAME").unwrap_or_else(|_| default_session_name()),
+            sts: Sts::from_env(),
+        }
     }
 
-    if let (Some(profile_name), Some(access_key), Some(secret_key)) =
-        (profile_name, access_key, secret_key)
+    /// Create a new `WebIdentityProvider` for the given token file and role,
+    /// using the given STS.
+    pub fn with_configuration<F, R>(token_file: F, role_arn: R, sts: Sts) -> WebIdentityProvider
+    where
+        F: Into<PathBuf>,
+        R: Into<String>,
     {
-        let creds = AwsCredentials::new(access_key, secret_key, None, in_ten_minutes());
-        profiles.insert(profile_name, creds);
+        WebIdentityProvider {
+            token_file: Some(token_file.into()),
+            role_arn: Some(role_arn.into()),
+            session_name: default_session_name(),
+            sts,
+        }
     }
+}
 
-    if profiles.is_empty() {
-        bail!("No credentials found.")
+impl ProvideAwsCredentials for WebIdentityProvider {
+    fn credentials(&self) -> SFuture<AwsCredentials> {
+        let (token_file, role_arn) = match (&self.token_file, &self.role_arn) {
+            (Some(token_file), Some(role_arn)) => (token_file, role_arn),
+            _ => {
+                return f_err(anyhow!(
+                    "No AWS_WEB_IDENTITY_TOKEN_FILE and AWS_ROLE_ARN in environment"
+                ))
+            }
+        };
+        // The token is rotated, so read it each time.
+        let token = ftry!(fs::read_to_string(token_file)
+            .with_context(|| format!("couldn't read web identity token {:?}", token_file)));
+        self.sts
+            .assume_role_with_web_identity(role_arn, &self.session_name, token.trim())
     }
-
-    Ok(profiles)
 }
 
 /// Provides AWS credentials from a resource's IAM role.
@@ -475,12 +634,14 @@ impl<P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider<
 ///
 /// 1. Environment variables: `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
 /// 2. AWS credentials file. Usually located at `~/.aws/credentials`.
-/// 3. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
+/// 3. Web identity token: `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN`
+/// 4. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
 ///
 /// If the sources are exhausted without finding credentials, an error is returned.
 #[derive(Clone)]
 pub struct ChainProvider {
     profile_providers: Vec<ProfileProvider>,
+    web_identity_provider: WebIdentityProvider,
 }
 
 impl ProvideAwsCredentials for ChainProvider {
@@ -494,8 +655,15 @@ impl ProvideAwsCredentials for ChainProvider {
             let alternate = provider.credentials();
             creds = Box::new(creds.or_else(|_| alternate));
         }
+        let web_identity_provider = self.web_identity_provider.clone();
         Box::new(
             creds
+                .or_else(move |_| {
+                    web_identity_provider.credentials().map(|c| {
+                        debug!("Using AWS credentials from web identity token");
+                        c
+                    })
+                })
                 .or_else(move |_| {
                     IamProvider::new().credentials().map(|c| {
                         debug!("Using AWS credentials from IAM");
@@ -504,8 +672,9 @@ impl ProvideAwsCredentials for ChainProvider {
                 })
                 .map_err(|_| {
                     anyhow!(
-                    "Couldn't find AWS credentials in environment, credentials file, or IAM role."
-                )
+                        "Couldn't find AWS credentials in environment, credentials file, \
+                         web identity token, or IAM role."
+                    )
                 }),
         )
     }
@@ -516,15 +685,237 @@ impl ChainProvider {
     pub fn new() -> ChainProvider {
         ChainProvider {
             profile_providers: ProfileProvider::new().into_iter().collect(),
+            web_identity_provider: WebIdentityProvider::new(),
         }
     }
 
     /// Create a new `ChainProvider` using the provided `ProfileProvider`s.
     pub fn with_profile_providers(profile_providers: Vec<ProfileProvider>) -> ChainProvider {
-        ChainProvider { profile_providers }
+        ChainProvider {
+            profile_providers,
+            web_identity_provider: WebIdentityProvider::new(),
+        }
     }
 }
 
 fn in_ten_minutes() -> DateTime<offset::Utc> {
     offset::Utc::now() + Duration::seconds(600)
 }
+
+/// A name for role sessions, which shows up in the role's CloudTrail logs.
+fn default_session_name() -> String {
+    format!("sccache-{}", offset::Utc::now().timestamp())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::simples3::sts::authorization;
+    use hyper::service::service_fn;
+    use hyper::{Body, Response, Server, StatusCode};
+    use std::sync::{Arc, Mutex};
+    use tempfile::NamedTempFile;
+    use tokio_compat::runtime::current_thread::Runtime;
+
+    const ROLE_ARN: &str = "arn:aws:iam::123456789012:role/ci";
+
+    /// Serve a stand-in for STS on a local port, and return a client for it.
+    /// It hands out credentials for `ROLE_ARN` to requests with the web
+    /// identity token `token`, or signed with the keys of the `base` profile
+    /// below, and records the bodies of the requests in `requests`.
+    fn serve_sts(runtime: &mut Runtime, requests: &Arc<Mutex<Vec<String>>>) -> Sts {
+        let requests = requests.clone();
+        let make_service = move || {
+            let requests = requests.clone();
+            service_fn(move |req: hyper::Request<Body>| {
+                let requests = requests.clone();
+                let headers = req.headers().clone();
+                req.into_body().concat2().map(move |body| {
+                    let body = String::from_utf8(body.to_vec()).unwrap();
+                    requests.lock().unwrap().push(body.clone());
+                    let header = |name: &str| {
+                        headers
+                            .get(name)
+                            .map(|value| value.to_str().unwrap().to_owned())
+                            .unwrap_or_default()
+                    };
+                    let authorized = if body.contains("Action=AssumeRoleWithWebIdentity") {
+                        body.ends_with("&WebIdentityToken=token")
+                    } else {
+                        let base = AwsCredentials::new("base", "base-secret", None, in_ten_minutes());
+                        let (content_type, host, date) =
+                            (header("Content-Type"), header("Host"), header("X-Amz-Date"));
+                        let expected = authorization(
+                            &base,
+                            "us-east-1",
+                            "sts",
+                            "POST",
+                            "/",
+                            "",
+                            &[
+                                ("content-type", content_type.as_str()),
+                                ("host", host.as_str()),
+                                ("x-amz-date", date.as_str()),
+                            ],
+                            body.as_bytes(),
+                        );
+                        header("Authorization") == expected
+                    };
+                    if !authorized
+                        || !body.contains("&RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fci&")
+                    {
+                        let mut res = Response::new(Body::from(
+                            "<ErrorResponse><Error><Message>Access denied</Message></Error></ErrorResponse>",
+                        ));
+                        *res.status_mut() = StatusCode::FORBIDDEN;
+                        return res;
+                    }
+                    Response::new(Body::from(
+                        "<AssumeRoleResponse><AssumeRoleResult><Credentials>\
+                         <AccessKeyId>role</AccessKeyId>\
+                         <SecretAccessKey>role-secret</SecretAccessKey>\
+                         <SessionToken>role-token</SessionToken>\
+                         <Expiration>2030-01-01T00:00:00Z</Expiration>\
+                         </Credentials></AssumeRoleResult></AssumeRoleResponse>",
+                    ))
+                })
+            })
+        };
+        let server = runtime
+            .block_on(future::lazy(|| {
+                future::ok::<_, ()>(Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service))
+            }))
+            .unwrap();
+        let endpoint = format!("http://{}/", server.local_addr());
+        runtime.spawn(server.map_err(|e| panic!("HTTP server failed: {}", e)));
+        Sts::new(endpoint, "us-east-1")
+    }
+
+    fn credentials_file(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    const CREDENTIALS: &str = "
+# A comment
+[base]
+aws_access_key_id = base
+aws_secret_access_key = base-secret
+
+[profile ci]
+role_arn = arn:aws:iam::123456789012:role/ci
+source_profile = base
+external_id = external
+
+[other]
+region = us-west-2
+";
+
+    #[test]
+    fn test_parse_credentials_file() {
+        let file = credentials_file(CREDENTIALS);
+        let profiles = parse_credentials_files(&[file.path()]).unwrap();
+        let mut names: Vec<_> = profiles.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["base", "ci"]);
+        assert_eq!(profiles["base"].access_key.as_ref().unwrap(), "base");
+        assert_eq!(profiles["base"].secret_key.as_ref().unwrap(), "base-secret");
+        assert_eq!(profiles["ci"].role_arn.as_ref().unwrap(), ROLE_ARN);
+        assert_eq!(profiles["ci"].source_profile.as_ref().unwrap(), "base");
+        assert_eq!(profiles["ci"].external_id.as_ref().unwrap(), "external");
+    }
+
+    #[test]
+    fn test_profile_assume_role() {
+        let mut runtime = Runtime::new().unwrap();
+        let requests = Arc::default();
+        let sts = serve_sts(&mut runtime, &requests);
+        let file = credentials_file(CREDENTIALS);
+
+        let mut provider = ProfileProvider::with_configuration(file.path(), "base");
+        provider.set_sts(sts.clone());
+        let creds = runtime
+            .block_on(future::lazy(|| provider.credentials()))
+            .unwrap();
+        assert_eq!(creds.aws_access_key_id(), "base");
+        assert!(requests.lock().unwrap().is_empty());
+
+        provider.set_profile("ci");
+        let creds = runtime
+            .block_on(future::lazy(|| provider.credentials()))
+            .unwrap();
+        assert_eq!(creds.aws_access_key_id(), "role");
+        assert_eq!(creds.aws_secret_access_key(), "role-secret");
+        assert_eq!(creds.token().as_ref().unwrap(), "role-token");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("Action=AssumeRole&ExternalId=external&"));
+    }
+
+    #[test]
+    fn test_profile_assume_role_from_config_file() {
+        let mut runtime = Runtime::new().unwrap();
+        let requests = Arc::default();
+        let sts = serve_sts(&mut runtime, &requests);
+        // The usual layout: keys in the credentials file, and the profiles
+        // that assume roles in the config file.
+        let credentials = credentials_file(
+            "
+[base]
+aws_access_key_id = base
+aws_secret_access_key = base-secret
+",
+        );
+        let config = credentials_file(
+            "
+[profile base]
+region = us-east-1
+
+[profile ci]
+role_arn = arn:aws:iam::123456789012:role/ci
+source_profile = base
+",
+        );
+
+        let mut provider = ProfileProvider::with_configuration(credentials.path(), "ci");
+        provider.set_config_file_path(config.path());
+        provider.set_sts(sts);
+        let creds = runtime
+            .block_on(future::lazy(|| provider.credentials()))
+            .unwrap();
+        assert_eq!(creds.aws_access_key_id(), "role");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Either file alone is enough for the profiles it has.
+        let mut provider = ProfileProvider::with_configuration("/nonexistent", "base");
+        provider.set_config_file_path(credentials.path());
+        let creds = runtime
+            .block_on(future::lazy(|| provider.credentials()))
+            .unwrap();
+        assert_eq!(creds.aws_access_key_id(), "base");
+    }
+
+    #[test]
+    fn test_web_identity_provider() {
+        let mut runtime = Runtime::new().unwrap();
+        let requests = Arc::default();
+        let sts = serve_sts(&mut runtime, &requests);
+
+        let token_file = credentials_file("token\n");
+        let provider =
+            WebIdentityProvider::with_configuration(token_file.path(), ROLE_ARN, sts.clone());
+        let creds = runtime
+            .block_on(future::lazy(|| provider.credentials()))
+            .unwrap();
+        assert_eq!(creds.aws_access_key_id(), "role");
+        assert_eq!(creds.token().as_ref().unwrap(), "role-token");
+
+        let token_file = credentials_file("other-token");
+        let provider = WebIdentityProvider::with_configuration(token_file.path(), ROLE_ARN, sts);
+        let err = runtime
+            .block_on(future::lazy(|| provider.credentials()))
+            .unwrap_err();
+        assert!(err.to_string().contains("Access denied"));
+    }
+}
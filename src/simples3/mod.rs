@@ -14,6 +14,7 @@
 
 mod credential;
 mod s3;
+mod sts;
 
 pub use crate::simples3::credential::*;
 pub use crate::simples3::s3::*;
@@ -4,19 +4,40 @@
 #[allow(unused_imports, deprecated)]
 use std::ascii::AsciiExt;
 use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use crate::cache::SpooledEntry;
 use crate::simples3::credential::*;
-use futures::{Future, Stream};
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
 use hmac::{Hmac, Mac, NewMac};
 use hyper::header::HeaderValue;
-use hyper::Method;
+use hyper::{HeaderMap, Method, StatusCode};
 use hyperx::header;
-use reqwest::r#async::{Client, Request};
+use reqwest::r#async::{Client, Request, Response};
 use sha1::Sha1;
+use tokio_timer::Delay;
 
 use crate::errors::*;
 use crate::util::HeadersExt;
 
+/// Entries larger than this are uploaded in parts by default.
+pub const DEFAULT_MULTIPART_THRESHOLD: u64 = 16 * 1024 * 1024;
+/// The size of the parts and ranges that large entries are transferred in by default.
+pub const DEFAULT_PART_SIZE: u64 = 8 * 1024 * 1024;
+/// The smallest part size S3 accepts for all but the last part of an upload.
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The number of parts or ranges of an entry to transfer at once.
+const PARALLEL_PARTS: usize = 4;
+/// The number of times to try a request before giving up.
+const REQUEST_ATTEMPTS: u32 = 3;
+/// How long to wait before retrying a request, multiplied by the number of
+/// attempts so far.
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
 /// Whether or not to use SSL.
@@ -49,11 +70,92 @@ fn signature(string_to_sign: &str, signing_key: &str) -> String {
     base64::encode_config(&s, base64::STANDARD)
 }
 
+/// Run the request made by `f`, retrying it a few times if it fails in a way
+/// that a retry might fix. `what` describes the request for logging.
+fn with_retries<T, F>(what: String, mut f: F) -> SFuture<T>
+where
+    T: 'static,
+    F: FnMut() -> SFuture<T> + 'static,
+{
+    Box::new(future::loop_fn(1, move |attempt| {
+        let what = what.clone();
+        f().then(move |result| -> SFuture<Loop<T, u32>> {
+            match result {
+                Ok(t) => f_ok(Loop::Break(t)),
+                Err(e) if attempt < REQUEST_ATTEMPTS && is_retryable(&e) => {
+                    debug!("{} failed, retrying: {:?}", what, e);
+                    let delay = Delay::new(Instant::now() + RETRY_DELAY * attempt);
+                    Box::new(delay.then(move |_| Ok::<_, Error>(Loop::Continue(attempt + 1))))
+                }
+                Err(e) => f_err(e),
+            }
+        })
+    }))
+}
+
+/// Whether a failed request might succeed if it's tried again. Requests that
+/// S3 rejected, such as for objects that don't exist, won't.
+fn is_retryable(e: &Error) -> bool {
+    match e.downcast_ref::<BadHttpStatusError>() {
+        Some(BadHttpStatusError(status)) => status.is_server_error(),
+        None => true,
+    }
+}
+
+/// Find the text of the first `tag` element in an XML response. This is all
+/// we need to read the few fields we use from S3 and STS responses.
+pub(crate) fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let len = xml[start..].find(&close)?;
+    Some(&xml[start..start + len])
+}
+
+/// Get the size of the whole object from the `Content-Range` header of a
+/// ranged response, such as `bytes 0-99/1234`.
+fn content_range_total(headers: &HeaderMap) -> Result<u64> {
+    let range = headers
+        .get("Content-Range")
+        .and_then(|value| value.to_str().ok())
+        .context("Missing `Content-Range` header")?;
+    range
+        .rsplit('/')
+        .next()
+        .and_then(|total| total.parse().ok())
+        .with_context(|| format!("Invalid `Content-Range` header: {}", range))
+}
+
+/// Read `len` bytes of `content` from `offset`, or fewer at the end.
+fn read_part(content: &SpooledEntry, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut reader = content.reader()?;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut part = Vec::with_capacity(len as usize);
+    reader.take(len).read_to_end(&mut part)?;
+    Ok(part)
+}
+
+/// A range of the contents of an object.
+struct ObjectRange {
+    bytes: Vec<u8>,
+    /// The size of the whole object.
+    total: u64,
+    /// The object's entity tag, to check that later ranges are of the same
+    /// version of the object.
+    etag: Option<String>,
+}
+
 /// An S3 bucket.
+#[derive(Clone)]
 pub struct Bucket {
     name: String,
     base_url: String,
     client: Client,
+    /// Entries larger than this are uploaded in parts.
+    multipart_threshold: u64,
+    /// The size of the parts that large entries are uploaded in, and of the
+    /// ranges that entries are downloaded in.
+    part_size: u64,
 }
 
 impl fmt::Display for Bucket {
@@ -63,33 +165,321 @@ impl fmt::Display for Bucket {
 }
 
 impl Bucket {
-    pub fn new(name: &str, endpoint: &str, ssl: Ssl) -> Result<Bucket> {
+    pub fn new(
+        name: &str,
+        endpoint: &str,
+        ssl: Ssl,
+        multipart_threshold: u64,
+        part_size: u64,
+    ) -> Result<Bucket> {
         let base_url = base_url(&endpoint, ssl);
         Ok(Bucket {
             name: name.to_owned(),
             base_url,
             client: Client::new(),
+            multipart_threshold,
+            part_size: part_size.max(1),
         })
     }
 
-    pub fn get(&self, key: &str, creds: Option<&AwsCredentials>) -> SFuture<Vec<u8>> {
+    /// Get the contents of `key`, in ranges of up to the part size that are
+    /// fetched in parallel and retried individually.
+    pub fn get(&self, key: &str, creds: Option<&AwsCredentials>) -> SFuture<SpooledEntry> {
         let url = format!("{}{}", self.base_url, key);
-        debug!("GET {}", url);
-        let url2 = url.clone();
-        let mut request = Request::new(Method::GET, url.parse().unwrap());
-        if let Some(creds) = creds {
-            let mut canonical_headers = String::new();
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let creds = creds.cloned();
+        Box::new(self.get_range(&key, 0, None, creds.as_ref()).and_then(
+            move |first| -> SFuture<SpooledEntry> {
+                let ObjectRange { bytes, total, etag } = first;
+                let mut entry = ftry!(SpooledEntry::new());
+                ftry!(entry.write_all(&bytes));
+                let starts: Vec<u64> = (bytes.len() as u64..total)
+                    .step_by(bucket.part_size as usize)
+                    .collect();
+                let ranges = stream::iter_ok(starts)
+                    .map(move |start| bucket.get_range(&key, start, etag.clone(), creds.as_ref()))
+                    .buffered(PARALLEL_PARTS);
+                Box::new(
+                    ranges
+                        .fold(entry, |mut entry, range| -> Result<_> {
+                            entry.write_all(&range.bytes)?;
+                            Ok(entry)
+                        })
+                        .and_then(move |entry| {
+                            if entry.len() != total {
+                                bail!(format!(
+                                    "Bad HTTP body size read: {}, expected {}",
+                                    entry.len(),
+                                    total
+                                ));
+                            }
+                            info!("Read {} bytes from {}", entry.len(), url);
+                            Ok(entry)
+                        }),
+                )
+            },
+        ))
+    }
 
-            if let Some(token) = creds.token().as_ref().map(|s| s.as_str()) {
+    /// Get up to the part size of the contents of `key` from `start`. If
+    /// `if_match` is set, fail if the object's entity tag doesn't match it.
+    fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        if_match: Option<String>,
+        creds: Option<&AwsCredentials>,
+    ) -> SFuture<ObjectRange> {
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let creds = creds.cloned();
+        let end = start + self.part_size - 1;
+        with_retries(format!("GET {} ({}-{})", key, start, end), move || {
+            let mut request = bucket.request(Method::GET, &key, "", "", creds.as_ref());
+            request.headers_mut().insert(
+                "Range",
+                HeaderValue::from_str(&format!("bytes={}-{}", start, end))
+                    .expect("Invalid `Range` header"),
+            );
+            if let Some(ref etag) = if_match {
                 request.headers_mut().insert(
-                    "x-amz-security-token",
-                    HeaderValue::from_str(token).expect("Invalid `x-amz-security-token` header"),
+                    "If-Match",
+                    HeaderValue::from_str(etag).expect("Invalid `If-Match` header"),
                 );
-                canonical_headers
-                    .push_str(format!("{}:{}\n", "x-amz-security-token", token).as_ref());
+            }
+            let url = request.url().to_string();
+            Box::new(
+                bucket
+                    .client
+                    .execute(request)
+                    .fwith_context(move || format!("failed GET: {}", url))
+                    .and_then(move |res| -> SFuture<ObjectRange> {
+                        let total = match res.status() {
+                            StatusCode::PARTIAL_CONTENT => {
+                                Some(ftry!(content_range_total(res.headers())))
+                            }
+                            // A server that doesn't support ranges sends the
+                            // whole object.
+                            StatusCode::OK if start == 0 => None,
+                            status => return f_err(BadHttpStatusError(status)),
+                        };
+                        let content_length = res
+                            .headers()
+                            .get_hyperx::<header::ContentLength>()
+                            .map(|header::ContentLength(len)| len);
+                        let etag = res
+                            .headers()
+                            .get("ETag")
+                            .and_then(|value| value.to_str().ok())
+                            .map(|etag| etag.to_owned());
+                        Box::new(
+                            res.into_body()
+                                .concat2()
+                                .fcontext("failed to read HTTP body")
+                                .and_then(move |body| {
+                                    let len = body.len() as u64;
+                                    let total = total.unwrap_or(len);
+                                    let expected = content_length
+                                        .unwrap_or_else(|| (end + 1).min(total) - start);
+                                    if len != expected {
+                                        bail!(format!(
+                                            "Bad HTTP body size read: {}, expected {}",
+                                            len, expected
+                                        ));
+                                    }
+                                    Ok(ObjectRange {
+                                        bytes: body.to_vec(),
+                                        total,
+                                        etag,
+                                    })
+                                }),
+                        )
+                    }),
+            )
+        })
+    }
+
+    /// Store `content` at `key`, in parts if it's larger than the multipart
+    /// threshold.
+    pub fn put(&self, key: &str, content: SpooledEntry, creds: &AwsCredentials) -> SFuture<()> {
+        if content.len() > self.multipart_threshold {
+            return self.put_multipart(key, content, creds);
+        }
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let creds = creds.clone();
+        let content = Rc::new(content);
+        with_retries(format!("PUT {}", key), move || {
+            let mut request = bucket.request(
+                Method::PUT,
+                &key,
+                "",
+                "application/octet-stream",
+                Some(&creds),
+            );
+            request
+                .headers_mut()
+                .set(header::ContentLength(content.len()));
+            request.headers_mut().set(header::CacheControl(vec![
+                // Two weeks
+                header::CacheDirective::MaxAge(1_296_000),
+            ]));
+            *request.body_mut() = Some(ftry!(content.stream()).into());
+            Box::new(bucket.send(request).map(|_| ()))
+        })
+    }
+
+    /// Delete the object at `key`.
+    pub fn delete(&self, key: &str, creds: &AwsCredentials) -> SFuture<()> {
+        let request = self.request(Method::DELETE, key, "", "", Some(creds));
+        Box::new(self.send(request).map(|_| ()))
+    }
+
+    fn put_multipart(
+        &self,
+        key: &str,
+        content: SpooledEntry,
+        creds: &AwsCredentials,
+    ) -> SFuture<()> {
+        let part_size = self.part_size;
+        let content = Rc::new(content);
+        Box::new(
+            self.initiate_multipart_upload(key, creds)
+                .and_then(move |upload| {
+                    let parts: Vec<(u32, u64)> = (0..content.len())
+                        .step_by(part_size as usize)
+                        .enumerate()
+                        .map(|(i, offset)| (i as u32 + 1, offset))
+                        .collect();
+                    debug!(
+                        "Uploading {} bytes to {} in {} parts",
+                        content.len(),
+                        upload.key,
+                        parts.len()
+                    );
+                    let upload = Rc::new(upload);
+                    let uploads = {
+                        let upload = upload.clone();
+                        stream::iter_ok(parts)
+                            .map(move |(number, offset)| {
+                                upload.upload_part(number, &content, offset)
+                            })
+                            .buffered(PARALLEL_PARTS)
+                            .collect()
+                    };
+                    let completion = {
+                        let upload = upload.clone();
+                        uploads.and_then(move |etags| upload.complete(etags))
+                    };
+                    completion.or_else(move |e| {
+                        upload.abort().then(move |result| {
+                            if let Err(abort_error) = result {
+                                warn!("Failed to abort S3 upload: {:?}", abort_error);
+                            }
+                            Err(e)
+                        })
+                    })
+                })
+                .fcontext("failed multipart upload"),
+        )
+    }
+
+    fn initiate_multipart_upload(
+        &self,
+        key: &str,
+        creds: &AwsCredentials,
+    ) -> SFuture<MultipartUpload> {
+        let bucket = self.clone();
+        let key = key.to_owned();
+        let creds = creds.clone();
+        with_retries(format!("initiating upload of {}", key), move || {
+            let mut request = bucket.request(
+                Method::POST,
+                &key,
+                "uploads",
+                "application/octet-stream",
+                Some(&creds),
+            );
+            request.headers_mut().set(header::CacheControl(vec![
+                // Two weeks
+                header::CacheDirective::MaxAge(1_296_000),
+            ]));
+            let upload_bucket = bucket.clone();
+            let key = key.clone();
+            let creds = creds.clone();
+            Box::new(
+                bucket
+                    .send(request)
+                    .and_then(|res| {
+                        res.into_body()
+                            .concat2()
+                            .fcontext("failed to read HTTP body")
+                    })
+                    .and_then(move |body| {
+                        let body = String::from_utf8_lossy(&body);
+                        let upload_id =
+                            xml_text(&body, "UploadId").context("S3 didn't return an upload ID")?;
+                        Ok(MultipartUpload {
+                            bucket: upload_bucket,
+                            key,
+                            upload_id: upload_id.to_owned(),
+                            creds,
+                        })
+                    }),
+            )
+        })
+    }
+
+    /// Build a request for `key` with the sub-resource query `subresource`,
+    /// if any, signed with `creds`, if any.
+    fn request(
+        &self,
+        method: Method,
+        key: &str,
+        subresource: &str,
+        content_type: &str,
+        creds: Option<&AwsCredentials>,
+    ) -> Request {
+        let path = if subresource.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}?{}", key, subresource)
+        };
+        let url = format!("{}{}", self.base_url, path);
+        debug!("{} {}", method, url);
+        let verb = method.as_str().to_owned();
+        let mut request = Request::new(method, url.parse().unwrap());
+        if !content_type.is_empty() {
+            request
+                .headers_mut()
+                .set(header::ContentType(content_type.parse().unwrap()));
+        }
+        if let Some(creds) = creds {
+            let mut canonical_headers = String::new();
+            let token = creds.token().as_ref().map(|s| s.as_str());
+            // Keep the list of header values sorted!
+            for (header, maybe_value) in &[("x-amz-security-token", token)] {
+                if let Some(ref value) = maybe_value {
+                    request.headers_mut().insert(
+                        *header,
+                        HeaderValue::from_str(value)
+                            .unwrap_or_else(|_| panic!("Invalid `{}` header", header)),
+                    );
+                    canonical_headers
+                        .push_str(format!("{}:{}\n", header.to_ascii_lowercase(), value).as_ref());
+                }
             }
             let date = chrono::offset::Utc::now().to_rfc2822();
-            let auth = self.auth("GET", &date, key, "", &canonical_headers, "", creds);
+            let auth = self.auth(
+                &verb,
+                &date,
+                &path,
+                "",
+                &canonical_headers,
+                content_type,
+                creds,
+            );
             request.headers_mut().insert(
                 "Date",
                 HeaderValue::from_str(&date).expect("Invalid date header"),
@@ -99,113 +489,33 @@ impl Bucket {
                 HeaderValue::from_str(&auth).expect("Invalid authentication"),
             );
         }
+        request
+    }
 
+    /// Send `request`, failing unless it gets a successful response.
+    fn send(&self, request: Request) -> SFuture<Response> {
+        let description = format!("{} {}", request.method(), request.url());
         Box::new(
             self.client
                 .execute(request)
-                .fwith_context(move || format!("failed GET: {}", url))
-                .and_then(|res| {
-                    if res.status().is_success() {
-                        let content_length = res
-                            .headers()
-                            .get_hyperx::<header::ContentLength>()
-                            .map(|header::ContentLength(len)| len);
-                        Ok((res.into_body(), content_length))
-                    } else {
-                        Err(BadHttpStatusError(res.status()).into())
-                    }
-                })
-                .and_then(|(body, content_length)| {
-                    body.fold(Vec::new(), |mut body, chunk| {
-                        body.extend_from_slice(&chunk);
-                        Ok::<_, reqwest::Error>(body)
-                    })
-                    .fcontext("failed to read HTTP body")
-                    .and_then(move |bytes| {
-                        if let Some(len) = content_length {
-                            if len != bytes.len() as u64 {
-                                bail!(format!(
-                                    "Bad HTTP body size read: {}, expected {}",
-                                    bytes.len(),
-                                    len
-                                ));
-                            } else {
-                                info!("Read {} bytes from {}", bytes.len(), url2);
-                            }
+                .then(move |result| match result {
+                    Ok(res) => {
+                        if res.status().is_success() {
+                            trace!("{} succeeded", description);
+                            Ok(res)
+                        } else {
+                            trace!("{} failed with HTTP status: {}", description, res.status());
+                            Err(BadHttpStatusError(res.status()).into())
                         }
-                        Ok(bytes)
-                    })
+                    }
+                    Err(e) => {
+                        trace!("{} failed with error: {:?}", description, e);
+                        Err(e.into())
+                    }
                 }),
         )
     }
 
-    pub fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials) -> SFuture<()> {
-        let url = format!("{}{}", self.base_url, key);
-        debug!("PUT {}", url);
-        let mut request = Request::new(Method::PUT, url.parse().unwrap());
-
-        let content_type = "application/octet-stream";
-        let date = chrono::offset::Utc::now().to_rfc2822();
-        let mut canonical_headers = String::new();
-        let token = creds.token().as_ref().map(|s| s.as_str());
-        // Keep the list of header values sorted!
-        for (header, maybe_value) in &[("x-amz-security-token", token)] {
-            if let Some(ref value) = maybe_value {
-                request.headers_mut().insert(
-                    *header,
-                    HeaderValue::from_str(value)
-                        .unwrap_or_else(|_| panic!("Invalid `{}` header", header)),
-                );
-                canonical_headers
-                    .push_str(format!("{}:{}\n", header.to_ascii_lowercase(), value).as_ref());
-            }
-        }
-        let auth = self.auth(
-            "PUT",
-            &date,
-            key,
-            "",
-            &canonical_headers,
-            content_type,
-            creds,
-        );
-        request.headers_mut().insert(
-            "Date",
-            HeaderValue::from_str(&date).expect("Invalid date header"),
-        );
-        request
-            .headers_mut()
-            .set(header::ContentType(content_type.parse().unwrap()));
-        request
-            .headers_mut()
-            .set(header::ContentLength(content.len() as u64));
-        request.headers_mut().set(header::CacheControl(vec![
-            // Two weeks
-            header::CacheDirective::MaxAge(1_296_000),
-        ]));
-        request.headers_mut().insert(
-            "Authorization",
-            HeaderValue::from_str(&auth).expect("Invalid authentication"),
-        );
-        *request.body_mut() = Some(content.into());
-
-        Box::new(self.client.execute(request).then(|result| match result {
-            Ok(res) => {
-                if res.status().is_success() {
-                    trace!("PUT succeeded");
-                    Ok(())
-                } else {
-                    trace!("PUT failed with HTTP status: {}", res.status());
-                    Err(BadHttpStatusError(res.status()).into())
-                }
-            }
-            Err(e) => {
-                trace!("PUT failed with error: {:?}", e);
-                Err(e.into())
-            }
-        }))
-    }
-
     // http://docs.aws.amazon.com/AmazonS3/latest/dev/RESTAuthentication.html
     #[allow(clippy::too_many_arguments)]
     fn auth(
@@ -232,9 +542,130 @@ impl Bucket {
     }
 }
 
+/// A multipart upload that has been started, but not yet completed.
+/// http://docs.aws.amazon.com/AmazonS3/latest/dev/mpuoverview.html
+#[derive(Clone)]
+struct MultipartUpload {
+    bucket: Bucket,
+    key: String,
+    upload_id: String,
+    creds: AwsCredentials,
+}
+
+impl MultipartUpload {
+    /// Upload part `number` of `content`, which starts at `offset`, and
+    /// return its number and entity tag.
+    fn upload_part(
+        &self,
+        number: u32,
+        content: &Rc<SpooledEntry>,
+        offset: u64,
+    ) -> SFuture<(u32, String)> {
+        let upload = self.clone();
+        let content = content.clone();
+        with_retries(
+            format!("uploading part {} of {}", number, self.key),
+            move || {
+                let part = ftry!(read_part(&content, offset, upload.bucket.part_size));
+                let subresource = format!("partNumber={}&uploadId={}", number, upload.upload_id);
+                let mut request = upload.bucket.request(
+                    Method::PUT,
+                    &upload.key,
+                    &subresource,
+                    "",
+                    Some(&upload.creds),
+                );
+                request
+                    .headers_mut()
+                    .set(header::ContentLength(part.len() as u64));
+                *request.body_mut() = Some(part.into());
+                Box::new(upload.bucket.send(request).and_then(move |res| {
+                    let etag = res
+                        .headers()
+                        .get("ETag")
+                        .and_then(|value| value.to_str().ok())
+                        .context("S3 didn't return an ETag for an uploaded part")?;
+                    Ok((number, etag.to_owned()))
+                }))
+            },
+        )
+    }
+
+    /// Assemble the uploaded `parts` into the object.
+    fn complete(&self, parts: Vec<(u32, String)>) -> SFuture<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in &parts {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number, etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let upload = self.clone();
+        with_retries(format!("completing upload of {}", self.key), move || {
+            let subresource = format!("uploadId={}", upload.upload_id);
+            let mut request = upload.bucket.request(
+                Method::POST,
+                &upload.key,
+                &subresource,
+                "application/xml",
+                Some(&upload.creds),
+            );
+            request
+                .headers_mut()
+                .set(header::ContentLength(body.len() as u64));
+            *request.body_mut() = Some(body.clone().into());
+            Box::new(
+                upload
+                    .bucket
+                    .send(request)
+                    .and_then(|res| {
+                        res.into_body()
+                            .concat2()
+                            .fcontext("failed to read HTTP body")
+                    })
+                    .and_then(|body| {
+                        // S3 can report an error completing the upload after
+                        // it has already sent a successful status.
+                        let body = String::from_utf8_lossy(&body);
+                        if body.contains("<Error>") {
+                            bail!(
+                                "Failed to complete upload: {}",
+                                xml_text(&body, "Code").unwrap_or("unknown error")
+                            );
+                        }
+                        Ok(())
+                    }),
+            )
+        })
+    }
+
+    /// Abandon the upload, so that S3 frees its parts.
+    fn abort(&self) -> SFuture<()> {
+        let subresource = format!("uploadId={}", self.upload_id);
+        let request = self.bucket.request(
+            Method::DELETE,
+            &self.key,
+            &subresource,
+            "",
+            Some(&self.creds),
+        );
+        Box::new(self.bucket.send(request).map(|_| ()))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use hyper::service::service_fn;
+    use hyper::{Body, Server};
+    use std::collections::{HashMap, HashSet};
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use tokio_compat::runtime::current_thread::Runtime;
+
+    const KEY_ID: &str = "AKIDEXAMPLE";
+    const SECRET: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
 
     #[test]
     fn test_signature() {
@@ -248,4 +679,315 @@ mod test {
             "F9gZMso3+P+QTEyRKQ6qhZ1YM6o="
         );
     }
+
+    /// The contents of a fake S3 bucket.
+    #[derive(Default)]
+    struct FakeS3 {
+        objects: HashMap<String, Vec<u8>>,
+        parts: HashMap<(String, u32), Vec<u8>>,
+        uploads: usize,
+        /// Whether to fail the first attempt at each distinct request.
+        fail_first_attempts: bool,
+        attempted: HashSet<String>,
+        /// A request to always fail.
+        broken: Option<String>,
+        /// The method and URI of each request that was made, in order.
+        requests: Vec<String>,
+    }
+
+    type Fake = Arc<Mutex<FakeS3>>;
+
+    fn query_param(query: &str, name: &str) -> Option<String> {
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(n), Some(value)) if n == name => Some(value.to_owned()),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    fn response(status: StatusCode, body: Vec<u8>) -> hyper::Response<Body> {
+        hyper::Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn handle(
+        fake: &mut FakeS3,
+        method: &Method,
+        path: String,
+        query: Option<String>,
+        headers: &HeaderMap,
+        body: Vec<u8>,
+    ) -> hyper::Response<Body> {
+        let request = match query {
+            Some(ref query) => format!("{} {}?{}", method, path, query),
+            None => format!("{} {}", method, path),
+        };
+        fake.requests.push(request.clone());
+
+        // Check the request is signed for its path and sub-resources.
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|value: &HeaderValue| value.to_str().unwrap().to_owned())
+                .unwrap_or_default()
+        };
+        let resource = request.split_once(' ').unwrap().1;
+        let string_to_sign = format!(
+            "{}\n\n{}\n{}\n{}",
+            method,
+            header("Content-Type"),
+            header("Date"),
+            resource
+        );
+        let expected = format!("AWS {}:{}", KEY_ID, signature(&string_to_sign, SECRET));
+        if header("Authorization") != expected {
+            return response(StatusCode::FORBIDDEN, vec![]);
+        }
+
+        if fake.broken.as_ref() == Some(&request) {
+            return response(StatusCode::SERVICE_UNAVAILABLE, vec![]);
+        }
+        let attempt = format!("{} {}", request, header("Range"));
+        if fake.fail_first_attempts && fake.attempted.insert(attempt) {
+            return response(StatusCode::SERVICE_UNAVAILABLE, vec![]);
+        }
+
+        let query = query.unwrap_or_default();
+        match *method {
+            Method::POST if query == "uploads" => {
+                fake.uploads += 1;
+                let xml = format!(
+                    "<InitiateMultipartUploadResult><UploadId>upload-{}</UploadId>\
+                     </InitiateMultipartUploadResult>",
+                    fake.uploads
+                );
+                response(StatusCode::OK, xml.into_bytes())
+            }
+            Method::POST => {
+                let upload_id = query_param(&query, "uploadId").unwrap();
+                let body = String::from_utf8(body).unwrap();
+                let mut object = vec![];
+                for part in body.split("<PartNumber>").skip(1) {
+                    let number: u32 = part[..part.find('<').unwrap()].parse().unwrap();
+                    match fake.parts.remove(&(upload_id.clone(), number)) {
+                        Some(bytes) => object.extend(bytes),
+                        None => return response(StatusCode::BAD_REQUEST, vec![]),
+                    }
+                }
+                fake.objects.insert(path, object);
+                let xml = "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>";
+                response(StatusCode::OK, xml.as_bytes().to_vec())
+            }
+            Method::PUT if query.is_empty() => {
+                fake.objects.insert(path, body);
+                response(StatusCode::OK, vec![])
+            }
+            Method::PUT => {
+                let upload_id = query_param(&query, "uploadId").unwrap();
+                let number = query_param(&query, "partNumber").unwrap();
+                fake.parts
+                    .insert((upload_id, number.parse().unwrap()), body);
+                let mut res = response(StatusCode::OK, vec![]);
+                res.headers_mut().insert(
+                    "ETag",
+                    HeaderValue::from_str(&format!("\"part-{}\"", number)).unwrap(),
+                );
+                res
+            }
+            Method::DELETE if query.is_empty() => {
+                fake.objects.remove(&path);
+                response(StatusCode::NO_CONTENT, vec![])
+            }
+            Method::DELETE => {
+                let upload_id = query_param(&query, "uploadId").unwrap();
+                fake.parts.retain(|(id, _), _| *id != upload_id);
+                response(StatusCode::NO_CONTENT, vec![])
+            }
+            Method::GET => {
+                let object = match fake.objects.get(&path) {
+                    Some(object) => object,
+                    None => return response(StatusCode::NOT_FOUND, vec![]),
+                };
+                let range = header("Range");
+                let mut bounds = range.trim_start_matches("bytes=").splitn(2, '-');
+                let start: usize = bounds.next().unwrap().parse().unwrap();
+                let end: usize = bounds.next().unwrap().parse().unwrap();
+                let end = end.min(object.len() - 1);
+                let mut res = response(StatusCode::PARTIAL_CONTENT, object[start..=end].to_vec());
+                res.headers_mut().insert(
+                    "Content-Range",
+                    HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, object.len()))
+                        .unwrap(),
+                );
+                res.headers_mut()
+                    .insert("ETag", HeaderValue::from_static("\"object\""));
+                res
+            }
+            _ => response(StatusCode::METHOD_NOT_ALLOWED, vec![]),
+        }
+    }
+
+    /// Serve the fake S3 bucket on a local port, and return its endpoint.
+    fn serve(runtime: &mut Runtime, fake: &Fake) -> String {
+        let fake = fake.clone();
+        let make_service = move || {
+            let fake = fake.clone();
+            service_fn(move |req: hyper::Request<Body>| {
+                let fake = fake.clone();
+                let method = req.method().clone();
+                let path = req.uri().path().to_owned();
+                let query = req.uri().query().map(|q| q.to_owned());
+                let headers = req.headers().clone();
+                req.into_body().concat2().map(move |body| {
+                    let mut fake = fake.lock().unwrap();
+                    handle(&mut fake, &method, path, query, &headers, body.to_vec())
+                })
+            })
+        };
+        let server = runtime
+            .block_on(future::lazy(|| {
+                future::ok::<_, ()>(Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service))
+            }))
+            .unwrap();
+        let endpoint = format!("{}/bucket", server.local_addr());
+        runtime.spawn(server.map_err(|e| panic!("HTTP server failed: {}", e)));
+        endpoint
+    }
+
+    fn creds() -> AwsCredentials {
+        AwsCredentials::new(
+            KEY_ID,
+            SECRET,
+            None,
+            chrono::offset::Utc::now() + chrono::Duration::hours(1),
+        )
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn put(runtime: &mut Runtime, bucket: &Bucket, key: &str, contents: &[u8]) -> Result<()> {
+        let entry = SpooledEntry::from_reader(&mut Cursor::new(contents)).unwrap();
+        runtime.block_on(future::lazy(|| bucket.put(key, entry, &creds())))
+    }
+
+    fn get(runtime: &mut Runtime, bucket: &Bucket, key: &str) -> Result<Vec<u8>> {
+        runtime
+            .block_on(future::lazy(|| bucket.get(key, Some(&creds()))))
+            .and_then(|entry| entry.into_vec())
+    }
+
+    #[test]
+    fn test_put_get() {
+        let mut runtime = Runtime::new().unwrap();
+        let fake = Fake::default();
+        let endpoint = serve(&mut runtime, &fake);
+        let bucket = Bucket::new("bucket", &endpoint, Ssl::No, 1000, 300).unwrap();
+
+        put(&mut runtime, &bucket, "a/small", &contents(1000)).unwrap();
+        assert_eq!(
+            fake.lock().unwrap().requests,
+            vec!["PUT /bucket/a/small".to_owned()]
+        );
+        fake.lock().unwrap().requests.clear();
+
+        // The entry is fetched in ranges of the part size.
+        assert_eq!(
+            get(&mut runtime, &bucket, "a/small").unwrap(),
+            contents(1000)
+        );
+        assert_eq!(fake.lock().unwrap().requests.len(), 4);
+
+        // Misses aren't retried.
+        fake.lock().unwrap().requests.clear();
+        assert!(get(&mut runtime, &bucket, "a/missing").is_err());
+        assert_eq!(fake.lock().unwrap().requests.len(), 1);
+
+        runtime
+            .block_on(future::lazy(|| bucket.delete("a/small", &creds())))
+            .unwrap();
+        assert!(get(&mut runtime, &bucket, "a/small").is_err());
+    }
+
+    #[test]
+    fn test_multipart_put() {
+        let mut runtime = Runtime::new().unwrap();
+        let fake = Fake::default();
+        let endpoint = serve(&mut runtime, &fake);
+        let bucket = Bucket::new("bucket", &endpoint, Ssl::No, 1000, 300).unwrap();
+
+        put(&mut runtime, &bucket, "a/large", &contents(2500)).unwrap();
+        {
+            let fake = fake.lock().unwrap();
+            assert_eq!(fake.objects["/bucket/a/large"], contents(2500));
+            assert!(fake.parts.is_empty());
+            let part_uploads = fake
+                .requests
+                .iter()
+                .filter(|r| r.contains("partNumber="))
+                .count();
+            assert_eq!(part_uploads, 9);
+        }
+        assert_eq!(
+            get(&mut runtime, &bucket, "a/large").unwrap(),
+            contents(2500)
+        );
+    }
+
+    #[test]
+    fn test_retries() {
+        let mut runtime = Runtime::new().unwrap();
+        let fake = Fake::default();
+        fake.lock().unwrap().fail_first_attempts = true;
+        let endpoint = serve(&mut runtime, &fake);
+        let bucket = Bucket::new("bucket", &endpoint, Ssl::No, 1000, 300).unwrap();
+
+        // Every request fails once, so each part and range is retried.
+        put(&mut runtime, &bucket, "a/small", &contents(500)).unwrap();
+        put(&mut runtime, &bucket, "a/large", &contents(2500)).unwrap();
+        assert_eq!(
+            get(&mut runtime, &bucket, "a/small").unwrap(),
+            contents(500)
+        );
+        assert_eq!(
+            get(&mut runtime, &bucket, "a/large").unwrap(),
+            contents(2500)
+        );
+        assert!(fake.lock().unwrap().parts.is_empty());
+    }
+
+    #[test]
+    fn test_multipart_put_aborts() {
+        let mut runtime = Runtime::new().unwrap();
+        let fake = Fake::default();
+        let endpoint = serve(&mut runtime, &fake);
+        let bucket = Bucket::new("bucket", &endpoint, Ssl::No, 1000, 300).unwrap();
+
+        // A part that keeps failing fails the upload, which is aborted.
+        fake.lock().unwrap().broken =
+            Some("PUT /bucket/a/large?partNumber=3&uploadId=upload-1".to_owned());
+        assert!(put(&mut runtime, &bucket, "a/large", &contents(2500)).is_err());
+        let fake = fake.lock().unwrap();
+        assert!(!fake.objects.contains_key("/bucket/a/large"));
+        assert!(fake.parts.is_empty());
+        assert_eq!(
+            fake.requests.last().unwrap(),
+            "DELETE /bucket/a/large?uploadId=upload-1"
+        );
+    }
+
+    #[test]
+    fn test_xml_text() {
+        let xml = "<Result><Bucket>b</Bucket><UploadId>abc.def</UploadId></Result>";
+        assert_eq!(xml_text(xml, "UploadId"), Some("abc.def"));
+        assert_eq!(xml_text(xml, "Key"), None);
+    }
 }
@@ -0,0 +1,344 @@
+// Copyright 2021 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A client for the AWS Security Token Service calls that credential
+//! providers use to assume roles.
+
+use std::env;
+
+use crate::simples3::credential::AwsCredentials;
+use crate::simples3::s3::xml_text;
+use chrono::{offset, DateTime};
+use futures::{Future, Stream};
+use hmac::{Hmac, Mac, NewMac};
+use hyper::header::HeaderValue;
+use hyper::Method;
+use reqwest::r#async::{Client, Request};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+use crate::errors::*;
+
+const STS_VERSION: &str = "2011-06-15";
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// An STS endpoint.
+#[derive(Clone, Debug)]
+pub struct Sts {
+    endpoint: String,
+    region: String,
+}
+
+impl Sts {
+    /// Use the STS at `endpoint`, signing requests for `region`.
+    pub fn new<E, R>(endpoint: E, region: R) -> Sts
+    where
+        E: Into<String>,
+        R: Into<String>,
+    {
+        Sts {
+            endpoint: endpoint.into(),
+            region: region.into(),
+        }
+    }
+
+    /// Use the regional STS for `AWS_REGION` or `AWS_DEFAULT_REGION`, or the
+    /// endpoint in `AWS_ENDPOINT_URL_STS` if it's set.
+    pub fn from_env() -> Sts {
+        let region = env::var("AWS_REGION")
+            .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_owned());
+        let endpoint = env::var("AWS_ENDPOINT_URL_STS").unwrap_or_else(|_| {
+            if region == "us-east-1" {
+                "https://sts.amazonaws.com/".to_owned()
+            } else {
+                format!("https://sts.{}.amazonaws.com/", region)
+            }
+        });
+        Sts::new(endpoint, region)
+    }
+
+    /// Exchange the web identity `token` for temporary credentials for the
+    /// role `role_arn`. This request isn't signed; the token is the proof of
+    /// identity.
+    pub fn assume_role_with_web_identity(
+        &self,
+        role_arn: &str,
+        session_name: &str,
+        token: &str,
+    ) -> SFuture<AwsCredentials> {
+        let body = form(&[
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("RoleArn", role_arn),
+            ("RoleSessionName", session_name),
+            ("Version", STS_VERSION),
+            ("WebIdentityToken", token),
+        ]);
+        self.send(body, None)
+    }
+
+    /// Get temporary credentials for the role `role_arn` using `creds`.
+    pub fn assume_role(
+        &self,
+        creds: &AwsCredentials,
+        role_arn: &str,
+        session_name: &str,
+        external_id: Option<&str>,
+    ) -> SFuture<AwsCredentials> {
+        let mut params = vec![("Action", "AssumeRole")];
+        if let Some(external_id) = external_id {
+            params.push(("ExternalId", external_id));
+        }
+        params.push(("RoleArn", role_arn));
+        params.push(("RoleSessionName", session_name));
+        params.push(("Version", STS_VERSION));
+        self.send(form(&params), Some(creds))
+    }
+
+    fn send(&self, body: String, creds: Option<&AwsCredentials>) -> SFuture<AwsCredentials> {
+        let url: Url = ftry!(self
+            .endpoint
+            .parse()
+            .with_context(|| format!("failed to parse `{}` as url", self.endpoint)));
+        debug!("Attempting to fetch credentials from {}", url);
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return f_err(anyhow!("STS endpoint `{}` has no host", url)),
+        };
+        let path = url.path().to_owned();
+        let mut request = Request::new(Method::POST, url);
+        request
+            .headers_mut()
+            .insert("Content-Type", HeaderValue::from_static(FORM_CONTENT_TYPE));
+        if let Some(creds) = creds {
+            let amz_date = offset::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+            let mut headers: Vec<(&str, &str)> = vec![
+                ("content-type", FORM_CONTENT_TYPE),
+                ("host", &host),
+                ("x-amz-date", &amz_date),
+            ];
+            if let Some(token) = creds.token() {
+                headers.push(("x-amz-security-token", token.as_str()));
+                request.headers_mut().insert(
+                    "x-amz-security-token",
+                    HeaderValue::from_str(token).expect("Invalid `x-amz-security-token` header"),
+                );
+            }
+            let auth = authorization(
+                creds,
+                &self.region,
+                "sts",
+                "POST",
+                &path,
+                "",
+                &headers,
+                body.as_bytes(),
+            );
+            request.headers_mut().insert(
+                "x-amz-date",
+                HeaderValue::from_str(&amz_date).expect("Invalid `x-amz-date` header"),
+            );
+            request.headers_mut().insert(
+                "Authorization",
+                HeaderValue::from_str(&auth).expect("Invalid authentication"),
+            );
+        }
+        *request.body_mut() = Some(body.into());
+
+        Box::new(
+            Client::new()
+                .execute(request)
+                .fcontext("failed to send STS request")
+                .and_then(|res| {
+                    let status = res.status();
+                    res.into_body()
+                        .concat2()
+                        .fcontext("failed to read STS response")
+                        .and_then(move |body| {
+                            let body = String::from_utf8_lossy(&body);
+                            if !status.is_success() {
+                                bail!(
+                                    "STS request failed with {}: {}",
+                                    status,
+                                    xml_text(&body, "Message").unwrap_or("")
+                                );
+                            }
+                            parse_credentials(&body)
+                        })
+                }),
+        )
+    }
+}
+
+/// Read the `Credentials` element of an `AssumeRole` or
+/// `AssumeRoleWithWebIdentity` response.
+fn parse_credentials(xml: &str) -> Result<AwsCredentials> {
+    let field = |name: &str| {
+        xml_text(xml, name).with_context(|| format!("Couldn't find {} in STS response", name))
+    };
+    let expiration: DateTime<offset::Utc> = field("Expiration")?
+        .parse()
+        .context("failed to parse expiration time")?;
+    Ok(AwsCredentials::new(
+        field("AccessKeyId")?,
+        field("SecretAccessKey")?,
+        Some(field("SessionToken")?.to_owned()),
+        expiration,
+    ))
+}
+
+/// Encode `params` as an `application/x-www-form-urlencoded` body.
+fn form(params: &[(&str, &str)]) -> String {
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", uri_encode(name), uri_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Percent-encode everything but the characters that AWS leaves unreserved.
+fn uri_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &byte in s.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::<Sha256>::new_varkey(key).expect("HMAC can take key of any size");
+    hmac.update(data);
+    hmac.finalize().into_bytes().to_vec()
+}
+
+/// Compute the `Authorization` header for a request signed with AWS
+/// Signature Version 4. `headers` must be lowercase and sorted by name.
+/// https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn authorization(
+    creds: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, &str)],
+    payload: &[u8],
+) -> String {
+    let amz_date = headers
+        .iter()
+        .find(|&&(name, _)| name == "x-amz-date")
+        .map(|&(_, value)| value)
+        .expect("Signed requests need an `x-amz-date` header");
+    let date = &amz_date[..8];
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|&(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        canonical_headers,
+        signed_headers,
+        hex(&Sha256::digest(payload))
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = hmac(
+        format!("AWS4{}", creds.aws_secret_access_key()).as_bytes(),
+        date.as_bytes(),
+    );
+    for part in &[region, service, "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        creds.aws_access_key_id(),
+        scope,
+        signed_headers,
+        hex(&hmac(&key, string_to_sign.as_bytes()))
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_authorization() {
+        // The example from the Signature Version 4 documentation.
+        let creds = AwsCredentials::new(
+            "AKIDEXAMPLE",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            None,
+            chrono::offset::Utc::now(),
+        );
+        let auth = authorization(
+            &creds,
+            "us-east-1",
+            "iam",
+            "GET",
+            "/",
+            "Action=ListUsers&Version=2010-05-08",
+            &[
+                (
+                    "content-type",
+                    "application/x-www-form-urlencoded; charset=utf-8",
+                ),
+                ("host", "iam.amazonaws.com"),
+                ("x-amz-date", "20150830T123600Z"),
+            ],
+            b"",
+        );
+        assert_eq!(
+            auth,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+    }
+
+    #[test]
+    fn test_form() {
+        assert_eq!(
+            form(&[
+                ("RoleArn", "arn:aws:iam::123456789012:role/ci"),
+                ("RoleSessionName", "sccache~1 2")
+            ]),
+            "RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fci&RoleSessionName=sccache~1%202"
+        );
+    }
+}
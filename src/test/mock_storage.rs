@@ -21,6 +21,7 @@ use std::time::Duration;
 /// A mock `Storage` implementation.
 pub struct MockStorage {
     gets: RefCell<Vec<SFuture<Cache>>>,
+    removes: RefCell<Vec<String>>,
 }
 
 impl MockStorage {
@@ -28,6 +29,7 @@ impl MockStorage {
     pub fn new() -> MockStorage {
         MockStorage {
             gets: RefCell::new(vec![]),
+            removes: RefCell::new(vec![]),
         }
     }
 
@@ -35,6 +37,11 @@ impl MockStorage {
     pub fn next_get(&self, res: SFuture<Cache>) {
         self.gets.borrow_mut().push(res)
     }
+
+    /// The keys that `Storage::remove` has been called with.
+    pub fn removed(&self) -> Vec<String> {
+        self.removes.borrow().clone()
+    }
 }
 
 impl Storage for MockStorage {
@@ -49,6 +56,10 @@ impl Storage for MockStorage {
     fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
         f_ok(Duration::from_secs(0))
     }
+    fn remove(&self, key: &str) -> SFuture<()> {
+        self.removes.borrow_mut().push(key.to_owned());
+        f_ok(())
+    }
     fn location(&self) -> String {
         "Mock Storage".to_string()
     }
@@ -21,10 +21,10 @@ use futures_03::future::TryFutureExt;
 use futures_03::task;
 use serde::Serialize;
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
+use std::fs::{self, File};
 use std::hash::Hasher;
 use std::io::prelude::*;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::{self, Stdio};
 use std::time;
 use std::time::Duration;
@@ -107,6 +107,60 @@ impl Default for Digest {
     }
 }
 
+/// One input to a hash key, along with its own digest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashInput {
+    /// A description of the input.
+    pub name: String,
+    /// The digest of the input alone.
+    pub digest: String,
+}
+
+/// A `Digest` for computing a hash key that can also record the digest of
+/// each of the key's inputs, to explain how the key was computed.
+#[derive(Clone)]
+pub struct HashKeyDigest {
+    digest: Digest,
+    inputs: Option<Vec<HashInput>>,
+}
+
+impl HashKeyDigest {
+    /// Create a new `HashKeyDigest`, recording its inputs if `explain` is set.
+    pub fn new(explain: bool) -> HashKeyDigest {
+        HashKeyDigest {
+            digest: Digest::new(),
+            inputs: if explain { Some(vec![]) } else { None },
+        }
+    }
+
+    /// Add an input to the key by running `update` on the key's digest.
+    ///
+    /// If inputs are being recorded, `update` is run again on a fresh digest
+    /// to get the digest of this input alone, and `name` is called to
+    /// describe it.
+    pub fn input<N, U>(&mut self, name: N, mut update: U)
+    where
+        N: FnOnce() -> String,
+        U: FnMut(&mut Digest),
+    {
+        update(&mut self.digest);
+        if let Some(ref mut inputs) = self.inputs {
+            let mut digest = Digest::new();
+            update(&mut digest);
+            inputs.push(HashInput {
+                name: name(),
+                digest: digest.finish(),
+            });
+        }
+    }
+
+    /// Return the hash key, and the inputs it was computed from if they
+    /// were recorded.
+    pub fn finish(self) -> (String, Vec<HashInput>) {
+        (self.digest.finish(), self.inputs.unwrap_or_default())
+    }
+}
+
 pub fn hex(bytes: &[u8]) -> String {
     let mut s = String::with_capacity(bytes.len() * 2);
     for &byte in bytes {
@@ -354,6 +408,114 @@ impl<'a> Hasher for HashToDigest<'a> {
     }
 }
 
+/// If `path` is an absolute path under `base_dir`, return it relative to
+/// `cwd`, so that it's the same in every checkout under `base_dir` that has
+/// the same layout.
+pub fn base_dir_relative(path: &Path, base_dir: &Path, cwd: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || !path.starts_with(base_dir) {
+        return None;
+    }
+    let mut path_components = path.components().peekable();
+    let mut cwd_components = cwd.components().peekable();
+    while let (Some(a), Some(b)) = (path_components.peek(), cwd_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        cwd_components.next();
+    }
+    let relative: PathBuf = cwd_components
+        .map(|_| Component::ParentDir)
+        .chain(path_components)
+        .collect();
+    if relative.as_os_str().is_empty() {
+        Some(PathBuf::from("."))
+    } else {
+        Some(relative)
+    }
+}
+
+/// Stands in for the working directory in dependency files stored in the cache.
+const DEPFILE_CWD: &str = "$(SCCACHE_CWD)";
+
+/// Apply `rewrite` to each path in the dependency file `contents`, keeping
+/// the paths it returns `None` for.
+fn rewrite_depfile_paths<F>(contents: &str, rewrite: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(contents.len());
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            out.push(c);
+            continue;
+        }
+        // Collect a whole path, including escaped spaces.
+        let mut word = c.to_string();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() && !(c == ' ' && word.ends_with('\\')) {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+        let (name, suffix) = if word.ends_with(':') {
+            (&word[..word.len() - 1], ":")
+        } else {
+            (&word[..], "")
+        };
+        match rewrite(&name.replace("\\ ", " ")) {
+            Some(name) => {
+                out.push_str(&name.replace(' ', "\\ "));
+                out.push_str(suffix);
+            }
+            None => out.push_str(&word),
+        }
+    }
+    out
+}
+
+/// Rewrite the paths under `base_dir` in the dependency file `contents` to be
+/// relative to `cwd`, so that the file can be stored in the cache and restored
+/// with `restore_depfile` in any checkout under `base_dir`.
+pub fn relativize_depfile(contents: &str, base_dir: &Path, cwd: &Path) -> String {
+    rewrite_depfile_paths(contents, |name| {
+        let relative = base_dir_relative(Path::new(name), base_dir, cwd)?;
+        let mut out = DEPFILE_CWD.to_owned();
+        for component in relative.components() {
+            out.push('/');
+            out.push_str(&component.as_os_str().to_string_lossy());
+        }
+        Some(out)
+    })
+}
+
+/// Rewrite the dependency file at `path`, restored from the cache, so that
+/// the paths `relativize_depfile` made relative are absolute paths under `cwd`.
+pub fn restore_depfile(path: &Path, cwd: &Path) -> Result<()> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read dependency file {}", path.display()))?;
+    let out = rewrite_depfile_paths(&contents, |name| {
+        if !name.starts_with(DEPFILE_CWD) {
+            return None;
+        }
+        let mut path = cwd.to_owned();
+        for component in name[DEPFILE_CWD.len()..].split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    path.pop();
+                }
+                component => path.push(component),
+            }
+        }
+        Some(path.to_string_lossy().into_owned())
+    });
+    fs::write(path, out)
+        .with_context(|| format!("Failed to write dependency file {}", path.display()))
+}
+
 /// Turns a slice of environment var tuples into the type expected by Command::envs.
 pub fn ref_env(env: &[(OsString, OsString)]) -> impl Iterator<Item = (&OsString, &OsString)> {
     env.iter().map(|&(ref k, ref v)| (k, v))
@@ -546,8 +708,9 @@ pub fn daemonize() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::OsStrExt;
+    use super::{base_dir_relative, OsStrExt};
     use std::ffi::{OsStr, OsString};
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn simple_starts_with() {
@@ -575,4 +738,52 @@ mod tests {
         assert_eq!(a.split_prefix("foo2"), None);
         assert_eq!(a.split_prefix("b"), None);
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_base_dir_relative() {
+        let base_dir = Path::new("/ws");
+        let cwd = Path::new("/ws/checkout/build");
+        let relative = |p: &str| base_dir_relative(Path::new(p), base_dir, cwd);
+        assert_eq!(
+            relative("/ws/checkout/src/foo.h"),
+            Some(PathBuf::from("../src/foo.h"))
+        );
+        assert_eq!(relative("/ws/checkout/build/foo.o"), Some("foo.o".into()));
+        assert_eq!(relative("/ws/checkout/build"), Some(".".into()));
+        assert_eq!(
+            relative("/ws/other/foo.h"),
+            Some("../../other/foo.h".into())
+        );
+        assert_eq!(relative("/usr/include/stdio.h"), None);
+        assert_eq!(relative("/wsx/foo.h"), None);
+        assert_eq!(relative("foo.h"), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_relativize_depfile() {
+        use super::{relativize_depfile, restore_depfile};
+        use crate::test::utils::TestFixture;
+        use std::fs;
+
+        let relative = relativize_depfile(
+            "foo.o: ../src/foo.c /ws/a/src/foo.h \\\n /ws/a/src/with\\ space.h \\\n              /usr/include/stdio.h\n/ws/a/src/foo.h:\n",
+            "/ws".as_ref(),
+            "/ws/a/build".as_ref(),
+        );
+        assert_eq!(
+            "foo.o: ../src/foo.c $(SCCACHE_CWD)/../src/foo.h \\\n $(SCCACHE_CWD)/../src/with\\ space.h \\\n              /usr/include/stdio.h\n$(SCCACHE_CWD)/../src/foo.h:\n",
+            relative
+        );
+        // Restoring in another checkout puts the paths under it.
+        let f = TestFixture::new();
+        let depfile = f.tempdir.path().join("foo.d");
+        fs::write(&depfile, &relative).unwrap();
+        restore_depfile(&depfile, "/ws/b/build".as_ref()).unwrap();
+        assert_eq!(
+            "foo.o: ../src/foo.c /ws/b/src/foo.h \\\n /ws/b/src/with\\ space.h \\\n              /usr/include/stdio.h\n/ws/b/src/foo.h:\n",
+            fs::read_to_string(&depfile).unwrap()
+        );
+    }
 }
@@ -138,8 +138,16 @@ pub fn sccache_client_cfg(tmpdir: &Path) -> sccache::config::FileConfig {
             disk: Some(disk_cache),
             gcs: None,
             memcached: None,
+            reapi: None,
             redis: None,
             s3: None,
+            webdav: None,
+            rw_mode: None,
+            content_addressed: None,
+            encryption: None,
+            signing: None,
+            compression_level: None,
+            compression_dictionary: None,
         },
         dist: sccache::config::DistConfig {
             auth: Default::default(), // dangerously_insecure
@@ -149,6 +157,7 @@ pub fn sccache_client_cfg(tmpdir: &Path) -> sccache::config::FileConfig {
             toolchain_cache_size: TC_CACHE_SIZE,
             rewrite_includes_only: false, // TODO
         },
+        base_dir: None,
     }
 }
 #[cfg(feature = "dist-server")]
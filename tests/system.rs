@@ -358,8 +358,36 @@ fn test_compile_with_define(compiler: Compiler, tempdir: &Path) {
         .stderr(predicates::str::contains("warning:").from_utf8().not());
 }
 
+fn test_explain(compiler: Compiler, tempdir: &Path) {
+    let Compiler {
+        name,
+        exe,
+        env_vars,
+    } = compiler;
+    trace!("test_explain: {}", name);
+    copy_to_tempdir(&[INPUT], tempdir);
+
+    let out_file = tempdir.join("explain.o");
+    trace!("explain");
+    sccache_command()
+        .arg("--explain")
+        .args(compile_cmdline(name, &exe, INPUT, "explain.o"))
+        .current_dir(tempdir)
+        .envs(env_vars)
+        .assert()
+        .success()
+        .stdout(
+            predicates::str::contains("preprocessor output")
+                .and(predicates::str::contains("hash key"))
+                .from_utf8(),
+        );
+    // Explaining doesn't run the compiler.
+    assert!(!out_file.exists());
+}
+
 fn run_sccache_command_tests(compiler: Compiler, tempdir: &Path) {
     test_basic_compile(compiler.clone(), tempdir);
+    test_explain(compiler.clone(), tempdir);
     test_compile_with_define(compiler.clone(), tempdir);
     if compiler.name == "cl.exe" {
         test_msvc_deps(compiler.clone(), tempdir);